@@ -0,0 +1,55 @@
+//! cost of a single wakeup in which every trapped client is due a line, against sockets that swallow everything and never send,
+//! both as the concrete stream type and boxed behind `dyn TrappedStream` as a tarpit mixing kinds of connection would hold them
+
+use std::io::empty;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use endlessh_rs::endlessh::{EndlesshOptions, EndlesshStats, Tarpit};
+use endlessh_rs::socket::TrappedStream;
+
+const MESSAGE_DELAY: Duration = Duration::from_secs(10);
+
+fn trapped<S: TrappedStream>(clients: usize, now: &Instant, stream: impl Fn() -> S) -> Tarpit<S> {
+    let options = EndlesshOptions {
+        max_clients: clients,
+        message_delay: MESSAGE_DELAY,
+        ..EndlesshOptions::default()
+    };
+    let mut tarpit = Tarpit::new(options, Arc::new(EndlesshStats::default())).unwrap();
+    for i in 0..clients {
+        let peer = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, (i % 65536) as u16));
+        tarpit.trap(stream(), peer, None, now);
+    }
+    tarpit
+}
+
+fn wakeup(c: &mut Criterion) {
+    bench_wakeup(c, "wakeup", empty);
+    bench_wakeup(c, "wakeup_boxed", || Box::new(empty()) as Box<dyn TrappedStream>);
+}
+
+fn bench_wakeup<S: TrappedStream>(c: &mut Criterion, name: &str, stream: impl Fn() -> S + Copy) {
+    let mut group = c.benchmark_group(name);
+    for clients in [1_000, 10_000, 100_000] {
+        group.throughput(Throughput::Elements(clients as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, &clients| {
+            let mut now = Instant::now();
+            let mut tarpit = trapped(clients, &now, stream);
+            // the first wakeup sends everyone their first line, after that each one is a full round
+            tarpit.wakeup(&now, |_| {});
+            b.iter(|| {
+                now += MESSAGE_DELAY;
+                tarpit.wakeup(&now, |_| {})
+            });
+            tarpit.drain_events().for_each(drop);
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, wakeup);
+criterion_main!(benches);
@@ -0,0 +1,208 @@
+//! bakes the configuration into the binary for the `fixed-config` feature
+//!
+//! the `ENDLESSH_*` variables read at runtime by env-only builds are read here at build time instead, validated, and
+//! written out as constants so the binary does no parsing at all
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+fn var<T: FromStr>(name: &str) -> Option<T> where T::Err: std::fmt::Display {
+    println!("cargo:rerun-if-env-changed={}", name);
+    let value = env::var(name).ok()?;
+    match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => panic!("invalid value \"{}\" for {} - {}", value, name, e),
+    }
+}
+
+fn socket_addr_expr(address: SocketAddr) -> String {
+    match address {
+        SocketAddr::V4(v4) => {
+            let [a, b, c, d] = v4.ip().octets();
+            format!("SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new({}, {}, {}, {}), {}))", a, b, c, d, v4.port())
+        },
+        SocketAddr::V6(v6) => {
+            let segments = v6.ip().segments().map(|s| s.to_string()).join(", ");
+            format!("SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new({}), {}, {}, {}))", segments, v6.port(), v6.flowinfo(), v6.scope_id())
+        },
+    }
+}
+
+/// a comma separated list, for the flags that may be given more than once
+fn var_list<T: FromStr>(name: &str) -> Option<Vec<T>> where T::Err: std::fmt::Display {
+    let value: String = var(name)?;
+    Some(value.split(',').map(|item| match item.trim().parse() {
+        Ok(parsed) => parsed,
+        Err(e) => panic!("invalid value \"{}\" in {} - {}", item, name, e),
+    }).collect())
+}
+
+/// `windows-firewall` to `WindowsFirewall`
+fn kebab_to_camel_case(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |first| first.to_uppercase().chain(chars).collect())
+        })
+        .collect()
+}
+
+/// `<ip>:<port>`, `ip:<ip>:<port>` or `unix:<path>`, as a `BakedListenAddress`
+fn listen_address_expr(address: &str) -> String {
+    if let Some(path) = address.strip_prefix("unix:") {
+        if env::var("CARGO_CFG_UNIX").is_err() {
+            panic!("invalid value \"{}\" in ENDLESSH_SSH_LISTEN_ADDRESS - unix sockets are not supported on the target platform", address);
+        }
+        return format!("BakedListenAddress::Unix({:?})", path);
+    }
+    match address.strip_prefix("ip:").unwrap_or(address).parse() {
+        Ok(ip) => format!("BakedListenAddress::Ip({})", socket_addr_expr(ip)),
+        Err(e) => panic!("invalid value \"{}\" in ENDLESSH_SSH_LISTEN_ADDRESS - {}", address, e),
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_FIXED_CONFIG").is_none() {
+        return;
+    }
+    if env::var_os("CARGO_FEATURE_CLI").is_some() {
+        println!("cargo:warning=fixed-config has no effect while the cli feature is enabled, build with --no-default-features");
+    }
+
+    let mut constants = String::new();
+    let listen_addresses: Option<Vec<String>> = var_list("ENDLESSH_SSH_LISTEN_ADDRESS");
+    let _ = writeln!(constants, "pub const SSH_LISTEN_ADDRESSES: Option<&[BakedListenAddress]> = {};",
+        listen_addresses.map_or("None".to_string(), |addresses| format!("Some(&[{}])", addresses.iter().map(|a| listen_address_expr(a)).collect::<Vec<_>>().join(", "))));
+    let listener_names: Option<Vec<String>> = var_list("ENDLESSH_SSH_LISTENER_NAME");
+    let _ = writeln!(constants, "pub const SSH_LISTENER_NAMES: Option<&[&str]> = {};",
+        listener_names.map_or("None".to_string(), |names| format!("Some(&{:?})", names)));
+    // the variants have commas of their own, so they're separated by semicolons
+    let variants: Option<String> = var("ENDLESSH_SSH_VARIANT");
+    let _ = writeln!(constants, "pub const SSH_VARIANTS: Option<&[&str]> = {};",
+        variants.map_or("None".to_string(), |variants| format!("Some(&{:?})", variants.split(';').collect::<Vec<_>>())));
+    // checked when the options are built, like everything read from a file
+    for (constant, name) in [("ALLOW_CIDRS", "ENDLESSH_ALLOW_CIDR"), ("DENY_CIDRS", "ENDLESSH_DENY_CIDR")] {
+        let cidrs: Option<Vec<String>> = var_list(name);
+        let _ = writeln!(constants, "pub const {}: Option<&[&str]> = {};", constant,
+            cidrs.map_or("None".to_string(), |cidrs| format!("Some(&{:?})", cidrs)));
+    }
+    for (constant, name, ty) in [
+        ("BIND_RETRY", "ENDLESSH_BIND_RETRY", "u32"),
+        ("WAIT_FOR_ADDRESS", "ENDLESSH_WAIT_FOR_ADDRESS", "u64"),
+        ("SSH_BANNER_LINE_LENGTH", "ENDLESSH_SSH_BANNER_LINE_LENGTH", "usize"),
+        ("SSH_BANNER_LINE_LENGTH_MIN", "ENDLESSH_SSH_BANNER_LINE_LENGTH_MIN", "usize"),
+        ("SSH_BANNER_LINE_LENGTH_MAX", "ENDLESSH_SSH_BANNER_LINE_LENGTH_MAX", "usize"),
+        ("SSH_MAX_CLIENTS", "ENDLESSH_SSH_MAX_CLIENTS", "usize"),
+        ("SSH_MAX_CLIENTS_PER_IP", "ENDLESSH_SSH_MAX_CLIENTS_PER_IP", "usize"),
+        ("SSH_MESSAGE_DELAY_MS", "ENDLESSH_SSH_MESSAGE_DELAY_MS", "u64"),
+        ("SSH_MESSAGE_DELAY_JITTER_MS", "ENDLESSH_SSH_MESSAGE_DELAY_JITTER_MS", "u64"),
+        ("SSH_ADAPTIVE_DELAY_MAX_MS", "ENDLESSH_SSH_ADAPTIVE_DELAY_MAX_MS", "u64"),
+        ("SSH_STALL_LIMIT", "ENDLESSH_SSH_STALL_LIMIT", "u32"),
+        ("SSH_ZERO_WINDOW_TIMEOUT_SECS", "ENDLESSH_SSH_ZERO_WINDOW_TIMEOUT_SECS", "u64"),
+        ("SSH_MAX_TRAP_SECONDS", "ENDLESSH_SSH_MAX_TRAP_SECONDS", "u64"),
+        ("SSH_CHATTY_BYTES", "ENDLESSH_SSH_CHATTY_BYTES", "usize"),
+        ("SSH_REPEAT_OFFENDER_TTL_SECS", "ENDLESSH_SSH_REPEAT_OFFENDER_TTL_SECS", "u64"),
+        ("SSH_REPEAT_OFFENDER_MAX_FACTOR", "ENDLESSH_SSH_REPEAT_OFFENDER_MAX_FACTOR", "u32"),
+        ("SSH_ACCEPT_RATE", "ENDLESSH_SSH_ACCEPT_RATE", "u64"),
+        ("SSH_ACCEPT_BURST", "ENDLESSH_SSH_ACCEPT_BURST", "u32"),
+        ("SSH_RCVBUF_BYTES", "ENDLESSH_SSH_RCVBUF_BYTES", "usize"),
+        ("SSH_SNDBUF_BYTES", "ENDLESSH_SSH_SNDBUF_BYTES", "usize"),
+        ("MAX_SEND_BYTES_PER_SEC", "ENDLESSH_MAX_SEND_BYTES_PER_SEC", "u64"),
+        ("RNG_SEED", "ENDLESSH_RNG_SEED", "u64"),
+        ("BLOCK_AFTER", "ENDLESSH_BLOCK_AFTER", "u32"),
+        ("BLOCK_DURATION_SECS", "ENDLESSH_BLOCK_DURATION_SECS", "u64"),
+        ("LOG_ROTATE_BYTES", "ENDLESSH_LOG_ROTATE_BYTES", "u64"),
+        ("LOG_ROTATE_INTERVAL_SECS", "ENDLESSH_LOG_ROTATE_INTERVAL_SECS", "u64"),
+        ("LOG_ROTATE_KEEP", "ENDLESSH_LOG_ROTATE_KEEP", "usize"),
+        ("REPORT_INTERVAL_SECS", "ENDLESSH_REPORT_INTERVAL_SECS", "u64"),
+        ("SHUTDOWN_LINGER_SECS", "ENDLESSH_SHUTDOWN_LINGER_SECS", "u64"),
+    ] {
+        let value: Option<u64> = var(name);
+        let _ = writeln!(constants, "pub const {}: Option<{}> = {:?};", constant, ty, value);
+    }
+    let trap_probability: Option<f64> = var("ENDLESSH_SSH_TRAP_PROBABILITY");
+    let _ = writeln!(constants, "pub const SSH_TRAP_PROBABILITY: Option<f64> = {:?};", trap_probability);
+    for (constant, name) in [
+        ("SSH_DRY_RUN", "ENDLESSH_SSH_DRY_RUN"),
+        ("PROXY_PROTOCOL", "ENDLESSH_PROXY_PROTOCOL"),
+        ("LOW_MEMORY", "ENDLESSH_LOW_MEMORY"),
+        ("EVENT_LOG_LINES", "ENDLESSH_EVENT_LOG_LINES"),
+    ] {
+        let value: Option<bool> = var(name);
+        let _ = writeln!(constants, "pub const {}: Option<bool> = {:?};", constant, value);
+    }
+    // Args only has the field with the feature
+    if env::var_os("CARGO_FEATURE_SANDBOX").is_some() {
+        let sandbox: Option<bool> = var("ENDLESSH_SANDBOX");
+        let _ = writeln!(constants, "pub const SANDBOX: Option<bool> = {:?};", sandbox);
+    }
+    let chatty_penalty: Option<String> = var("ENDLESSH_SSH_CHATTY_PENALTY");
+    let _ = writeln!(constants, "pub const SSH_CHATTY_PENALTY: Option<ChattyPenalty> = {};", match chatty_penalty.as_deref() {
+        None => "None".to_string(),
+        Some("hold") => "Some(ChattyPenalty::Hold)".to_string(),
+        Some(factor) => match factor.parse::<u32>() {
+            Ok(factor) if factor >= 2 => format!("Some(ChattyPenalty::Slow({}))", factor),
+            _ => panic!("invalid value \"{}\" for ENDLESSH_SSH_CHATTY_PENALTY - expected \"hold\" or a delay factor of at least 2", factor),
+        },
+    });
+    let max_startups: Option<String> = var("ENDLESSH_SSH_MAX_STARTUPS");
+    let _ = writeln!(constants, "pub const SSH_MAX_STARTUPS: Option<MaxStartups> = {};", match max_startups {
+        None => "None".to_string(),
+        Some(v) => match v.split(':').map(str::parse::<usize>).collect::<Result<Vec<_>, _>>().as_deref() {
+            Ok(&[start, rate, full]) if rate <= 100 && start < full => format!("Some(MaxStartups {{ start: {}, rate: {}, full: {} }})", start, rate, full),
+            _ => panic!("invalid value \"{}\" for ENDLESSH_SSH_MAX_STARTUPS - expected start:rate:full with a rate of at most 100 and start below full", v),
+        },
+    });
+    // the terminator's bytes, which fixed_config.rs turns back into the NewLine they name
+    let newline: Option<String> = var("ENDLESSH_SSH_NEWLINE");
+    let _ = writeln!(constants, "pub const SSH_NEWLINE: Option<&[u8]> = {};", match newline.as_deref() {
+        None => "None".to_string(),
+        Some("lf") => "Some(b\"\\n\")".to_string(),
+        Some("crlf") => "Some(b\"\\r\\n\")".to_string(),
+        Some("none") => "Some(b\"\")".to_string(),
+        Some(v) => match v.strip_prefix("hex:").filter(|digits| !digits.is_empty() && digits.len() % 2 == 0 && digits.is_ascii())
+            .and_then(|digits| (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect::<Option<Vec<_>>>())
+        {
+            Some(bytes) if !bytes.windows(4).any(|window| window == b"SSH-") => format!("Some(&{:?})", bytes),
+            _ => panic!("invalid value \"{}\" for ENDLESSH_SSH_NEWLINE - expected lf, crlf, none or hex: and the bytes of a terminator without SSH- in it", v),
+        },
+    });
+    // an unknown name becomes an unknown variant, so it still fails the build
+    for (constant, name, ty) in [
+        ("SSH_BANNER_STYLE", "ENDLESSH_SSH_BANNER_STYLE", "BannerStyle"),
+        ("BLOCK_BACKEND", "ENDLESSH_BLOCK_BACKEND", "FirewallBackend"),
+        ("LOG_LEVEL", "ENDLESSH_LOG_LEVEL", "LevelFilter"),
+        ("LOG_FORMAT", "ENDLESSH_LOG_FORMAT", "LogFormat"),
+        ("LOG_OUTPUT", "ENDLESSH_LOG_OUTPUT", "LogOutput"),
+    ] {
+        let value: Option<String> = var(name);
+        let _ = writeln!(constants, "pub const {}: Option<{}> = {};", constant, ty,
+            value.map_or("None".to_string(), |v| format!("Some({}::{})", ty, kebab_to_camel_case(&v))));
+    }
+    for (constant, name) in [
+        ("STATS_FILE", "ENDLESSH_STATS_FILE"),
+        ("USER", "ENDLESSH_USER"),
+        ("GROUP", "ENDLESSH_GROUP"),
+        ("SSH_BANNER_WORDLIST", "ENDLESSH_SSH_BANNER_WORDLIST"),
+        ("SSH_BANNER_TEMPLATE", "ENDLESSH_SSH_BANNER_TEMPLATE"),
+        ("ALLOW_CIDR_FILE", "ENDLESSH_ALLOW_CIDR_FILE"),
+        ("DENY_CIDR_FILE", "ENDLESSH_DENY_CIDR_FILE"),
+        ("LOG_FILE", "ENDLESSH_LOG_FILE"),
+        ("EVENT_LOG", "ENDLESSH_EVENT_LOG"),
+        ("SENSOR_ID", "ENDLESSH_SENSOR_ID"),
+        ("FAREWELL_LINE", "ENDLESSH_FAREWELL_LINE"),
+        ("BLOCK_STATE_FILE", "ENDLESSH_BLOCK_STATE_FILE"),
+        ("REPORT_FILE", "ENDLESSH_REPORT_FILE"),
+    ] {
+        let value: Option<String> = var(name);
+        let _ = writeln!(constants, "pub const {}: Option<&str> = {:?};", constant, value);
+    }
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("fixed_config.rs");
+    fs::write(out, constants).expect("failed to write fixed_config.rs");
+}
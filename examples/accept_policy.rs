@@ -0,0 +1,73 @@
+//! deciding who gets trapped with accept policies - clients from privileged source ports are hung up on, and an
+//! address that keeps coming back is banned
+//!
+//! a ban is only an event here. the endlessh-rs binary blocks banned addresses with its --block-after firewall, an
+//! embedder would hand them to whatever it blocks with
+//!
+//! cargo run --example accept_policy -- 127.0.0.1:2222 3
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, Token};
+
+use endlessh_rs::endlessh::{EndlesshOptions, EndlesshServer};
+use endlessh_rs::policy::{AcceptPolicy, Arrival, Verdict};
+
+const SSH_TOKEN: Token = Token(0);
+
+/// nothing a scanner sends from a port below 1024 is worth the slot
+struct PrivilegedSourcePorts;
+
+impl AcceptPolicy for PrivilegedSourcePorts {
+    fn decide(&mut self, arrival: &Arrival) -> Option<Verdict> {
+        (arrival.peer.port() < 1024).then_some(Verdict::Close)
+    }
+}
+
+/// bans an address once it's connected `limit` times
+struct Strikes {
+    limit: u32,
+}
+
+impl AcceptPolicy for Strikes {
+    fn decide(&mut self, arrival: &Arrival) -> Option<Verdict> {
+        (arrival.strikes >= self.limit).then_some(Verdict::Ban)
+    }
+}
+
+fn main() -> endlessh_rs::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let address: SocketAddr = args.next().as_deref().unwrap_or("127.0.0.1:2222").parse().expect("expected <ip>:<port>");
+    let limit = args.next().as_deref().unwrap_or("3").parse().expect("expected a number of strikes");
+    let mut poll = Poll::new().expect("failed to create poll");
+    let mut events = Events::with_capacity(64);
+    let options = EndlesshOptions {
+        message_delay: Duration::from_secs(5),
+        ..EndlesshOptions::default()
+    };
+    let mut server = EndlesshServer::bind(options, address, SSH_TOKEN, &poll)?;
+    // the first policy with a verdict decides
+    server.add_policy(PrivilegedSourcePorts);
+    server.add_policy(Strikes { limit });
+    println!("trapping ssh clients on {}, banning them after {} connections", address, limit);
+
+    let mut timeout = None;
+    loop {
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("failed to poll: {}", e);
+        }
+        let now = Instant::now();
+        for event in events.iter() {
+            server.try_handle_event(event, &now);
+        }
+        timeout = server.handle_wakeup(&now);
+        for event in server.drain_events() {
+            println!("{}", serde_json::to_string(&event).expect("failed to serialize connection event"));
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! serving the tarpit's stats from an existing axum app instead of the built in metric server
+//!
+//! the tarpit keeps a thread and poll loop of its own. its stats are atomics behind an Arc, so axum's handlers read them
+//! from tokio's threads with no locking and no channel back to the event loop
+//!
+//! cargo run --example axum_metrics -- 127.0.0.1:2222 127.0.0.1:8080
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use axum::extract::State;
+use axum::http::header;
+use axum::routing::get;
+use axum::{Json, Router};
+use mio::{Events, Poll, Token};
+
+use endlessh_rs::endlessh::{EndlesshOptions, EndlesshServer, EndlesshStats};
+use endlessh_rs::exposition;
+
+const SSH_TOKEN: Token = Token(0);
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let ssh_address: SocketAddr = args.next().as_deref().unwrap_or("127.0.0.1:2222").parse().expect("expected <ip>:<port>");
+    let http_address: SocketAddr = args.next().as_deref().unwrap_or("127.0.0.1:8080").parse().expect("expected <ip>:<port>");
+
+    let poll = Poll::new().expect("failed to create poll");
+    let server = EndlesshServer::bind(EndlesshOptions::default(), ssh_address, SSH_TOKEN, &poll).expect("failed to start the tarpit");
+    let stats = server.stats().clone();
+    thread::spawn(move || run_tarpit(poll, server));
+
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/stats", get(stats_json))
+        .with_state(stats);
+    let listener = tokio::net::TcpListener::bind(http_address).await.expect("failed to bind the http listener");
+    println!("trapping ssh clients on {}, stats on http://{}/stats", ssh_address, http_address);
+    axum::serve(listener, app).await.expect("http server failed");
+}
+
+/// the same loop as the minimal example, without the printing - axum is the only window into it
+fn run_tarpit(mut poll: Poll, mut server: EndlesshServer) {
+    let mut events = Events::with_capacity(64);
+    let mut timeout = None;
+    loop {
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("failed to poll: {}", e);
+        }
+        let now = Instant::now();
+        for event in events.iter() {
+            server.try_handle_event(event, &now);
+        }
+        timeout = server.handle_wakeup(&now);
+        server.drain_events().for_each(drop);
+    }
+}
+
+async fn metrics(State(stats): State<Arc<EndlesshStats>>) -> ([(header::HeaderName, &'static str); 1], String) {
+    ([(header::CONTENT_TYPE, exposition::CONTENT_TYPE)], stats.to_string())
+}
+
+async fn stats_json(State(stats): State<Arc<EndlesshStats>>) -> Json<serde_json::Value> {
+    Json(serde_json::to_value(&*stats).expect("failed to serialize stats"))
+}
@@ -0,0 +1,45 @@
+//! the smallest useful embedding - one ssh tarpit on one port, driven by the embedder's own poll loop, printing each
+//! connection event as json as it happens
+//!
+//! cargo run --example minimal_tarpit -- 127.0.0.1:2222
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, Token};
+
+use endlessh_rs::endlessh::{EndlesshOptions, EndlesshServer};
+
+const SSH_TOKEN: Token = Token(0);
+
+fn main() -> endlessh_rs::Result<()> {
+    let address: SocketAddr = std::env::args().nth(1).as_deref().unwrap_or("127.0.0.1:2222").parse().expect("expected <ip>:<port>");
+    let mut poll = Poll::new().expect("failed to create poll");
+    let mut events = Events::with_capacity(64);
+    let options = EndlesshOptions {
+        message_delay: Duration::from_secs(5),
+        ..EndlesshOptions::default()
+    };
+    let mut server = EndlesshServer::bind(options, address, SSH_TOKEN, &poll)?;
+    println!("trapping ssh clients on {}", address);
+
+    // nobody is trapped yet, so there's nothing to wake up for until someone connects
+    let mut timeout = None;
+    loop {
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("failed to poll: {}", e);
+        }
+        let now = Instant::now();
+        for event in events.iter() {
+            server.try_handle_event(event, &now);
+        }
+        timeout = server.handle_wakeup(&now);
+        for event in server.drain_events() {
+            println!("{}", serde_json::to_string(&event).expect("failed to serialize connection event"));
+        }
+    }
+}
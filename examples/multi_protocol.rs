@@ -0,0 +1,91 @@
+//! an ssh tarpit alongside an smtp one, from a single poll loop
+//!
+//! ssh goes through [EndlesshServer] as usual. smtp drives a [Tarpit] directly, which only knows how to send lines to
+//! streams - the embedder accepts the connections and hands them over. a greeting of endless `220-` continuation lines
+//! is the smtp equivalent of an endless banner: every line ends with the next one's `220-`, after one sent by hand
+//!
+//! cargo run --example multi_protocol -- 127.0.0.1:2222 127.0.0.1:2525
+
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+
+use endlessh_rs::endlessh::{EndlesshOptions, EndlesshServer, EndlesshStats, NewLine, Tarpit};
+use endlessh_rs::EndlesshError;
+
+const SSH_TOKEN: Token = Token(0);
+const SMTP_TOKEN: Token = Token(1);
+const SMTP_CONTINUATION: &[u8] = b"220-";
+
+fn main() -> endlessh_rs::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let ssh_address: SocketAddr = args.next().as_deref().unwrap_or("127.0.0.1:2222").parse().expect("expected <ip>:<port>");
+    let smtp_address: SocketAddr = args.next().as_deref().unwrap_or("127.0.0.1:2525").parse().expect("expected <ip>:<port>");
+    let mut poll = Poll::new().expect("failed to create poll");
+    let mut events = Events::with_capacity(64);
+
+    let mut ssh = EndlesshServer::bind(EndlesshOptions::default(), ssh_address, SSH_TOKEN, &poll)?;
+
+    let mut smtp_listener = TcpListener::bind(smtp_address).map_err(|e| EndlesshError::bind(smtp_address, Some(smtp_address.port()), e))?;
+    poll.registry().register(&mut smtp_listener, SMTP_TOKEN, Interest::READABLE)
+        .map_err(|source| EndlesshError::Register { what: "smtp listener", source })?;
+    let smtp_options = EndlesshOptions {
+        banner_line_length: 60,
+        message_delay: Duration::from_secs(20),
+        newline: NewLine::Custom([b"\r\n", SMTP_CONTINUATION].concat()),
+        ..EndlesshOptions::default()
+    };
+    // the two tarpits count separately
+    let mut smtp: Tarpit<TcpStream> = Tarpit::new(smtp_options, Arc::new(EndlesshStats::default()))?;
+    println!("trapping ssh clients on {} and smtp clients on {}", ssh_address, smtp_address);
+
+    let mut timeout = None;
+    loop {
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            panic!("failed to poll: {}", e);
+        }
+        let now = Instant::now();
+        for event in events.iter() {
+            if event.token() == SMTP_TOKEN {
+                accept_smtp(&smtp_listener, &mut smtp, &now);
+            } else {
+                ssh.try_handle_event(event, &now);
+            }
+        }
+        let ssh_wakeup = ssh.handle_wakeup(&now);
+        // a slot freed by a client leaving is only refilled on the next readable event, good enough for an example
+        let smtp_wakeup = smtp.wakeup(&now, |_| {});
+        timeout = match (ssh_wakeup, smtp_wakeup) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        for event in ssh.drain_events().chain(smtp.drain_events()) {
+            println!("{}", serde_json::to_string(&event).expect("failed to serialize connection event"));
+        }
+    }
+}
+
+fn accept_smtp(listener: &TcpListener, tarpit: &mut Tarpit<TcpStream>, now: &Instant) {
+    while tarpit.has_capacity() {
+        match listener.accept() {
+            Ok((mut stream, peer)) => {
+                // the first line's prefix, the tarpit sends the rest with each newline
+                if stream.write_all(SMTP_CONTINUATION).is_ok() {
+                    tarpit.trap(stream, peer, Some(Arc::from("smtp")), now);
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) => {
+                eprintln!("failed to accept an smtp connection: {}", e);
+                return;
+            },
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! feeds arbitrary bytes through the metric server's request buffer and parser, in arbitrarily sized reads
+//!
+//! run with `cargo +nightly fuzz run http_request` from the repository root
+
+#![no_main]
+
+use std::io::{ErrorKind, Read};
+
+use libfuzzer_sys::fuzz_target;
+
+use endlessh_rs::metrics::{MetricServerOptions, MetricSource, RequestBuffer, RequestOutcome};
+
+struct FuzzSource;
+
+impl MetricSource for FuzzSource {
+    fn metrics(&self) -> String {
+        "endlessh_ssh_connections_opened: 0\n".to_string()
+    }
+
+    fn stats_json(&self) -> String {
+        "{}".to_string()
+    }
+
+    fn timeseries_json(&self) -> String {
+        "[]".to_string()
+    }
+
+    fn recent_json(&self) -> String {
+        "[]".to_string()
+    }
+
+    fn healthz(&self) -> String {
+        "{}".to_string()
+    }
+
+    fn readyz(&self) -> (bool, String) {
+        (true, "{}".to_string())
+    }
+}
+
+/// hands out at most `chunk` bytes per read, then reports WouldBlock like a non-blocking socket
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk: usize,
+    exhausted: bool,
+}
+
+impl Read for ChunkedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.exhausted {
+            self.exhausted = false;
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let n = self.chunk.min(self.data.len()).min(buf.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        self.exhausted = true;
+        Ok(n)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, data)) = data.split_first() else {
+        return;
+    };
+    // small limits so the size and header caps get hit as often as the happy path
+    let options = MetricServerOptions {
+        request_max_size: 512,
+        max_headers: 8,
+        max_requests_per_connection: 4,
+        ..MetricServerOptions::default()
+    };
+    let mut request = RequestBuffer::new(options.request_max_size);
+    let mut reader = ChunkedReader {
+        data,
+        chunk: chunk as usize + 1,
+        exhausted: false,
+    };
+    loop {
+        match request.fill(&mut reader) {
+            // eof, the server would hang up
+            Ok(0) => return,
+            Ok(_) => {},
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::WriteZero => {},
+            Err(e) => panic!("unexpected read error {}", e),
+        }
+        // pipelined requests are answered from what's already buffered, before reading more
+        loop {
+            match request.parse(&options, &FuzzSource) {
+                RequestOutcome::Incomplete => break,
+                RequestOutcome::Respond { response, keep_alive } => {
+                    assert!(response.starts_with("HTTP/1.1 "));
+                    if !keep_alive {
+                        return;
+                    }
+                },
+                RequestOutcome::Close => return,
+            }
+        }
+    }
+});
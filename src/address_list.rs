@@ -0,0 +1,142 @@
+//! sets of addresses given as cidr blocks, e.g. a published blocklist
+//!
+//! blocks are kept as sorted, merged ranges of integers, so a lookup is a binary search however long the feed is
+
+use std::net::IpAddr;
+
+#[derive(Clone, Debug, Default)]
+pub struct AddressList {
+    /// inclusive ranges, sorted and non-overlapping
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl AddressList {
+
+    /// reads one cidr block (or bare address) per line, skipping blank lines and `#` or `;` comments.
+    /// returns the list and how many lines couldn't be parsed
+    pub fn parse(text: &str) -> (Self, usize) {
+        let mut list = AddressList::default();
+        let mut invalid = 0;
+        for line in text.lines() {
+            let entry = line.split(['#', ';']).next().unwrap_or_default().split_whitespace().next();
+            let Some(entry) = entry else {
+                continue;
+            };
+            match parse_cidr(entry) {
+                Some(Range::V4(start, end)) => list.v4.push((start, end)),
+                Some(Range::V6(start, end)) => list.v6.push((start, end)),
+                None => invalid += 1,
+            }
+        }
+        merge(&mut list.v4);
+        merge(&mut list.v6);
+        (list, invalid)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // dual stack listeners see ipv4 peers as ::ffff:a.b.c.d
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => range_contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => range_contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    /// the number of ranges left after merging overlapping blocks
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+enum Range {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+fn parse_cidr(entry: &str) -> Option<Range> {
+    let (address, prefix_len) = match entry.split_once('/') {
+        Some((address, prefix_len)) => (address, Some(prefix_len.parse::<u32>().ok()?)),
+        None => (entry, None),
+    };
+    match address.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let prefix_len = prefix_len.unwrap_or(32);
+            if prefix_len > 32 {
+                return None;
+            }
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some(Range::V4(start, start | !mask))
+        },
+        IpAddr::V6(ip) => {
+            let prefix_len = prefix_len.unwrap_or(128);
+            if prefix_len > 128 {
+                return None;
+            }
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some(Range::V6(start, start | !mask))
+        },
+    }
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn range_contains<T: Ord + Copy>(ranges: &[(T, T)], value: T) -> bool {
+    let after = ranges.partition_point(|&(start, _)| start <= value);
+    after > 0 && ranges[after - 1].1 >= value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn blocks_and_bare_addresses_are_read_skipping_comments() {
+        let (list, invalid) = AddressList::parse("\
+            # a feed\n\
+            192.0.2.0/24 ; documentation\n\
+            \n\
+            198.51.100.7\n\
+            2001:db8::/32 # more documentation\n\
+            not an address\n\
+            10.0.0.0/33\n");
+        assert_eq!((list.len(), invalid), (3, 2));
+        assert!(list.contains(ip("192.0.2.0")) && list.contains(ip("192.0.2.255")));
+        assert!(!list.contains(ip("192.0.3.0")));
+        assert!(list.contains(ip("198.51.100.7")) && !list.contains(ip("198.51.100.8")));
+        assert!(list.contains(ip("2001:db8:ffff::1")) && !list.contains(ip("2001:db9::1")));
+        // as a dual stack listener sees an ipv4 peer
+        assert!(list.contains(ip("::ffff:192.0.2.1")));
+    }
+
+    #[test]
+    fn overlapping_blocks_are_merged() {
+        let (list, _) = AddressList::parse("10.0.0.0/8\n10.1.0.0/16\n11.0.0.0/8\n12.0.0.1\n0.0.0.0/0\n::/0\n");
+        assert_eq!(list.len(), 2);
+        assert!(list.contains(ip("255.255.255.255")) && list.contains(ip("ffff::1")));
+
+        let (list, _) = AddressList::parse("10.0.0.0/16\n10.0.1.0/24\n10.2.0.0/16\n");
+        assert_eq!(list.len(), 2);
+        assert!(list.contains(ip("10.0.255.255")) && !list.contains(ip("10.1.0.0")) && list.contains(ip("10.2.0.0")));
+        assert!(AddressList::default().is_empty());
+    }
+}
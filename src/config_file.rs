@@ -0,0 +1,114 @@
+//! `--config <path>` - any of the top level command line flags, written as a toml file
+//!
+//! each key is a flag without its leading dashes, in either spelling (`ssh_max_clients = 100` or `ssh-max-clients = 100`),
+//! and flags given on the command line win. the file is turned back into flags and parsed along with the real ones, so
+//! every flag works in it without any extra code
+
+use std::fs;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use toml::{Table, Value};
+
+/// the flags set in the file at `path`, leaving out those `matches` already has from the command line
+pub fn flags(path: &Path, command: &Command, matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("failed to read config file {} - {}", path.display(), e))?;
+    let table: Table = text.parse().map_err(|e| format!("invalid config file {} - {}", path.display(), e))?;
+    let mut flags = Vec::new();
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == id.as_str() && arg.get_long().is_some()) else {
+            return Err(format!("unknown option \"{}\" in config file {}", key, path.display()));
+        };
+        if id == "config" {
+            return Err(format!("config file {} can't name another config file", path.display()));
+        }
+        if matches.value_source(&id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                // switches like low_memory take no value, they're just there or not
+                Value::Boolean(on) if !arg.get_action().takes_values() => {
+                    if on {
+                        flags.push(flag.clone());
+                    }
+                },
+                Value::Boolean(on) => flags.push(format!("{}={}", flag, on)),
+                Value::String(s) => flags.push(format!("{}={}", flag, s)),
+                Value::Integer(n) => flags.push(format!("{}={}", flag, n)),
+                Value::Float(n) => flags.push(format!("{}={}", flag, n)),
+                Value::Datetime(_) | Value::Table(_) | Value::Array(_) => {
+                    return Err(format!("\"{}\" in config file {} must be a string, number, boolean or a list of them", key, path.display()));
+                },
+            }
+        }
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::Args;
+
+    /// the flags `text` adds to `cli`, written to a file of its own
+    fn file_flags(name: &str, text: &str, cli: &[&str]) -> Result<Vec<String>, String> {
+        let path = std::env::temp_dir().join(format!("endlessh-rs-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let mut command = Args::command();
+        let matches = command.try_get_matches_from_mut(["endlessh-rs"].iter().chain(cli)).unwrap();
+        let flags = flags(&path, &command, &matches);
+        let _ = fs::remove_file(&path);
+        flags
+    }
+
+    #[test]
+    fn keys_become_flags_in_either_spelling() {
+        let text = r#"
+            ssh_max_clients = 100
+            ssh-banner-style = "hex"
+            ssh_trap_probability = 0.5
+            ssh_listen_address = ["0.0.0.0:22", "[::]:22"]
+            low_memory = true
+            ssh_dry_run = false
+        "#;
+        let mut flags = file_flags("spellings", text, &[]).unwrap();
+        flags.sort();
+        assert_eq!(flags, [
+            "--low-memory",
+            "--ssh-banner-style=hex",
+            "--ssh-listen-address=0.0.0.0:22",
+            "--ssh-listen-address=[::]:22",
+            "--ssh-max-clients=100",
+            "--ssh-trap-probability=0.5",
+        ]);
+    }
+
+    #[test]
+    fn the_command_line_wins() {
+        let flags = file_flags("command-line", "ssh_max_clients = 100\nlow_memory = true", &["--ssh-max-clients", "5"]).unwrap();
+        assert_eq!(flags, ["--low-memory"]);
+    }
+
+    #[test]
+    fn unknown_or_unusable_keys_are_errors() {
+        let error = |name, text| file_flags(name, text, &[]).unwrap_err();
+        assert!(error("unknown", "ssh_max_client = 1").contains("unknown option \"ssh_max_client\""));
+        assert!(error("nested", "config = \"other.toml\"").contains("can't name another config file"));
+        assert!(error("table", "[ssh_max_clients]\nvalue = 1").contains("must be a string, number, boolean"));
+        assert!(error("invalid", "ssh_max_clients =").contains("invalid config file"));
+        assert!(flags(&PathBuf::from("/nonexistent/endlessh-rs.toml"), &Args::command(), &Args::command().get_matches_from(["endlessh-rs"]))
+            .unwrap_err().contains("failed to read config file"));
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use endlessh_rs::endlessh::{unix_seconds, EndlesshStats};
+
+#[derive(Serialize)]
+struct CrashRecord<'a> {
+    crashed_at_seconds: u64,
+    message: String,
+    location: Option<String>,
+    stats: &'a EndlesshStats,
+}
+
+/// appends the final stats and what went wrong to `stats_file` before the default panic output,
+/// so a crash after weeks of uptime doesn't lose every counter
+pub fn install_panic_hook(stats: Arc<EndlesshStats>, stats_file: PathBuf) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Err(e) = write_crash_record(&stats, &stats_file, info) {
+            eprintln!("failed to write crash record to {} - {}", stats_file.display(), e);
+        }
+        default_hook(info);
+    }));
+}
+
+fn write_crash_record(stats: &EndlesshStats, stats_file: &Path, info: &PanicHookInfo) -> std::io::Result<()> {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => info.payload().downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".to_string()),
+    };
+    let record = CrashRecord {
+        crashed_at_seconds: unix_seconds(SystemTime::now()),
+        message,
+        location: info.location().map(|l| l.to_string()),
+        stats,
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(stats_file)?;
+    writeln!(file, "{}", serde_json::to_string(&record)?)?;
+    file.sync_all()
+}
@@ -0,0 +1,107 @@
+//! `endlessh-rs ctl` - a client for the admin api of a running instance
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::exit;
+
+use endlessh_rs::endlessh::is_valid_listener_name;
+use log::LevelFilter;
+
+use crate::MultiListener;
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct CtlArgs {
+    /// the --admin-listen-address of the instance to control
+    #[arg(long)]
+    admin_address: MultiListener,
+    #[command(subcommand)]
+    action: CtlAction,
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+enum CtlAction {
+    /// change the log level without restarting
+    LogLevel {
+        level: LevelFilter,
+    },
+    /// list the addresses ssh clients are being trapped on
+    Listeners,
+    /// start trapping ssh clients on another address, which shares the running tarpit and its options
+    AddListener {
+        address: SocketAddr,
+        /// tag the clients it traps with this name in logs, events and metrics
+        #[arg(long, value_parser = parse_listener_name)]
+        name: Option<String>,
+    },
+    /// stop listening on an address, clients already trapped from it stay trapped
+    RemoveListener {
+        address: SocketAddr,
+    },
+    /// re-read the config and banner word list like SIGHUP does, keeping the trapped clients
+    Reload,
+}
+
+fn parse_listener_name(name: &str) -> Result<String, String> {
+    if is_valid_listener_name(name) {
+        Ok(name.to_string())
+    } else {
+        Err("must be up to 64 letters, digits, '-', '_' or '.'".to_string())
+    }
+}
+
+/// sends `request` and returns the raw response, the server closes the connection after answering
+fn send(address: &MultiListener, request: &str) -> std::io::Result<String> {
+    let mut response = String::new();
+    match address {
+        MultiListener::Ip(ip) => {
+            let mut stream = TcpStream::connect(ip)?;
+            stream.write_all(request.as_bytes())?;
+            stream.read_to_string(&mut response)?;
+        },
+        #[cfg(unix)]
+        MultiListener::Unix(path) => {
+            let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+            stream.write_all(request.as_bytes())?;
+            stream.read_to_string(&mut response)?;
+        },
+        #[cfg(not(unix))]
+        MultiListener::Unix(_) => {
+            return Err(std::io::Error::other("unix sockets are not supported on this platform"));
+        },
+        MultiListener::Fd(_) => {
+            return Err(std::io::Error::other("a passed in socket can't be connected to, give its ip: or unix: address"));
+        },
+        MultiListener::Disabled => {
+            return Err(std::io::Error::other("no admin address given"));
+        },
+    }
+    Ok(response)
+}
+
+pub fn run(args: &CtlArgs) -> ! {
+    let (method, path) = match &args.action {
+        CtlAction::LogLevel { level } => ("POST", format!("/log-level/{}", level)),
+        CtlAction::Listeners => ("GET", "/listeners".to_string()),
+        CtlAction::AddListener { address, name: None } => ("POST", format!("/listeners/{}", address)),
+        CtlAction::AddListener { address, name: Some(name) } => ("POST", format!("/listeners/{}?name={}", address, name)),
+        CtlAction::RemoveListener { address } => ("DELETE", format!("/listeners/{}", address)),
+        CtlAction::Reload => ("POST", "/reload".to_string()),
+    };
+    let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", method, path);
+    let response = send(&args.admin_address, &request).unwrap_or_else(|e| {
+        eprintln!("failed to reach {} - {}", args.admin_address, e);
+        exit(1);
+    });
+    let status = response.lines().next().unwrap_or_default();
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    match status.split(' ').nth(1) {
+        Some("200") => {
+            print!("{}", body);
+            exit(0);
+        },
+        Some("403") => eprintln!("{} refused - is it the instance's --admin-listen-address rather than a metrics listener?", args.admin_address),
+        _ if !body.is_empty() => eprintln!("{} answered \"{}\": {}", args.admin_address, status, body.trim_end()),
+        _ => eprintln!("{} answered \"{}\"", args.admin_address, status),
+    }
+    exit(1);
+}
@@ -0,0 +1,351 @@
+//! ships connection events to an elasticsearch (or opensearch) `_bulk` endpoint, into an index named by the event's date
+//!
+//! like the hpfeeds publisher the requests are made on a thread of their own, fed over a bounded channel. events are
+//! sent in batches of `batch_size`, or whatever has arrived after `flush_interval`. a failed batch is retried every
+//! `flush_interval`, and only once the backlog passes `MAX_PENDING_EVENTS` are the oldest events dropped. on shutdown
+//! [ElasticsearchExporter::finish] gives the thread `FINISH_TIMEOUT` to send what's left
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::endlessh::ConnectionEvent;
+use crate::error::{self, EndlesshError};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// events waiting to be picked up by the exporter thread
+const QUEUE_LENGTH: usize = 4096;
+/// events held back while the cluster is unreachable
+const MAX_PENDING_EVENTS: usize = 100_000;
+/// how long shutdown waits for the last events to be sent
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct ElasticsearchOptions {
+    /// the cluster's base url, e.g. http://localhost:9200
+    pub url: String,
+    /// the index to write each event to, with `%Y`, `%m` and `%d` replaced by the event's utc date
+    pub index: String,
+    /// sent as `Authorization: ApiKey <key>`
+    pub api_key: Option<String>,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+}
+
+/// the event loop's end of the exporter thread
+pub struct ElasticsearchExporter {
+    sender: SyncSender<ConnectionEvent>,
+    /// since the queue last had room
+    dropped: u64,
+    worker: JoinHandle<()>,
+    /// hung up on by the thread as it returns, so [ElasticsearchExporter::finish] can wait with a timeout
+    finished: Receiver<()>,
+}
+
+impl ElasticsearchExporter {
+
+    pub fn start(options: ElasticsearchOptions) -> error::Result<Self> {
+        if options.batch_size == 0 || options.flush_interval.is_zero() {
+            return Err(EndlesshError::Config("the elasticsearch batch size and flush interval must be above zero".to_string()));
+        }
+        if !options.url.starts_with("http://") && !options.url.starts_with("https://") {
+            return Err(EndlesshError::Config(format!("elasticsearch url {} must start with http:// or https://", options.url)));
+        }
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let (finishing, finished) = mpsc::channel::<()>();
+        let worker = thread::spawn(move || {
+            Bulk::new(options).run(receiver);
+            drop(finishing);
+        });
+        Ok(ElasticsearchExporter {
+            sender,
+            dropped: 0,
+            worker,
+            finished,
+        })
+    }
+
+    pub fn export(&mut self, event: &ConnectionEvent) {
+        match self.sender.try_send(event.clone()) {
+            Ok(()) if self.dropped > 0 => {
+                warn!("dropped {} events while the elasticsearch exporter wasn't keeping up", self.dropped);
+                self.dropped = 0;
+            },
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            _ => {},
+        }
+    }
+
+    /// hangs up on the thread, which makes one last try at sending what's pending, and waits up to `FINISH_TIMEOUT`
+    /// for it to return
+    pub fn finish(self) {
+        drop(self.sender);
+        match self.finished.recv_timeout(FINISH_TIMEOUT) {
+            Err(RecvTimeoutError::Timeout) => warn!("gave up waiting for the last events to be exported to elasticsearch"),
+            _ => {
+                let _ = self.worker.join();
+            },
+        }
+    }
+}
+
+struct Bulk {
+    options: ElasticsearchOptions,
+    endpoint: String,
+    agent: ureq::Agent,
+    /// oldest first, including any that failed to send
+    pending: VecDeque<ConnectionEvent>,
+    /// whether the last flush failed, in which case only the timer retries rather than every full batch
+    failing: bool,
+}
+
+impl Bulk {
+
+    fn new(options: ElasticsearchOptions) -> Self {
+        Bulk {
+            endpoint: format!("{}/_bulk", options.url.trim_end_matches('/')),
+            agent: ureq::AgentBuilder::new()
+                .timeout(REQUEST_TIMEOUT)
+                .user_agent(concat!("endlessh-rs/", env!("CARGO_PKG_VERSION")))
+                .build(),
+            pending: VecDeque::new(),
+            failing: false,
+            options,
+        }
+    }
+
+    fn run(mut self, events: Receiver<ConnectionEvent>) {
+        let mut next_flush = Instant::now() + self.options.flush_interval;
+        loop {
+            match events.recv_timeout(next_flush.saturating_duration_since(Instant::now())) {
+                Ok(event) => {
+                    if self.pending.len() == MAX_PENDING_EVENTS {
+                        self.pending.pop_front();
+                    }
+                    self.pending.push_back(event);
+                    if self.failing || self.pending.len() < self.options.batch_size {
+                        continue;
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                // the event loop is gone - one last try for what's left
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = self.flush();
+                    return;
+                },
+            }
+            self.failing = !self.flush();
+            next_flush = Instant::now() + self.options.flush_interval;
+        }
+    }
+
+    /// sends the pending events a batch at a time, stopping at the first failure. returns whether they all went
+    fn flush(&mut self) -> bool {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(self.options.batch_size);
+            match self.send(count) {
+                Ok(()) => {
+                    debug!("exported {} events to {}", count, self.endpoint);
+                    self.pending.drain(..count);
+                },
+                Err(e) => {
+                    warn!("{}, retrying {} events in {:?}", e, self.pending.len(), self.options.flush_interval);
+                    return false;
+                },
+            }
+        }
+        true
+    }
+
+    fn send(&self, count: usize) -> error::Result<()> {
+        let fail = |reason: String| EndlesshError::Export {
+            url: self.endpoint.clone(),
+            reason,
+        };
+        let body = self.pending.iter().take(count).map(|event| self.bulk_lines(event)).collect::<String>();
+        let mut request = self.agent.post(&self.endpoint).set("Content-Type", "application/x-ndjson");
+        if let Some(key) = &self.options.api_key {
+            request = request.set("Authorization", &format!("ApiKey {}", key));
+        }
+        let response = request.send_string(&body).map_err(|e| fail(e.to_string()))?;
+        // the request as a whole succeeds even when some of its documents are rejected
+        let reply = response.into_string().map_err(|e| fail(e.to_string()))?;
+        let reply: serde_json::Value = serde_json::from_str(&reply).map_err(|e| fail(format!("unexpected reply - {}", e)))?;
+        if reply["errors"] == true {
+            let first = reply["items"].as_array()
+                .and_then(|items| items.iter().find_map(|item| item["index"].get("error")))
+                .map_or_else(String::new, |error| error.to_string());
+            // resending the same documents would just be rejected again
+            warn!("elasticsearch rejected some of {} events: {}", count, first);
+        }
+        Ok(())
+    }
+
+    /// the action and document lines for one event
+    fn bulk_lines(&self, event: &ConnectionEvent) -> String {
+        let (year, month, day, hour, minute, second, millis) = utc_date_time(event.time_seconds);
+        let index = self.options.index
+            .replace("%Y", &format!("{:04}", year))
+            .replace("%m", &format!("{:02}", month))
+            .replace("%d", &format!("{:02}", day));
+        let mut document = serde_json::to_value(event).expect("failed to serialize connection event");
+        // what kibana's index patterns default to as the time field
+        document["@timestamp"] = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hour, minute, second, millis).into();
+        format!("{}\n{}\n", serde_json::json!({ "index": { "_index": index } }), document)
+    }
+}
+
+/// seconds since the unix epoch to (year, month, day, hour, minute, second, millisecond) in utc
+fn utc_date_time(seconds: f64) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let whole = seconds.floor() as i64;
+    let days = whole.div_euclid(86_400);
+    let of_day = whole.rem_euclid(86_400);
+    // howard hinnant's days_from_civil, backwards
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let millis = (((seconds - seconds.floor()) * 1000.0) as u32).min(999);
+    (year, month, day, (of_day / 3600) as u32, (of_day % 3600 / 60) as u32, (of_day % 60) as u32, millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    fn event(time_seconds: f64, peer: &str) -> ConnectionEvent {
+        serde_json::from_value(serde_json::json!({
+            "event": "closed", "time_seconds": time_seconds, "peer": peer, "bytes_sent": 10, "trapped_seconds": 2.5,
+        })).unwrap()
+    }
+
+    fn options(url: String) -> ElasticsearchOptions {
+        ElasticsearchOptions {
+            url,
+            index: "endlessh-%Y.%m.%d".to_string(),
+            api_key: Some("c2VjcmV0".to_string()),
+            batch_size: 2,
+            flush_interval: Duration::from_secs(60),
+        }
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)
+    }
+
+    /// answers one request per response with it, handing back each request's head and body
+    fn serve(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || responses.into_iter().map(|response| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut head).unwrap() > 2 && !head.ends_with("\r\n\r\n") {}
+            let length = head.lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|length| length.trim().parse().unwrap()))
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+            (head, String::from_utf8(body).unwrap())
+        }).collect());
+        (url, server)
+    }
+
+    #[test]
+    fn event_times_become_utc_dates() {
+        assert_eq!(utc_date_time(0.0), (1970, 1, 1, 0, 0, 0, 0));
+        assert_eq!(utc_date_time(951_782_400.0), (2000, 2, 29, 0, 0, 0, 0));
+        assert_eq!(utc_date_time(1_700_000_000.25), (2023, 11, 14, 22, 13, 20, 250));
+        assert_eq!(utc_date_time(-1.5), (1969, 12, 31, 23, 59, 58, 500));
+    }
+
+    #[test]
+    fn events_go_to_the_index_for_their_day() {
+        let bulk = Bulk::new(options("http://localhost:9200".to_string()));
+        let text = bulk.bulk_lines(&event(1_700_000_000.25, "192.0.2.1:22"));
+        let lines = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect::<Vec<serde_json::Value>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], serde_json::json!({ "index": { "_index": "endlessh-2023.11.14" } }));
+        assert_eq!(lines[1]["@timestamp"], "2023-11-14T22:13:20.250Z");
+        assert_eq!(lines[1]["peer"], "192.0.2.1:22");
+        assert_eq!(lines[1]["bytes_sent"], 10);
+    }
+
+    #[test]
+    fn batches_are_posted_to_the_bulk_endpoint() {
+        let accepted = r#"{"took":1,"errors":false,"items":[]}"#;
+        let (url, server) = serve(vec![response("200 OK", accepted), response("200 OK", accepted)]);
+        let mut bulk = Bulk::new(options(url));
+        bulk.pending.extend((1..=3).map(|i| event(f64::from(i), &format!("192.0.2.{}:22", i))));
+        assert!(bulk.flush());
+        assert!(bulk.pending.is_empty());
+
+        let requests = server.join().unwrap();
+        let (head, body) = &requests[0];
+        assert!(head.starts_with("POST /_bulk "), "{}", head);
+        let head = head.to_ascii_lowercase();
+        assert!(head.contains("authorization: apikey c2vjcmv0"), "{}", head);
+        assert!(head.contains("content-type: application/x-ndjson"), "{}", head);
+        assert_eq!(body.lines().count(), 4);
+        assert!(body.contains("192.0.2.1:22") && body.contains("192.0.2.2:22"));
+        assert_eq!(requests[1].1.lines().count(), 2);
+        assert!(requests[1].1.contains("192.0.2.3:22"));
+    }
+
+    #[test]
+    fn failed_batches_are_kept_for_the_next_flush() {
+        let (url, server) = serve(vec![
+            response("503 Service Unavailable", "{}"),
+            response("200 OK", r#"{"errors":true,"items":[{"index":{"error":{"type":"mapper_parsing_exception"}}}]}"#),
+        ]);
+        let mut bulk = Bulk::new(options(url));
+        bulk.pending.push_back(event(1.0, "192.0.2.1:22"));
+        assert!(!bulk.flush());
+        assert_eq!(bulk.pending.len(), 1);
+        // rejected documents aren't resent
+        assert!(bulk.flush());
+        assert!(bulk.pending.is_empty());
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].1, requests[1].1);
+    }
+
+    #[test]
+    fn exporting_needs_a_batch_size_interval_and_http_url() {
+        let url = "http://localhost:9200".to_string();
+        assert!(ElasticsearchExporter::start(ElasticsearchOptions { batch_size: 0, ..options(url.clone()) }).is_err());
+        assert!(ElasticsearchExporter::start(ElasticsearchOptions { flush_interval: Duration::ZERO, ..options(url) }).is_err());
+        assert!(ElasticsearchExporter::start(options("localhost:9200".to_string())).is_err());
+    }
+
+    #[test]
+    fn exported_events_reach_the_cluster() {
+        let (url, server) = serve(vec![response("200 OK", r#"{"errors":false}"#)]);
+        let mut exporter = ElasticsearchExporter::start(options(url)).unwrap();
+        exporter.export(&event(1.0, "192.0.2.1:22"));
+        exporter.export(&event(2.0, "192.0.2.2:22"));
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].1.lines().count(), 4);
+    }
+
+    #[test]
+    fn finishing_sends_what_is_left_of_a_batch() {
+        let (url, server) = serve(vec![response("200 OK", r#"{"errors":false}"#)]);
+        let mut exporter = ElasticsearchExporter::start(options(url)).unwrap();
+        exporter.export(&event(1.0, "192.0.2.1:22"));
+        exporter.finish();
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].1.lines().count(), 2);
+    }
+}
@@ -1,148 +1,1963 @@
 
 use std::fmt::Display;
-use std::time::{Instant,Duration};
+use std::time::{Instant,Duration,SystemTime,UNIX_EPOCH};
 
-use std::collections::VecDeque;
-use mio::net::{TcpListener,TcpStream};
-use mio::{Poll, Token};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use mio::net::TcpListener;
+use mio::{Poll, Registry, Token};
 use mio::{Interest,event};
-use std::io::{ErrorKind, Write};
+use std::io::{ErrorKind, Read};
+use std::str::FromStr;
 use std::fmt::Formatter;
+use crate::address_list::AddressList;
+use crate::error::{self, EndlesshError};
+use crate::exposition::{Exposition, MetricType};
+use crate::offenders::{OffenderMemory, RepeatOffenders};
+use crate::pacing::{AcceptBudget, SendBudget};
+use crate::policy::{AcceptPolicy, Admission, Verdict};
+use crate::proxy_protocol::{self, ProxyHeader};
+use crate::socket::{ClientStream, ListenAddress, ListenSocket, TcpStats, TrappedStream, UNIX_PEER};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use serde::ser::SerializeStruct;
 
 const SSH_LINE_BUFFER_SIZE: usize = 256;
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// how much is read from a client at a time, whenever it sends something or on its turn while watching for chatty clients
+const DRAIN_BUFFER_SIZE: usize = 4096;
+/// past this a client's input is left for the next time it sends or has its turn, so a flood can't hold up the rest
+const MAX_DRAIN_BYTES_PER_TURN: usize = 64 * 1024;
+/// how long a load balancer gets to send the PROXY header of a connection
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// how often connections still waiting for their PROXY header are read again
+const PROXY_HEADER_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+/// connections a listener holds waiting for their PROXY header, past this the rest wait in the listen backlog
+const MAX_PENDING_PROXY_HEADERS: usize = 64;
+/// source ports are counted in ranges this wide - coarse enough to stay a handful of series, fine enough to tell the
+/// usual ephemeral ranges (linux 32768-60999, windows and bsd 49152-65535) from a NAT gateway using all of them
+const SOURCE_PORT_RANGE_WIDTH: usize = 4096;
+const SOURCE_PORT_RANGES: usize = 65536 / SOURCE_PORT_RANGE_WIDTH;
+/// a wakeup this much later than the tarpit asked for means every due client's line went out late, e.g. because
+/// something else on the event loop was slow
+const LATE_WAKEUP_THRESHOLD: Duration = Duration::from_secs(1);
+/// a wakeup this much later than the tarpit asked for means the process wasn't running in between - the machine was
+/// suspended, the vm paused or the process stopped - rather than that it was busy
+const TIME_JUMP_THRESHOLD: Duration = Duration::from_secs(30);
+/// the share of `max_clients` in use above which an adaptive delay starts to grow
+const ADAPTIVE_DELAY_LOAD_FLOOR: f64 = 0.5;
+/// upper bounds of the trapped duration histogram, from a quick disconnect to a whole day
+pub const DEFAULT_TRAPPED_SECONDS_BUCKETS: [f64; 9] = [10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 21600.0, 86400.0];
+/// upper bounds of the lines per connection histogram
+pub const DEFAULT_LINES_PER_CONNECTION_BUCKETS: [f64; 8] = [1.0, 3.0, 10.0, 30.0, 100.0, 300.0, 1000.0, 10000.0];
+/// upper bounds of the trap score histogram, from a scanner that left after the first line to one held for a day
+pub const DEFAULT_TRAP_SCORE_BUCKETS: [f64; 8] = [2.0, 5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 40.0];
 
+/// the byte sequence written after each banner line
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum NewLine {
     LF,
-    #[allow(dead_code)]
     CRLF,
+    /// never terminate a line - the client sees one endless, ever-growing line
+    None,
+    /// an arbitrary terminator (must fit in the line buffer alongside the banner line)
+    Custom(Vec<u8>),
 }
 
 impl NewLine {
     fn get_data(&self) -> &[u8] {
         match self {
-            NewLine::LF => &[ '\n' as u8 ],
-            NewLine::CRLF => &[ '\r' as u8, '\n' as u8 ],
+            NewLine::LF => b"\n",
+            NewLine::CRLF => b"\r\n",
+            NewLine::None => &[],
+            NewLine::Custom(bytes) => bytes,
+        }
+    }
+}
+
+impl From<&[u8]> for NewLine {
+    /// the named terminators for their bytes, so they're shown by name
+    fn from(bytes: &[u8]) -> Self {
+        match bytes {
+            b"\n" => NewLine::LF,
+            b"\r\n" => NewLine::CRLF,
+            b"" => NewLine::None,
+            _ => NewLine::Custom(bytes.to_vec()),
+        }
+    }
+}
+
+impl FromStr for NewLine {
+    type Err = String;
+
+    /// "lf", "crlf", "none", or "hex:" and the bytes of any other terminator, e.g. "hex:0d0a20"
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v {
+            "lf" => Ok(NewLine::LF),
+            "crlf" => Ok(NewLine::CRLF),
+            "none" => Ok(NewLine::None),
+            _ => {
+                let Some(digits) = v.strip_prefix("hex:").filter(|digits| !digits.is_empty() && digits.len() % 2 == 0 && digits.is_ascii()) else {
+                    return Err(format!("\"{}\" is neither \"lf\", \"crlf\", \"none\" nor \"hex:\" and an even number of hex digits", v));
+                };
+                let bytes = (0..digits.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| format!("\"{}\" isn't a hex byte", &digits[i..i + 2])))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // the next line would start with it, and the client would take it for the end of the banner
+                if bytes.windows(4).any(|window| window == b"SSH-") {
+                    return Err(format!("\"{}\" contains SSH-", v));
+                }
+                Ok(NewLine::from(bytes.as_slice()))
+            },
+        }
+    }
+}
+
+impl Display for NewLine {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            NewLine::LF => write!(f, "lf"),
+            NewLine::CRLF => write!(f, "crlf"),
+            NewLine::None => write!(f, "none"),
+            NewLine::Custom(bytes) => write!(f, "hex:{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
         }
     }
 }
 
 pub struct EndlesshOptions {
     pub max_clients: usize,
+    /// trap at most this many clients from one address at a time, hanging up on the rest, so a single scanner can't
+    /// take every slot. unix socket clients have no address and aren't limited
+    pub max_clients_per_ip: Option<usize>,
+    /// if set, only addresses in this list are trapped, the rest are hung up on
+    pub allow_cidrs: Option<AddressList>,
+    /// addresses that are hung up on instead of trapped, even if `allow_cidrs` has them. unix socket clients have no
+    /// address and are checked against neither
+    pub deny_cidrs: Option<AddressList>,
     pub banner_line_length: usize,
+    /// if set, each line is given a random length from `banner_line_length` up to this, so they aren't all the same size
+    pub banner_line_length_max: Option<usize>,
+    pub banner: BannerGenerator,
     pub message_delay: Duration,
+    /// if set, each line's delay is moved by up to this much either way, drawn again for every line, so the drip isn't
+    /// perfectly periodic. must be shorter than `message_delay`
+    pub message_delay_jitter: Option<Duration>,
+    /// if any, each new client is trapped with the delay and banner of one of these, picked by weight, instead of the
+    /// options above, and the totals of each are kept apart to compare them. the adaptive delay only applies to the
+    /// options above, so it can't be combined with variants
+    pub variants: Vec<StrategyVariant>,
+    /// if set, the delay grows from `message_delay` towards this as the client slots fill up, and shrinks back as they empty
+    pub adaptive_delay_max: Option<Duration>,
+    /// hang up on a client once this many sends in a row found its socket buffer still full, since it has most likely vanished
+    pub stall_limit: Option<u32>,
+    /// hang up on a client once it has advertised a zero window for this long, where that can be told (linux)
+    pub zero_window_timeout: Option<Duration>,
+    /// hang up on a client once it has been trapped this long, so no one client holds a slot forever
+    pub max_trap_duration: Option<Duration>,
+    /// read (and throw away) what clients send, penalizing any that send more than this many bytes between two lines
+    pub chatty_threshold: Option<usize>,
+    pub chatty_penalty: ChattyPenalty,
+    /// added to every connection event, to tell the sensors of a fleet apart once their events are combined
+    pub sensor_id: Option<String>,
+    /// trap addresses more slowly each time they come back, unix socket clients have no address and never are
+    pub repeat_offenders: Option<RepeatOffenders>,
+    /// refuse some new connections at random as the client count nears the limit, instead of all at once at `max_clients`
+    pub max_startups: Option<MaxStartups>,
+    /// the fraction of accepted connections that are trapped, the rest are hung up on straight away
+    pub trap_probability: f64,
+    /// hang up on every client as soon as it's accepted, while still counting it and recording its events, to see what an
+    /// address attracts before trapping anyone there
+    pub dry_run: bool,
+    /// expect a PROXY header (v1 or v2) ahead of every connection, and trap the client it names rather than the balancer
+    pub proxy_protocol: bool,
     pub newline: NewLine,
+    /// reserve room for `max_clients` up front rather than growing as clients arrive
+    pub preallocate_clients: bool,
+    /// record an event for every line sent as well as for clients arriving and leaving - one per client per delay
+    pub line_events: bool,
+    /// hold the bytes sent to all clients together to this rate, stretching the delay for everyone once it's reached
+    pub max_send_rate: Option<u64>,
+    /// accept at most this many connections a second, leaving the rest in the listen backlog for later wakeups, so a
+    /// flood of them can't hold up the lines
+    pub accept_rate: Option<u64>,
+    /// how many connections can be accepted at once under `accept_rate`, a second's worth if not given
+    pub accept_burst: Option<u32>,
+    /// the kernel receive buffer (SO_RCVBUF) of each accepted socket, shrunk so thousands of clients cost little
+    /// kernel memory. linux only
+    pub receive_buffer: Option<usize>,
+    /// the kernel send buffer (SO_SNDBUF) of each accepted socket, linux only
+    pub send_buffer: Option<usize>,
+    /// used by [EndlesshServer::create] for the histograms in its stats
+    pub histogram_buckets: HistogramBuckets,
+    /// seed the random numbers behind the banner lines, jitter and sampling, so the same clients arriving at the same
+    /// times get the same lines. kept from the start when reconfigured
+    pub rng_seed: Option<u64>,
+}
+
+/// what happens to a client that floods us with data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChattyPenalty {
+    /// only send it every nth line
+    Slow(u32),
+    /// stop sending altogether and just hold on to the socket
+    Hold,
+}
+
+impl FromStr for ChattyPenalty {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v {
+            "hold" => Ok(ChattyPenalty::Hold),
+            _ => match v.parse() {
+                Ok(factor) if factor >= 2 => Ok(ChattyPenalty::Slow(factor)),
+                _ => Err(format!("\"{}\" is neither \"hold\" nor a delay factor of at least 2", v)),
+            },
+        }
+    }
+}
+
+impl Display for ChattyPenalty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ChattyPenalty::Slow(factor) => write!(f, "{}", factor),
+            ChattyPenalty::Hold => write!(f, "hold"),
+        }
+    }
+}
+
+/// the kinds of [BannerGenerator], for picking one by name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BannerStyle {
+    Alphanumeric,
+    Hex,
+    Words,
+    Template,
+}
+
+impl FromStr for BannerStyle {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v {
+            "alphanumeric" => Ok(BannerStyle::Alphanumeric),
+            "hex" => Ok(BannerStyle::Hex),
+            "words" => Ok(BannerStyle::Words),
+            "template" => Ok(BannerStyle::Template),
+            _ => Err(format!("unknown banner style \"{}\", expected alphanumeric, hex, words or template", v)),
+        }
+    }
+}
+
+impl Display for BannerStyle {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            BannerStyle::Alphanumeric => write!(f, "alphanumeric"),
+            BannerStyle::Hex => write!(f, "hex"),
+            BannerStyle::Words => write!(f, "words"),
+            BannerStyle::Template => write!(f, "template"),
+        }
+    }
+}
+
+/// what banner lines are made of. whichever it is, no line starts with "SSH-", which the client would take for the end
+/// of the banner - see https://datatracker.ietf.org/doc/html/rfc4253#section-4.2
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum BannerGenerator {
+    /// random letters and digits, like the original endlessh
+    #[default]
+    Alphanumeric,
+    /// random lowercase hex digits, like a dump of a key or a hash
+    Hex,
+    /// random words of this list separated by spaces, the last one cut off where the line ends. the words must be
+    /// printable ascii, and none may start with "SSH-"
+    Words(Vec<String>),
+    /// a line of text with random parts, drawn again for every line
+    Template(BannerTemplate),
+}
+
+impl BannerGenerator {
+
+    pub fn style(&self) -> BannerStyle {
+        match self {
+            BannerGenerator::Alphanumeric => BannerStyle::Alphanumeric,
+            BannerGenerator::Hex => BannerStyle::Hex,
+            BannerGenerator::Words(_) => BannerStyle::Words,
+            BannerGenerator::Template(_) => BannerStyle::Template,
+        }
+    }
+
+    fn check(&self) -> error::Result<()> {
+        match self {
+            BannerGenerator::Words(words) => check_banner_words(words),
+            BannerGenerator::Template(template) => template.words.as_deref().map_or(Ok(()), check_banner_words),
+            BannerGenerator::Alphanumeric | BannerGenerator::Hex => Ok(()),
+        }
+    }
+
+    /// writes a line into the buffer, returning how much of it the line takes up - all of it, except for a template
+    /// that comes out shorter
+    pub fn fill(&self, buffer: &mut [u8], rng: &mut fastrand::Rng) -> usize {
+        let length = match self {
+            BannerGenerator::Alphanumeric => {
+                // the "alphanumeric" distribution never generates '-' so should not be a problem
+                buffer.fill_with(|| rng.alphanumeric() as u8);
+                buffer.len()
+            },
+            BannerGenerator::Hex => {
+                buffer.fill_with(|| random_hex_digit(rng));
+                buffer.len()
+            },
+            BannerGenerator::Words(words) => {
+                word_line(buffer, words, rng);
+                buffer.len()
+            },
+            BannerGenerator::Template(template) => template.render(buffer, rng),
+        };
+        // only a template's text can spell it out, but a line that did would end the trap
+        if buffer[..length].starts_with(b"SSH-") {
+            buffer[3] = b'_';
+        }
+        length
+    }
+}
+
+fn check_banner_words(words: &[String]) -> error::Result<()> {
+    if words.is_empty() {
+        return Err(EndlesshError::Config("the banner word list is empty".to_string()));
+    }
+    match words.iter().find(|word| word.is_empty() || word.starts_with("SSH-") || !word.bytes().all(|b| b.is_ascii_graphic())) {
+        Some(word) => Err(EndlesshError::Config(format!("banner word {:?} isn't printable ascii, or starts with SSH-", word))),
+        None => Ok(()),
+    }
+}
+
+fn random_hex_digit(rng: &mut fastrand::Rng) -> u8 {
+    b"0123456789abcdef"[rng.usize(..16)]
+}
+
+/// random words separated by spaces, the last one cut off where the line ends
+fn word_line(buffer: &mut [u8], words: &[String], rng: &mut fastrand::Rng) {
+    let mut position = 0;
+    while position < buffer.len() {
+        let word = words[rng.usize(..words.len())].as_bytes();
+        let length = word.len().min(buffer.len() - position);
+        buffer[position..position + length].copy_from_slice(&word[..length]);
+        position += length;
+        if position < buffer.len() {
+            buffer[position] = b' ';
+            position += 1;
+        }
+    }
+}
+
+/// one piece of a [BannerTemplate]
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Text(String),
+    Alphanumeric(usize),
+    Hex(usize),
+    Digits(usize),
+    Word,
+}
+
+/// a line of printable ascii with placeholders - `{alnum:N}`, `{hex:N}` and `{digits:N}` for N random letters and
+/// digits, hex digits or digits (one without the `:N`), and `{word}` for a random word of a list. a line comes out as
+/// long as the template makes it, cut off at the banner line length
+#[derive(Clone, Debug, PartialEq)]
+pub struct BannerTemplate {
+    parts: Vec<TemplatePart>,
+    words: Option<Vec<String>>,
+}
+
+impl BannerTemplate {
+
+    /// the placeholders of `template`, with the list `{word}` draws from if it uses any
+    pub fn parse(template: &str, words: Option<Vec<String>>) -> error::Result<Self> {
+        let invalid = |reason: &str| EndlesshError::Config(format!("invalid banner template {:?} - {}", template, reason));
+        if !template.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+            return Err(invalid("it must be a single line of printable ascii"));
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or_else(|| invalid("a placeholder isn't closed"))? + start;
+            let placeholder = &rest[start + 1..end];
+            let (name, count) = match placeholder.split_once(':') {
+                Some((name, count)) => (name, count.parse().ok().filter(|&count| count > 0)
+                    .ok_or_else(|| invalid(&format!("{{{}}} needs a count above zero", placeholder)))?),
+                None => (placeholder, 1),
+            };
+            parts.push(match name {
+                "alnum" => TemplatePart::Alphanumeric(count),
+                "hex" => TemplatePart::Hex(count),
+                "digits" => TemplatePart::Digits(count),
+                "word" if placeholder == "word" => TemplatePart::Word,
+                _ => return Err(invalid(&format!("unknown placeholder {{{}}}", placeholder))),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        if parts.contains(&TemplatePart::Word) && words.is_none() {
+            return Err(invalid("{word} needs a word list"));
+        }
+        Ok(BannerTemplate { parts, words })
+    }
+
+    /// fills in the placeholders as far as the buffer goes, returning how much of it the line takes up
+    fn render(&self, buffer: &mut [u8], rng: &mut fastrand::Rng) -> usize {
+        let mut position = 0;
+        for part in &self.parts {
+            if position == buffer.len() {
+                break;
+            }
+            let rest = &mut buffer[position..];
+            let length = match part {
+                TemplatePart::Text(text) => {
+                    let length = text.len().min(rest.len());
+                    rest[..length].copy_from_slice(&text.as_bytes()[..length]);
+                    length
+                },
+                TemplatePart::Alphanumeric(count) => {
+                    let length = (*count).min(rest.len());
+                    rest[..length].fill_with(|| rng.alphanumeric() as u8);
+                    length
+                },
+                TemplatePart::Hex(count) => {
+                    let length = (*count).min(rest.len());
+                    rest[..length].fill_with(|| random_hex_digit(rng));
+                    length
+                },
+                TemplatePart::Digits(count) => {
+                    let length = (*count).min(rest.len());
+                    rest[..length].fill_with(|| rng.digit(10) as u8);
+                    length
+                },
+                TemplatePart::Word => {
+                    let words = self.words.as_deref().unwrap_or_default();
+                    let word = words[rng.usize(..words.len())].as_bytes();
+                    let length = word.len().min(rest.len());
+                    rest[..length].copy_from_slice(&word[..length]);
+                    length
+                },
+            };
+            position += length;
+        }
+        position
+    }
+}
+
+/// another way of trapping clients, to compare with the others - a share of the new connections gets its delay and
+/// banner instead of the main options', see [EndlesshOptions::variants]
+#[derive(Clone, Debug, PartialEq)]
+pub struct StrategyVariant {
+    /// goes in metric labels and log lines, so it's held to the same characters as a listener name
+    pub name: String,
+    /// its share of the new connections, relative to the other variants' weights
+    pub weight: u32,
+    pub message_delay: Duration,
+    pub message_delay_jitter: Option<Duration>,
+    pub banner: BannerGenerator,
+    pub banner_line_length: usize,
+    pub banner_line_length_max: Option<usize>,
+}
+
+impl StrategyVariant {
+    fn longest_banner_line(&self) -> usize {
+        self.banner_line_length_max.unwrap_or(self.banner_line_length)
+    }
+}
+
+/// openssh's `MaxStartups start:rate:full` - from `start` clients on, new connections are refused with a probability of
+/// `rate` percent, rising linearly to 100% at `full`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxStartups {
+    pub start: usize,
+    pub rate: u8,
+    pub full: usize,
+}
+
+impl MaxStartups {
+    /// the chance of refusing a connection with `clients` already trapped, from 0 to 1
+    pub fn refuse_probability(&self, clients: usize) -> f64 {
+        if clients < self.start {
+            return 0.0;
+        }
+        if clients >= self.full {
+            return 1.0;
+        }
+        let rate = f64::from(self.rate) / 100.0;
+        rate + (1.0 - rate) * (clients - self.start) as f64 / (self.full - self.start) as f64
+    }
+}
+
+impl FromStr for MaxStartups {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("\"{}\" isn't start:rate:full, e.g. 1000:30:4000", v);
+        let mut parts = v.split(':');
+        let (Some(start), Some(rate), Some(full), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let max_startups = MaxStartups {
+            start: start.parse().map_err(|_| invalid())?,
+            rate: rate.parse().map_err(|_| invalid())?,
+            full: full.parse().map_err(|_| invalid())?,
+        };
+        if max_startups.rate > 100 || max_startups.start >= max_startups.full {
+            return Err(format!("\"{}\" needs a rate of at most 100 and start below full", v));
+        }
+        Ok(max_startups)
+    }
+}
+
+impl Display for MaxStartups {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}:{}:{}", self.start, self.rate, self.full)
+    }
+}
+
+/// upper bounds of the histogram buckets, which suit a 1 second delay test rig and a 60 second one very differently
+#[derive(Clone, Debug)]
+pub struct HistogramBuckets {
+    pub trapped_seconds: Vec<f64>,
+    pub lines_per_connection: Vec<f64>,
+    pub trap_score: Vec<f64>,
+}
+
+impl Default for HistogramBuckets {
+    fn default() -> Self {
+        HistogramBuckets {
+            trapped_seconds: DEFAULT_TRAPPED_SECONDS_BUCKETS.to_vec(),
+            lines_per_connection: DEFAULT_LINES_PER_CONNECTION_BUCKETS.to_vec(),
+            trap_score: DEFAULT_TRAP_SCORE_BUCKETS.to_vec(),
+        }
+    }
+}
+
+/// counts observations by the smallest bound they're at or below, exported as an openmetrics histogram
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// one per bound plus one for anything above the last, not cumulative
+    counts: Vec<AtomicU64>,
+    /// only the event loop observes, so a load and a store is enough
+    sum_bits: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: Vec<f64>) -> Self {
+        Histogram {
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            sum_bits: AtomicU64::new(0_f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        self.counts[self.bounds.partition_point(|bound| *bound < value)].fetch_add(1, Ordering::Relaxed);
+        self.sum_bits.store((self.sum() + value).to_bits(), Ordering::Relaxed);
+    }
+
+    /// (upper bound, cumulative count) pairs, ending with infinity
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.bounds.iter().copied().chain([f64::INFINITY]).zip(&self.counts)
+            .map(|(bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect()
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum_bits.load(Ordering::Relaxed))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
 }
 
 impl Default for EndlesshOptions {
     fn default() -> Self {
         EndlesshOptions {
             max_clients: 4096,
+            max_clients_per_ip: None,
+            allow_cidrs: None,
+            deny_cidrs: None,
             banner_line_length: 32,
+            banner_line_length_max: None,
+            banner: BannerGenerator::Alphanumeric,
             message_delay: Duration::from_secs(10),
+            message_delay_jitter: None,
+            variants: Vec::new(),
+            adaptive_delay_max: None,
+            stall_limit: None,
+            zero_window_timeout: None,
+            max_trap_duration: None,
+            chatty_threshold: None,
+            chatty_penalty: ChattyPenalty::Slow(4),
+            sensor_id: None,
+            repeat_offenders: None,
+            max_startups: None,
+            trap_probability: 1.0,
+            dry_run: false,
+            proxy_protocol: false,
             newline: NewLine::LF,
+            preallocate_clients: true,
+            line_events: false,
+            max_send_rate: None,
+            accept_rate: None,
+            accept_burst: None,
+            receive_buffer: None,
+            send_buffer: None,
+            histogram_buckets: HistogramBuckets::default(),
+            rng_seed: None,
         }
     }
 }
 
+impl EndlesshOptions {
+    /// the configured limits as (name, help, value), exported as gauges
+    pub fn fields(&self) -> [(&'static str, &'static str, f64); 5] {
+        [
+            ("max_clients", "the most clients trapped at once", self.max_clients as f64),
+            ("message_delay_seconds", "the configured delay between lines", self.message_delay.as_secs_f64()),
+            ("message_delay_jitter_seconds", "the most the delay between lines is varied by", self.message_delay_jitter.unwrap_or_default().as_secs_f64()),
+            ("banner_line_length", "the length of a banner line, or the shortest with a range", self.banner_line_length as f64),
+            ("banner_line_length_max", "the longest a banner line can be", self.longest_banner_line() as f64),
+        ]
+    }
+
+    /// the longest a banner line can be, not counting the newline
+    pub fn longest_banner_line(&self) -> usize {
+        self.banner_line_length_max.unwrap_or(self.banner_line_length)
+    }
+}
+
+/// counters shared between the event loop and any readers (exporters, admin threads)
+///
+/// all fields are atomics so an `Arc<EndlesshStats>` can be read without borrowing the server
 pub struct EndlesshStats {
     pub started_time: Instant,
-    pub last_known_time: Instant,
-    pub connections_opened: usize,
-    pub connections_closed: usize,
-    pub bytes_generated: usize,
-    pub bytes_sent: usize,
-    pub trapped_time: Duration,
+    /// wall-clock counterpart of `started_time`, meaningful across restarts
+    pub started_at: SystemTime,
+    last_known_time_nanos: AtomicU64,
+    pub connections_opened: AtomicUsize,
+    pub connections_closed: AtomicUsize,
+    pub bytes_generated: AtomicUsize,
+    pub bytes_sent: AtomicUsize,
+    /// read from clients as it arrives once they are watched (see [EndlesshServer::watch_clients]), and on their turn while
+    /// watching for chatty clients
+    pub bytes_received: AtomicUsize,
+    pub accept_errors: AtomicUsize,
+    /// connections hung up on straight away by `max_startups`
+    pub connections_refused: AtomicUsize,
+    /// connections hung up on straight away because they weren't picked by `trap_probability`
+    pub connections_sampled_out: AtomicUsize,
+    /// clients hung up on after hitting `stall_limit`
+    pub stalled_evictions: AtomicUsize,
+    /// lines the socket buffer only had room for part of, the rest going out on the client's next turn
+    pub partial_writes: AtomicUsize,
+    /// clients hung up on after hitting `zero_window_timeout`
+    pub zero_window_evictions: AtomicUsize,
+    /// clients hung up on after being trapped for `max_trap_duration`
+    pub max_trap_evictions: AtomicUsize,
+    /// clients penalized for sending more than `chatty_threshold`
+    pub chatty_clients: AtomicUsize,
+    /// clients trapped more slowly for having been trapped before, see `repeat_offenders`
+    pub repeat_offenders: AtomicUsize,
+    /// wakeups that left due clients waiting because `max_send_rate` was used up
+    pub send_budget_waits: AtomicUsize,
+    /// times `accept_rate` left connections waiting in the listen backlog
+    pub accepts_deferred: AtomicUsize,
+    /// connections dropped for a missing, malformed or late PROXY header
+    pub proxy_header_errors: AtomicUsize,
+    /// connections hung up on for coming from outside `allow_cidrs` or inside `deny_cidrs`
+    pub connections_filtered: AtomicUsize,
+    /// connections hung up on for `max_clients_per_ip`, or by an [AcceptPolicy](crate::policy::AcceptPolicy)
+    pub connections_rejected: AtomicUsize,
+    /// connections hung up on and handed to the embedder to block, by an [AcceptPolicy](crate::policy::AcceptPolicy)
+    pub connections_banned: AtomicUsize,
+    /// wakeups far later than asked for, or clock readings earlier than the last, whose time wasn't counted as trapped
+    pub time_jumps: AtomicUsize,
+    /// wakeups late enough to delay clients' lines, but not a time jump
+    pub late_wakeups: AtomicUsize,
+    /// connections opened from each range of source ports, see [EndlesshStats::source_ports]
+    source_ports: [AtomicUsize; SOURCE_PORT_RANGES],
+    trapped_time_nanos: AtomicU64,
+    /// see [EndlesshStats::take_worst_wakeup_lateness]
+    worst_wakeup_lateness_nanos: AtomicU64,
+    last_connection_opened_at: AtomicU64,
+    last_connection_closed_at: AtomicU64,
+    last_rate_update_nanos: AtomicU64,
+    connection_rate: EwmaRate,
+    bytes_sent_rate: EwmaRate,
+    /// how long each closed connection was trapped for
+    pub trapped_seconds: Histogram,
+    /// how many lines each closed connection was sent
+    pub lines_per_connection: Histogram,
+    /// the [trap_score] of each closed connection
+    pub trap_score: Histogram,
+}
+
+/// an exponentially weighted moving average of how fast a counter grows, in events per second
+///
+/// only the event loop updates it, the atomics just make it readable from elsewhere
+struct EwmaRate {
+    per_second_bits: AtomicU64,
+    last_total: AtomicU64,
+}
+
+impl EwmaRate {
+    fn new() -> Self {
+        EwmaRate {
+            per_second_bits: AtomicU64::new(0_f64.to_bits()),
+            last_total: AtomicU64::new(0),
+        }
+    }
+
+    /// the rate after folding in the growth of `total` over `elapsed`
+    fn current(&self, total: u64, elapsed: Duration) -> f64 {
+        let previous = f64::from_bits(self.per_second_bits.load(Ordering::Relaxed));
+        if elapsed.is_zero() {
+            return previous;
+        }
+        let growth = total.saturating_sub(self.last_total.load(Ordering::Relaxed)) as f64;
+        let alpha = 1.0 - (-elapsed.as_secs_f64() / RATE_WINDOW.as_secs_f64()).exp();
+        previous + alpha * (growth / elapsed.as_secs_f64() - previous)
+    }
+
+    fn update(&self, total: u64, elapsed: Duration) {
+        self.per_second_bits.store(self.current(total, elapsed).to_bits(), Ordering::Relaxed);
+        self.last_total.store(total, Ordering::Relaxed);
+    }
 }
 
 impl Default for EndlesshStats {
     fn default() -> Self {
-        let now = Instant::now();
+        Self::new(&HistogramBuckets::default())
+    }
+}
+
+impl EndlesshStats {
+    pub fn new(buckets: &HistogramBuckets) -> Self {
         Self {
-            started_time: now,
-            last_known_time: now,
-            trapped_time: Duration::ZERO,
-            connections_opened: 0,
-            connections_closed: 0,
-            bytes_generated: 0,
+            started_time: Instant::now(),
+            started_at: SystemTime::now(),
+            last_known_time_nanos: AtomicU64::new(0),
+            trapped_time_nanos: AtomicU64::new(0),
+            worst_wakeup_lateness_nanos: AtomicU64::new(0),
+            connections_opened: AtomicUsize::new(0),
+            connections_closed: AtomicUsize::new(0),
+            bytes_generated: AtomicUsize::new(0),
+            bytes_sent: AtomicUsize::new(0),
+            bytes_received: AtomicUsize::new(0),
+            accept_errors: AtomicUsize::new(0),
+            connections_refused: AtomicUsize::new(0),
+            connections_sampled_out: AtomicUsize::new(0),
+            stalled_evictions: AtomicUsize::new(0),
+            partial_writes: AtomicUsize::new(0),
+            zero_window_evictions: AtomicUsize::new(0),
+            max_trap_evictions: AtomicUsize::new(0),
+            chatty_clients: AtomicUsize::new(0),
+            repeat_offenders: AtomicUsize::new(0),
+            send_budget_waits: AtomicUsize::new(0),
+            accepts_deferred: AtomicUsize::new(0),
+            proxy_header_errors: AtomicUsize::new(0),
+            connections_filtered: AtomicUsize::new(0),
+            connections_rejected: AtomicUsize::new(0),
+            connections_banned: AtomicUsize::new(0),
+            time_jumps: AtomicUsize::new(0),
+            late_wakeups: AtomicUsize::new(0),
+            source_ports: std::array::from_fn(|_| AtomicUsize::new(0)),
+            last_connection_opened_at: AtomicU64::new(0),
+            last_connection_closed_at: AtomicU64::new(0),
+            last_rate_update_nanos: AtomicU64::new(0),
+            connection_rate: EwmaRate::new(),
+            bytes_sent_rate: EwmaRate::new(),
+            trapped_seconds: Histogram::new(buckets.trapped_seconds.clone()),
+            lines_per_connection: Histogram::new(buckets.lines_per_connection.clone()),
+            trap_score: Histogram::new(buckets.trap_score.clone()),
+        }
+    }
+
+    /// (name, help, histogram)
+    pub fn histograms(&self) -> [(&'static str, &'static str, &Histogram); 3] {
+        [
+            ("trapped_seconds", "deprecated, the same as endlessh_trap_duration_seconds", &self.trapped_seconds),
+            ("lines_per_connection", "how many lines each closed connection was sent", &self.lines_per_connection),
+            ("trap_score", "the trap score of each closed connection", &self.trap_score),
+        ]
+    }
+
+    /// adds the stats and histograms to an exposition, as `endlessh_ssh_*` families
+    pub fn expose(&self, exposition: &mut Exposition, labels: &impl Display) {
+        self.snapshot().expose(exposition, labels);
+        for (name, help, histogram) in self.histograms() {
+            exposition.family(&format!("endlessh_ssh_{}", name), MetricType::Histogram, help);
+            exposition.histogram(labels, histogram);
+        }
+        // endlessh_ssh_trapped_seconds under the name it was asked for, the old one staying until dashboards have moved
+        exposition.family("endlessh_trap_duration_seconds", MetricType::Histogram, "how long each closed connection was trapped for, from accept to hang up");
+        exposition.histogram(labels, &self.trapped_seconds);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        Duration::from_nanos(self.last_known_time_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn trapped_time(&self) -> Duration {
+        Duration::from_nanos(self.trapped_time_nanos.load(Ordering::Relaxed))
+    }
+
+    /// the latest any wakeup was since the previous call, for a single reader checking in periodically (the health check)
+    pub fn take_worst_wakeup_lateness(&self) -> Duration {
+        Duration::from_nanos(self.worst_wakeup_lateness_nanos.swap(0, Ordering::Relaxed))
+    }
+
+    fn observe_time(&self, now: &Instant) {
+        let since_start = now.duration_since(self.started_time).as_nanos() as u64;
+        let last_known = self.last_known_time_nanos.load(Ordering::Relaxed);
+        if since_start < last_known {
+            // a broken monotonic clock, or an embedder passing in a stale instant. carry on from the latest time seen
+            warn!(behind_ms = (last_known - since_start) / 1_000_000; "time jump, the clock went backwards");
+            self.time_jumps.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.last_known_time_nanos.store(since_start, Ordering::Relaxed);
+
+        let since_rate_update = Duration::from_nanos(since_start.saturating_sub(self.last_rate_update_nanos.load(Ordering::Relaxed)));
+        if since_rate_update >= RATE_SAMPLE_INTERVAL {
+            self.connection_rate.update(self.connections_opened.load(Ordering::Relaxed) as u64, since_rate_update);
+            self.bytes_sent_rate.update(self.bytes_sent.load(Ordering::Relaxed) as u64, since_rate_update);
+            self.last_rate_update_nanos.store(since_start, Ordering::Relaxed);
+        }
+    }
+
+    /// connections opened from each range of source ports, as (first port, last port, count). unix socket clients have
+    /// no port and aren't counted
+    pub fn source_ports(&self) -> impl Iterator<Item = (u16, u16, usize)> + '_ {
+        self.source_ports.iter().enumerate().map(|(i, count)| {
+            let first = i * SOURCE_PORT_RANGE_WIDTH;
+            (first as u16, (first + SOURCE_PORT_RANGE_WIDTH - 1) as u16, count.load(Ordering::Relaxed))
+        })
+    }
+
+    fn record_connection_opened(&self, peer: SocketAddr, at: SystemTime) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+        self.last_connection_opened_at.store(unix_seconds(at), Ordering::Relaxed);
+        if peer != UNIX_PEER {
+            self.source_ports[peer.port() as usize / SOURCE_PORT_RANGE_WIDTH].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_connection_closed(&self, at: SystemTime) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+        self.last_connection_closed_at.store(unix_seconds(at), Ordering::Relaxed);
+    }
+
+    fn add_trapped_time(&self, trapped: Duration) {
+        self.trapped_time_nanos.fetch_add(trapped.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// the canonical (name, value) view of the stats, shared by every output format
+    fn fields(&self) -> [(&'static str, u64); 27] {
+        [
+            ("start_time_seconds", unix_seconds(self.started_at)),
+            ("uptime_seconds", self.uptime().as_secs()),
+            ("trapped_time_seconds", self.trapped_time().as_secs()),
+            ("connections_opened", self.connections_opened.load(Ordering::Relaxed) as u64),
+            ("connections_closed", self.connections_closed.load(Ordering::Relaxed) as u64),
+            ("bytes_generated", self.bytes_generated.load(Ordering::Relaxed) as u64),
+            ("bytes_sent", self.bytes_sent.load(Ordering::Relaxed) as u64),
+            ("bytes_received", self.bytes_received.load(Ordering::Relaxed) as u64),
+            ("accept_errors", self.accept_errors.load(Ordering::Relaxed) as u64),
+            ("connections_refused", self.connections_refused.load(Ordering::Relaxed) as u64),
+            ("connections_sampled_out", self.connections_sampled_out.load(Ordering::Relaxed) as u64),
+            ("stalled_evictions", self.stalled_evictions.load(Ordering::Relaxed) as u64),
+            ("partial_writes", self.partial_writes.load(Ordering::Relaxed) as u64),
+            ("zero_window_evictions", self.zero_window_evictions.load(Ordering::Relaxed) as u64),
+            ("max_trap_evictions", self.max_trap_evictions.load(Ordering::Relaxed) as u64),
+            ("chatty_clients", self.chatty_clients.load(Ordering::Relaxed) as u64),
+            ("repeat_offenders", self.repeat_offenders.load(Ordering::Relaxed) as u64),
+            ("send_budget_waits", self.send_budget_waits.load(Ordering::Relaxed) as u64),
+            ("accepts_deferred", self.accepts_deferred.load(Ordering::Relaxed) as u64),
+            ("proxy_header_errors", self.proxy_header_errors.load(Ordering::Relaxed) as u64),
+            ("connections_filtered", self.connections_filtered.load(Ordering::Relaxed) as u64),
+            ("connections_rejected", self.connections_rejected.load(Ordering::Relaxed) as u64),
+            ("connections_banned", self.connections_banned.load(Ordering::Relaxed) as u64),
+            ("time_jumps", self.time_jumps.load(Ordering::Relaxed) as u64),
+            ("late_wakeups", self.late_wakeups.load(Ordering::Relaxed) as u64),
+            ("last_connection_opened_time_seconds", self.last_connection_opened_at.load(Ordering::Relaxed)),
+            ("last_connection_closed_time_seconds", self.last_connection_closed_at.load(Ordering::Relaxed)),
+        ]
+    }
+
+    /// how a field from [EndlesshStats::fields] is exported, and what it means
+    fn describe(name: &str) -> (MetricType, &'static str) {
+        match name {
+            "start_time_seconds" => (MetricType::Gauge, "when the tarpit started, in seconds since the unix epoch"),
+            "uptime_seconds" => (MetricType::Gauge, "how long the tarpit has been running"),
+            "trapped_time_seconds" => (MetricType::Counter, "the time clients have spent trapped, added up"),
+            "connections_opened" => (MetricType::Counter, "connections accepted"),
+            "connections_closed" => (MetricType::Counter, "connections closed"),
+            "bytes_generated" => (MetricType::Counter, "bytes of banner generated"),
+            "bytes_sent" => (MetricType::Counter, "bytes written to clients"),
+            "bytes_received" => (MetricType::Counter, "bytes read from clients"),
+            "accept_errors" => (MetricType::Counter, "failed accepts"),
+            "connections_refused" => (MetricType::Counter, "connections hung up on by --ssh-max-startups"),
+            "connections_sampled_out" => (MetricType::Counter, "connections hung up on for not being picked by --ssh-trap-probability"),
+            "stalled_evictions" => (MetricType::Counter, "clients hung up on after --ssh-stall-limit sends that wrote nothing"),
+            "partial_writes" => (MetricType::Counter, "lines only part of which fit in the socket buffer"),
+            "zero_window_evictions" => (MetricType::Counter, "clients hung up on after --ssh-zero-window-timeout-secs"),
+            "max_trap_evictions" => (MetricType::Counter, "clients hung up on after --ssh-max-trap-seconds"),
+            "chatty_clients" => (MetricType::Counter, "clients penalized for sending more than --ssh-chatty-bytes"),
+            "repeat_offenders" => (MetricType::Counter, "clients trapped more slowly for having been trapped before"),
+            "send_budget_waits" => (MetricType::Counter, "wakeups that left clients waiting for --max-send-bytes-per-sec"),
+            "accepts_deferred" => (MetricType::Counter, "times --ssh-accept-rate left connections in the listen backlog"),
+            "proxy_header_errors" => (MetricType::Counter, "connections dropped for a missing, malformed or late PROXY header"),
+            "connections_filtered" => (MetricType::Counter, "connections hung up on by the allow and deny lists"),
+            "connections_rejected" => (MetricType::Counter, "connections hung up on by --ssh-max-clients-per-ip or an accept policy"),
+            "connections_banned" => (MetricType::Counter, "connections hung up on and handed over to be blocked"),
+            "time_jumps" => (MetricType::Counter, "wakeups far later than asked for, or clock readings earlier than the last"),
+            "late_wakeups" => (MetricType::Counter, "wakeups late enough to delay clients' lines"),
+            "last_connection_opened_time_seconds" => (MetricType::Gauge, "when the latest connection was accepted, in seconds since the unix epoch"),
+            "last_connection_closed_time_seconds" => (MetricType::Gauge, "when the latest connection was closed, in seconds since the unix epoch"),
+            _ => unreachable!("stats field {} isn't described", name),
+        }
+    }
+
+    /// rolling rates for readers without a time series database, decayed up to the current time
+    fn rates(&self) -> [(&'static str, f64); 2] {
+        let since_rate_update = Instant::now().duration_since(self.started_time)
+            .saturating_sub(Duration::from_nanos(self.last_rate_update_nanos.load(Ordering::Relaxed)));
+        [
+            ("connections_per_minute", 60.0 * self.connection_rate.current(self.connections_opened.load(Ordering::Relaxed) as u64, since_rate_update)),
+            ("bytes_sent_per_second", self.bytes_sent_rate.current(self.bytes_sent.load(Ordering::Relaxed) as u64, since_rate_update)),
+        ]
+    }
+}
+
+/// binds a tcp listener, explaining the likely cause when that fails
+pub fn bind_listener(address: SocketAddr) -> error::Result<TcpListener> {
+    TcpListener::bind(address).map_err(|source| EndlesshError::bind(address, Some(address.port()), source))
+}
+
+/// seconds since the unix epoch, or 0 if the clock is set before it
+pub fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// a point-in-time copy of the stats - the canonical representation shared by every output format
+#[derive(Clone)]
+pub struct StatsSnapshot {
+    fields: [(&'static str, u64); 27],
+    rates: [(&'static str, f64); 2],
+}
+
+impl EndlesshStats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            fields: self.fields(),
+            rates: self.rates(),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    pub fn fields(&self) -> &[(&'static str, u64)] {
+        &self.fields
+    }
+
+    pub fn rates(&self) -> &[(&'static str, f64)] {
+        &self.rates
+    }
+}
+
+impl StatsSnapshot {
+    /// adds the fields and rates to an exposition, as `endlessh_ssh_*` families
+    pub fn expose(&self, exposition: &mut Exposition, labels: &impl Display) {
+        for (name, value) in self.fields {
+            let (metric_type, help) = EndlesshStats::describe(name);
+            exposition.family(&format!("endlessh_ssh_{}", name), metric_type, help);
+            exposition.sample(labels, value);
+        }
+        for (name, value) in self.rates {
+            exposition.family(&format!("endlessh_ssh_{}", name), MetricType::Gauge, "a moving average over about the last minute");
+            exposition.sample(labels, value);
+        }
+    }
+}
+
+/// the snapshot as a complete openmetrics exposition
+impl Display for StatsSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let mut exposition = Exposition::new();
+        self.expose(&mut exposition, &"");
+        f.write_str(&exposition.finish())
+    }
+}
+
+impl Serialize for StatsSnapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("StatsSnapshot", self.fields.len() + self.rates.len())?;
+        for (name, value) in self.fields {
+            state.serialize_field(name, &value)?;
+        }
+        for (name, value) in self.rates {
+            state.serialize_field(name, &value)?;
+        }
+        state.end()
+    }
+}
+
+/// the stats and histograms as a complete openmetrics exposition
+impl Display for EndlesshStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let mut exposition = Exposition::new();
+        self.expose(&mut exposition, &"");
+        f.write_str(&exposition.finish())
+    }
+}
+
+impl Serialize for EndlesshStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+pub struct EndlesshServer {
+    listeners: Vec<SshListener>,
+    admission: Admission,
+    tarpit: Tarpit<ClientStream>,
+}
+
+/// one of the addresses clients are trapped from, they all feed the same tarpit
+struct SshListener {
+    listener: ListenSocket,
+    token: Token,
+    address: ListenAddress,
+    /// e.g. "edge-22", attached to the logs, events and metrics of the clients it accepted
+    name: Option<Arc<str>>,
+    connections_opened: usize,
+    accept_available: bool,
+    /// accepted with `proxy_protocol`, still waiting for their header
+    pending: Vec<PendingClient>,
+}
+
+/// a connection from a load balancer that hasn't yet said who it's for
+struct PendingClient {
+    stream: ClientStream,
+    /// the balancer's end
+    peer: SocketAddr,
+    accepted_time: Instant,
+    /// what has arrived so far - anything the client sent after the header is read along with it, and dropped
+    received: Vec<u8>,
+}
+
+impl PendingClient {
+
+    /// the client's real address once the header is complete, its own for a LOCAL header, None while it's incomplete
+    fn read_header(&mut self) -> Result<Option<SocketAddr>, String> {
+        let mut buffer = [0; 256];
+        loop {
+            match proxy_protocol::parse(&self.received)? {
+                Some(ProxyHeader::Proxied(peer)) => return Ok(Some(peer)),
+                Some(ProxyHeader::Local) => return Ok(Some(self.peer)),
+                None => {},
+            }
+            let wanted = buffer.len().min(proxy_protocol::MAX_HEADER_LENGTH - self.received.len());
+            match self.stream.read(&mut buffer[..wanted]) {
+                Ok(0) => return Err("closed before sending a PROXY header".to_string()),
+                Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+}
+
+/// what the admin api and metrics show of a listener
+#[derive(Clone, Debug, Serialize)]
+pub struct ListenerSummary {
+    pub address: ListenAddress,
+    pub name: Option<String>,
+    /// clients trapped from this listener
+    pub connections_opened: usize,
+}
+
+/// what the metrics show of a strategy variant, for comparing them - all but `connections_opened` are counted as the
+/// connections close
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VariantTotals {
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+    pub bytes_sent: u64,
+    pub lines_sent: u64,
+    pub trapped_seconds: f64,
+    /// the [trap_score]s added up
+    pub trap_score: f64,
+}
+
+impl ListenerSummary {
+    /// the name, or the address for unnamed listeners
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.address.to_string())
+    }
+}
+
+/// the index of a variant picked at random by weight, None if there are none
+fn pick_variant(variants: &[StrategyVariant], rng: &mut fastrand::Rng) -> Option<usize> {
+    let total: u64 = variants.iter().map(|variant| variant.weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = rng.u64(..total);
+    variants.iter().position(|variant| match pick.checked_sub(variant.weight as u64) {
+        Some(rest) => {
+            pick = rest;
+            false
+        },
+        None => true,
+    })
+}
+
+/// listener names end up in metric labels and log lines, so keep them to something that needs no escaping
+pub fn is_valid_listener_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+/// the socket-independent core of the server - decides when each client is due a line and sends it
+///
+/// generic over the stream so the scheduling can be driven (and benchmarked) with mock clients
+pub struct Tarpit<S> {
+    line_buffer: [u8; SSH_LINE_BUFFER_SIZE],
+    /// how much of `line_buffer` the current line takes up, newline included
+    line_length: usize,
+    /// the strategy variant the current line was made for
+    line_variant: Option<usize>,
+    /// one per strategy variant, in the same order
+    variant_totals: Vec<VariantTotals>,
+    /// everything random about the tarpit comes from here, for `rng_seed`
+    rng: fastrand::Rng,
+    /// what clients send is read into here and dropped
+    drain_buffer: [u8; DRAIN_BUFFER_SIZE],
+    /// registers clients with the poll, once [EndlesshServer::watch_clients] is called
+    watch: Option<ClientWatch>,
+    /// new clients are left in the listen backlog while paused
+    paused: bool,
+    clients: VecDeque<EndlesshClient<S>>,
+    /// how many of the clients came from each address, for `max_clients_per_ip`
+    clients_per_ip: HashMap<IpAddr, usize>,
+    /// the addresses trapped before, for `repeat_offenders`
+    offenders: OffenderMemory,
+    /// `options.message_delay`, or wherever the adaptive delay currently is
+    message_delay: Duration,
+    /// `options.max_send_rate`'s bucket, drawn from by every line sent
+    send_budget: Option<SendBudget>,
+    /// `options.accept_rate`'s bucket, drawn from by every connection accepted
+    accept_budget: Option<AcceptBudget>,
+    /// when the accept budget has room again for the connections it left in the backlog
+    accepts_deferred_until: Option<Instant>,
+    events: VecDeque<ConnectionEvent>,
+    /// when the last wakeup asked to be woken up again, to tell a late one
+    expected_wakeup: Option<Instant>,
+    stats: Arc<EndlesshStats>,
+    options: EndlesshOptions,
+}
+
+struct EndlesshClient<S> {
+    stream: S,
+    peer: SocketAddr,
+    /// the name of the listener it was accepted on
+    listener: Option<Arc<str>>,
+    connected_time: Instant,
+    connected_at: SystemTime,
+    last_send_time: Option<Instant>,
+    bytes_sent: usize,
+    lines_sent: u64,
+    /// sends in a row that found the socket buffer full
+    stalled_sends: u32,
+    /// when the client was first seen advertising a zero window, since its last successful send
+    zero_window_since: Option<Instant>,
+    /// sent more than `chatty_threshold` between two lines at some point
+    chatty: bool,
+    /// how many times the message delay it waits between lines, above 1 for a repeat offender
+    delay_factor: u32,
+    /// how many sessions in a row its address had before this one, with `repeat_offenders`
+    returns: u32,
+    /// how far its next line is moved from the message delay, in nanoseconds either way, see `message_delay_jitter`
+    delay_jitter_nanos: i64,
+    /// lines skipped since the last one a chatty client or repeat offender was sent
+    skipped_sends: u32,
+    /// its index in `options.variants`, if there are any
+    variant: Option<usize>,
+    /// what it's registered with the poll under, None if it isn't
+    token: Option<Token>,
+    /// bytes read from it since its last turn, for `chatty_threshold`
+    received: usize,
+    /// the rest of a line its socket buffer only had room for part of, sent before it gets another
+    pending: Vec<u8>,
+}
+
+/// hands out the tokens trapped clients are registered with the poll under
+struct ClientWatch {
+    registry: Registry,
+    tokens: Range<usize>,
+    /// the lowest token not yet handed out
+    next: usize,
+    /// tokens of clients that have left, handed out again first
+    released: Vec<Token>,
+}
+
+impl ClientWatch {
+    /// registers a newly trapped client for its input, None if the tokens have run out or registering failed
+    fn register(&mut self, stream: &mut ClientStream) -> Option<Token> {
+        let token = self.released.pop().or_else(|| (self.next < self.tokens.end).then(|| {
+            self.next += 1;
+            Token(self.next - 1)
+        }))?;
+        match self.registry.register(stream, token, Interest::READABLE) {
+            Ok(()) => Some(token),
+            Err(e) => {
+                warn!("failed to register ssh client: {}", e);
+                self.released.push(token);
+                None
+            },
+        }
+    }
+}
+
+/// where a trapped client is in the schedule, for /clients
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientSchedule {
+    pub peer: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    /// seconds since the unix epoch
+    pub connected_time_seconds: f64,
+    pub lines_sent: u64,
+    pub bytes_sent: usize,
+    /// how long between the lines this client is sent, None while it's held for being chatty
+    pub delay_seconds: Option<f64>,
+    /// how long until its next line, 0 if it's overdue and None while it's held
+    pub next_send_seconds: Option<f64>,
+    pub stalled_sends: u32,
+    /// how long it has been advertising a zero window, i.e. not reading but still there
+    pub zero_window_seconds: Option<f64>,
+    pub chatty: bool,
+    /// the strategy variant it's trapped with, if there are any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionEventKind {
+    Accepted,
+    /// only recorded with [EndlesshOptions::line_events]
+    LineSent,
+    Closed,
+    /// hung up on by an [AcceptPolicy](crate::policy::AcceptPolicy) without being trapped, for the embedder to block
+    Banned,
+}
+
+/// something that happened to a single trapped client
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectionEvent {
+    pub event: ConnectionEventKind,
+    /// seconds since the unix epoch
+    pub time_seconds: f64,
+    pub peer: SocketAddr,
+    /// bytes sent to the client so far
+    pub bytes_sent: usize,
+    /// how long the client has been connected so far
+    pub trapped_seconds: f64,
+    /// lines sent to the client so far, missing from event logs written before it was added
+    #[serde(default)]
+    pub lines_sent: u64,
+    /// the name of the listener the client came in on, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    /// what the kernel saw of the connection, on close events where that can be had (linux)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<TcpStats>,
+    /// the `sensor_id` of the instance that trapped the client, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+    /// the [trap_score] of the connection, on close events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    /// the strategy variant the client was trapped with, if there are any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// one number to compare how well different banners and delays hold on to clients: each doubling of the time a client
+/// was trapped, of the lines it took and of the kilobytes it read adds a point, and each time its address came back in
+/// a row (counted with `repeat_offenders`) adds another
+pub fn trap_score(trapped_seconds: f64, lines_sent: u64, bytes_sent: usize, returns: u32) -> f64 {
+    (1.0 + trapped_seconds.max(0.0)).log2() + (1.0 + lines_sent as f64).log2() + (1.0 + bytes_sent as f64 / 1024.0).log2() + returns as f64
+}
+
+impl ConnectionEvent {
+    fn new<S>(event: ConnectionEventKind, client: &EndlesshClient<S>, now: &Instant) -> Self {
+        ConnectionEvent {
+            event,
+            time_seconds: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+            peer: client.peer,
+            bytes_sent: client.bytes_sent,
+            trapped_seconds: now.duration_since(client.connected_time).as_secs_f64(),
+            lines_sent: client.lines_sent,
+            listener: client.listener.as_deref().map(str::to_string),
+            tcp: None,
+            sensor: None,
+            score: None,
+            variant: None,
+        }
+    }
+
+    fn banned(peer: SocketAddr, listener: Option<&str>) -> Self {
+        ConnectionEvent {
+            event: ConnectionEventKind::Banned,
+            time_seconds: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+            peer,
             bytes_sent: 0,
+            trapped_seconds: 0.0,
+            lines_sent: 0,
+            listener: listener.map(str::to_string),
+            tcp: None,
+            sensor: None,
+            score: None,
+            variant: None,
         }
     }
 }
 
-impl Display for EndlesshStats {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f,
-            concat!(
-                "endlessh_ssh_uptime_seconds: {}\n",
-                "endlessh_ssh_trapped_time_seconds: {}\n",
-                "endlessh_ssh_connections_opened: {}\n",
-                "endlessh_ssh_connections_closed: {}\n",
-                "endlessh_ssh_bytes_generated: {}\n",
-                "endlessh_ssh_bytes_sent: {}\n",
-            ),
-            self.last_known_time.duration_since(self.started_time).as_secs(),
-            self.connections_opened,
-            self.connections_closed,
-            self.bytes_generated,
-            self.bytes_sent,
-            self.trapped_time.as_secs(),
-        )?;
-        Ok(())
+impl EndlesshServer {
+
+    /// binds a listener to `address` and creates a server on it
+    pub fn bind(options: EndlesshOptions, address: SocketAddr, listener_token: Token, poll: &Poll) -> error::Result<Self> {
+        Self::create(options, bind_listener(address)?, None, listener_token, poll)
+    }
+
+    pub fn create(options: EndlesshOptions, listener: impl Into<ListenSocket>, listener_name: Option<&str>, listener_token: Token, poll: &Poll) -> error::Result<Self> {
+        let stats = Arc::new(EndlesshStats::new(&options.histogram_buckets));
+        let mut server = EndlesshServer {
+            listeners: Vec::new(),
+            admission: Admission::default(),
+            tarpit: Tarpit::new(options, stats)?,
+        };
+        server.add_listener(listener, listener_name, listener_token, poll)?;
+        Ok(server)
+    }
+
+    /// starts trapping clients from another listener, returning the address it's bound to
+    pub fn add_listener(&mut self, listener: impl Into<ListenSocket>, name: Option<&str>, token: Token, poll: &Poll) -> error::Result<ListenAddress> {
+        let mut listener = listener.into();
+        let address = listener.local_address().map_err(|source| EndlesshError::Register { what: "ssh listener", source })?;
+        poll.registry().register(&mut listener, token, Interest::READABLE)
+            .map_err(|source| EndlesshError::Register { what: "ssh listener", source })?;
+        self.listeners.push(SshListener {
+            listener,
+            token,
+            address: address.clone(),
+            name: name.map(Arc::from),
+            connections_opened: 0,
+            accept_available: false,
+            pending: Vec::new(),
+        });
+        Ok(address)
+    }
+
+    /// registers clients trapped from now on with the poll under tokens from `tokens`, so what they send is read and
+    /// dropped as it arrives rather than left in the socket buffers. clients beyond the range are only read on their
+    /// turn, while watching for chatty clients
+    pub fn watch_clients(&mut self, tokens: Range<usize>, poll: &Poll) -> error::Result<()> {
+        let registry = poll.registry().try_clone().map_err(|source| EndlesshError::Register { what: "ssh clients", source })?;
+        self.tarpit.watch = Some(ClientWatch { registry, next: tokens.start, tokens, released: Vec::new() });
+        Ok(())
+    }
+
+    /// stops listening on `address`, clients already trapped from it stay trapped. returns false if there's no such listener
+    pub fn remove_listener(&mut self, address: &ListenAddress, poll: &Poll) -> bool {
+        let Some(index) = self.listeners.iter().position(|l| l.address == *address) else {
+            return false;
+        };
+        let mut removed = self.listeners.remove(index);
+        if let Err(e) = poll.registry().deregister(&mut removed.listener) {
+            warn!("failed to deregister ssh listener {}: {}", address, e);
+        }
+        true
+    }
+
+    pub fn listen_addresses(&self) -> impl Iterator<Item = &ListenAddress> + '_ {
+        self.listeners.iter().map(|l| &l.address)
+    }
+
+    pub fn listeners(&self) -> impl Iterator<Item = ListenerSummary> + '_ {
+        self.listeners.iter().map(|l| ListenerSummary {
+            address: l.address.clone(),
+            name: l.name.as_deref().map(str::to_string),
+            connections_opened: l.connections_opened,
+        })
+    }
+
+    pub fn try_handle_event(&mut self, event: &event::Event, now: &Instant) -> bool {
+        self.tarpit.stats.observe_time(now);
+        let Some(listener) = self.listeners.iter_mut().find(|l| l.token == event.token()) else {
+            let listeners = &mut self.listeners;
+            let admission = &mut self.admission;
+            // a client that hung up frees a slot, so fill it straight away
+            return self.tarpit.receive(event.token(), now, |tarpit| Self::accept_from_all(listeners, admission, tarpit, now));
+        };
+        listener.accept_available = true;
+        Self::accept_new_connections(listener, &mut self.admission, &mut self.tarpit, now);
+        true
+    }
+
+    pub fn handle_wakeup(&mut self, now: &Instant) -> Option<Duration> {
+        let mut headers_pending = false;
+        for listener in self.listeners.iter_mut().filter(|l| !l.pending.is_empty()) {
+            // reading headers makes room to accept the connections they held back
+            Self::accept_new_connections(listener, &mut self.admission, &mut self.tarpit, now);
+            headers_pending |= !listener.pending.is_empty();
+        }
+        if self.tarpit.accepts_deferred_until.is_some_and(|until| until <= *now) {
+            self.tarpit.accepts_deferred_until = None;
+            Self::accept_from_all(&mut self.listeners, &mut self.admission, &mut self.tarpit, now);
+        }
+        let listeners = &mut self.listeners;
+        let admission = &mut self.admission;
+        // a closed client frees a slot, so fill it straight away
+        let mut next_wakeup = self.tarpit.wakeup(now, |tarpit| Self::accept_from_all(listeners, admission, tarpit, now));
+        if headers_pending {
+            next_wakeup = next_wakeup.map_or(Some(PROXY_HEADER_RETRY_INTERVAL), |wakeup| Some(wakeup.min(PROXY_HEADER_RETRY_INTERVAL)));
+        }
+        if let Some(until) = self.tarpit.accepts_deferred_until {
+            let wait = until.saturating_duration_since(*now);
+            next_wakeup = next_wakeup.map_or(Some(wait), |wakeup| Some(wakeup.min(wait)));
+        }
+        next_wakeup
+    }
+
+    /// replaces the addresses that are never trapped, those already trapped stay trapped
+    pub fn set_allowlist(&mut self, allowlist: AddressList) {
+        self.admission.allowlist = allowlist;
+    }
+
+    /// adds a step to the decision on each arriving client, after the built in ones and any added before it
+    pub fn add_policy(&mut self, policy: impl AcceptPolicy + 'static) {
+        self.admission.add_policy(Box::new(policy));
+    }
+
+    /// stops accepting while `accepting` is false, e.g. to shed load - queued connections are picked up on resume
+    pub fn set_accepting(&mut self, accepting: bool, now: &Instant) {
+        self.tarpit.set_paused(!accepting);
+        if accepting {
+            Self::accept_from_all(&mut self.listeners, &mut self.admission, &mut self.tarpit, now);
+        }
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        !self.tarpit.is_paused()
+    }
+
+    /// swaps in new options without touching the trapped clients, see [Tarpit::reconfigure]. a raised `max_clients`
+    /// is filled from the listen backlog straight away
+    pub fn reconfigure(&mut self, options: EndlesshOptions, now: &Instant) -> error::Result<()> {
+        self.tarpit.reconfigure(options)?;
+        Self::accept_from_all(&mut self.listeners, &mut self.admission, &mut self.tarpit, now);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &Arc<EndlesshStats> {
+        self.tarpit.stats()
+    }
+
+    pub fn options(&self) -> &EndlesshOptions {
+        self.tarpit.options()
+    }
+
+    /// every strategy variant with its totals so far
+    pub fn variants(&self) -> impl Iterator<Item = (&StrategyVariant, &VariantTotals)> + '_ {
+        self.tarpit.variants()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.tarpit.client_count()
+    }
+
+    pub fn message_delay(&self) -> Duration {
+        self.tarpit.message_delay()
+    }
+
+    /// see [Tarpit::schedule]
+    pub fn schedule(&self, now: &Instant) -> Vec<ClientSchedule> {
+        self.tarpit.schedule(now)
+    }
+
+    /// takes the connection events recorded since the last call, marked with the `sensor_id` unless they came from
+    /// another sensor
+    pub fn drain_events(&mut self) -> impl Iterator<Item = ConnectionEvent> + '_ {
+        let sensor = self.tarpit.options.sensor_id.clone();
+        self.tarpit.drain_events().map(move |mut event| {
+            if event.sensor.is_none() {
+                event.sensor.clone_from(&sensor);
+            }
+            event
+        })
+    }
+
+    /// hangs up on every trapped client, see [Tarpit::close_all]
+    pub fn close_all(&mut self, farewell: Option<&str>, now: &Instant) {
+        self.tarpit.close_all(farewell, now);
+    }
+
+    /// counts a previously recorded event as if it just happened, for `endlessh-rs replay`
+    pub fn replay_event(&mut self, mut event: ConnectionEvent, now: &Instant) {
+        event.time_seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64());
+        self.ingest_event(event, now);
+    }
+
+    /// counts an event another sensor recorded, keeping its time, for `endlessh-rs aggregate`
+    pub fn ingest_event(&mut self, event: ConnectionEvent, now: &Instant) {
+        let stats = &self.tarpit.stats;
+        stats.observe_time(now);
+        let at = SystemTime::now();
+        match event.event {
+            ConnectionEventKind::Accepted => stats.record_connection_opened(event.peer, at),
+            // the close event carries the totals
+            ConnectionEventKind::LineSent => {},
+            ConnectionEventKind::Banned => {
+                stats.connections_banned.fetch_add(1, Ordering::Relaxed);
+            },
+            ConnectionEventKind::Closed => {
+                stats.record_connection_closed(at);
+                // a live client is counted line by line, a replayed one all at once when it goes
+                stats.bytes_generated.fetch_add(event.bytes_sent, Ordering::Relaxed);
+                stats.bytes_sent.fetch_add(event.bytes_sent, Ordering::Relaxed);
+                stats.add_trapped_time(Duration::try_from_secs_f64(event.trapped_seconds).unwrap_or_default());
+                stats.trapped_seconds.observe(event.trapped_seconds);
+                stats.lines_per_connection.observe(event.lines_sent as f64);
+                if let Some(score) = event.score {
+                    stats.trap_score.observe(score);
+                }
+            },
+        }
+        self.tarpit.events.push_back(event);
+    }
+
+    fn accept_from_all(listeners: &mut [SshListener], admission: &mut Admission, tarpit: &mut Tarpit<ClientStream>, now: &Instant) {
+        for listener in listeners {
+            Self::accept_new_connections(listener, admission, tarpit, now);
+        }
+    }
+
+    fn accept_new_connections(listener: &mut SshListener, admission: &mut Admission, tarpit: &mut Tarpit<ClientStream>, now: &Instant) {
+        Self::read_proxy_headers(listener, admission, tarpit, now);
+        while listener.accept_available && tarpit.has_capacity() && listener.pending.len() < MAX_PENDING_PROXY_HEADERS {
+            if let Some(wait) = tarpit.accept_budget.as_mut().and_then(|budget| budget.wait(now)) {
+                // the rest wait in the backlog, handle_wakeup comes back for them
+                if tarpit.accepts_deferred_until.is_none() {
+                    tarpit.stats.accepts_deferred.fetch_add(1, Ordering::Relaxed);
+                }
+                tarpit.accepts_deferred_until = Some(*now + wait);
+                break;
+            }
+            let accepted = listener.listener.accept();
+            if accepted.is_ok() {
+                if let Some(budget) = &mut tarpit.accept_budget {
+                    budget.take();
+                }
+            }
+            if let Ok((stream, peer)) = &accepted {
+                if let Err(e) = stream.set_buffer_sizes(tarpit.options.receive_buffer, tarpit.options.send_buffer) {
+                    debug!(peer:% = peer, listener = listener.name.as_deref().unwrap_or_default(); "failed to size socket buffers: {}", e);
+                }
+            }
+            match accepted {
+                Ok((stream, peer)) if tarpit.options.proxy_protocol => {
+                    let mut client = PendingClient {
+                        stream,
+                        peer,
+                        accepted_time: *now,
+                        received: Vec::new(),
+                    };
+                    // the balancer usually sends the header along with the connection, so it's likely already here
+                    match client.read_header() {
+                        Ok(Some(peer)) => Self::admit(listener, client.stream, peer, admission, tarpit, now),
+                        Ok(None) => listener.pending.push(client),
+                        Err(reason) => Self::reject_proxy_header(listener, &client, &reason, tarpit),
+                    }
+                },
+                Ok((stream, peer)) => Self::admit(listener, stream, peer, admission, tarpit, now),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    listener.accept_available = false;
+                }
+                Err(e) => {
+                    // e.g. out of file descriptors - leave the connection queued and retry on the next wakeup
+                    warn!("failed to accept connection: {}", e);
+                    tarpit.stats.accept_errors.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+            };
+        }
+    }
+
+    /// admits the clients whose PROXY headers have arrived, and drops those whose headers are bad or overdue
+    fn read_proxy_headers(listener: &mut SshListener, admission: &mut Admission, tarpit: &mut Tarpit<ClientStream>, now: &Instant) {
+        for mut client in std::mem::take(&mut listener.pending) {
+            match client.read_header() {
+                Ok(Some(peer)) => Self::admit(listener, client.stream, peer, admission, tarpit, now),
+                Ok(None) if now.saturating_duration_since(client.accepted_time) >= PROXY_HEADER_TIMEOUT => {
+                    Self::reject_proxy_header(listener, &client, "timed out waiting for a PROXY header", tarpit);
+                },
+                Ok(None) => listener.pending.push(client),
+                Err(reason) => Self::reject_proxy_header(listener, &client, &reason, tarpit),
+            }
+        }
+    }
+
+    fn reject_proxy_header(listener: &SshListener, client: &PendingClient, reason: &str, tarpit: &Tarpit<ClientStream>) {
+        debug!(peer:% = client.peer, listener = listener.name.as_deref().unwrap_or_default(), reason; "dropping connection without a valid PROXY header");
+        tarpit.stats.proxy_header_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// traps (or doesn't) a client that has just arrived, `peer` being its real address
+    fn admit(listener: &mut SshListener, mut stream: ClientStream, peer: SocketAddr, admission: &mut Admission, tarpit: &mut Tarpit<ClientStream>, now: &Instant) {
+        let name = listener.name.as_deref().unwrap_or_default();
+        if admission.allowlist.contains(peer.ip()) {
+            debug!(peer:% = peer, listener = name; "not trapping allowlisted client");
+            return;
+        } else if tarpit.is_filtered(peer) {
+            debug!(peer:% = peer, listener = name; "not trapping client, filtered by address");
+            tarpit.stats.connections_filtered.fetch_add(1, Ordering::Relaxed);
+            return;
+        } else if tarpit.is_over_ip_limit(peer) {
+            debug!(peer:% = peer, listener = name; "not trapping client, too many from its address");
+            tarpit.stats.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            return;
+        } else if tarpit.should_refuse() {
+            debug!(peer:% = peer, listener = name, clients = tarpit.client_count(); "refusing client");
+            tarpit.stats.connections_refused.fetch_add(1, Ordering::Relaxed);
+            return;
+        } else if tarpit.rng.f64() >= tarpit.options.trap_probability {
+            debug!(peer:% = peer, listener = name; "not trapping unsampled client");
+            tarpit.stats.connections_sampled_out.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let verdict = admission.decide(peer, listener.name.as_deref(), tarpit.client_count(), tarpit.options.max_clients).unwrap_or(Verdict::Trap);
+        match verdict {
+            Verdict::Trap | Verdict::Pass => {
+                listener.connections_opened += 1;
+                if verdict == Verdict::Pass || tarpit.options.dry_run {
+                    tarpit.pass(stream, peer, listener.name.clone(), now);
+                } else {
+                    let token = tarpit.watch.as_mut().and_then(|watch| watch.register(&mut stream));
+                    tarpit.trap_watched(stream, peer, listener.name.clone(), token, now);
+                }
+            },
+            Verdict::Close => {
+                debug!(peer:% = peer, listener = name; "not trapping client, closed by policy");
+                tarpit.stats.connections_rejected.fetch_add(1, Ordering::Relaxed);
+            },
+            Verdict::Ban => {
+                debug!(peer:% = peer, listener = name; "not trapping client, banned by policy");
+                tarpit.stats.connections_banned.fetch_add(1, Ordering::Relaxed);
+                tarpit.events.push_back(ConnectionEvent::banned(peer, listener.name.as_deref()));
+            },
+        }
+    }
+
+}
+
+// clients are handed back by value in an Err to be closed, boxing each one for that would cost more than it saves
+#[allow(clippy::result_large_err)]
+impl<S: TrappedStream> Tarpit<S> {
+
+    pub fn new(options: EndlesshOptions, stats: Arc<EndlesshStats>) -> error::Result<Self> {
+        Self::check_line_length(&options)?;
+        options.banner.check()?;
+        Self::check_variants(&options)?;
+        Self::check_budgets(&options)?;
+
+        Ok(Tarpit {
+            line_buffer: [0; SSH_LINE_BUFFER_SIZE],
+            line_length: 0,
+            line_variant: None,
+            variant_totals: vec![VariantTotals::default(); options.variants.len()],
+            rng: options.rng_seed.map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed),
+            drain_buffer: [0; DRAIN_BUFFER_SIZE],
+            watch: None,
+            paused: false,
+            clients: VecDeque::with_capacity(if options.preallocate_clients { options.max_clients } else { 0 }),
+            clients_per_ip: HashMap::new(),
+            offenders: OffenderMemory::default(),
+            message_delay: options.message_delay,
+            send_budget: Self::send_budget(&options),
+            accept_budget: Self::accept_budget(&options),
+            accepts_deferred_until: None,
+            events: VecDeque::new(),
+            expected_wakeup: None,
+            stats,
+            options,
+        })
+    }
+
+    /// fails if the longest banner line and its newline don't fit in the line buffer
+    fn check_line_length(options: &EndlesshOptions) -> error::Result<()> {
+        let ranges = [(options.banner_line_length, options.banner_line_length_max)].into_iter()
+            .chain(options.variants.iter().map(|variant| (variant.banner_line_length, variant.banner_line_length_max)));
+        for (shortest, longest) in ranges {
+            if longest.is_some_and(|longest| longest < shortest) {
+                return Err(EndlesshError::Config("the longest banner line can't be shorter than the shortest".to_string()));
+            }
+        }
+        if Self::longest_line(options) + options.newline.get_data().len() > SSH_LINE_BUFFER_SIZE {
+            return Err(EndlesshError::Config(format!("banner lines including the newline must fit in {} bytes", SSH_LINE_BUFFER_SIZE)));
+        }
+        Ok(())
+    }
+
+    /// the longest banner line of the options or any of their variants, not counting the newline
+    fn longest_line(options: &EndlesshOptions) -> usize {
+        options.variants.iter().map(StrategyVariant::longest_banner_line).fold(options.longest_banner_line(), usize::max)
+    }
+
+    fn check_variants(options: &EndlesshOptions) -> error::Result<()> {
+        for (i, variant) in options.variants.iter().enumerate() {
+            if !is_valid_listener_name(&variant.name) || options.variants[..i].iter().any(|other| other.name == variant.name) {
+                return Err(EndlesshError::Config(format!("strategy variant name {:?} is invalid or taken", variant.name)));
+            }
+            if variant.weight == 0 {
+                return Err(EndlesshError::Config(format!("strategy variant {} needs a weight above zero", variant.name)));
+            }
+            if variant.message_delay_jitter.is_some_and(|jitter| jitter >= variant.message_delay) {
+                return Err(EndlesshError::Config(format!("strategy variant {}'s jitter must be shorter than its delay", variant.name)));
+            }
+            variant.banner.check()?;
+        }
+        if !options.variants.is_empty() && options.adaptive_delay_max.is_some() {
+            return Err(EndlesshError::Config("strategy variants can't be combined with an adaptive delay".to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_budgets(options: &EndlesshOptions) -> error::Result<()> {
+        if options.max_send_rate == Some(0) {
+            return Err(EndlesshError::Config("the send budget must be above zero".to_string()));
+        }
+        if options.accept_rate == Some(0) || options.accept_burst == Some(0) {
+            return Err(EndlesshError::Config("the accept rate and burst must be above zero".to_string()));
+        }
+        Ok(())
+    }
+
+    fn accept_budget(options: &EndlesshOptions) -> Option<AcceptBudget> {
+        options.accept_rate.map(|rate| AcceptBudget::new(rate, options.accept_burst.unwrap_or(rate.min(u32::MAX as u64) as u32), &Instant::now()))
+    }
+
+    fn send_budget(options: &EndlesshOptions) -> Option<SendBudget> {
+        options.max_send_rate.map(|rate| SendBudget::new(rate, Self::longest_line(options) + options.newline.get_data().len(), &Instant::now()))
+    }
+
+    /// applies new options to the running tarpit - clients already trapped stay, and take the new delay from their next line.
+    /// lowering `max_clients` below the current count only stops new clients being accepted until enough have left.
+    /// the histogram buckets are fixed when the stats are created, so those in `options` are ignored
+    pub fn reconfigure(&mut self, mut options: EndlesshOptions) -> error::Result<()> {
+        options.banner.check()?;
+        Self::check_line_length(&options)?;
+        Self::check_variants(&options)?;
+        Self::check_budgets(&options)?;
+        options.histogram_buckets = std::mem::take(&mut self.options.histogram_buckets);
+        self.message_delay = options.message_delay;
+        self.send_budget = Self::send_budget(&options);
+        self.accept_budget = Self::accept_budget(&options);
+        let old = std::mem::replace(&mut self.options, options);
+        self.reassign_variants(&old.variants);
+        self.adapt_delay();
+        Ok(())
+    }
+
+    /// moves the totals and clients of the variants still there to their new places, and the clients of those that
+    /// are gone to new ones. their delays may have changed, so the queue is put back in send order
+    fn reassign_variants(&mut self, old: &[StrategyVariant]) {
+        if old.is_empty() && self.options.variants.is_empty() {
+            return;
+        }
+        let variants = &self.options.variants;
+        let new_index = |i: usize| variants.iter().position(|variant| variant.name == old[i].name);
+        let mut totals = vec![VariantTotals::default(); variants.len()];
+        for (i, old_totals) in std::mem::take(&mut self.variant_totals).into_iter().enumerate() {
+            if let Some(j) = new_index(i) {
+                totals[j] = old_totals;
+            }
+        }
+        self.variant_totals = totals;
+        for client in self.clients.iter_mut() {
+            client.variant = client.variant.and_then(new_index).or_else(|| pick_variant(variants, &mut self.rng));
+        }
+        let mut clients = Vec::from(std::mem::take(&mut self.clients));
+        clients.sort_by_key(|client| self.next_send_time(client));
+        self.clients = clients.into();
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        !self.paused && self.clients.len() < self.options.max_clients
+    }
+
+    /// rolls the dice for `max_startups`
+    fn should_refuse(&mut self) -> bool {
+        self.options.max_startups.is_some_and(|max_startups| self.rng.f64() < max_startups.refuse_probability(self.clients.len()))
+    }
+
+    /// whether `allow_cidrs` or `deny_cidrs` keep the peer's address out of the tarpit
+    fn is_filtered(&self, peer: SocketAddr) -> bool {
+        peer != UNIX_PEER && (self.options.allow_cidrs.as_ref().is_some_and(|allow| !allow.contains(peer.ip()))
+            || self.options.deny_cidrs.as_ref().is_some_and(|deny| deny.contains(peer.ip())))
+    }
+
+    /// whether `max_clients_per_ip` clients from the peer's address are trapped already
+    fn is_over_ip_limit(&self, peer: SocketAddr) -> bool {
+        self.options.max_clients_per_ip.is_some_and(|limit| peer != UNIX_PEER
+            && self.clients_per_ip.get(&peer.ip().to_canonical()).is_some_and(|&count| count >= limit))
+    }
+
+    /// stops (or resumes) taking on new clients, those already trapped are unaffected
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// starts trapping a newly connected client, it gets its first line on the next wakeup
+    pub fn trap(&mut self, stream: S, peer: SocketAddr, listener: Option<Arc<str>>, now: &Instant) {
+        self.trap_watched(stream, peer, listener, None, now);
+    }
+
+    /// [Self::trap] for a client registered with the poll under `token`
+    fn trap_watched(&mut self, stream: S, peer: SocketAddr, listener: Option<Arc<str>>, token: Option<Token>, now: &Instant) {
+        let mut client = self.open_client(stream, peer, listener, now);
+        client.token = token;
+        debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(); "trapping client");
+        self.requeue(client);
     }
-}
 
-pub struct EndlesshServer {
-    listener: TcpListener,
-    listener_token: Token,
-    listener_accept_available: bool,
-    line_buffer: [u8; SSH_LINE_BUFFER_SIZE],
-    clients: VecDeque<EndlesshClient>,
-    stats: EndlesshStats,
-    options: EndlesshOptions,
-}
+    /// counts a newly connected client and hangs up on it straight away, for a dry run
+    pub fn pass(&mut self, stream: S, peer: SocketAddr, listener: Option<Arc<str>>, now: &Instant) {
+        let client = self.open_client(stream, peer, listener, now);
+        debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(); "hanging up on client, dry run");
+        self.close_client(client, now);
+    }
 
-struct EndlesshClient {
-    stream: TcpStream,
-    connected_time: Instant,
-    last_send_time: Option<Instant>,
-}
+    fn open_client(&mut self, stream: S, peer: SocketAddr, listener: Option<Arc<str>>, now: &Instant) -> EndlesshClient<S> {
+        let mut client = EndlesshClient {
+            stream,
+            peer,
+            listener,
+            connected_time: *now,
+            connected_at: SystemTime::now(),
+            last_send_time: None,
+            bytes_sent: 0,
+            lines_sent: 0,
+            stalled_sends: 0,
+            zero_window_since: None,
+            chatty: false,
+            delay_factor: 1,
+            returns: 0,
+            delay_jitter_nanos: 0,
+            skipped_sends: 0,
+            variant: pick_variant(&self.options.variants, &mut self.rng),
+            token: None,
+            received: 0,
+            pending: Vec::new(),
+        };
+        self.stats.record_connection_opened(client.peer, client.connected_at);
+        if client.peer != UNIX_PEER {
+            *self.clients_per_ip.entry(client.peer.ip().to_canonical()).or_insert(0) += 1;
+            if let Some(config) = &self.options.repeat_offenders {
+                client.returns = self.offenders.returns(client.peer.ip(), config, now);
+                client.delay_factor = OffenderMemory::delay_factor(client.returns, config);
+            }
+            if client.delay_factor > 1 {
+                debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(), factor = client.delay_factor;
+                    "slowing down repeat offender");
+                self.stats.repeat_offenders.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(i) = client.variant {
+            self.variant_totals[i].connections_opened += 1;
+        }
+        self.events.push_back(self.client_event(ConnectionEventKind::Accepted, &client, now));
+        client
+    }
 
-impl EndlesshServer {
+    fn client_event(&self, event: ConnectionEventKind, client: &EndlesshClient<S>, now: &Instant) -> ConnectionEvent {
+        let mut event = ConnectionEvent::new(event, client, now);
+        event.variant = self.variant_name(client).map(str::to_string);
+        event
+    }
 
-    pub fn create(options: EndlesshOptions, mut listener: TcpListener, listener_token: Token, poll: &Poll) -> Self {
-        let mut line_buffer = [0_u8; SSH_LINE_BUFFER_SIZE];
-        assert!(options.banner_line_length + options.newline.get_data().len() <= SSH_LINE_BUFFER_SIZE);
-        line_buffer[options.banner_line_length..options.banner_line_length+options.newline.get_data().len()].copy_from_slice(&options.newline.get_data());
-        let clients = VecDeque::with_capacity(options.max_clients);
+    fn variant_name(&self, client: &EndlesshClient<S>) -> Option<&str> {
+        client.variant.map(|i| self.options.variants[i].name.as_str())
+    }
 
-        poll.registry().register(&mut listener, listener_token, Interest::READABLE).unwrap();
+    /// sends a line to every client that is due one, calling `on_close` after each client that went away
+    ///
+    /// returns how long until the next client is due, or None if there are no clients
+    pub fn wakeup(&mut self, now: &Instant, on_close: impl FnMut(&mut Self)) -> Option<Duration> {
+        self.stats.observe_time(now);
+        if let Some(late) = self.expected_wakeup.and_then(|expected| now.checked_duration_since(expected)) {
+            if late >= TIME_JUMP_THRESHOLD {
+                self.skip_time(late);
+            } else {
+                self.record_lateness(late);
+            }
+        }
+        let next_wakeup = self.send_due_lines(now, on_close);
+        self.expected_wakeup = next_wakeup.and_then(|wait| now.checked_add(wait));
+        next_wakeup
+    }
 
-        EndlesshServer {
-            listener,
-            listener_token,
-            listener_accept_available: false,
-            line_buffer,
-            clients,
-            stats: EndlesshStats::default(),
-            options,
+    /// the lines are sent now all the same, each client's next one a full delay later, so lateness never piles up
+    fn record_lateness(&mut self, late: Duration) {
+        self.stats.worst_wakeup_lateness_nanos.fetch_max(late.as_nanos() as u64, Ordering::Relaxed);
+        if late >= LATE_WAKEUP_THRESHOLD {
+            warn!(late_ms = late.as_millis() as u64, clients = self.clients.len(); "woke up late, something on the event loop is slow");
+            self.stats.late_wakeups.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    pub fn try_handle_event(&mut self, event: &event::Event, now: &Instant) -> bool {
-        assert!(*now >= self.stats.last_known_time, "time went backwards!");
-        self.stats.last_known_time = *now;
-        return if self.listener_token == event.token() {
-            self.listener_accept_available = true;
-            self.accept_new_connections(now);
-            true
-        } else {
-            false
+    /// moves every client's clock forward by `skipped`, so time the process wasn't running for isn't counted as
+    /// trapping anyone, and lines overdue because of it aren't all sent in a burst
+    fn skip_time(&mut self, skipped: Duration) {
+        warn!(skipped_ms = skipped.as_millis() as u64, clients = self.clients.len(); "time jump, the process wasn't running");
+        self.stats.time_jumps.fetch_add(1, Ordering::Relaxed);
+        for client in self.clients.iter_mut() {
+            client.connected_time += skipped;
+            client.last_send_time = client.last_send_time.map(|last_send| last_send + skipped);
+            client.zero_window_since = client.zero_window_since.map(|since| since + skipped);
         }
     }
 
-    pub fn handle_wakeup(&mut self, now: &Instant) -> Option<Duration> {
-        assert!(*now >= self.stats.last_known_time, "time went backwards!");
-        self.stats.last_known_time = *now;
+    fn send_due_lines(&mut self, now: &Instant, mut on_close: impl FnMut(&mut Self)) -> Option<Duration> {
+        self.adapt_delay();
         let mut generated_line = false;
         while let Some(client) = self.clients.pop_front() {
 
@@ -151,29 +1966,40 @@ impl EndlesshServer {
                     // client has never received a line - send immediately
                     None
                 },
-                Some(last_send) => {
-                    // client has received a line before - send if the message window has elapsed 
-                    (last_send + self.options.message_delay).checked_duration_since(*now)
+                Some(_) => {
+                    // client has received a line before - send if the message window has elapsed, including exactly now
+                    self.next_send_time(&client).checked_duration_since(*now).filter(|wait| !wait.is_zero())
                 },
             };
 
             match send_or_wait {
                 None => {
-                    if !generated_line {
-                        Self::rand_line(&mut self.line_buffer[..self.options.banner_line_length]);
-                        self.stats.bytes_generated += self.options.banner_line_length;
-                        generated_line = true;
+                    // out of budget - everyone waits, and the queue stays in send order. the next line's length isn't
+                    // drawn yet, so wait for room for the longest
+                    let line_length = Self::longest_line(&self.options) + self.options.newline.get_data().len();
+                    if let Some(wait) = self.send_budget.as_mut().and_then(|budget| budget.wait_for(line_length, now)) {
+                        self.stats.send_budget_waits.fetch_add(1, Ordering::Relaxed);
+                        self.clients.push_front(client);
+                        return Some(wait);
                     }
-                    match self.send_line(client, now) {
-                        Some(c) => self.clients.push_back(c),
-                        None => {
+                    match self.take_turn(client, now, &mut generated_line) {
+                        Ok(mut c) => {
+                            if let Some(jitter) = self.delay_jitter(&c) {
+                                let jitter = jitter.as_nanos().min(i64::MAX as u128) as i64;
+                                c.delay_jitter_nanos = self.rng.i64(-jitter..=jitter);
+                            }
+                            self.requeue(c);
+                        },
+                        Err(c) => {
                             // drop the client
-                            self.accept_new_connections(now);
+                            self.close_client(c, now);
+                            on_close(self);
                         },
                     }
                 },
                 Some(need_to_wait) => {
-                    self.clients.push_back(client);
+                    // still the next one due, so it stays at the front
+                    self.clients.push_front(client);
                     return Some(need_to_wait);
                 }
             }
@@ -182,63 +2008,1042 @@ impl EndlesshServer {
         None
     }
 
-    pub fn stats(&mut self) -> &EndlesshStats {
+    /// when the client is next due a line, right away if it hasn't had one yet
+    fn next_send_time(&self, client: &EndlesshClient<S>) -> Instant {
+        let Some(last_send) = client.last_send_time else {
+            return client.connected_time;
+        };
+        let delay = self.delay(client);
+        let jitter = Duration::from_nanos(client.delay_jitter_nanos.unsigned_abs());
+        match client.delay_jitter_nanos {
+            0.. => last_send + delay.saturating_add(jitter),
+            _ => last_send + delay.saturating_sub(jitter),
+        }
+    }
+
+    /// the delay between the client's turns, its variant's if it has one
+    fn delay(&self, client: &EndlesshClient<S>) -> Duration {
+        client.variant.map_or(self.message_delay, |i| self.options.variants[i].message_delay)
+    }
+
+    fn delay_jitter(&self, client: &EndlesshClient<S>) -> Option<Duration> {
+        client.variant.map_or(self.options.message_delay_jitter, |i| self.options.variants[i].message_delay_jitter)
+    }
+
+    /// puts a client back in the queue, which is in send order. without jitter or variants every client waits the same
+    /// delay, so that's the back, with them the client goes wherever its own next line falls due
+    fn requeue(&mut self, client: EndlesshClient<S>) {
+        if self.options.message_delay_jitter.is_none() && self.options.variants.is_empty() {
+            self.clients.push_back(client);
+            return;
+        }
+        let due = self.next_send_time(&client);
+        let position = self.clients.partition_point(|other| self.next_send_time(other) <= due);
+        self.clients.insert(position, client);
+    }
+
+    pub fn stats(&self) -> &Arc<EndlesshStats> {
         &self.stats
     }
 
-    fn accept_new_connections(&mut self, now: &Instant) {
-        while self.listener_accept_available && self.clients.len() < self.options.max_clients {
-            match self.listener.accept() {
-                Ok((stream, _address)) => {
-                    self.clients.push_back(EndlesshClient {
-                        stream,
-                        connected_time: *now,
-                        last_send_time: None,
-                    });
-                    self.stats.connections_opened += 1;
-                },
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    self.listener_accept_available = false;
-                }
-                Err(e) => {
-                    panic!("failed to accept connection: {}", e);
+    pub fn options(&self) -> &EndlesshOptions {
+        &self.options
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// every strategy variant with its totals so far
+    pub fn variants(&self) -> impl Iterator<Item = (&StrategyVariant, &VariantTotals)> + '_ {
+        self.options.variants.iter().zip(&self.variant_totals)
+    }
+
+    /// the delay between lines right now, which only differs from the configured one with an adaptive delay
+    pub fn message_delay(&self) -> Duration {
+        self.message_delay
+    }
+
+    /// every trapped client and when it's next due a line, in the order they'll be sent
+    pub fn schedule(&self, now: &Instant) -> Vec<ClientSchedule> {
+        self.clients.iter().map(|client| {
+            // a penalized client's next line is as many turns away as it has left to skip
+            let turns_per_line = self.turns_per_line(client);
+            let turns = turns_per_line.map(|turns| turns.saturating_sub(client.skipped_sends).max(1));
+            let next_send = turns.map(|turns| match client.last_send_time {
+                None => Duration::ZERO,
+                Some(_) => (self.next_send_time(client) + self.delay(client) * (turns - 1)).saturating_duration_since(*now),
+            });
+            ClientSchedule {
+                peer: client.peer,
+                listener: client.listener.as_deref().map(str::to_string),
+                connected_time_seconds: client.connected_at.duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64()),
+                lines_sent: client.lines_sent,
+                bytes_sent: client.bytes_sent,
+                delay_seconds: turns_per_line.map(|turns| (self.delay(client) * turns).as_secs_f64()),
+                next_send_seconds: next_send.map(|d| d.as_secs_f64()),
+                stalled_sends: client.stalled_sends,
+                zero_window_seconds: client.zero_window_since.map(|since| now.saturating_duration_since(since).as_secs_f64()),
+                chatty: client.chatty,
+                variant: self.variant_name(client).map(str::to_string),
+            }
+        }).collect()
+    }
+
+    /// stretches the delay linearly from `message_delay` to `adaptive_delay_max` as the slots go from half to completely full,
+    /// so a scan storm costs fewer writes per second instead of more. every client shares the delay, so the queue stays in send order
+    fn adapt_delay(&mut self) {
+        let Some(max_delay) = self.options.adaptive_delay_max else {
+            return;
+        };
+        let load = self.clients.len() as f64 / self.options.max_clients.max(1) as f64;
+        let stretch = ((load - ADAPTIVE_DELAY_LOAD_FLOOR) / (1.0 - ADAPTIVE_DELAY_LOAD_FLOOR)).clamp(0.0, 1.0);
+        let min_delay = self.options.message_delay;
+        let delay = min_delay + max_delay.saturating_sub(min_delay).mul_f64(stretch);
+        if delay != self.message_delay {
+            debug!(delay_ms = delay.as_millis() as u64, clients = self.clients.len(); "message delay changed");
+            self.message_delay = delay;
+        }
+    }
+
+    /// takes the connection events recorded since the last call
+    pub fn drain_events(&mut self) -> impl Iterator<Item = ConnectionEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    /// hangs up on every client, e.g. when shutting down, after sending each of them `farewell` if given
+    pub fn close_all(&mut self, farewell: Option<&str>, now: &Instant) {
+        self.stats.observe_time(now);
+        let farewell = farewell.map(|line| [line.as_bytes(), self.options.newline.get_data()].concat());
+        while let Some(mut client) = self.clients.pop_front() {
+            if let Some(farewell) = &farewell {
+                // best effort, there's no waiting around for a full socket buffer. it follows the rest of the last line
+                if let Ok(n) = client.stream.write(&[&client.pending[..], farewell].concat()) {
+                    self.stats.bytes_sent.fetch_add(n, Ordering::Relaxed);
+                    client.bytes_sent += n;
                 }
+            }
+            // otherwise the time since their last line would never be counted
+            self.stats.add_trapped_time(now.duration_since(client.last_send_time.unwrap_or(client.connected_time)));
+            self.close_client(client, now);
+        }
+    }
+
+    /// drains the client's input if watching for chatty clients, then sends it a line unless it's being penalized, or
+    /// hangs up on it if it has been trapped for `max_trap_duration`
+    fn take_turn(&mut self, client: EndlesshClient<S>, now: &Instant, generated_line: &mut bool) -> Result<EndlesshClient<S>, EndlesshClient<S>> {
+        if self.options.max_trap_duration.is_some_and(|limit| now.duration_since(client.connected_time) >= limit) {
+            debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(),
+                trapped_seconds = now.duration_since(client.connected_time).as_secs_f64(); "hanging up on client, trapped long enough");
+            self.stats.max_trap_evictions.fetch_add(1, Ordering::Relaxed);
+            // otherwise the time since its last line would never be counted
+            self.stats.add_trapped_time(now.duration_since(client.last_send_time.unwrap_or(client.connected_time)));
+            return Err(client);
+        }
+        let mut client = self.drain_input(client)?;
+        let skip = match self.turns_per_line(&client) {
+            Some(turns) => client.skipped_sends + 1 < turns,
+            None => true,
+        };
+        if skip {
+            // still trapped, just not costing us a write
+            self.stats.add_trapped_time(now.duration_since(client.last_send_time.unwrap_or(client.connected_time)));
+            client.last_send_time = Some(*now);
+            client.skipped_sends += 1;
+            return Ok(client);
+        }
+        client.skipped_sends = 0;
+        if !client.pending.is_empty() {
+            // the rest of its last line comes first, or the lines would run into each other
+            return self.send_line(client, now);
+        }
+        if !*generated_line || self.line_variant != client.variant {
+            let (banner, shortest, longest) = match client.variant.map(|i| &self.options.variants[i]) {
+                Some(variant) => (&variant.banner, variant.banner_line_length, variant.longest_banner_line()),
+                None => (&self.options.banner, self.options.banner_line_length, self.options.longest_banner_line()),
             };
+            let line_length = self.rng.usize(shortest..=longest);
+            let length = banner.fill(&mut self.line_buffer[..line_length], &mut self.rng);
+            self.line_variant = client.variant;
+            let newline = self.options.newline.get_data();
+            self.line_buffer[length..length + newline.len()].copy_from_slice(newline);
+            self.line_length = length + newline.len();
+            self.stats.bytes_generated.fetch_add(length, Ordering::Relaxed);
+            *generated_line = true;
+        }
+        self.send_line(client, now)
+    }
+
+    /// how many turns the client waits between lines, the chatty penalty times the repeat offender one, or None if it's held
+    fn turns_per_line(&self, client: &EndlesshClient<S>) -> Option<u32> {
+        match (client.chatty, self.options.chatty_penalty) {
+            (true, ChattyPenalty::Hold) => None,
+            (true, ChattyPenalty::Slow(factor)) => Some(factor.saturating_mul(client.delay_factor)),
+            (false, _) => Some(client.delay_factor),
+        }
+    }
+
+    /// reads and drops what a watched client sent, or closes it straight away if it hung up, calling `on_close` after.
+    /// returns false if the token isn't one of the tarpit's
+    fn receive(&mut self, token: Token, now: &Instant, mut on_close: impl FnMut(&mut Self)) -> bool {
+        if !self.watch.as_ref().is_some_and(|watch| watch.tokens.contains(&token.0)) {
+            return false;
+        }
+        // a client that has left since is already gone
+        let Some(i) = self.clients.iter().position(|client| client.token == Some(token)) else {
+            return true;
+        };
+        if !Self::read_input(&mut self.drain_buffer, &self.stats, &mut self.clients[i]) {
+            let client = self.clients.remove(i).expect("client was just found");
+            debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(); "client hung up");
+            // otherwise the time since its last line would never be counted
+            self.stats.add_trapped_time(now.duration_since(client.last_send_time.unwrap_or(client.connected_time)));
+            self.close_client(client, now);
+            on_close(self);
+        }
+        true
+    }
+
+    /// reads and drops whatever the client sent since its last turn, handing it back as an error if it hung up
+    fn drain_input(&mut self, mut client: EndlesshClient<S>) -> Result<EndlesshClient<S>, EndlesshClient<S>> {
+        let Some(threshold) = self.options.chatty_threshold else {
+            client.received = 0;
+            return Ok(client);
+        };
+        if !Self::read_input(&mut self.drain_buffer, &self.stats, &mut client) {
+            return Err(client);
+        }
+        let received = std::mem::take(&mut client.received);
+        if received > threshold && !client.chatty {
+            debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(), bytes = received, penalty:% = self.options.chatty_penalty;
+                "penalizing chatty client");
+            self.stats.chatty_clients.fetch_add(1, Ordering::Relaxed);
+            client.chatty = true;
         }
+        Ok(client)
     }
 
-    // the SSH client will try to parse lines starting with "SSH-", ending the banner
-    // the "alphanumeric" distribution never generates '-' so should not be a problem
-    // see https://datatracker.ietf.org/doc/html/rfc4253#section-4.2 for more
-    fn rand_line(buffer: &mut[u8]) {
-        for element in buffer.iter_mut() {
-            *element = fastrand::alphanumeric() as u8;
+    /// reads and drops what the client has sent, up to `MAX_DRAIN_BYTES_PER_TURN`, returning false if it hung up
+    fn read_input(buffer: &mut [u8], stats: &EndlesshStats, client: &mut EndlesshClient<S>) -> bool {
+        let mut received = 0;
+        let connected = loop {
+            if received >= MAX_DRAIN_BYTES_PER_TURN {
+                break true;
+            }
+            match client.stream.read(buffer) {
+                Ok(0) => break false,
+                Ok(n) => received += n,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break true,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break false,
+            }
+        };
+        stats.bytes_received.fetch_add(received, Ordering::Relaxed);
+        client.received += received;
+        connected
+    }
+
+    fn close_client(&mut self, client: EndlesshClient<S>, now: &Instant) {
+        // dropping the stream takes it out of the poll
+        if let (Some(token), Some(watch)) = (client.token, self.watch.as_mut()) {
+            watch.released.push(token);
+        }
+        if let Some(count) = self.clients_per_ip.get_mut(&client.peer.ip().to_canonical()) {
+            *count -= 1;
+            if *count == 0 {
+                self.clients_per_ip.remove(&client.peer.ip().to_canonical());
+            }
+        }
+        if let Some(config) = &self.options.repeat_offenders {
+            if client.peer != UNIX_PEER {
+                self.offenders.record(client.peer.ip(), config, now);
+            }
         }
+        self.stats.record_connection_closed(SystemTime::now());
+        self.stats.trapped_seconds.observe(now.duration_since(client.connected_time).as_secs_f64());
+        self.stats.lines_per_connection.observe(client.lines_sent as f64);
+        let mut event = self.client_event(ConnectionEventKind::Closed, &client, now);
+        event.tcp = client.stream.tcp_stats();
+        // what was written is what the client read, near enough - a write only succeeds once it fits in the buffers
+        let score = trap_score(event.trapped_seconds, client.lines_sent, client.bytes_sent, client.returns);
+        self.stats.trap_score.observe(score);
+        event.score = Some(score);
+        if let Some(totals) = client.variant.map(|i| &mut self.variant_totals[i]) {
+            totals.connections_closed += 1;
+            totals.bytes_sent += client.bytes_sent as u64;
+            totals.lines_sent += client.lines_sent;
+            totals.trapped_seconds += event.trapped_seconds;
+            totals.trap_score += score;
+        }
+        let listener = client.listener.as_deref().unwrap_or_default();
+        match &event.tcp {
+            Some(tcp) => debug!(peer:% = client.peer, listener, trapped_seconds = event.trapped_seconds, bytes = client.bytes_sent, score,
+                rtt_us = tcp.rtt_us, retransmits = tcp.retransmits, bytes_acked = tcp.bytes_acked; "client left"),
+            None => debug!(peer:% = client.peer, listener, trapped_seconds = event.trapped_seconds, bytes = client.bytes_sent, score; "client left"),
+        }
+        self.events.push_back(event);
     }
 
-    fn send_line(&mut self, mut client: EndlesshClient, now: &Instant) -> Option<EndlesshClient> {
-        match client.stream.write(&self.line_buffer[..self.options.banner_line_length + self.options.newline.get_data().len()]) {
+    /// returns the client if it should stay trapped, or hands it back as an error to be closed
+    /// sends the client the rest of its last line if it has one, or the current line
+    fn send_line(&mut self, mut client: EndlesshClient<S>, now: &Instant) -> Result<EndlesshClient<S>, EndlesshClient<S>> {
+        let line = if client.pending.is_empty() { &self.line_buffer[..self.line_length] } else { &client.pending[..] };
+        match client.stream.write(line) {
             Ok(0) => {
                 // client disconnected, goodbye 👋
-                None
+                Err(client)
             },
             Ok(n) => {
                 // send (at least partially) succeeded
-                self.stats.bytes_sent += n;
-                self.stats.trapped_time += now.duration_since(client.last_send_time.unwrap_or(client.connected_time));
-                
+                let line_done = if !client.pending.is_empty() {
+                    client.pending.drain(..n);
+                    client.pending.is_empty()
+                } else if n < self.line_length {
+                    self.stats.partial_writes.fetch_add(1, Ordering::Relaxed);
+                    client.pending.extend_from_slice(&self.line_buffer[n..self.line_length]);
+                    false
+                } else {
+                    true
+                };
+                self.stats.bytes_sent.fetch_add(n, Ordering::Relaxed);
+                self.stats.add_trapped_time(now.duration_since(client.last_send_time.unwrap_or(client.connected_time)));
+                if let Some(budget) = &mut self.send_budget {
+                    budget.spend(n);
+                }
+
+                client.bytes_sent += n;
                 client.last_send_time = Some(*now);
-                Some(client)
+                client.stalled_sends = 0;
+                client.zero_window_since = None;
+                if !line_done {
+                    return Ok(client);
+                }
+                client.lines_sent += 1;
+                if self.options.line_events {
+                    self.events.push_back(self.client_event(ConnectionEventKind::LineSent, &client, now));
+                }
+                Ok(client)
             },
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                // couldn't send - oh well
-                Some(client)
+                // couldn't send - oh well, try again next time round rather than spinning on a full buffer
+                self.stats.add_trapped_time(now.duration_since(client.last_send_time.unwrap_or(client.connected_time)));
+                client.last_send_time = Some(*now);
+                client.stalled_sends += 1;
+                if self.options.stall_limit.is_some_and(|limit| client.stalled_sends >= limit) {
+                    debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(), stalled_sends = client.stalled_sends;
+                        "hanging up on stalled client");
+                    self.stats.stalled_evictions.fetch_add(1, Ordering::Relaxed);
+                    return Err(client);
+                }
+                match client.stream.window_closed() {
+                    Some(true) => {
+                        let since = *client.zero_window_since.get_or_insert(*now);
+                        if self.options.zero_window_timeout.is_some_and(|timeout| now.duration_since(since) >= timeout) {
+                            debug!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(),
+                                zero_window_seconds = now.duration_since(since).as_secs_f64(); "hanging up on zero window client");
+                            self.stats.zero_window_evictions.fetch_add(1, Ordering::Relaxed);
+                            return Err(client);
+                        }
+                    },
+                    Some(false) => client.zero_window_since = None,
+                    None => {},
+                }
+                Ok(client)
             },
             Err(_e) => {
                 // 🤷 goodbye 👋
-                None
+                Err(client)
             },
         }
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{empty, Empty, Read, Write};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::rc::Rc;
+
+    fn tarpit<S: TrappedStream>(options: EndlesshOptions) -> Tarpit<S> {
+        Tarpit::new(options, Arc::new(EndlesshStats::default())).unwrap()
+    }
+
+    /// a client socket the test scripts through its own clone
+    #[derive(Clone, Default)]
+    struct Mock(Rc<RefCell<MockSocket>>);
+
+    #[derive(Default)]
+    struct MockSocket {
+        written: Vec<u8>,
+        /// how much each write takes, everything if None and WouldBlock if 0
+        room: Option<usize>,
+        /// read and written with an error, as a reset connection would be
+        gone: bool,
+        input: Vec<u8>,
+        window_closed: Option<bool>,
+    }
+
+    impl Mock {
+        fn lines(&self) -> usize {
+            self.0.borrow().written.iter().filter(|&&byte| byte == b'\n').count()
+        }
+    }
+
+    impl Read for Mock {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut socket = self.0.borrow_mut();
+            if socket.gone {
+                return Err(ErrorKind::ConnectionReset.into());
+            }
+            if socket.input.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(socket.input.len());
+            buf[..n].copy_from_slice(&socket.input[..n]);
+            socket.input.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Mock {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut socket = self.0.borrow_mut();
+            if socket.gone {
+                return Err(ErrorKind::ConnectionReset.into());
+            }
+            let n = socket.room.map_or(buf.len(), |room| room.min(buf.len()));
+            if n == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            socket.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TrappedStream for Mock {
+        fn window_closed(&self) -> Option<bool> {
+            self.0.borrow().window_closed
+        }
+    }
+
+    /// traps a scripted client from the next port, handing back its socket
+    fn trap_mock(tarpit: &mut Tarpit<Mock>, now: &Instant) -> Mock {
+        let mock = Mock::default();
+        let port = tarpit.client_count() as u16 + 1;
+        tarpit.trap(mock.clone(), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port)), None, now);
+        mock
+    }
+
+    /// traps `clients` more, each from its own port
+    fn trap(tarpit: &mut Tarpit<Empty>, clients: usize, now: &Instant) {
+        for _ in 0..clients {
+            let port = tarpit.client_count() as u16 + 1;
+            tarpit.trap(empty(), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port)), None, now);
+        }
+    }
+
+    #[test]
+    fn each_client_gets_its_lines_on_its_own_stream_until_it_goes_away() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        let staying = trap_mock(&mut tarpit, &now);
+        let leaving = trap_mock(&mut tarpit, &now);
+        assert_eq!(tarpit.wakeup(&now, |_| {}), Some(Duration::from_secs(10)));
+        assert_eq!((staying.lines(), leaving.lines()), (1, 1));
+        assert_eq!(staying.0.borrow().written.len(), 33);
+
+        leaving.0.borrow_mut().gone = true;
+        let mut closed = 0;
+        let later = now + Duration::from_secs(10);
+        tarpit.wakeup(&later, |_| closed += 1);
+        assert_eq!((closed, tarpit.client_count()), (1, 1));
+        assert_eq!((staying.lines(), leaving.lines()), (2, 1));
+        let events: Vec<_> = tarpit.drain_events().map(|event| event.event).collect();
+        assert_eq!(events, [ConnectionEventKind::Accepted, ConnectionEventKind::Accepted, ConnectionEventKind::Closed]);
+        assert_eq!(tarpit.stats.connections_closed.load(Ordering::Relaxed), 1);
+        assert_eq!(tarpit.stats.bytes_sent.load(Ordering::Relaxed), 33 * 3);
+    }
+
+    #[test]
+    fn paused_tarpits_take_no_new_clients_but_keep_sending() {
+        let mut tarpit = tarpit(EndlesshOptions { max_clients: 2, preallocate_clients: false, ..EndlesshOptions::default() });
+        assert_eq!(tarpit.clients.capacity(), 0);
+        let now = Instant::now();
+        let client = trap_mock(&mut tarpit, &now);
+        assert!(tarpit.has_capacity());
+        tarpit.set_paused(true);
+        assert!(!tarpit.has_capacity());
+        tarpit.wakeup(&now, |_| {});
+        assert_eq!(client.lines(), 1);
+        tarpit.set_paused(false);
+        assert!(tarpit.has_capacity());
+        trap_mock(&mut tarpit, &now);
+        assert!(!tarpit.has_capacity());
+    }
+
+    const MAX_STARTUPS: MaxStartups = MaxStartups { start: 10, rate: 30, full: 80 };
+
+    #[test]
+    fn max_startups_parse_as_start_rate_full() {
+        assert_eq!("10:30:80".parse(), Ok(MAX_STARTUPS));
+        assert_eq!(MAX_STARTUPS.to_string().parse(), Ok(MAX_STARTUPS));
+        assert_eq!("0:100:1".parse(), Ok(MaxStartups { start: 0, rate: 100, full: 1 }));
+        for invalid in ["10:30", "10:30:80:1", "10:30:", "ten:30:80", "10:-1:80", "10:30:80.5", ""] {
+            assert!(invalid.parse::<MaxStartups>().is_err(), "{}", invalid);
+        }
+        // a rate over 100%, or no room between start and full
+        for invalid in ["10:101:80", "80:30:80", "90:30:80"] {
+            assert!(invalid.parse::<MaxStartups>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn refusals_start_at_the_rate_and_rise_to_certainty_at_full() {
+        assert_eq!(MAX_STARTUPS.refuse_probability(0), 0.0);
+        assert_eq!(MAX_STARTUPS.refuse_probability(9), 0.0);
+        assert_eq!(MAX_STARTUPS.refuse_probability(10), 0.3);
+        assert!((MAX_STARTUPS.refuse_probability(45) - 0.65).abs() < 1e-9);
+        assert!(MAX_STARTUPS.refuse_probability(79) < 1.0);
+        assert_eq!(MAX_STARTUPS.refuse_probability(80), 1.0);
+        assert_eq!(MAX_STARTUPS.refuse_probability(1000), 1.0);
+    }
+
+    /// how many of 10000 rolls refuse, with `clients` trapped
+    fn refusals(max_startups: Option<MaxStartups>, clients: usize) -> usize {
+        let mut tarpit = tarpit(EndlesshOptions { max_startups, rng_seed: Some(1), ..EndlesshOptions::default() });
+        trap(&mut tarpit, clients, &Instant::now());
+        (0..10000).filter(|_| tarpit.should_refuse()).count()
+    }
+
+    #[test]
+    fn connections_are_refused_with_the_probability_for_the_client_count() {
+        assert_eq!(refusals(None, 100), 0);
+        assert_eq!(refusals(Some(MAX_STARTUPS), 9), 0);
+        assert!((2700..3300).contains(&refusals(Some(MAX_STARTUPS), 10)));
+        assert!((6200..6800).contains(&refusals(Some(MAX_STARTUPS), 45)));
+        assert_eq!(refusals(Some(MAX_STARTUPS), 80), 10000);
+        assert_eq!(refusals(Some(MAX_STARTUPS), 81), 10000);
+    }
+
+    #[test]
+    fn rates_move_towards_the_latest_rate_by_the_share_of_the_window_elapsed() {
+        let rate = EwmaRate::new();
+        assert_eq!(rate.current(100, Duration::ZERO), 0.0);
+        // a full window at 10/s gets 1 - 1/e of the way there from 0
+        let expected = 10.0 * (1.0 - (-1_f64).exp());
+        assert!((rate.current(600, RATE_WINDOW) - expected).abs() < 1e-9);
+        // reading doesn't fold the growth in, updating does
+        assert_eq!(rate.current(0, Duration::ZERO), 0.0);
+        rate.update(600, RATE_WINDOW);
+        assert!((rate.current(0, Duration::ZERO) - expected).abs() < 1e-9);
+
+        // growth counts from the last update, so a steady 10/s settles on 10/s
+        let settled = (0..600).fold(600, |total, _| {
+            rate.update(total + 10, Duration::from_secs(1));
+            total + 10
+        });
+        assert!((rate.current(settled, Duration::ZERO) - 10.0).abs() < 0.01);
+
+        // and a short idle stretch only brings it down a little
+        rate.update(settled, Duration::from_secs(1));
+        let idle = rate.current(settled, Duration::ZERO);
+        assert!(idle < 10.0 && idle > 9.0, "{}", idle);
+    }
+
+    #[test]
+    fn rates_ignore_a_total_going_backwards() {
+        let rate = EwmaRate::new();
+        rate.update(600, RATE_WINDOW);
+        let before = rate.current(600, Duration::ZERO);
+        // a reset counter is no growth, so the rate decays rather than going negative
+        rate.update(0, Duration::from_secs(1));
+        let after = rate.current(0, Duration::ZERO);
+        assert!(after > 0.0 && after < before);
+    }
+
+    #[test]
+    fn farewells_follow_the_rest_of_the_last_line_and_are_counted() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        let whole = trap_mock(&mut tarpit, &now);
+        let partial = trap_mock(&mut tarpit, &now);
+        partial.0.borrow_mut().room = Some(10);
+        tarpit.wakeup(&now, |_| {});
+        partial.0.borrow_mut().room = None;
+
+        tarpit.close_all(Some("bye"), &now);
+        assert_eq!(tarpit.client_count(), 0);
+        for mock in [&whole, &partial] {
+            let socket = mock.0.borrow();
+            assert_eq!(socket.written.len(), 33 + 4);
+            assert!(socket.written.ends_with(b"\nbye\n"));
+        }
+        assert_eq!(tarpit.stats.bytes_sent.load(Ordering::Relaxed), (33 + 4) * 2);
+        let closed: Vec<_> = tarpit.drain_events().filter(|event| event.event == ConnectionEventKind::Closed).map(|event| event.bytes_sent).collect();
+        assert_eq!(closed, [37, 37]);
+    }
+
+    #[test]
+    fn clients_that_cannot_take_a_farewell_are_still_closed() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        let full = trap_mock(&mut tarpit, &now);
+        full.0.borrow_mut().room = Some(0);
+        let gone = trap_mock(&mut tarpit, &now);
+        gone.0.borrow_mut().gone = true;
+        let quiet = trap_mock(&mut tarpit, &now);
+
+        tarpit.close_all(Some("bye"), &now);
+        assert_eq!(tarpit.client_count(), 0);
+        assert_eq!(tarpit.stats.connections_closed.load(Ordering::Relaxed), 3);
+        assert!(full.0.borrow().written.is_empty());
+        assert_eq!(quiet.0.borrow().written, b"bye\n");
+
+        let mut without = self::tarpit(EndlesshOptions::default());
+        let silent = trap_mock(&mut without, &now);
+        without.close_all(None, &now);
+        assert!(silent.0.borrow().written.is_empty());
+    }
+
+    #[test]
+    fn histograms_count_each_observation_under_the_smallest_bound_it_fits() {
+        let histogram = Histogram::new(vec![1.0, 10.0]);
+        assert_eq!(histogram.buckets(), [(1.0, 0), (10.0, 0), (f64::INFINITY, 0)]);
+        for value in [0.5, 1.0, 1.5, 10.0, 11.0, 1000.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.buckets(), [(1.0, 2), (10.0, 4), (f64::INFINITY, 6)]);
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.sum(), 1024.0);
+
+        let unbounded = Histogram::new(Vec::new());
+        unbounded.observe(3.0);
+        assert_eq!(unbounded.buckets(), [(f64::INFINITY, 1)]);
+    }
+
+    #[test]
+    fn closed_clients_are_observed_in_the_configured_buckets() {
+        let buckets = HistogramBuckets { trapped_seconds: vec![5.0, 60.0], lines_per_connection: vec![1.0], trap_score: vec![1.0, 100.0] };
+        let mut tarpit: Tarpit<Mock> = Tarpit::new(EndlesshOptions::default(), Arc::new(EndlesshStats::new(&buckets))).unwrap();
+        let now = Instant::now();
+        trap_mock(&mut tarpit, &now);
+        let mut later = now;
+        for _ in 0..3 {
+            tarpit.wakeup(&later, |_| {});
+            later += Duration::from_secs(10);
+        }
+        tarpit.close_all(None, &later);
+
+        let stats = &tarpit.stats;
+        assert_eq!(stats.trapped_seconds.buckets(), [(5.0, 0), (60.0, 1), (f64::INFINITY, 1)]);
+        assert_eq!(stats.trapped_seconds.sum(), 30.0);
+        assert_eq!(stats.lines_per_connection.buckets(), [(1.0, 0), (f64::INFINITY, 1)]);
+        assert_eq!(stats.lines_per_connection.sum(), 3.0);
+        assert_eq!(stats.trap_score.buckets(), [(1.0, 0), (100.0, 1), (f64::INFINITY, 1)]);
+    }
+
+    #[test]
+    fn delays_stretch_from_half_to_completely_full() {
+        let mut tarpit = tarpit(EndlesshOptions {
+            max_clients: 4,
+            message_delay: Duration::from_secs(10),
+            adaptive_delay_max: Some(Duration::from_secs(30)),
+            ..EndlesshOptions::default()
+        });
+        let now = Instant::now();
+        let mut delays = Vec::new();
+        for _ in 0..4 {
+            trap(&mut tarpit, 1, &now);
+            tarpit.wakeup(&now, |_| {});
+            delays.push(tarpit.message_delay().as_secs());
+        }
+        assert_eq!(delays, [10, 10, 20, 30]);
+        // every client waits the shared delay
+        assert_eq!(tarpit.wakeup(&now, |_| {}), Some(Duration::from_secs(30)));
+
+        tarpit.close_all(None, &now);
+        tarpit.wakeup(&now, |_| {});
+        assert_eq!(tarpit.message_delay(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn delays_stay_put_without_a_maximum() {
+        let mut tarpit = tarpit(EndlesshOptions { max_clients: 2, ..EndlesshOptions::default() });
+        let now = Instant::now();
+        trap(&mut tarpit, 2, &now);
+        tarpit.wakeup(&now, |_| {});
+        assert_eq!(tarpit.message_delay(), EndlesshOptions::default().message_delay);
+    }
+
+    /// wakes the tarpit every `delay` from `start`, `turns` times, handing back when the next turn would be
+    fn take_turns<S: TrappedStream>(tarpit: &mut Tarpit<S>, start: Instant, turns: u32) -> Instant {
+        let delay = tarpit.message_delay();
+        let mut now = start;
+        for _ in 0..turns {
+            tarpit.wakeup(&now, |_| {});
+            now += delay;
+        }
+        now
+    }
+
+    #[test]
+    fn clients_are_hung_up_on_after_the_stall_limit_of_full_buffers_in_a_row() {
+        let mut tarpit = tarpit(EndlesshOptions { stall_limit: Some(3), ..EndlesshOptions::default() });
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        client.0.borrow_mut().room = Some(0);
+        let now = take_turns(&mut tarpit, start, 2);
+        // a line getting through starts the count again
+        client.0.borrow_mut().room = None;
+        let now = take_turns(&mut tarpit, now, 1);
+        client.0.borrow_mut().room = Some(0);
+        let now = take_turns(&mut tarpit, now, 2);
+        assert_eq!(tarpit.client_count(), 1);
+        assert_eq!(client.lines(), 1);
+
+        take_turns(&mut tarpit, now, 1);
+        assert_eq!(tarpit.client_count(), 0);
+        assert_eq!(tarpit.stats.stalled_evictions.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn stalled_clients_are_kept_without_a_limit() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        client.0.borrow_mut().room = Some(0);
+        let next = take_turns(&mut tarpit, start, 100);
+        assert_eq!(tarpit.client_count(), 1);
+        assert_eq!(tarpit.stats.stalled_evictions.load(Ordering::Relaxed), 0);
+        // and waited on like any other, rather than retried straight away
+        let delay = tarpit.message_delay();
+        assert_eq!(tarpit.wakeup(&(next - delay), |_| {}), Some(delay));
+    }
+
+    #[test]
+    fn chatty_penalties_parse_as_hold_or_a_factor_of_two_or_more() {
+        assert_eq!("hold".parse(), Ok(ChattyPenalty::Hold));
+        assert_eq!("4".parse(), Ok(ChattyPenalty::Slow(4)));
+        for invalid in ["0", "1", "-2", "slow", ""] {
+            assert!(invalid.parse::<ChattyPenalty>().is_err(), "{}", invalid);
+        }
+        for penalty in [ChattyPenalty::Hold, ChattyPenalty::Slow(2)] {
+            assert_eq!(penalty.to_string().parse(), Ok(penalty));
+        }
+    }
+
+    /// how many lines a client sending `input` up front gets in 6 turns, and whether it was counted as chatty
+    fn lines_when_chatty(penalty: ChattyPenalty, input: usize) -> (usize, usize) {
+        let mut tarpit = tarpit(EndlesshOptions { chatty_threshold: Some(10), chatty_penalty: penalty, ..EndlesshOptions::default() });
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        client.0.borrow_mut().input = vec![b'x'; input];
+        take_turns(&mut tarpit, start, 6);
+        assert_eq!(tarpit.stats.bytes_received.load(Ordering::Relaxed), input);
+        (client.lines(), tarpit.stats.chatty_clients.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn clients_sending_more_than_the_threshold_are_slowed_or_held() {
+        assert_eq!(lines_when_chatty(ChattyPenalty::Slow(3), 10), (6, 0));
+        assert_eq!(lines_when_chatty(ChattyPenalty::Slow(3), 11), (2, 1));
+        assert_eq!(lines_when_chatty(ChattyPenalty::Hold, 11), (0, 1));
+    }
+
+    #[test]
+    fn watched_clients_hanging_up_are_closed_on_their_turn() {
+        let mut tarpit = tarpit(EndlesshOptions { chatty_threshold: Some(10), ..EndlesshOptions::default() });
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        take_turns(&mut tarpit, start, 1);
+        client.0.borrow_mut().gone = true;
+        let mut closed = 0;
+        tarpit.wakeup(&(start + tarpit.message_delay()), |_| closed += 1);
+        assert_eq!((closed, tarpit.client_count()), (1, 0));
+        assert_eq!(client.lines(), 1);
+    }
+
+    #[test]
+    fn listener_names_need_no_escaping() {
+        for valid in ["ssh", "port-22", "eu_west.2", &"x".repeat(64)] {
+            assert!(is_valid_listener_name(valid), "{}", valid);
+        }
+        for invalid in ["", "two words", "quote\"", "new\nline", "ünïcode", "a=b", &"x".repeat(65)] {
+            assert!(!is_valid_listener_name(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn events_carry_the_listener_the_client_came_in_on() {
+        let mut tarpit: Tarpit<Mock> = tarpit(EndlesshOptions { line_events: true, ..EndlesshOptions::default() });
+        let now = Instant::now();
+        let peer = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1));
+        tarpit.trap(Mock::default(), peer, Some("public".into()), &now);
+        trap_mock(&mut tarpit, &now);
+        tarpit.wakeup(&now, |_| {});
+        tarpit.close_all(None, &now);
+        let listeners: Vec<_> = tarpit.drain_events().map(|event| (event.event, event.peer.port(), event.listener)).collect();
+        let public = || Some("public".to_string());
+        assert_eq!(listeners, [
+            (ConnectionEventKind::Accepted, 1, public()),
+            (ConnectionEventKind::Accepted, 2, None),
+            (ConnectionEventKind::LineSent, 1, public()),
+            (ConnectionEventKind::LineSent, 2, None),
+            (ConnectionEventKind::Closed, 1, public()),
+            (ConnectionEventKind::Closed, 2, None),
+        ]);
+    }
+
+    #[test]
+    fn events_record_what_the_client_was_sent_so_far() {
+        let mut tarpit: Tarpit<Mock> = tarpit(EndlesshOptions { line_events: true, ..EndlesshOptions::default() });
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        tarpit.wakeup(&start, |_| {});
+        tarpit.wakeup(&(start + Duration::from_secs(10)), |_| {});
+        tarpit.close_all(None, &(start + Duration::from_secs(15)));
+        let sent = client.0.borrow().written.len();
+        let events: Vec<_> = tarpit.drain_events()
+            .map(|event| (event.event, event.lines_sent, event.bytes_sent, event.trapped_seconds))
+            .collect();
+        assert_eq!(events[0], (ConnectionEventKind::Accepted, 0, 0, 0.0));
+        assert_eq!(events[1].0, ConnectionEventKind::LineSent);
+        assert_eq!((events[1].1, events[1].3), (1, 0.0));
+        assert_eq!(events[2].0, ConnectionEventKind::LineSent);
+        assert_eq!((events[2].1, events[2].3), (2, 10.0));
+        assert_eq!(events[3], (ConnectionEventKind::Closed, 2, sent, 15.0));
+    }
+
+    #[test]
+    fn boxed_streams_of_different_kinds_share_one_tarpit() {
+        let mut tarpit: Tarpit<Box<dyn TrappedStream>> = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        let mock = Mock::default();
+        mock.0.borrow_mut().window_closed = Some(true);
+        tarpit.trap(Box::new(mock.clone()), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1)), None, &now);
+        tarpit.trap(Box::new(empty()), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 2)), None, &now);
+        tarpit.wakeup(&now, |_| {});
+        assert_eq!(mock.lines(), 1);
+        assert_eq!(tarpit.client_count(), 2);
+        // the empty stream took its line too
+        assert!(tarpit.stats.bytes_sent.load(Ordering::Relaxed) > mock.0.borrow().written.len());
+
+        let boxed: Box<dyn TrappedStream> = Box::new(mock);
+        assert_eq!(boxed.window_closed(), Some(true));
+        assert_eq!(boxed.tcp_stats(), None);
+    }
+
+    #[test]
+    fn unix_socket_peers_are_never_filtered_or_limited() {
+        let (everything, _) = AddressList::parse("0.0.0.0/0\n::/0\n");
+        let mut tarpit = tarpit(EndlesshOptions { deny_cidrs: Some(everything), max_clients_per_ip: Some(1), ..EndlesshOptions::default() });
+        let now = Instant::now();
+        let peer = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 1));
+        tarpit.trap(empty(), peer, None, &now);
+        tarpit.trap(empty(), UNIX_PEER, None, &now);
+        assert!(tarpit.is_filtered(peer) && tarpit.is_over_ip_limit(peer));
+        assert!(!tarpit.is_filtered(UNIX_PEER) && !tarpit.is_over_ip_limit(UNIX_PEER));
+        // nor counted towards the source ports
+        assert_eq!(tarpit.stats.source_ports.iter().map(|count| count.load(Ordering::Relaxed)).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn source_ports_are_counted_in_ranges_of_4096() {
+        let mut tarpit: Tarpit<Empty> = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        for port in [1, 4095, 4096, 50_000, 65_535] {
+            tarpit.trap(empty(), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), port)), None, &now);
+        }
+        let ranges: Vec<_> = tarpit.stats.source_ports().collect();
+        assert_eq!(ranges.len(), 16);
+        assert_eq!((ranges[0], ranges[1]), ((0, 4095, 2), (4096, 8191, 1)));
+        assert_eq!((ranges[12], ranges[15]), ((49_152, 53_247, 1), (61_440, 65_535, 1)));
+        assert_eq!(ranges.iter().map(|&(_, _, opened)| opened).sum::<usize>(), 5);
+    }
+
+    #[test]
+    fn lines_are_only_recorded_when_asked_for() {
+        let mut tarpit: Tarpit<Mock> = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        trap_mock(&mut tarpit, &now);
+        tarpit.wakeup(&now, |_| {});
+        tarpit.close_all(None, &now);
+        let kinds: Vec<_> = tarpit.drain_events().map(|event| event.event).collect();
+        assert_eq!(kinds, [ConnectionEventKind::Accepted, ConnectionEventKind::Closed]);
+    }
+
+    #[test]
+    fn events_are_written_as_one_line_of_json_without_the_missing_fields() {
+        let mut tarpit: Tarpit<Mock> = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        trap_mock(&mut tarpit, &now);
+        let event = tarpit.drain_events().next().unwrap();
+        let line = serde_json::to_string(&event).unwrap();
+        assert!(!line.contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["event"], "accepted");
+        assert_eq!(json["peer"], "192.0.2.1:1");
+        assert!(json["time_seconds"].as_f64().unwrap() > 1e9);
+        for missing in ["listener", "tcp", "sensor", "score", "variant"] {
+            assert!(json.get(missing).is_none(), "{}", missing);
+        }
+    }
+
+    #[test]
+    fn clients_advertising_a_zero_window_too_long_are_hung_up_on() {
+        let mut tarpit = tarpit(EndlesshOptions { zero_window_timeout: Some(Duration::from_secs(25)), ..EndlesshOptions::default() });
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        client.0.borrow_mut().room = Some(0);
+        client.0.borrow_mut().window_closed = Some(true);
+        // closed at 0s, still closed at 10s and 20s
+        let now = take_turns(&mut tarpit, start, 3);
+        assert_eq!(tarpit.client_count(), 1);
+        let schedule = &tarpit.schedule(&now)[0];
+        assert_eq!(schedule.zero_window_seconds, Some(30.0));
+
+        take_turns(&mut tarpit, now, 1);
+        assert_eq!(tarpit.client_count(), 0);
+        assert_eq!(tarpit.stats.zero_window_evictions.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_window_reopening_starts_the_zero_window_clock_again() {
+        let mut tarpit = tarpit(EndlesshOptions { zero_window_timeout: Some(Duration::from_secs(25)), ..EndlesshOptions::default() });
+        let start = Instant::now();
+        let client = trap_mock(&mut tarpit, &start);
+        client.0.borrow_mut().room = Some(0);
+        // closed since 20s by 40s, a window that can't be told about leaving the clock running
+        let mut now = start;
+        for window_closed in [Some(true), Some(false), Some(true), None, Some(true)] {
+            client.0.borrow_mut().window_closed = window_closed;
+            now = take_turns(&mut tarpit, now, 1);
+        }
+        assert_eq!(tarpit.client_count(), 1);
+        take_turns(&mut tarpit, now, 1);
+        assert_eq!(tarpit.client_count(), 0);
+    }
+
+    #[test]
+    fn reconfiguring_keeps_the_clients_and_applies_from_their_next_line() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        let client = trap_mock(&mut tarpit, &now);
+        trap_mock(&mut tarpit, &now);
+        tarpit.wakeup(&now, |_| {});
+
+        let buckets = HistogramBuckets { trapped_seconds: vec![1.0], ..HistogramBuckets::default() };
+        tarpit.reconfigure(EndlesshOptions {
+            max_clients: 1,
+            message_delay: Duration::from_secs(5),
+            banner_line_length: 8,
+            histogram_buckets: buckets,
+            ..EndlesshOptions::default()
+        }).unwrap();
+        assert_eq!(tarpit.client_count(), 2);
+        assert!(!tarpit.has_capacity());
+        assert_eq!(tarpit.wakeup(&now, |_| {}), Some(Duration::from_secs(5)));
+        tarpit.wakeup(&(now + Duration::from_secs(5)), |_| {});
+        assert_eq!(client.0.borrow().written.len(), 33 + 9);
+        // the stats were made with the old buckets
+        assert_eq!(tarpit.options().histogram_buckets.trapped_seconds, HistogramBuckets::default().trapped_seconds);
+    }
+
+    #[test]
+    fn reloaded_word_lists_are_drawn_from_on_the_next_line() {
+        let words = |list: &[&str]| BannerGenerator::Words(list.iter().map(|word| word.to_string()).collect());
+        let mut tarpit = tarpit(EndlesshOptions { banner: words(&["alpha"]), banner_line_length: 16, ..EndlesshOptions::default() });
+        let now = Instant::now();
+        let client = trap_mock(&mut tarpit, &now);
+        tarpit.wakeup(&now, |_| {});
+        tarpit.reconfigure(EndlesshOptions { banner: words(&["gamma"]), banner_line_length: 16, ..EndlesshOptions::default() }).unwrap();
+        // a list the banner can't be made of is turned away, the last good one stays
+        assert!(tarpit.reconfigure(EndlesshOptions { banner: words(&["SSH-2.0-OpenSSH"]), ..EndlesshOptions::default() }).is_err());
+        tarpit.wakeup(&(now + Duration::from_secs(10)), |_| {});
+        let written = String::from_utf8(client.0.borrow().written.clone()).unwrap();
+        assert_eq!(written, "alpha alpha alph\ngamma gamma gamm\n");
+    }
+
+    #[test]
+    fn invalid_options_are_rejected_keeping_the_current_ones() {
+        let mut tarpit: Tarpit<Mock> = tarpit(EndlesshOptions { message_delay: Duration::from_secs(5), ..EndlesshOptions::default() });
+        for invalid in [
+            EndlesshOptions { banner_line_length: 40, banner_line_length_max: Some(20), ..EndlesshOptions::default() },
+            EndlesshOptions { banner_line_length: SSH_LINE_BUFFER_SIZE, ..EndlesshOptions::default() },
+            EndlesshOptions { max_send_rate: Some(0), ..EndlesshOptions::default() },
+            EndlesshOptions { accept_rate: Some(0), ..EndlesshOptions::default() },
+            EndlesshOptions { accept_rate: Some(10), accept_burst: Some(0), ..EndlesshOptions::default() },
+        ] {
+            assert!(tarpit.reconfigure(invalid).is_err());
+        }
+        for invalid in [
+            EndlesshOptions { max_send_rate: Some(0), ..EndlesshOptions::default() },
+            EndlesshOptions { accept_burst: Some(0), accept_rate: Some(10), ..EndlesshOptions::default() },
+        ] {
+            assert!(Tarpit::<Mock>::new(invalid, Arc::new(EndlesshStats::default())).is_err());
+        }
+        assert_eq!(tarpit.options().message_delay, Duration::from_secs(5));
+        assert_eq!(tarpit.message_delay(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn late_wakeups_are_measured_and_counted_past_the_threshold() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        trap(&mut tarpit, 1, &now);
+        let mut expected = now + tarpit.wakeup(&now, |_| {}).unwrap();
+
+        let woke = expected + Duration::from_millis(200);
+        expected = woke + tarpit.wakeup(&woke, |_| {}).unwrap();
+        assert_eq!(tarpit.stats.late_wakeups.load(Ordering::Relaxed), 0);
+        assert_eq!(tarpit.stats.take_worst_wakeup_lateness(), Duration::from_millis(200));
+
+        let woke = expected + LATE_WAKEUP_THRESHOLD;
+        tarpit.wakeup(&woke, |_| {});
+        assert_eq!(tarpit.stats.late_wakeups.load(Ordering::Relaxed), 1);
+        assert_eq!(tarpit.stats.take_worst_wakeup_lateness(), LATE_WAKEUP_THRESHOLD);
+        assert_eq!(tarpit.stats.time_jumps.load(Ordering::Relaxed), 0);
+        assert_eq!(tarpit.clients[0].lines_sent, 3);
+    }
+
+    #[test]
+    fn time_the_process_was_suspended_for_is_skipped() {
+        let mut tarpit = tarpit(EndlesshOptions::default());
+        let now = Instant::now();
+        trap(&mut tarpit, 2, &now);
+        let delay = tarpit.wakeup(&now, |_| {}).unwrap();
+        let suspended = Duration::from_secs(3600);
+
+        let woke = now + delay + suspended;
+        assert_eq!(tarpit.wakeup(&woke, |_| {}), Some(delay));
+        assert_eq!(tarpit.stats.time_jumps.load(Ordering::Relaxed), 1);
+        assert_eq!(tarpit.stats.late_wakeups.load(Ordering::Relaxed), 0);
+        for client in &tarpit.clients {
+            // one line each rather than the 360 they'd have been owed, and none of the hour counted as trapped
+            assert_eq!(client.lines_sent, 2);
+            assert_eq!(client.connected_time, now + suspended);
+            assert_eq!(client.last_send_time, Some(woke));
+        }
+    }
+
+    #[test]
+    fn a_clock_going_backwards_counts_as_a_time_jump() {
+        let stats = EndlesshStats::default();
+        let started = stats.started_time;
+        stats.observe_time(&(started + Duration::from_secs(10)));
+        stats.observe_time(&(started + Duration::from_secs(5)));
+        assert_eq!(stats.time_jumps.load(Ordering::Relaxed), 1);
+        // and carries on from the latest time seen
+        stats.observe_time(&(started + Duration::from_secs(8)));
+        assert_eq!(stats.time_jumps.load(Ordering::Relaxed), 2);
+        stats.observe_time(&(started + Duration::from_secs(11)));
+        assert_eq!(stats.time_jumps.load(Ordering::Relaxed), 2);
+    }
+}
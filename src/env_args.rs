@@ -0,0 +1,140 @@
+//! configuration from `ENDLESSH_*` environment variables, for builds without the `cli` feature
+//!
+//! each variable is the upper case form of the matching command line flag, e.g. `--ssh-max-clients` is `ENDLESSH_SSH_MAX_CLIENTS`
+
+use std::env;
+use std::str::FromStr;
+
+use endlessh_rs::endlessh::NewLine;
+use endlessh_rs::socket::ListenAddress;
+use log::LevelFilter;
+
+use crate::logging::{LogFormat, LogOutput};
+use crate::{Args, DEFAULT_BLOCK_DURATION_SECS, DEFAULT_LOG_ROTATE_KEEP, DEFAULT_REPORT_INTERVAL_SECS, DEFAULT_SSH_BANNER_LINE_LENGTH, DEFAULT_SSH_CHATTY_PENALTY, DEFAULT_SSH_LISTEN_ADDRESS, DEFAULT_SSH_MAX_CLIENTS, DEFAULT_SSH_MESSAGE_DELAY_MS,
+    DEFAULT_SSH_REPEAT_OFFENDER_MAX_FACTOR};
+
+/// the variable's value, or None if it isn't set
+fn var<T: FromStr>(name: &str) -> Result<Option<T>, String> where T::Err: std::fmt::Display {
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| format!("invalid value \"{}\" for {} - {}", value, name, e)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(format!("{} is not valid unicode", name)),
+    }
+}
+
+/// a comma separated list, for the flags that may be given more than once
+fn var_list<T: FromStr>(name: &str) -> Result<Option<Vec<T>>, String> where T::Err: std::fmt::Display {
+    let Some(value) = var::<String>(name)? else {
+        return Ok(None);
+    };
+    value.split(',')
+        .map(|item| item.trim().parse().map_err(|e| format!("invalid value \"{}\" in {} - {}", item, name, e)))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+pub fn parse() -> Result<Args, String> {
+    Ok(Args {
+        log_level: var("ENDLESSH_LOG_LEVEL")?.unwrap_or(LevelFilter::Info),
+        log_format: var("ENDLESSH_LOG_FORMAT")?.unwrap_or(LogFormat::Text),
+        log_output: var("ENDLESSH_LOG_OUTPUT")?.unwrap_or(LogOutput::Stdout),
+        log_file: var("ENDLESSH_LOG_FILE")?,
+        log_rotate_bytes: var("ENDLESSH_LOG_ROTATE_BYTES")?,
+        log_rotate_interval_secs: var("ENDLESSH_LOG_ROTATE_INTERVAL_SECS")?,
+        log_rotate_keep: var("ENDLESSH_LOG_ROTATE_KEEP")?.unwrap_or(DEFAULT_LOG_ROTATE_KEEP),
+        stats_file: var("ENDLESSH_STATS_FILE")?,
+        farewell_line: var("ENDLESSH_FAREWELL_LINE")?,
+        shutdown_linger_secs: var("ENDLESSH_SHUTDOWN_LINGER_SECS")?,
+        event_log: var("ENDLESSH_EVENT_LOG")?,
+        sensor_id: var("ENDLESSH_SENSOR_ID")?,
+        event_log_lines: var("ENDLESSH_EVENT_LOG_LINES")?.unwrap_or(false),
+        bind_retry: var("ENDLESSH_BIND_RETRY")?.unwrap_or(0),
+        wait_for_address: var("ENDLESSH_WAIT_FOR_ADDRESS")?,
+        user: var("ENDLESSH_USER")?,
+        group: var("ENDLESSH_GROUP")?,
+        #[cfg(feature = "sandbox")]
+        sandbox: var("ENDLESSH_SANDBOX")?.unwrap_or(false),
+        ssh_listen_address: var_list("ENDLESSH_SSH_LISTEN_ADDRESS")?.unwrap_or_else(|| vec![ListenAddress::Ip(DEFAULT_SSH_LISTEN_ADDRESS)]),
+        ssh_listener_name: var_list("ENDLESSH_SSH_LISTENER_NAME")?.unwrap_or_default(),
+        ssh_banner_line_length: var("ENDLESSH_SSH_BANNER_LINE_LENGTH")?.unwrap_or(DEFAULT_SSH_BANNER_LINE_LENGTH),
+        ssh_banner_line_length_min: var("ENDLESSH_SSH_BANNER_LINE_LENGTH_MIN")?,
+        ssh_banner_line_length_max: var("ENDLESSH_SSH_BANNER_LINE_LENGTH_MAX")?,
+        ssh_banner_style: var("ENDLESSH_SSH_BANNER_STYLE")?,
+        ssh_banner_wordlist: var("ENDLESSH_SSH_BANNER_WORDLIST")?,
+        ssh_banner_template: var("ENDLESSH_SSH_BANNER_TEMPLATE")?,
+        ssh_max_clients: var("ENDLESSH_SSH_MAX_CLIENTS")?.unwrap_or(DEFAULT_SSH_MAX_CLIENTS),
+        ssh_max_clients_per_ip: var("ENDLESSH_SSH_MAX_CLIENTS_PER_IP")?,
+        allow_cidr: var_list("ENDLESSH_ALLOW_CIDR")?.unwrap_or_default(),
+        allow_cidr_file: var("ENDLESSH_ALLOW_CIDR_FILE")?,
+        deny_cidr: var_list("ENDLESSH_DENY_CIDR")?.unwrap_or_default(),
+        deny_cidr_file: var("ENDLESSH_DENY_CIDR_FILE")?,
+        ssh_message_delay_ms: var("ENDLESSH_SSH_MESSAGE_DELAY_MS")?.unwrap_or(DEFAULT_SSH_MESSAGE_DELAY_MS),
+        ssh_message_delay_jitter_ms: var("ENDLESSH_SSH_MESSAGE_DELAY_JITTER_MS")?,
+        // the variants have commas of their own, so they're separated by semicolons
+        ssh_variant: var::<String>("ENDLESSH_SSH_VARIANT")?.map(|variants| variants.split(';').map(str::to_string).collect()).unwrap_or_default(),
+        ssh_adaptive_delay_max_ms: var("ENDLESSH_SSH_ADAPTIVE_DELAY_MAX_MS")?,
+        ssh_stall_limit: var("ENDLESSH_SSH_STALL_LIMIT")?,
+        ssh_chatty_bytes: var("ENDLESSH_SSH_CHATTY_BYTES")?,
+        ssh_chatty_penalty: var("ENDLESSH_SSH_CHATTY_PENALTY")?.unwrap_or(DEFAULT_SSH_CHATTY_PENALTY),
+        ssh_repeat_offender_ttl_secs: var("ENDLESSH_SSH_REPEAT_OFFENDER_TTL_SECS")?,
+        ssh_repeat_offender_max_factor: var("ENDLESSH_SSH_REPEAT_OFFENDER_MAX_FACTOR")?.unwrap_or(DEFAULT_SSH_REPEAT_OFFENDER_MAX_FACTOR),
+        ssh_max_startups: var("ENDLESSH_SSH_MAX_STARTUPS")?,
+        ssh_trap_probability: var("ENDLESSH_SSH_TRAP_PROBABILITY")?.unwrap_or(1.0),
+        ssh_newline: var("ENDLESSH_SSH_NEWLINE")?.unwrap_or(NewLine::LF),
+        rng_seed: var("ENDLESSH_RNG_SEED")?,
+        ssh_accept_rate: var("ENDLESSH_SSH_ACCEPT_RATE")?,
+        ssh_accept_burst: var("ENDLESSH_SSH_ACCEPT_BURST")?,
+        ssh_rcvbuf_bytes: var("ENDLESSH_SSH_RCVBUF_BYTES")?,
+        ssh_sndbuf_bytes: var("ENDLESSH_SSH_SNDBUF_BYTES")?,
+        max_send_bytes_per_sec: var("ENDLESSH_MAX_SEND_BYTES_PER_SEC")?,
+        ssh_zero_window_timeout_secs: var("ENDLESSH_SSH_ZERO_WINDOW_TIMEOUT_SECS")?,
+        ssh_max_trap_seconds: var("ENDLESSH_SSH_MAX_TRAP_SECONDS")?,
+        ssh_dry_run: var("ENDLESSH_SSH_DRY_RUN")?.unwrap_or(false),
+        proxy_protocol: var("ENDLESSH_PROXY_PROTOCOL")?.unwrap_or(false),
+        low_memory: var("ENDLESSH_LOW_MEMORY")?.unwrap_or(false),
+        block_after: var("ENDLESSH_BLOCK_AFTER")?,
+        block_duration_secs: var("ENDLESSH_BLOCK_DURATION_SECS")?.unwrap_or(DEFAULT_BLOCK_DURATION_SECS),
+        block_backend: var("ENDLESSH_BLOCK_BACKEND")?,
+        block_state_file: var("ENDLESSH_BLOCK_STATE_FILE")?,
+        report_file: var("ENDLESSH_REPORT_FILE")?,
+        report_interval_secs: var("ENDLESSH_REPORT_INTERVAL_SECS")?.unwrap_or(DEFAULT_REPORT_INTERVAL_SECS),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each test sets variables of its own, the tests run in parallel
+
+    #[test]
+    fn variables_parse_or_name_themselves_in_the_error() {
+        env::set_var("ENDLESSH_TEST_DELAY", "250");
+        env::set_var("ENDLESSH_TEST_BAD_DELAY", "soon");
+        assert_eq!(var::<u64>("ENDLESSH_TEST_DELAY"), Ok(Some(250)));
+        assert_eq!(var::<u64>("ENDLESSH_TEST_UNSET"), Ok(None));
+        let error = var::<u64>("ENDLESSH_TEST_BAD_DELAY").unwrap_err();
+        assert!(error.contains("\"soon\"") && error.contains("ENDLESSH_TEST_BAD_DELAY"), "{}", error);
+    }
+
+    #[test]
+    fn repeated_flags_are_comma_separated() {
+        env::set_var("ENDLESSH_TEST_ADDRESSES", "ip:0.0.0.0:22, unix:/run/endlessh.sock");
+        env::set_var("ENDLESSH_TEST_BAD_ADDRESSES", "ip:0.0.0.0:22,nowhere");
+        let addresses = var_list::<ListenAddress>("ENDLESSH_TEST_ADDRESSES").unwrap().unwrap();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(var_list::<ListenAddress>("ENDLESSH_TEST_UNSET_ADDRESSES"), Ok(None));
+        let error = var_list::<ListenAddress>("ENDLESSH_TEST_BAD_ADDRESSES").unwrap_err();
+        assert!(error.contains("\"nowhere\"") && error.contains("ENDLESSH_TEST_BAD_ADDRESSES"), "{}", error);
+    }
+
+    #[test]
+    fn unset_variables_take_the_flag_defaults() {
+        let args = parse().unwrap();
+        assert_eq!(args.ssh_max_clients, DEFAULT_SSH_MAX_CLIENTS);
+        assert_eq!(args.ssh_message_delay_ms, DEFAULT_SSH_MESSAGE_DELAY_MS);
+        assert_eq!(args.ssh_listen_address.len(), 1);
+        assert!(args.ssh_listener_name.is_empty());
+        assert_eq!(args.ssh_trap_probability, 1.0);
+    }
+}
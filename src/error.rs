@@ -0,0 +1,113 @@
+use std::fmt::Display;
+use std::io;
+
+use thiserror::Error;
+
+/// everything the public APIs can fail with, so embedders can match on the failure instead of catching panics
+#[derive(Debug, Error)]
+pub enum EndlesshError {
+    #[error("failed to bind to {address}: {source}{}", .hint.map(|hint| format!(" - {}", hint)).unwrap_or_default())]
+    Bind {
+        address: String,
+        #[source]
+        source: io::Error,
+        /// the likely cause, for the usual suspects
+        hint: Option<&'static str>,
+    },
+    #[error("failed to register {what} with the poll: {source}")]
+    Register {
+        what: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to accept a connection: {0}")]
+    Accept(#[source] io::Error),
+    #[error("failed to set up the firewall: {0}")]
+    Firewall(#[source] io::Error),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+    #[error("history store {store}: {reason}")]
+    Store {
+        store: String,
+        reason: String,
+    },
+    #[cfg(feature = "remote-lists")]
+    #[error("failed to fetch {url}: {reason}")]
+    Fetch {
+        url: String,
+        reason: String,
+    },
+    #[cfg(feature = "elasticsearch")]
+    #[error("failed to export to {url}: {reason}")]
+    Export {
+        url: String,
+        reason: String,
+    },
+    #[cfg(feature = "metrics-tls")]
+    #[error("metrics tls: {0}")]
+    Tls(String),
+    #[cfg(feature = "hpfeeds")]
+    #[error("hpfeeds broker {broker}: {reason}")]
+    Hpfeeds {
+        broker: String,
+        reason: String,
+    },
+}
+
+impl EndlesshError {
+
+    /// a bind failure, with a guess at its cause - `port` is None for unix sockets
+    pub fn bind(address: impl Display, port: Option<u16>, source: io::Error) -> Self {
+        let hint = match source.kind() {
+            io::ErrorKind::PermissionDenied if port.is_some_and(|p| p < 1024) => {
+                Some("ports below 1024 need root or CAP_NET_BIND_SERVICE")
+            },
+            io::ErrorKind::PermissionDenied => Some("check the permissions of the socket's directory"),
+            io::ErrorKind::AddrInUse => Some("something else is already listening there, e.g. sshd"),
+            io::ErrorKind::AddrNotAvailable => Some("no interface has that address (yet?)"),
+            _ => None,
+        };
+        EndlesshError::Bind {
+            address: address.to_string(),
+            source,
+            hint,
+        }
+    }
+
+    /// whether trying again later might help, e.g. once the network is up or the previous instance has exited
+    pub fn is_transient(&self) -> bool {
+        match self {
+            EndlesshError::Bind { source, .. } => matches!(source.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable),
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, EndlesshError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endlessh::bind_listener;
+
+    #[test]
+    fn bind_failures_name_the_likely_cause() {
+        let denied = EndlesshError::bind("0.0.0.0:22", Some(22), io::ErrorKind::PermissionDenied.into());
+        assert!(denied.to_string().ends_with(" - ports below 1024 need root or CAP_NET_BIND_SERVICE"), "{}", denied);
+        let denied = EndlesshError::bind("/run/endlessh/ssh.sock", None, io::ErrorKind::PermissionDenied.into());
+        assert!(denied.to_string().ends_with(" - check the permissions of the socket's directory"), "{}", denied);
+        let other = EndlesshError::bind("0.0.0.0:2222", Some(2222), io::ErrorKind::Other.into());
+        assert!(other.to_string().starts_with("failed to bind to 0.0.0.0:2222: ") && !other.to_string().contains(" - "), "{}", other);
+    }
+
+    #[test]
+    fn only_addresses_in_use_or_not_yet_there_are_worth_retrying() {
+        let taken = bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let in_use = bind_listener(taken.local_addr().unwrap()).unwrap_err();
+        assert!(in_use.is_transient());
+        assert!(in_use.to_string().ends_with(" - something else is already listening there, e.g. sshd"), "{}", in_use);
+        assert!(EndlesshError::bind("192.0.2.1:22", Some(22), io::ErrorKind::AddrNotAvailable.into()).is_transient());
+        assert!(!EndlesshError::bind("0.0.0.0:22", Some(22), io::ErrorKind::PermissionDenied.into()).is_transient());
+        assert!(!EndlesshError::Config("no".to_string()).is_transient());
+    }
+}
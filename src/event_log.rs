@@ -0,0 +1,184 @@
+//! a json-lines record of every connection event, and playing one back for `endlessh-rs replay`
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::endlessh::ConnectionEvent;
+
+/// appends each event as one line of json, buffered until [EventLog::flush] so a busy loop costs one write
+pub struct EventLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl EventLog {
+
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        // a line cut short by a crash would swallow the first event written after it
+        let len = file.metadata()?.len();
+        if len > 0 {
+            let mut last = [0];
+            file.seek(SeekFrom::Start(len - 1))?;
+            file.read_exact(&mut last)?;
+            if last != *b"\n" {
+                file.write_all(b"\n")?;
+            }
+        }
+        Ok(EventLog {
+            path,
+            file: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(&mut self, event: &ConnectionEvent) {
+        let mut line = serde_json::to_string(event).expect("failed to serialize connection event");
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("failed to write to event log {} - {}", self.path.display(), e);
+        }
+    }
+
+    /// writes out the buffered events, once per pass of the event loop
+    pub fn flush(&mut self) {
+        if let Err(e) = self.file.flush() {
+            warn!("failed to write to event log {} - {}", self.path.display(), e);
+        }
+    }
+}
+
+/// reads an event log, skipping (and counting) lines that aren't events, e.g. one cut short by a crash
+pub fn read_events(path: &Path) -> io::Result<(Vec<ConnectionEvent>, usize)> {
+    let mut events = Vec::new();
+    let mut invalid = 0;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(event) => events.push(event),
+            Err(_) => invalid += 1,
+        }
+    }
+    Ok((events, invalid))
+}
+
+/// hands out recorded events as they fall due, keeping their original spacing divided by `speed`
+pub struct EventReplay {
+    events: VecDeque<ConnectionEvent>,
+    /// when the first event was recorded, in seconds since the unix epoch
+    first_recorded: f64,
+    started: Instant,
+    speed: f64,
+}
+
+impl EventReplay {
+
+    pub fn new(mut events: Vec<ConnectionEvent>, speed: f64, now: &Instant) -> Self {
+        events.sort_by(|a, b| a.time_seconds.total_cmp(&b.time_seconds));
+        EventReplay {
+            first_recorded: events.first().map_or(0.0, |e| e.time_seconds),
+            events: events.into(),
+            started: *now,
+            speed,
+        }
+    }
+
+    fn due_time(&self, event: &ConnectionEvent) -> Instant {
+        let offset = (event.time_seconds - self.first_recorded) / self.speed;
+        self.started + Duration::try_from_secs_f64(offset).unwrap_or_default()
+    }
+
+    /// the events due by `now`, oldest first
+    pub fn take_due(&mut self, now: &Instant) -> Vec<ConnectionEvent> {
+        let mut due = Vec::new();
+        while self.events.front().is_some_and(|event| self.due_time(event) <= *now) {
+            due.extend(self.events.pop_front());
+        }
+        if !due.is_empty() && self.events.is_empty() {
+            info!("replay finished, still serving metrics");
+        }
+        due
+    }
+
+    /// how long until the next event is due, or None once they've all been replayed
+    pub fn timeout(&self, now: &Instant) -> Option<Duration> {
+        self.events.front().map(|event| self.due_time(event).saturating_duration_since(*now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::endlessh::ConnectionEventKind;
+
+    fn event(kind: ConnectionEventKind, time_seconds: f64, port: u16) -> ConnectionEvent {
+        serde_json::from_value(serde_json::json!({
+            "event": kind,
+            "time_seconds": time_seconds,
+            "peer": SocketAddr::from(([192, 0, 2, 1], port)),
+            "bytes_sent": 0,
+            "trapped_seconds": 0.0,
+        })).unwrap()
+    }
+
+    #[test]
+    fn events_are_read_back_skipping_lines_cut_short() {
+        let path = std::env::temp_dir().join(format!("endlessh-rs-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut log = EventLog::open(path.clone()).unwrap();
+        log.write(&event(ConnectionEventKind::Accepted, 1.0, 1));
+        log.write(&event(ConnectionEventKind::Closed, 2.0, 1));
+        log.flush();
+        drop(log);
+        // a crash mid-write, then a restart appending after it
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"\n{\"event\":\"acc").unwrap();
+        drop(file);
+        let mut log = EventLog::open(path.clone()).unwrap();
+        log.write(&event(ConnectionEventKind::Accepted, 3.0, 2));
+        log.flush();
+
+        let (events, invalid) = read_events(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(invalid, 1);
+        let read: Vec<_> = events.iter().map(|e| (e.event, e.time_seconds, e.peer.port())).collect();
+        assert_eq!(read, [
+            (ConnectionEventKind::Accepted, 1.0, 1),
+            (ConnectionEventKind::Closed, 2.0, 1),
+            (ConnectionEventKind::Accepted, 3.0, 2),
+        ]);
+        assert!(read_events(&path).is_err());
+    }
+
+    #[test]
+    fn replays_keep_the_recorded_spacing_divided_by_the_speed() {
+        let start = Instant::now();
+        let events = vec![
+            event(ConnectionEventKind::Closed, 1010.0, 1),
+            event(ConnectionEventKind::Accepted, 1000.0, 1),
+            event(ConnectionEventKind::Accepted, 1004.0, 2),
+        ];
+        let mut replay = EventReplay::new(events, 2.0, &start);
+
+        assert_eq!(replay.timeout(&start), Some(Duration::ZERO));
+        let due = replay.take_due(&start);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].time_seconds, 1000.0);
+        assert_eq!(replay.timeout(&start), Some(Duration::from_secs(2)));
+
+        assert!(replay.take_due(&(start + Duration::from_secs(1))).is_empty());
+        // everything overdue comes out at once, oldest first
+        let due = replay.take_due(&(start + Duration::from_secs(6)));
+        assert_eq!(due.iter().map(|e| e.time_seconds).collect::<Vec<_>>(), [1004.0, 1010.0]);
+        assert_eq!(replay.timeout(&(start + Duration::from_secs(6))), None);
+    }
+}
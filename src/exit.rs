@@ -0,0 +1,54 @@
+use std::fmt::Display;
+use std::process;
+
+use serde::Serialize;
+
+/// the class of failure that stopped the process, each with its own exit code
+/// (taken from sysexits.h) so supervisors can react without parsing messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FatalError {
+    /// the command line or configuration is invalid
+    Config,
+    /// a listener couldn't be created
+    Bind,
+    /// switching to an unprivileged user failed
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    PrivilegeDrop,
+    /// the OS refused resources the event loop needs
+    ResourceExhaustion,
+    /// the sandbox couldn't be installed, or blocked a system call
+    #[cfg(all(feature = "sandbox", target_os = "linux"))]
+    Sandbox,
+}
+
+impl FatalError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FatalError::Config => 78,
+            FatalError::Bind => 69,
+            FatalError::PrivilegeDrop => 77,
+            FatalError::ResourceExhaustion => 71,
+            #[cfg(all(feature = "sandbox", target_os = "linux"))]
+            FatalError::Sandbox => 70,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FatalReport {
+    fatal: FatalError,
+    exit_code: i32,
+    message: String,
+}
+
+/// prints a single json line describing the failure to stderr and exits with the class's code
+pub fn fatal(error: FatalError, message: impl Display) -> ! {
+    let report = FatalReport {
+        fatal: error,
+        exit_code: error.exit_code(),
+        message: message.to_string(),
+    };
+    eprintln!("{}", serde_json::to_string(&report).expect("failed to serialize fatal error"));
+    process::exit(report.exit_code)
+}
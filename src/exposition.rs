@@ -0,0 +1,93 @@
+//! the openmetrics text format, shared by `/metrics`, the textfile collector and embedders serving the stats themselves
+//!
+//! a family is announced with its `# HELP` and `# TYPE` lines and followed by all of its samples - openmetrics parsers
+//! reject a family that shows up twice, so callers with labelled samples go through their labels once per family
+
+use std::fmt::{Display, Write};
+
+use crate::endlessh::Histogram;
+
+/// the content type to serve an [Exposition] with
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricType {
+    /// only goes up, its samples are named with a `_total` suffix
+    Counter,
+    Gauge,
+    /// samples are added with [Exposition::histogram]
+    Histogram,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+/// `labels` (`{key="value",...}` or empty) plus one more, its value already escaped
+pub fn with_label(labels: &str, key: &str, value: &str) -> String {
+    match labels.strip_suffix('}') {
+        Some(labels) => format!("{},{}=\"{}\"}}", labels, key, value),
+        None => format!("{{{}=\"{}\"}}", key, value),
+    }
+}
+
+/// an exposition built up one family at a time, see [Exposition::finish]
+#[derive(Default)]
+pub struct Exposition {
+    body: String,
+    /// the family the next samples belong to
+    family: String,
+    metric_type: Option<MetricType>,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// starts a family, e.g. `endlessh_ssh_connections_opened` - counters are named without their `_total`
+    pub fn family(&mut self, name: &str, metric_type: MetricType, help: &str) {
+        let _ = writeln!(self.body, "# HELP {} {}", name, help.replace('\\', "\\\\").replace('\n', "\\n"));
+        let _ = writeln!(self.body, "# TYPE {} {}", name, metric_type.as_str());
+        self.family.clear();
+        self.family.push_str(name);
+        self.metric_type = Some(metric_type);
+    }
+
+    /// a sample of the counter or gauge family started last. `labels` is `{key="value",...}` or empty, and a float
+    /// value must be finite
+    pub fn sample(&mut self, labels: impl Display, value: impl Display) {
+        let suffix = match self.metric_type {
+            Some(MetricType::Counter) => "_total",
+            Some(MetricType::Gauge) => "",
+            Some(MetricType::Histogram) => panic!("histogram {} takes its samples from Exposition::histogram", self.family),
+            None => panic!("sample added before any family"),
+        };
+        let _ = writeln!(self.body, "{}{}{} {}", self.family, suffix, labels, value);
+    }
+
+    /// the buckets, sum and count of the histogram family started last
+    pub fn histogram(&mut self, labels: impl Display, histogram: &Histogram) {
+        assert_eq!(self.metric_type, Some(MetricType::Histogram), "{} isn't a histogram", self.family);
+        let labels = labels.to_string();
+        for (bound, count) in histogram.buckets() {
+            // openmetrics wants bounds as floats, "1.0" rather than "1"
+            let le = if bound.is_infinite() { "+Inf".to_string() } else { format!("{:?}", bound) };
+            let _ = writeln!(self.body, "{}_bucket{} {}", self.family, with_label(&labels, "le", &le), count);
+        }
+        let _ = writeln!(self.body, "{}_sum{} {}", self.family, labels, histogram.sum());
+        let _ = writeln!(self.body, "{}_count{} {}", self.family, labels, histogram.count());
+    }
+
+    /// the exposition, ending with the `# EOF` openmetrics requires
+    pub fn finish(mut self) -> String {
+        self.body.push_str("# EOF\n");
+        self.body
+    }
+}
@@ -0,0 +1,821 @@
+//! blocks addresses that keep coming back for more, by adding temporary firewall rules
+//!
+//! rules are added and removed by shelling out, so a block costs a process spawn - fine at the rate clients get trapped,
+//! but not on the event loop, so the commands are queued for a worker thread that reports back through a waker. each
+//! address gets its own rule, for systems without ipset or nftables sets
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::{rename, File};
+use std::io::{self, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+use mio::Waker;
+use serde::{Deserialize, Serialize};
+
+use crate::address_list::AddressList;
+use crate::endlessh::unix_seconds;
+use crate::error::{self, EndlesshError};
+
+/// prefix of every rule we add, so they're easy to find and clean up by hand
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+const RULE_NAME_PREFIX: &str = "endlessh-rs block";
+/// the nftables table (in the inet family) and chain our rules live in
+#[cfg(target_os = "linux")]
+const NFT_TABLE: &str = "endlessh_rs";
+#[cfg(target_os = "linux")]
+const NFT_CHAIN: &str = "input";
+/// past this many distinct addresses the disconnect counts are forgotten, rather than growing without bound
+const MAX_TRACKED_ADDRESSES: usize = 65536;
+/// how many commands can wait for the worker thread, past that new blocks are skipped rather than holding up the loop
+const COMMAND_QUEUE_LENGTH: usize = 1024;
+/// what each firewall says when asked to remove a rule that isn't there, e.g. after a reboot flushed them
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+const MISSING_RULE_MESSAGES: &[&str] = &[
+    // iptables and ip6tables
+    "does a matching rule exist",
+    // nft, for a handle that's gone
+    "Could not process rule: No such file or directory",
+    // netsh
+    "No rules match",
+];
+
+/// the firewalls we know how to add block rules to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirewallBackend {
+    /// inbound block rules via `netsh advfirewall`
+    #[cfg(windows)]
+    WindowsFirewall,
+    /// DROP rules at the top of the INPUT chain, via `iptables` and `ip6tables`
+    #[cfg(target_os = "linux")]
+    Iptables,
+    /// drop rules in a chain of our own `inet endlessh_rs` table, via `nft`
+    #[cfg(target_os = "linux")]
+    Nftables,
+}
+
+/// what it takes to remove a block again
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Rule {
+    /// the rule can be found again from the address
+    ByAddress,
+    /// nftables rules can only be deleted by handle
+    #[cfg(target_os = "linux")]
+    Handle(u64),
+}
+
+impl FromStr for FirewallBackend {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v {
+            #[cfg(windows)]
+            "windows-firewall" => Ok(FirewallBackend::WindowsFirewall),
+            #[cfg(target_os = "linux")]
+            "iptables" => Ok(FirewallBackend::Iptables),
+            #[cfg(target_os = "linux")]
+            "nftables" => Ok(FirewallBackend::Nftables),
+            _ => Err(format!("unknown firewall backend \"{}\", this platform supports: {}", v, FirewallBackend::SUPPORTED.join(", "))),
+        }
+    }
+}
+
+impl Display for FirewallBackend {
+    #[cfg_attr(not(any(windows, target_os = "linux")), allow(unused_variables))]
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match *self {
+            #[cfg(windows)]
+            FirewallBackend::WindowsFirewall => write!(f, "windows-firewall"),
+            #[cfg(target_os = "linux")]
+            FirewallBackend::Iptables => write!(f, "iptables"),
+            #[cfg(target_os = "linux")]
+            FirewallBackend::Nftables => write!(f, "nftables"),
+        }
+    }
+}
+
+impl FirewallBackend {
+    const SUPPORTED: &'static [&'static str] = &[
+        #[cfg(windows)]
+        "windows-firewall",
+        #[cfg(target_os = "linux")]
+        "iptables",
+        #[cfg(target_os = "linux")]
+        "nftables",
+    ];
+
+    /// one-off setup before the first block, e.g. creating our own table
+    fn prepare(&self) -> io::Result<()> {
+        match *self {
+            #[cfg(target_os = "linux")]
+            FirewallBackend::Nftables => {
+                // both are no-ops if they already exist
+                run("nft", &["add", "table", "inet", NFT_TABLE])?;
+                run("nft", &["add", "chain", "inet", NFT_TABLE, NFT_CHAIN, "{ type filter hook input priority -10 ; }"])?;
+                Ok(())
+            },
+            #[allow(unreachable_patterns)]
+            _ => Ok(()),
+        }
+    }
+}
+
+/// what the worker thread changes the rules of - a backend, or a stand-in in the tests
+trait Firewall: Display + Send + 'static {
+    fn block(&self, ip: IpAddr) -> io::Result<Rule>;
+
+    /// fails with [io::ErrorKind::NotFound] if the rule is already gone
+    fn unblock(&self, ip: IpAddr, rule: Rule) -> io::Result<()>;
+}
+
+impl Firewall for FirewallBackend {
+    #[cfg_attr(not(any(windows, target_os = "linux")), allow(unused_variables))]
+    fn block(&self, ip: IpAddr) -> io::Result<Rule> {
+        match *self {
+            #[cfg(windows)]
+            FirewallBackend::WindowsFirewall => run("netsh", &[
+                "advfirewall", "firewall", "add", "rule",
+                &format!("name={} {}", RULE_NAME_PREFIX, ip),
+                "dir=in",
+                "action=block",
+                &format!("remoteip={}", ip),
+            ]).map(|_| Rule::ByAddress),
+            #[cfg(target_os = "linux")]
+            FirewallBackend::Iptables => run(iptables_for(ip), &iptables_rule("-I", ip)).map(|_| Rule::ByAddress),
+            #[cfg(target_os = "linux")]
+            FirewallBackend::Nftables => {
+                let family = if ip.is_ipv4() { "ip" } else { "ip6" };
+                // --echo --handle prints the new rule, ending "# handle <n>"
+                let output = run("nft", &[
+                    "--echo", "--handle", "add", "rule", "inet", NFT_TABLE, NFT_CHAIN,
+                    family, "saddr", &ip.to_string(), "drop", "comment", &format!("\"{}\"", RULE_NAME_PREFIX),
+                ])?;
+                nft_handle(&output).map(Rule::Handle)
+            },
+        }
+    }
+
+    #[cfg_attr(not(any(windows, target_os = "linux")), allow(unused_variables))]
+    fn unblock(&self, ip: IpAddr, rule: Rule) -> io::Result<()> {
+        let result = match (*self, rule) {
+            #[cfg(windows)]
+            (FirewallBackend::WindowsFirewall, _) => run("netsh", &[
+                "advfirewall", "firewall", "delete", "rule",
+                &format!("name={} {}", RULE_NAME_PREFIX, ip),
+            ]).map(drop),
+            #[cfg(target_os = "linux")]
+            (FirewallBackend::Iptables, _) => run(iptables_for(ip), &iptables_rule("-D", ip)).map(drop),
+            #[cfg(target_os = "linux")]
+            (FirewallBackend::Nftables, Rule::Handle(handle)) => {
+                run("nft", &["delete", "rule", "inet", NFT_TABLE, NFT_CHAIN, "handle", &handle.to_string()]).map(drop)
+            },
+            #[allow(unreachable_patterns)]
+            (backend, rule) => Err(io::Error::other(format!("{} can't remove a {:?} rule", backend, rule))),
+        };
+        result.map_err(missing_rule)
+    }
+}
+
+/// the handle nft prints for a rule added with --echo --handle
+#[cfg(target_os = "linux")]
+fn nft_handle(output: &str) -> io::Result<u64> {
+    output.rsplit_once("# handle ")
+        .and_then(|(_, handle)| handle.trim().parse().ok())
+        .ok_or_else(|| io::Error::other(format!("no rule handle in nft output \"{}\"", output.trim())))
+}
+
+/// turns a firewall's complaint that the rule to remove isn't there into [io::ErrorKind::NotFound]
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+fn missing_rule(e: io::Error) -> io::Error {
+    let message = e.to_string();
+    if MISSING_RULE_MESSAGES.iter().any(|missing| message.contains(missing)) {
+        io::Error::new(io::ErrorKind::NotFound, message)
+    } else {
+        e
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn iptables_for(ip: IpAddr) -> &'static str {
+    if ip.is_ipv4() { "iptables" } else { "ip6tables" }
+}
+
+/// the same arguments find the rule again, so `-I` adds it and `-D` removes it
+#[cfg(target_os = "linux")]
+fn iptables_rule(action: &str, ip: IpAddr) -> Vec<String> {
+    [action, "INPUT", "-s", &ip.to_string(), "-m", "comment", "--comment", RULE_NAME_PREFIX, "-j", "DROP"]
+        .map(str::to_string)
+        .to_vec()
+}
+
+/// runs a firewall command, returning its stdout. a failure carries what the command said (netsh says it on stdout),
+/// which is how a rule that's already gone is told apart
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+fn run(program: &str, args: &[impl AsRef<std::ffi::OsStr>]) -> io::Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        // not NotFound for a missing program, that's kept for a missing rule
+        .map_err(|e| io::Error::other(format!("failed to run {} - {}", program, e)))?;
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+    let said = match String::from_utf8_lossy(&output.stderr).trim() {
+        "" => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        stderr => stderr.to_string(),
+    };
+    Err(io::Error::other(format!("{} exited with {} - {}", program, output.status, said)))
+}
+
+/// a change for the worker thread to make
+enum FirewallCommand {
+    Block(IpAddr),
+    Unblock(IpAddr, Rule),
+    /// removes the rules a previous process saved, whether or not they survived, then blocks the live ones again
+    Restore {
+        stale: Vec<(IpAddr, Rule)>,
+        live: Vec<IpAddr>,
+    },
+}
+
+/// what came of a command, sent back to the event loop
+enum FirewallResult {
+    Blocked(IpAddr, io::Result<Rule>),
+    Unblocked(IpAddr, io::Result<()>),
+}
+
+/// runs the queued commands until the [Blocker] hangs up, waking the event loop for each result
+fn work(firewall: impl Firewall, commands: Receiver<FirewallCommand>, results: Sender<FirewallResult>, waker: Arc<Waker>) {
+    let report = |result| {
+        // the blocker is gone, the rest of the queue still runs so no rule is left half done
+        if results.send(result).is_ok() {
+            if let Err(e) = waker.wake() {
+                warn!("failed to wake the event loop for a firewall result: {}", e);
+            }
+        }
+    };
+    for command in commands {
+        match command {
+            FirewallCommand::Block(ip) => report(FirewallResult::Blocked(ip, firewall.block(ip))),
+            FirewallCommand::Unblock(ip, rule) => report(FirewallResult::Unblocked(ip, firewall.unblock(ip, rule))),
+            FirewallCommand::Restore { stale, live } => {
+                for (ip, rule) in stale {
+                    match firewall.unblock(ip, rule) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => warn!("failed to remove the saved block of {} with {}: {}", ip, firewall, e),
+                        _ => {},
+                    }
+                }
+                for ip in live {
+                    report(FirewallResult::Blocked(ip, firewall.block(ip)));
+                }
+            },
+        }
+    }
+}
+
+/// the blocks and disconnect counts written to the state file, so a restart doesn't forgive everyone
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    blocked: Vec<SavedBlock>,
+    disconnects: HashMap<IpAddr, u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBlock {
+    ip: IpAddr,
+    /// seconds since the unix epoch, since an Instant means nothing to the next process
+    until: u64,
+    rule: Rule,
+}
+
+/// where a block's rule is at, the worker thread adds and removes them
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockState {
+    Adding,
+    Active(Rule),
+    /// goes back to active if removing it fails, to be tried again
+    Removing(Rule),
+}
+
+/// counts how often each address has been trapped and let go, and blocks it for a while once that passes a threshold
+pub struct Blocker {
+    /// the backend's name, for the logs
+    firewall: String,
+    threshold: u32,
+    block_duration: Duration,
+    disconnects: HashMap<IpAddr, u32>,
+    /// when each blocked address gets unblocked, and where its rule is at
+    blocked: HashMap<IpAddr, (Instant, BlockState)>,
+    /// addresses that are blocked on their first disconnect
+    blocklist: AddressList,
+    /// where [Blocker::save] writes to, see [Blocker::persist_to]
+    state_file: Option<PathBuf>,
+    /// None once [Blocker::unblock_all] has hung up on the worker
+    commands: Option<SyncSender<FirewallCommand>>,
+    results: Receiver<FirewallResult>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Blocker {
+
+    /// prepares the firewall and starts the thread that runs its commands, which wakes `waker` with each result for
+    /// [Blocker::handle_results]
+    pub fn new(backend: FirewallBackend, threshold: u32, block_duration: Duration, waker: Arc<Waker>) -> error::Result<Self> {
+        backend.prepare().map_err(EndlesshError::Firewall)?;
+        Ok(Blocker::start(backend, threshold, block_duration, waker))
+    }
+
+    fn start(firewall: impl Firewall, threshold: u32, block_duration: Duration, waker: Arc<Waker>) -> Self {
+        let name = firewall.to_string();
+        let (commands, queue) = mpsc::sync_channel(COMMAND_QUEUE_LENGTH);
+        let (report, results) = mpsc::channel();
+        let worker = thread::spawn(move || work(firewall, queue, report, waker));
+        Blocker {
+            firewall: name,
+            threshold,
+            block_duration,
+            disconnects: HashMap::new(),
+            blocked: HashMap::new(),
+            blocklist: AddressList::default(),
+            state_file: None,
+            commands: Some(commands),
+            results,
+            worker: Some(worker),
+        }
+    }
+
+    /// replaces the addresses that skip the threshold, e.g. from a published feed
+    pub fn set_blocklist(&mut self, blocklist: AddressList) {
+        self.blocklist = blocklist;
+    }
+
+    /// counts a disconnect, blocking the address once it reaches the threshold
+    pub fn record_disconnect(&mut self, ip: IpAddr, now: &Instant) {
+        if self.blocked.contains_key(&ip) {
+            return;
+        }
+        if self.disconnects.len() >= MAX_TRACKED_ADDRESSES && !self.disconnects.contains_key(&ip) {
+            self.disconnects.clear();
+        }
+        let threshold = if self.blocklist.contains(ip) { 1 } else { self.threshold };
+        let disconnects = self.disconnects.entry(ip).or_insert(0);
+        *disconnects += 1;
+        if *disconnects < threshold {
+            return;
+        }
+        self.disconnects.remove(&ip);
+        self.block(ip, now);
+    }
+
+    /// blocks an address straight away, e.g. one an accept policy banned
+    pub fn block(&mut self, ip: IpAddr, now: &Instant) {
+        if self.blocked.contains_key(&ip) {
+            return;
+        }
+        match self.queue(FirewallCommand::Block(ip)) {
+            Ok(()) => {
+                self.blocked.insert(ip, (*now + self.block_duration, BlockState::Adding));
+            },
+            Err(e) => warn!("failed to block {} with {}: {}", ip, self.firewall, e),
+        }
+    }
+
+    fn queue(&self, command: FirewallCommand) -> Result<(), &'static str> {
+        match self.commands.as_ref().map(|commands| commands.try_send(command)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(TrySendError::Full(_))) => Err("too many firewall commands are waiting"),
+            Some(Err(TrySendError::Disconnected(_))) | None => Err("the firewall thread is gone"),
+        }
+    }
+
+    /// lifts the blocks that have run their course
+    pub fn expire(&mut self, now: &Instant) {
+        let due: Vec<(IpAddr, Rule)> = self.blocked.iter()
+            .filter_map(|(ip, (unblock_time, state))| match state {
+                BlockState::Active(rule) if *unblock_time <= *now => Some((*ip, *rule)),
+                _ => None,
+            })
+            .collect();
+        for (ip, rule) in due {
+            // the rest are left for the next pass
+            if let Err(e) = self.queue(FirewallCommand::Unblock(ip, rule)) {
+                warn!("failed to unblock {} with {}: {}", ip, self.firewall, e);
+                return;
+            }
+            if let Some((_, state)) = self.blocked.get_mut(&ip) {
+                *state = BlockState::Removing(rule);
+            }
+        }
+    }
+
+    /// takes in what the worker thread made of the commands so far
+    pub fn handle_results(&mut self, now: &Instant) {
+        let results: Vec<FirewallResult> = self.results.try_iter().collect();
+        for result in results {
+            self.apply(result, now);
+        }
+    }
+
+    fn apply(&mut self, result: FirewallResult, now: &Instant) {
+        match result {
+            FirewallResult::Blocked(ip, Ok(rule)) => {
+                if let Some((unblock_time, state)) = self.blocked.get_mut(&ip) {
+                    *state = BlockState::Active(rule);
+                    info!("blocked {} with {} for {}s", ip, self.firewall, unblock_time.saturating_duration_since(*now).as_secs_f64().ceil());
+                }
+            },
+            FirewallResult::Blocked(ip, Err(e)) => {
+                self.blocked.remove(&ip);
+                warn!("failed to block {} with {}: {}", ip, self.firewall, e);
+            },
+            FirewallResult::Unblocked(ip, Ok(())) => {
+                self.blocked.remove(&ip);
+                info!("unblocked {}", ip);
+            },
+            // e.g. flushed by hand, trying again would only fail again
+            FirewallResult::Unblocked(ip, Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+                self.blocked.remove(&ip);
+                info!("unblocked {}, whose rule was already gone", ip);
+            },
+            // left for the next pass rather than orphaning the rule
+            FirewallResult::Unblocked(ip, Err(e)) => {
+                if let Some((_, state)) = self.blocked.get_mut(&ip) {
+                    if let BlockState::Removing(rule) = *state {
+                        *state = BlockState::Active(rule);
+                    }
+                }
+                warn!("failed to unblock {} with {}: {}", ip, self.firewall, e);
+            },
+        }
+    }
+
+    /// lifts every block, e.g. before exiting. waits for the commands already queued, so blocks still being added are
+    /// lifted too, then for the worker thread to finish
+    pub fn unblock_all(&mut self) {
+        let Some(commands) = self.commands.take() else {
+            return;
+        };
+        let now = Instant::now();
+        // every block being added or removed has a result on the way
+        while self.blocked.values().any(|(_, state)| !matches!(state, BlockState::Active(_))) {
+            match self.results.recv() {
+                Ok(result) => self.apply(result, &now),
+                Err(_) => break,
+            }
+        }
+        for (ip, (_, state)) in &self.blocked {
+            if let BlockState::Active(rule) = state {
+                if commands.send(FirewallCommand::Unblock(*ip, *rule)).is_err() {
+                    break;
+                }
+            }
+        }
+        // the worker stops once the queue runs dry, hanging up on the results
+        drop(commands);
+        let results: Vec<FirewallResult> = self.results.iter().collect();
+        for result in results {
+            self.apply(result, &now);
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+        self.blocked.clear();
+    }
+
+    /// writes the blocks and disconnect counts to the state file, if there is one
+    pub fn save(&self, now: &Instant) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        if let Err(e) = self.write_state(path, now) {
+            warn!("failed to save the block state to {} - {}", path.display(), e);
+        }
+    }
+
+    // a crash mid-write mustn't lose the previous state, so write a copy and swap it in
+    fn write_state(&self, path: &Path, now: &Instant) -> io::Result<()> {
+        let unix_now = unix_seconds(SystemTime::now());
+        let state = SavedState {
+            blocked: self.blocked.iter().filter_map(|(ip, (unblock_time, state))| match *state {
+                BlockState::Active(rule) | BlockState::Removing(rule) => Some(SavedBlock {
+                    ip: *ip,
+                    until: unix_now + unblock_time.saturating_duration_since(*now).as_secs(),
+                    rule,
+                }),
+                // there's no rule to remove yet, it's saved once the firewall has added it
+                BlockState::Adding => None,
+            }).collect(),
+            disconnects: self.disconnects.clone(),
+        };
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut temp_file = File::create(&temp_path)?;
+        serde_json::to_writer(&mut temp_file, &state)?;
+        temp_file.write_all(b"\n")?;
+        temp_file.sync_all()?;
+        rename(&temp_path, path)
+    }
+
+    /// picks up where a previous process saved to `path` left off (a missing file is a clean slate), and saves there from now on.
+    ///
+    /// the saved rules may or may not have survived (e.g. a reboot flushes them), so the worker thread removes them all
+    /// first and adds back the blocks that haven't run their course, at most as many as disconnect counts are kept for
+    pub fn persist_to(&mut self, path: PathBuf, now: &Instant) -> io::Result<()> {
+        let state: SavedState = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => SavedState::default(),
+            Err(e) => return Err(e),
+        };
+        let unix_now = unix_seconds(SystemTime::now());
+        for saved in state.blocked.iter().filter(|saved| saved.until > unix_now).take(MAX_TRACKED_ADDRESSES) {
+            self.blocked.insert(saved.ip, (*now + Duration::from_secs(saved.until - unix_now), BlockState::Adding));
+        }
+        let restore = FirewallCommand::Restore {
+            stale: state.blocked.iter().map(|saved| (saved.ip, saved.rule)).collect(),
+            live: self.blocked.keys().copied().collect(),
+        };
+        self.queue(restore).map_err(io::Error::other)?;
+        self.disconnects = state.disconnects.into_iter()
+            .filter(|(ip, _)| !self.blocked.contains_key(ip))
+            .take(MAX_TRACKED_ADDRESSES)
+            .collect();
+        info!("restoring {} blocks and {} disconnect counts from {}", self.blocked.len(), self.disconnects.len(), path.display());
+        self.state_file = Some(path);
+        Ok(())
+    }
+
+    pub fn blocked_count(&self) -> usize {
+        self.blocked.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    use mio::{Poll, Token};
+
+    const BLOCK_DURATION: Duration = Duration::from_secs(600);
+
+    /// records the commands it's given, fails to remove the rules of the addresses in `stuck`, and finds those of the
+    /// addresses in `gone` already removed
+    #[derive(Clone, Default)]
+    struct FakeFirewall {
+        commands: Arc<Mutex<Vec<String>>>,
+        stuck: Arc<Mutex<HashSet<IpAddr>>>,
+        gone: Arc<Mutex<HashSet<IpAddr>>>,
+    }
+
+    impl Display for FakeFirewall {
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+            write!(f, "fake")
+        }
+    }
+
+    impl Firewall for FakeFirewall {
+        fn block(&self, ip: IpAddr) -> io::Result<Rule> {
+            self.commands.lock().unwrap().push(format!("block {}", ip));
+            Ok(Rule::ByAddress)
+        }
+
+        fn unblock(&self, ip: IpAddr, _rule: Rule) -> io::Result<()> {
+            self.commands.lock().unwrap().push(format!("unblock {}", ip));
+            if self.stuck.lock().unwrap().contains(&ip) {
+                return Err(io::Error::other("stuck"));
+            }
+            if self.gone.lock().unwrap().contains(&ip) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "gone"));
+            }
+            Ok(())
+        }
+    }
+
+    impl FakeFirewall {
+        fn take_commands(&self) -> Vec<String> {
+            std::mem::take(&mut *self.commands.lock().unwrap())
+        }
+    }
+
+    /// a blocker on a fake firewall, with the poll its waker needs
+    fn blocker(threshold: u32) -> (Blocker, FakeFirewall, Poll) {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), Token(0)).unwrap());
+        let firewall = FakeFirewall::default();
+        (Blocker::start(firewall.clone(), threshold, BLOCK_DURATION, waker), firewall, poll)
+    }
+
+    /// waits for the worker to finish every command queued so far
+    fn settle(blocker: &mut Blocker, now: &Instant) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while blocker.blocked.values().any(|(_, state)| !matches!(state, BlockState::Active(_))) {
+            assert!(Instant::now() < deadline, "the firewall thread never finished");
+            thread::sleep(Duration::from_millis(1));
+            blocker.handle_results(now);
+        }
+    }
+
+    fn state_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("endlessh-rs-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn addresses_are_blocked_once_they_reach_the_threshold() {
+        let (mut blocker, firewall, _poll) = blocker(3);
+        let now = Instant::now();
+        blocker.record_disconnect(IP, &now);
+        blocker.record_disconnect(IP, &now);
+        blocker.record_disconnect(OTHER_IP, &now);
+        settle(&mut blocker, &now);
+        assert!(firewall.take_commands().is_empty());
+        assert_eq!(blocker.blocked_count(), 0);
+
+        blocker.record_disconnect(IP, &now);
+        assert_eq!(blocker.blocked[&IP].1, BlockState::Adding);
+        settle(&mut blocker, &now);
+        assert_eq!(firewall.take_commands(), ["block 192.0.2.1"]);
+        assert_eq!(blocker.blocked[&IP].1, BlockState::Active(Rule::ByAddress));
+        // blocked addresses aren't counted, or blocked twice
+        blocker.record_disconnect(IP, &now);
+        blocker.block(IP, &now);
+        settle(&mut blocker, &now);
+        assert!(firewall.take_commands().is_empty());
+        assert!(!blocker.disconnects.contains_key(&IP));
+    }
+
+    #[test]
+    fn blocklisted_addresses_are_blocked_on_their_first_disconnect() {
+        let (mut blocker, firewall, _poll) = blocker(5);
+        blocker.set_blocklist(AddressList::parse("192.0.2.0/24").0);
+        let now = Instant::now();
+        blocker.record_disconnect(IP, &now);
+        settle(&mut blocker, &now);
+        assert_eq!(firewall.take_commands(), ["block 192.0.2.1"]);
+    }
+
+    #[test]
+    fn blocks_are_lifted_once_they_run_their_course() {
+        let (mut blocker, firewall, _poll) = blocker(1);
+        let now = Instant::now();
+        blocker.block(IP, &now);
+        settle(&mut blocker, &now);
+        firewall.take_commands();
+
+        blocker.expire(&(now + BLOCK_DURATION - Duration::from_millis(1)));
+        settle(&mut blocker, &now);
+        assert!(firewall.take_commands().is_empty());
+
+        blocker.expire(&(now + BLOCK_DURATION));
+        assert_eq!(blocker.blocked[&IP].1, BlockState::Removing(Rule::ByAddress));
+        let later = now + BLOCK_DURATION;
+        settle(&mut blocker, &later);
+        assert_eq!(firewall.take_commands(), ["unblock 192.0.2.1"]);
+        assert_eq!(blocker.blocked_count(), 0);
+    }
+
+    #[test]
+    fn failed_unblocks_are_tried_again() {
+        let (mut blocker, firewall, _poll) = blocker(1);
+        let now = Instant::now();
+        let later = now + BLOCK_DURATION;
+        blocker.block(IP, &now);
+        settle(&mut blocker, &now);
+        firewall.stuck.lock().unwrap().insert(IP);
+        blocker.expire(&later);
+        settle(&mut blocker, &later);
+        assert_eq!(blocker.blocked[&IP].1, BlockState::Active(Rule::ByAddress));
+
+        firewall.stuck.lock().unwrap().clear();
+        blocker.expire(&later);
+        settle(&mut blocker, &later);
+        assert_eq!(blocker.blocked_count(), 0);
+        assert_eq!(firewall.take_commands(), ["block 192.0.2.1", "unblock 192.0.2.1", "unblock 192.0.2.1"]);
+    }
+
+    #[test]
+    fn blocks_whose_rule_is_gone_are_dropped() {
+        let (mut blocker, firewall, _poll) = blocker(1);
+        let now = Instant::now();
+        let later = now + BLOCK_DURATION;
+        blocker.block(IP, &now);
+        settle(&mut blocker, &now);
+        firewall.gone.lock().unwrap().insert(IP);
+        blocker.expire(&later);
+        settle(&mut blocker, &later);
+        assert_eq!(blocker.blocked_count(), 0);
+    }
+
+    #[test]
+    fn unblocking_all_waits_for_blocks_still_being_added() {
+        let (mut blocker, firewall, _poll) = blocker(1);
+        let now = Instant::now();
+        blocker.block(IP, &now);
+        blocker.block(OTHER_IP, &now);
+        blocker.unblock_all();
+        let mut commands = firewall.take_commands();
+        commands.sort();
+        assert_eq!(commands, ["block 192.0.2.1", "block 192.0.2.2", "unblock 192.0.2.1", "unblock 192.0.2.2"]);
+        assert_eq!(blocker.blocked_count(), 0);
+        // later blocks have nowhere to go
+        blocker.block(IP, &now);
+        assert_eq!(blocker.blocked_count(), 0);
+    }
+
+    #[test]
+    fn saved_blocks_and_counts_are_restored() {
+        let path = state_file("round-trip");
+        let now = Instant::now();
+        let (mut first, _, _poll) = blocker(3);
+        first.persist_to(path.clone(), &now).unwrap();
+        first.block(IP, &now);
+        first.record_disconnect(OTHER_IP, &now);
+        settle(&mut first, &now);
+        first.save(&now);
+
+        let (mut second, firewall, _poll) = blocker(3);
+        second.persist_to(path.clone(), &now).unwrap();
+        settle(&mut second, &now);
+        // the saved rule is removed whether or not it survived, then added again
+        assert_eq!(firewall.take_commands(), ["unblock 192.0.2.1", "block 192.0.2.1"]);
+        let remaining = second.blocked[&IP].0 - now;
+        assert!(remaining <= BLOCK_DURATION && remaining >= BLOCK_DURATION - Duration::from_secs(2), "{:?}", remaining);
+        assert_eq!(second.disconnects, HashMap::from([(OTHER_IP, 1)]));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn expired_saved_blocks_are_removed_but_not_added_again() {
+        let path = state_file("expired");
+        let unix_now = unix_seconds(SystemTime::now());
+        let state = SavedState {
+            blocked: vec![
+                SavedBlock { ip: IP, until: unix_now - 10, rule: Rule::ByAddress },
+                SavedBlock { ip: OTHER_IP, until: unix_now + 100, rule: Rule::ByAddress },
+            ],
+            disconnects: HashMap::from([(IP, 2)]),
+        };
+        std::fs::write(&path, serde_json::to_string(&state).unwrap()).unwrap();
+        let now = Instant::now();
+        let (mut blocker, firewall, _poll) = blocker(3);
+        blocker.persist_to(path.clone(), &now).unwrap();
+        settle(&mut blocker, &now);
+        assert_eq!(firewall.take_commands(), ["unblock 192.0.2.1", "unblock 192.0.2.2", "block 192.0.2.2"]);
+        assert_eq!(blocker.blocked.keys().collect::<Vec<_>>(), [&OTHER_IP]);
+        assert_eq!(blocker.disconnects, HashMap::from([(IP, 2)]));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_missing_state_file_is_a_clean_slate() {
+        let (mut blocker, firewall, _poll) = blocker(3);
+        let now = Instant::now();
+        blocker.persist_to(state_file("missing"), &now).unwrap();
+        settle(&mut blocker, &now);
+        assert!(firewall.take_commands().is_empty());
+        assert_eq!(blocker.blocked_count(), 0);
+    }
+
+    #[test]
+    fn missing_rules_are_told_apart_from_failures() {
+        let missing = [
+            "iptables exited with exit status: 1 - iptables: Bad rule (does a matching rule exist in that chain?).",
+            "nft exited with exit status: 1 - Error: Could not process rule: No such file or directory",
+            "netsh exited with exit code: 1 - No rules match the specified criteria.",
+        ];
+        for message in missing {
+            assert_eq!(missing_rule(io::Error::other(message)).kind(), io::ErrorKind::NotFound, "{}", message);
+        }
+        let failed = [
+            "iptables exited with exit status: 4 - iptables: Resource temporarily unavailable.",
+            "failed to run nft - No such file or directory (os error 2)",
+        ];
+        for message in failed {
+            assert_eq!(missing_rule(io::Error::other(message)).kind(), io::ErrorKind::Other, "{}", message);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn nft_handles_are_read_from_the_echoed_rule() {
+        let output = "add rule inet endlessh_rs input ip saddr 192.0.2.1 drop comment \"endlessh-rs block\" # handle 42\n";
+        assert_eq!(nft_handle(output).unwrap(), 42);
+        assert!(nft_handle("add rule inet endlessh_rs input ip saddr 192.0.2.1 drop\n").is_err());
+        assert!(nft_handle("# handle nope").is_err());
+    }
+}
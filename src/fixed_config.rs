@@ -0,0 +1,145 @@
+//! configuration baked in at build time, for builds with `fixed-config` and without `cli`
+//!
+//! see build.rs - unset variables fall back to the usual defaults
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use endlessh_rs::endlessh::NewLine;
+use endlessh_rs::socket::ListenAddress;
+
+use log::LevelFilter;
+
+use crate::logging::{LogFormat, LogOutput};
+use crate::{Args, DEFAULT_BLOCK_DURATION_SECS, DEFAULT_LOG_ROTATE_KEEP, DEFAULT_REPORT_INTERVAL_SECS, DEFAULT_SSH_BANNER_LINE_LENGTH, DEFAULT_SSH_CHATTY_PENALTY, DEFAULT_SSH_LISTEN_ADDRESS, DEFAULT_SSH_MAX_CLIENTS, DEFAULT_SSH_MESSAGE_DELAY_MS,
+    DEFAULT_SSH_REPEAT_OFFENDER_MAX_FACTOR};
+
+/// a [ListenAddress] that can be a constant, its unix socket path being a plain str
+// only constructed by the generated constants, when an address is baked in
+#[allow(dead_code)]
+enum BakedListenAddress {
+    Ip(SocketAddr),
+    Unix(&'static str),
+}
+
+impl BakedListenAddress {
+    fn to_listen_address(&self) -> ListenAddress {
+        match self {
+            BakedListenAddress::Ip(address) => ListenAddress::Ip(*address),
+            #[cfg(unix)]
+            BakedListenAddress::Unix(path) => ListenAddress::Unix(PathBuf::from(path)),
+            // build.rs only bakes unix paths on unix targets
+            #[cfg(not(unix))]
+            BakedListenAddress::Unix(_) => unreachable!("unix sockets are not supported on this platform"),
+        }
+    }
+}
+
+/// the generated constants, which spell out addresses with whichever of these they need
+#[allow(unused_imports)]
+mod baked {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use super::BakedListenAddress;
+    use endlessh_rs::endlessh::{BannerStyle, ChattyPenalty, MaxStartups};
+    use endlessh_rs::firewall::FirewallBackend;
+    use log::LevelFilter;
+    use crate::logging::{LogFormat, LogOutput};
+    include!(concat!(env!("OUT_DIR"), "/fixed_config.rs"));
+}
+
+pub fn args() -> Args {
+    Args {
+        log_level: baked::LOG_LEVEL.unwrap_or(LevelFilter::Info),
+        log_format: baked::LOG_FORMAT.unwrap_or(LogFormat::Text),
+        log_output: baked::LOG_OUTPUT.unwrap_or(LogOutput::Stdout),
+        log_file: baked::LOG_FILE.map(PathBuf::from),
+        log_rotate_bytes: baked::LOG_ROTATE_BYTES,
+        log_rotate_interval_secs: baked::LOG_ROTATE_INTERVAL_SECS,
+        log_rotate_keep: baked::LOG_ROTATE_KEEP.unwrap_or(DEFAULT_LOG_ROTATE_KEEP),
+        stats_file: baked::STATS_FILE.map(PathBuf::from),
+        farewell_line: baked::FAREWELL_LINE.map(str::to_string),
+        shutdown_linger_secs: baked::SHUTDOWN_LINGER_SECS,
+        event_log: baked::EVENT_LOG.map(PathBuf::from),
+        sensor_id: baked::SENSOR_ID.map(str::to_string),
+        event_log_lines: baked::EVENT_LOG_LINES.unwrap_or(false),
+        bind_retry: baked::BIND_RETRY.unwrap_or(0),
+        wait_for_address: baked::WAIT_FOR_ADDRESS,
+        user: baked::USER.map(str::to_string),
+        group: baked::GROUP.map(str::to_string),
+        #[cfg(feature = "sandbox")]
+        sandbox: baked::SANDBOX.unwrap_or(false),
+        ssh_listen_address: baked::SSH_LISTEN_ADDRESSES.map_or_else(|| vec![ListenAddress::Ip(DEFAULT_SSH_LISTEN_ADDRESS)], |addresses| {
+            addresses.iter().map(BakedListenAddress::to_listen_address).collect()
+        }),
+        ssh_listener_name: baked::SSH_LISTENER_NAMES.unwrap_or_default().iter().map(|name| name.to_string()).collect(),
+        ssh_banner_line_length: baked::SSH_BANNER_LINE_LENGTH.unwrap_or(DEFAULT_SSH_BANNER_LINE_LENGTH),
+        ssh_banner_line_length_min: baked::SSH_BANNER_LINE_LENGTH_MIN,
+        ssh_banner_line_length_max: baked::SSH_BANNER_LINE_LENGTH_MAX,
+        ssh_banner_style: baked::SSH_BANNER_STYLE,
+        ssh_banner_wordlist: baked::SSH_BANNER_WORDLIST.map(PathBuf::from),
+        ssh_banner_template: baked::SSH_BANNER_TEMPLATE.map(str::to_string),
+        ssh_max_clients: baked::SSH_MAX_CLIENTS.unwrap_or(DEFAULT_SSH_MAX_CLIENTS),
+        ssh_max_clients_per_ip: baked::SSH_MAX_CLIENTS_PER_IP,
+        allow_cidr: baked::ALLOW_CIDRS.unwrap_or_default().iter().map(|cidr| cidr.to_string()).collect(),
+        allow_cidr_file: baked::ALLOW_CIDR_FILE.map(PathBuf::from),
+        deny_cidr: baked::DENY_CIDRS.unwrap_or_default().iter().map(|cidr| cidr.to_string()).collect(),
+        deny_cidr_file: baked::DENY_CIDR_FILE.map(PathBuf::from),
+        ssh_message_delay_ms: baked::SSH_MESSAGE_DELAY_MS.unwrap_or(DEFAULT_SSH_MESSAGE_DELAY_MS),
+        ssh_message_delay_jitter_ms: baked::SSH_MESSAGE_DELAY_JITTER_MS,
+        ssh_variant: baked::SSH_VARIANTS.unwrap_or_default().iter().map(|variant| variant.to_string()).collect(),
+        ssh_adaptive_delay_max_ms: baked::SSH_ADAPTIVE_DELAY_MAX_MS,
+        ssh_stall_limit: baked::SSH_STALL_LIMIT,
+        ssh_chatty_bytes: baked::SSH_CHATTY_BYTES,
+        ssh_chatty_penalty: baked::SSH_CHATTY_PENALTY.unwrap_or(DEFAULT_SSH_CHATTY_PENALTY),
+        ssh_repeat_offender_ttl_secs: baked::SSH_REPEAT_OFFENDER_TTL_SECS,
+        ssh_repeat_offender_max_factor: baked::SSH_REPEAT_OFFENDER_MAX_FACTOR.unwrap_or(DEFAULT_SSH_REPEAT_OFFENDER_MAX_FACTOR),
+        ssh_max_startups: baked::SSH_MAX_STARTUPS,
+        ssh_trap_probability: baked::SSH_TRAP_PROBABILITY.unwrap_or(1.0),
+        ssh_newline: baked::SSH_NEWLINE.map_or(NewLine::LF, NewLine::from),
+        rng_seed: baked::RNG_SEED,
+        ssh_accept_rate: baked::SSH_ACCEPT_RATE,
+        ssh_accept_burst: baked::SSH_ACCEPT_BURST,
+        ssh_rcvbuf_bytes: baked::SSH_RCVBUF_BYTES,
+        ssh_sndbuf_bytes: baked::SSH_SNDBUF_BYTES,
+        max_send_bytes_per_sec: baked::MAX_SEND_BYTES_PER_SEC,
+        ssh_zero_window_timeout_secs: baked::SSH_ZERO_WINDOW_TIMEOUT_SECS,
+        ssh_max_trap_seconds: baked::SSH_MAX_TRAP_SECONDS,
+        ssh_dry_run: baked::SSH_DRY_RUN.unwrap_or(false),
+        proxy_protocol: baked::PROXY_PROTOCOL.unwrap_or(false),
+        low_memory: baked::LOW_MEMORY.unwrap_or(false),
+        block_after: baked::BLOCK_AFTER,
+        block_duration_secs: baked::BLOCK_DURATION_SECS.unwrap_or(DEFAULT_BLOCK_DURATION_SECS),
+        block_backend: baked::BLOCK_BACKEND,
+        block_state_file: baked::BLOCK_STATE_FILE.map(PathBuf::from),
+        report_file: baked::REPORT_FILE.map(PathBuf::from),
+        report_interval_secs: baked::REPORT_INTERVAL_SECS.unwrap_or(DEFAULT_REPORT_INTERVAL_SECS),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    /// what the runtime parser makes of the variable build.rs baked in, so the two can't drift apart
+    fn parsed<T: FromStr>(baked: Option<&str>) -> Option<T> where T::Err: std::fmt::Debug {
+        baked.map(|value| value.parse().unwrap())
+    }
+
+    #[test]
+    fn baked_addresses_are_those_the_variable_names() {
+        let expected = option_env!("ENDLESSH_SSH_LISTEN_ADDRESS").map_or_else(|| vec![ListenAddress::Ip(DEFAULT_SSH_LISTEN_ADDRESS)], |addresses| {
+            addresses.split(',').map(|address| address.trim().parse().unwrap()).collect()
+        });
+        assert_eq!(args().ssh_listen_address, expected);
+    }
+
+    #[test]
+    fn baked_settings_parse_as_they_would_at_runtime() {
+        let args = args();
+        assert_eq!(args.stats_file, option_env!("ENDLESSH_STATS_FILE").map(PathBuf::from));
+        assert_eq!(args.ssh_banner_line_length, parsed(option_env!("ENDLESSH_SSH_BANNER_LINE_LENGTH")).unwrap_or(DEFAULT_SSH_BANNER_LINE_LENGTH));
+        assert_eq!(args.ssh_max_clients, parsed(option_env!("ENDLESSH_SSH_MAX_CLIENTS")).unwrap_or(DEFAULT_SSH_MAX_CLIENTS));
+        assert_eq!(args.ssh_message_delay_ms, parsed(option_env!("ENDLESSH_SSH_MESSAGE_DELAY_MS")).unwrap_or(DEFAULT_SSH_MESSAGE_DELAY_MS));
+    }
+}
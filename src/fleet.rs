@@ -0,0 +1,185 @@
+//! combining the event logs of several sensors for `endlessh-rs aggregate`, following each file as its sensor appends to
+//! it, so a small fleet can be watched from one place without any log shipping stack beyond copying the files over
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use crate::endlessh::{is_valid_listener_name, ConnectionEvent, ConnectionEventKind};
+
+/// what one sensor has reported so far
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SensorTotals {
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+    pub bytes_sent: u64,
+    pub trapped_seconds: f64,
+    /// when its latest event happened, in seconds since the unix epoch, to tell a quiet sensor from a dead one
+    pub last_event_seconds: f64,
+}
+
+/// an event log being followed from where it was last read
+struct FollowedLog {
+    path: PathBuf,
+    /// the sensor its events are counted under when they don't name one, the file's name without its extension
+    sensor: String,
+    reader: Option<BufReader<File>>,
+    /// how far into the file has been read, to notice it being truncated
+    position: u64,
+    /// the start of a line still being written
+    partial: String,
+    /// to notice the file being replaced, e.g. by log rotation
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FollowedLog {
+
+    fn new(path: PathBuf) -> Self {
+        let sensor = path.file_stem().map(|stem| stem.to_string_lossy().into_owned())
+            .filter(|stem| is_valid_listener_name(stem))
+            .unwrap_or_else(|| "unknown".to_string());
+        FollowedLog {
+            path,
+            sensor,
+            reader: None,
+            position: 0,
+            partial: String::new(),
+            #[cfg(unix)]
+            inode: 0,
+        }
+    }
+
+    /// (re)opens the file from the start, leaving it closed if it isn't there (yet)
+    fn open(&mut self) {
+        self.reader = None;
+        self.position = 0;
+        self.partial.clear();
+        match File::open(&self.path) {
+            Ok(file) => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    self.inode = file.metadata().map_or(0, |metadata| metadata.ino());
+                }
+                self.reader = Some(BufReader::new(file));
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => warn!("failed to open event log {} - {}", self.path.display(), e),
+        }
+    }
+
+    /// whether the file at the path is no longer the one being read
+    fn replaced(&self) -> bool {
+        let Ok(metadata) = std::fs::metadata(&self.path) else {
+            return false;
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if metadata.ino() != self.inode {
+                return true;
+            }
+        }
+        metadata.len() < self.position
+    }
+
+    /// the lines appended since the last read, counting those that aren't events in `invalid`
+    fn read_events(&mut self, events: &mut Vec<(String, ConnectionEvent)>, invalid: &mut usize) {
+        if self.reader.is_none() {
+            self.open();
+        }
+        let Some(reader) = self.reader.as_mut() else {
+            return;
+        };
+        let mut line = std::mem::take(&mut self.partial);
+        loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(n) => self.position += n as u64,
+                Err(e) => {
+                    warn!("failed to read event log {} - {}", self.path.display(), e);
+                    break;
+                },
+            }
+            if !line.ends_with('\n') {
+                break;
+            }
+            if !line.trim().is_empty() {
+                match serde_json::from_str::<ConnectionEvent>(&line) {
+                    Ok(event) => {
+                        let sensor = event.sensor.clone().filter(|sensor| is_valid_listener_name(sensor)).unwrap_or_else(|| self.sensor.clone());
+                        events.push((sensor, event));
+                    },
+                    Err(_) => *invalid += 1,
+                }
+            }
+            line.clear();
+        }
+        self.partial = line;
+    }
+}
+
+/// follows every sensor's event log, handing out the events appended since the last poll
+pub struct Fleet {
+    logs: Vec<FollowedLog>,
+    sensors: BTreeMap<String, SensorTotals>,
+}
+
+impl Fleet {
+
+    pub fn new(paths: &[PathBuf]) -> Self {
+        Fleet {
+            logs: paths.iter().cloned().map(FollowedLog::new).collect(),
+            sensors: BTreeMap::new(),
+        }
+    }
+
+    /// the events every log has gained since the last poll, each with the sensor it's from set, after counting them
+    /// towards their sensor's totals. a log that was truncated or replaced is read again from the start
+    pub fn poll(&mut self) -> Vec<ConnectionEvent> {
+        let mut events = Vec::new();
+        let mut invalid = 0;
+        for log in &mut self.logs {
+            log.read_events(&mut events, &mut invalid);
+            if log.reader.is_some() && log.replaced() {
+                info!("event log {} was rotated, following the new file", log.path.display());
+                log.open();
+                log.read_events(&mut events, &mut invalid);
+            }
+        }
+        if invalid > 0 {
+            warn!("skipped {} lines of the sensors' event logs that aren't connection events", invalid);
+        }
+        events.into_iter().map(|(sensor, mut event)| {
+            self.count(&sensor, &event);
+            event.sensor = Some(sensor);
+            event
+        }).collect()
+    }
+
+    fn count(&mut self, sensor: &str, event: &ConnectionEvent) {
+        let totals = match self.sensors.get_mut(sensor) {
+            Some(totals) => totals,
+            None => self.sensors.entry(sensor.to_string()).or_default(),
+        };
+        match event.event {
+            ConnectionEventKind::Accepted => totals.connections_opened += 1,
+            ConnectionEventKind::Closed => {
+                totals.connections_closed += 1;
+                totals.bytes_sent += event.bytes_sent as u64;
+                totals.trapped_seconds += event.trapped_seconds;
+            },
+            ConnectionEventKind::LineSent | ConnectionEventKind::Banned => {},
+        }
+        totals.last_event_seconds = totals.last_event_seconds.max(event.time_seconds);
+    }
+
+    /// every sensor seen so far, by name
+    pub fn sensors(&self) -> &BTreeMap<String, SensorTotals> {
+        &self.sensors
+    }
+}
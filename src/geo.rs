@@ -0,0 +1,163 @@
+//! which country and network (autonomous system) an address is announced from, for ranking them in reports
+//!
+//! there's no geoip data in the tree, the table is read from a file like iptoasn.com's `ip2asn-combined.tsv` - one range
+//! per line as `<first address>\t<last address>\t<asn>\t<country>\t<network name>`. ranges are kept sorted, so a lookup
+//! is a binary search however big the table is
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// what an address was found to belong to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Network<'a> {
+    pub asn: u32,
+    /// the two letter code the network is registered under, if the table has one
+    pub country: Option<&'a str>,
+    pub name: &'a str,
+}
+
+#[derive(Debug)]
+struct NetworkRange {
+    /// inclusive, with ipv4 addresses mapped into ipv6 so both kinds sort together
+    first: u128,
+    last: u128,
+    asn: u32,
+    country: Option<[u8; 2]>,
+}
+
+#[derive(Debug, Default)]
+pub struct NetworkTable {
+    /// sorted by first address, and not overlapping
+    ranges: Vec<NetworkRange>,
+    names: HashMap<u32, Box<str>>,
+}
+
+impl NetworkTable {
+
+    /// reads the table, skipping blank lines, `#` comments and ranges that aren't routed (asn 0).
+    /// returns the table and how many lines couldn't be parsed, including ranges overlapping an earlier one
+    pub fn parse(text: &str) -> (Self, usize) {
+        let mut table = NetworkTable::default();
+        let mut invalid = 0;
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_range(line) {
+                Some((range, _)) if range.asn == 0 => {},
+                Some((range, name)) => {
+                    table.names.entry(range.asn).or_insert_with(|| name.into());
+                    table.ranges.push(range);
+                },
+                None => invalid += 1,
+            }
+        }
+        table.ranges.sort_unstable_by_key(|range| range.first);
+        let before = table.ranges.len();
+        let mut last = None;
+        table.ranges.retain(|range| {
+            let keep = last.is_none_or(|last| range.first > last);
+            if keep {
+                last = Some(range.last);
+            }
+            keep
+        });
+        let overlapping = before - table.ranges.len();
+        (table, invalid + overlapping)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Network<'_>> {
+        let ip = key(ip);
+        let range = &self.ranges[..self.ranges.partition_point(|range| range.first <= ip)].last()?;
+        (ip <= range.last).then(|| Network {
+            asn: range.asn,
+            country: range.country.as_ref().and_then(|code| std::str::from_utf8(code).ok()),
+            name: self.name(range.asn).unwrap_or_default(),
+        })
+    }
+
+    /// the name the table gives the network
+    pub fn name(&self, asn: u32) -> Option<&str> {
+        self.names.get(&asn).map(|name| &**name)
+    }
+
+    /// the number of routed ranges
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn parse_range(line: &str) -> Option<(NetworkRange, &str)> {
+    let mut fields = line.split('\t');
+    let first: IpAddr = fields.next()?.trim().parse().ok()?;
+    let last: IpAddr = fields.next()?.trim().parse().ok()?;
+    if first.is_ipv4() != last.is_ipv4() {
+        return None;
+    }
+    let asn = fields.next()?.trim().parse().ok()?;
+    // "None" (or anything else that isn't a code) where the registry has no country
+    let country = match fields.next()?.trim().as_bytes() {
+        &[a, b] if a.is_ascii_uppercase() && b.is_ascii_uppercase() => Some([a, b]),
+        _ => None,
+    };
+    // the name ends up in report emails, so nothing that could break a line
+    let name = fields.next().unwrap_or_default().trim();
+    if name.chars().any(char::is_control) {
+        return None;
+    }
+    let (first, last) = (key(first), key(last));
+    (first <= last).then_some((NetworkRange { first, last, asn, country }, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "\
+# range_start\trange_end\tAS_number\tcountry_code\tAS_description
+1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET
+1.0.1.0\t1.0.3.255\t0\tNone\tNot routed
+1.0.4.0\t1.0.7.255\t38803\tAU\tGTELECOM-AUSTRALIA Gtelecom Pty Ltd
+
+2001:200::\t2001:200:e00:ffff:ffff:ffff:ffff:ffff\t2500\tJP\tWIDE-BB WIDE Project
+2001:db8::\t2001:db8::ffff\t64496\tNone\tDOCUMENTATION
+not\tan\taddress\tUS\tBROKEN
+1.0.5.0\t1.0.5.255\t64497\tAU\tOVERLAPPING
+1.0.9.0\t::1\t64498\tZZ\tMIXED
+";
+
+    #[test]
+    fn addresses_are_found_in_the_range_holding_them() {
+        let (table, invalid) = NetworkTable::parse(TABLE);
+        assert_eq!(invalid, 3);
+        assert_eq!(table.len(), 4);
+        let cloudflare = Some(Network { asn: 13335, country: Some("US"), name: "CLOUDFLARENET" });
+        assert_eq!(table.lookup("1.0.0.0".parse().unwrap()), cloudflare);
+        assert_eq!(table.lookup("1.0.0.255".parse().unwrap()), cloudflare);
+        // dual stack listeners see ipv4 peers as ::ffff:a.b.c.d
+        assert_eq!(table.lookup("::ffff:1.0.0.1".parse().unwrap()), cloudflare);
+        assert_eq!(table.lookup("1.0.5.1".parse().unwrap()).map(|network| network.asn), Some(38803));
+        assert_eq!(table.lookup("2001:200:e00::1".parse().unwrap()).map(|network| network.country), Some(Some("JP")));
+        assert_eq!(table.lookup("2001:db8::1".parse().unwrap()), Some(Network { asn: 64496, country: None, name: "DOCUMENTATION" }));
+    }
+
+    #[test]
+    fn addresses_outside_every_routed_range_are_not_found() {
+        let (table, _) = NetworkTable::parse(TABLE);
+        for ip in ["0.255.255.255", "1.0.2.1", "1.0.8.0", "2001:db8::1:0", "::1", "255.255.255.255"] {
+            assert_eq!(table.lookup(ip.parse().unwrap()), None, "{}", ip);
+        }
+        assert_eq!(NetworkTable::default().lookup("1.0.0.1".parse().unwrap()), None);
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use crate::endlessh::EndlesshServer;
+use crate::process::resident_memory_bytes;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCondition {
+    /// accepting connections failed since the last check (e.g. out of file descriptors)
+    AcceptErrors,
+    /// every client slot is taken - busy, but working as intended
+    AtCapacity,
+    /// resident memory is above the configured limit
+    MemoryPressure,
+    /// an exporter failed the last time it ran
+    ExporterFailing,
+    /// a metrics listener broke and hasn't been bound again
+    MetricsListenerDown,
+    /// the event loop woke up late since the last check, so clients were sent their lines late
+    SchedulingLag,
+    /// there's no ssh listener to accept on, e.g. they were all removed through the admin api
+    NoSshListeners,
+    /// new connections are left in the backlog, while resident memory is high or shutting down
+    NotAccepting,
+}
+
+#[derive(Clone, Serialize)]
+struct HealthReason {
+    condition: HealthCondition,
+    detail: String,
+}
+
+/// how punctual the event loop has been
+#[derive(Clone, Copy, Default, Serialize)]
+struct Watchdog {
+    late_wakeups: usize,
+    /// the latest a wakeup was in the last check's interval
+    worst_wakeup_lateness_ms: u64,
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    status: &'static str,
+    reasons: Vec<HealthReason>,
+}
+
+#[derive(Serialize)]
+struct LivenessReport {
+    status: &'static str,
+    watchdog: Watchdog,
+}
+
+/// tracks the conditions that make a tarpit busy or degraded, checked periodically and reported by /readyz
+pub struct Health {
+    max_resident_memory_bytes: Option<u64>,
+    last_accept_errors: usize,
+    failing_exporters: BTreeSet<&'static str>,
+    /// each down metrics listener, and why
+    down_listeners: BTreeMap<String, String>,
+    reasons: Vec<HealthReason>,
+    watchdog: Watchdog,
+}
+
+impl Health {
+
+    pub fn new(max_resident_memory_bytes: Option<u64>) -> Self {
+        Health {
+            max_resident_memory_bytes,
+            last_accept_errors: 0,
+            failing_exporters: BTreeSet::new(),
+            down_listeners: BTreeMap::new(),
+            reasons: Vec::new(),
+            watchdog: Watchdog::default(),
+        }
+    }
+
+    pub fn record_exporter_result(&mut self, exporter: &'static str, succeeded: bool) {
+        if succeeded {
+            self.failing_exporters.remove(exporter);
+        } else {
+            self.failing_exporters.insert(exporter);
+        }
+    }
+
+    /// None for a listener that's up
+    pub fn record_listener_result(&mut self, listener: &str, failure: Option<&str>) {
+        match failure {
+            Some(failure) => {
+                self.down_listeners.insert(listener.to_string(), failure.to_string());
+            },
+            None => {
+                self.down_listeners.remove(listener);
+            },
+        }
+    }
+
+    /// re-checks every condition, replacing the previous reasons
+    pub fn evaluate(&mut self, endlessh_server: &EndlesshServer) {
+        self.reasons.clear();
+
+        let accept_errors = endlessh_server.stats().accept_errors.load(Ordering::Relaxed);
+        if accept_errors > self.last_accept_errors {
+            self.reasons.push(HealthReason {
+                condition: HealthCondition::AcceptErrors,
+                detail: format!("{} accept errors since the last check", accept_errors - self.last_accept_errors),
+            });
+        }
+        self.last_accept_errors = accept_errors;
+
+        let late_wakeups = endlessh_server.stats().late_wakeups.load(Ordering::Relaxed);
+        let worst_lateness = endlessh_server.stats().take_worst_wakeup_lateness();
+        if late_wakeups > self.watchdog.late_wakeups {
+            self.reasons.push(HealthReason {
+                condition: HealthCondition::SchedulingLag,
+                detail: format!("{} late wakeups since the last check, the worst {}ms late", late_wakeups - self.watchdog.late_wakeups,
+                    worst_lateness.as_millis()),
+            });
+        }
+        self.watchdog = Watchdog {
+            late_wakeups,
+            worst_wakeup_lateness_ms: worst_lateness.as_millis() as u64,
+        };
+
+        if let (Some(limit), Some(resident)) = (self.max_resident_memory_bytes, resident_memory_bytes()) {
+            if resident > limit {
+                self.reasons.push(HealthReason {
+                    condition: HealthCondition::MemoryPressure,
+                    detail: format!("resident memory is {} bytes, the limit is {}", resident, limit),
+                });
+            }
+        }
+
+        for exporter in &self.failing_exporters {
+            self.reasons.push(HealthReason {
+                condition: HealthCondition::ExporterFailing,
+                detail: format!("the {} exporter failed", exporter),
+            });
+        }
+
+        for (listener, failure) in &self.down_listeners {
+            self.reasons.push(HealthReason {
+                condition: HealthCondition::MetricsListenerDown,
+                detail: format!("metrics listener {} is down - {}", listener, failure),
+            });
+        }
+    }
+
+    /// the json served at /healthz - answering at all is the liveness signal, a busy or degraded tarpit is for /readyz
+    /// to report, so a liveness probe doesn't restart it
+    pub fn liveness_json(&self) -> String {
+        serde_json::to_string(&LivenessReport {
+            status: "alive",
+            watchdog: self.watchdog,
+        }).expect("failed to serialize liveness")
+    }
+
+}
+
+/// whether the tarpit can take new clients right now, and the json reasons why not, for /readyz - along with whatever
+/// the last [Health::evaluate] found, if there's a `health` being kept
+///
+/// the listeners, slots and accepting are checked afresh on every request, so a probe sees a slot free up straight away
+pub fn readiness(endlessh_server: &EndlesshServer, health: Option<&Health>) -> (bool, String) {
+    let mut reasons: Vec<HealthReason> = health.map(|health| health.reasons.clone()).unwrap_or_default();
+    if endlessh_server.listeners().next().is_none() {
+        reasons.push(HealthReason {
+            condition: HealthCondition::NoSshListeners,
+            detail: "no ssh listener is bound".to_string(),
+        });
+    }
+    let max_clients = endlessh_server.options().max_clients;
+    if endlessh_server.client_count() >= max_clients {
+        reasons.push(HealthReason {
+            condition: HealthCondition::AtCapacity,
+            detail: format!("all {} client slots are in use", max_clients),
+        });
+    }
+    if !endlessh_server.is_accepting() {
+        reasons.push(HealthReason {
+            condition: HealthCondition::NotAccepting,
+            detail: "new connections are paused".to_string(),
+        });
+    }
+    let report = ReadinessReport {
+        status: if reasons.is_empty() { "ready" } else { "not_ready" },
+        reasons,
+    };
+    (report.reasons.is_empty(), serde_json::to_string(&report).expect("failed to serialize readiness"))
+}
+
+#[cfg(test)]
+mod tests {
+    use mio::net::TcpListener;
+    use mio::{Poll, Token};
+
+    use super::*;
+    use crate::endlessh::EndlesshOptions;
+
+    fn server(poll: &Poll) -> EndlesshServer {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        EndlesshServer::create(EndlesshOptions::default(), listener, None, Token(0), poll).unwrap()
+    }
+
+    #[test]
+    fn degradations_fail_readiness_but_not_liveness() {
+        let poll = Poll::new().unwrap();
+        let server = server(&poll);
+        let mut health = Health::new(None);
+        health.evaluate(&server);
+        assert!(readiness(&server, Some(&health)).0);
+
+        health.record_exporter_result("textfile", false);
+        health.record_listener_result("ip:127.0.0.1:9000", Some("gone"));
+        health.evaluate(&server);
+        let (ready, body) = readiness(&server, Some(&health));
+        assert!(!ready);
+        assert!(body.contains("\"exporter_failing\"") && body.contains("\"metrics_listener_down\""), "unexpected readiness {}", body);
+        assert!(health.liveness_json().starts_with(r#"{"status":"alive","#), "unexpected liveness {}", health.liveness_json());
+
+        health.record_exporter_result("textfile", true);
+        health.record_listener_result("ip:127.0.0.1:9000", None);
+        health.evaluate(&server);
+        assert!(readiness(&server, Some(&health)).0);
+    }
+
+    #[test]
+    fn late_wakeups_and_accept_errors_count_against_one_check() {
+        let poll = Poll::new().unwrap();
+        let server = server(&poll);
+        let mut health = Health::new(None);
+        server.stats().late_wakeups.fetch_add(2, Ordering::Relaxed);
+        server.stats().accept_errors.fetch_add(1, Ordering::Relaxed);
+        health.evaluate(&server);
+        let (ready, body) = readiness(&server, Some(&health));
+        assert!(!ready);
+        assert!(body.contains("2 late wakeups since the last check") && body.contains("1 accept errors since the last check"), "unexpected readiness {}", body);
+        let liveness: serde_json::Value = serde_json::from_str(&health.liveness_json()).unwrap();
+        assert_eq!(liveness["watchdog"]["late_wakeups"], 2);
+
+        // only what's new since the last check counts
+        health.evaluate(&server);
+        assert!(readiness(&server, Some(&health)).0);
+        server.stats().late_wakeups.fetch_add(1, Ordering::Relaxed);
+        health.evaluate(&server);
+        assert!(readiness(&server, Some(&health)).1.contains("\"scheduling_lag\""));
+    }
+}
+
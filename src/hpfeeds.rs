@@ -0,0 +1,286 @@
+//! publishes connection events to an hpfeeds broker, the protocol most honeypot networks collect their sensors' data with
+//!
+//! the broker connection lives on a thread of its own, fed over a bounded channel - while the broker is slow or down
+//! events are dropped (and counted) rather than queued without limit or stalling the event loop
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+
+use crate::endlessh::ConnectionEvent;
+use crate::error::{self, EndlesshError};
+
+const OP_ERROR: u8 = 0;
+const OP_INFO: u8 = 1;
+const OP_AUTH: u8 = 2;
+const OP_PUBLISH: u8 = 3;
+/// a 4 byte length and the opcode
+const HEADER_LENGTH: usize = 5;
+/// brokers only send us short info and error messages
+const MAX_RECEIVED_MESSAGE_LENGTH: usize = 64 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long the broker gets to reject our credentials, it says nothing when it accepts them
+const AUTH_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// events waiting for the broker, past this they're dropped
+const QUEUE_LENGTH: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct HpfeedsOptions {
+    /// host:port
+    pub broker: String,
+    pub ident: String,
+    pub secret: String,
+    pub channel: String,
+}
+
+/// the event loop's end of the publisher thread
+pub struct HpfeedsPublisher {
+    sender: SyncSender<ConnectionEvent>,
+    /// since the queue last had room
+    dropped: u64,
+}
+
+impl HpfeedsPublisher {
+
+    /// starts the thread that connects to the broker (retrying with backoff) and publishes what it's sent
+    pub fn start(options: HpfeedsOptions) -> error::Result<Self> {
+        // each is sent with a one byte length
+        for (what, value) in [("ident", &options.ident), ("channel", &options.channel)] {
+            if value.is_empty() || value.len() > u8::MAX as usize {
+                return Err(EndlesshError::Config(format!("the hpfeeds {} must be 1 to 255 bytes", what)));
+            }
+        }
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        thread::spawn(move || run(options, receiver));
+        Ok(HpfeedsPublisher {
+            sender,
+            dropped: 0,
+        })
+    }
+
+    pub fn publish(&mut self, event: &ConnectionEvent) {
+        match self.sender.try_send(event.clone()) {
+            Ok(()) if self.dropped > 0 => {
+                warn!("dropped {} events while the hpfeeds broker wasn't keeping up", self.dropped);
+                self.dropped = 0;
+            },
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            _ => {},
+        }
+    }
+}
+
+fn run(options: HpfeedsOptions, events: Receiver<ConnectionEvent>) {
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    loop {
+        let mut stream = match connect(&options) {
+            Ok(stream) => {
+                info!("publishing connection events to hpfeeds channel {} on {}", options.channel, options.broker);
+                delay = RECONNECT_INITIAL_DELAY;
+                stream
+            },
+            Err(e) => {
+                warn!("{}, retrying in {:?}", e, delay);
+                thread::sleep(delay);
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                continue;
+            },
+        };
+        loop {
+            // the event loop is gone, so are we
+            let Ok(event) = events.recv() else {
+                return;
+            };
+            let payload = serde_json::to_vec(&event).expect("failed to serialize connection event");
+            if let Err(e) = stream.write_all(&publish_message(&options, &payload)) {
+                warn!("lost the hpfeeds broker {} - {}, reconnecting", options.broker, e);
+                break;
+            }
+        }
+    }
+}
+
+/// connects and authenticates
+fn connect(options: &HpfeedsOptions) -> error::Result<TcpStream> {
+    let fail = |reason: String| EndlesshError::Hpfeeds {
+        broker: options.broker.clone(),
+        reason,
+    };
+    let address = options.broker.to_socket_addrs()
+        .map_err(|e| fail(e.to_string()))?
+        .next()
+        .ok_or_else(|| fail("no address found".to_string()))?;
+    let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).map_err(|e| fail(e.to_string()))?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT)).map_err(|e| fail(e.to_string()))?;
+
+    let (opcode, payload) = read_message(&mut stream).map_err(|e| fail(e.to_string()))?;
+    let nonce = match opcode {
+        OP_INFO => {
+            // the broker's name, then the nonce to sign
+            let name_length = *payload.first().ok_or_else(|| fail("empty info message".to_string()))? as usize;
+            payload.get(1 + name_length..).ok_or_else(|| fail("truncated info message".to_string()))?
+        },
+        OP_ERROR => return Err(fail(String::from_utf8_lossy(&payload).into_owned())),
+        opcode => return Err(fail(format!("expected an info message, got opcode {}", opcode))),
+    };
+    let signature = digest(&SHA1_FOR_LEGACY_USE_ONLY, &[nonce, options.secret.as_bytes()].concat());
+    let mut auth = Vec::new();
+    push_string(&mut auth, &options.ident);
+    auth.extend_from_slice(signature.as_ref());
+    stream.write_all(&message(OP_AUTH, &auth)).map_err(|e| fail(e.to_string()))?;
+
+    // a rejection comes back as an error message, silence means we're in
+    stream.set_read_timeout(Some(AUTH_REPLY_TIMEOUT)).map_err(|e| fail(e.to_string()))?;
+    match read_message(&mut stream) {
+        Ok((OP_ERROR, reason)) => Err(fail(format!("rejected {} - {}", options.ident, String::from_utf8_lossy(&reason)))),
+        Ok((opcode, _)) => Err(fail(format!("unexpected opcode {} after authenticating", opcode))),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(stream),
+        Err(e) => Err(fail(e.to_string())),
+    }
+}
+
+fn read_message(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; HEADER_LENGTH];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if !(HEADER_LENGTH..=MAX_RECEIVED_MESSAGE_LENGTH).contains(&length) {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("bad message length {}", length)));
+    }
+    let mut payload = vec![0; length - HEADER_LENGTH];
+    stream.read_exact(&mut payload)?;
+    Ok((header[4], payload))
+}
+
+fn message(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let length = (HEADER_LENGTH + payload.len()) as u32;
+    let mut message = Vec::with_capacity(length as usize);
+    message.extend_from_slice(&length.to_be_bytes());
+    message.push(opcode);
+    message.extend_from_slice(payload);
+    message
+}
+
+/// a string with a one byte length, checked to fit by [HpfeedsPublisher::start]
+fn push_string(buffer: &mut Vec<u8>, s: &str) {
+    buffer.push(s.len() as u8);
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn publish_message(options: &HpfeedsOptions, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + options.ident.len() + options.channel.len() + payload.len());
+    push_string(&mut body, &options.ident);
+    push_string(&mut body, &options.channel);
+    body.extend_from_slice(payload);
+    message(OP_PUBLISH, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc::Sender;
+
+    use super::*;
+
+    const NONCE: &[u8] = b"n0nce";
+
+    fn options(broker: SocketAddr) -> HpfeedsOptions {
+        HpfeedsOptions { broker: broker.to_string(), ident: "sensor".to_string(), secret: "s3cret".to_string(), channel: "endlessh.events".to_string() }
+    }
+
+    /// a broker that takes one client, answering its authentication with `reply` if given, and passing on the messages it
+    /// gets after that
+    fn broker(reply: Option<Vec<u8>>) -> (SocketAddr, Receiver<(u8, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || serve(listener, reply, sender));
+        (address, receiver)
+    }
+
+    fn serve(listener: TcpListener, reply: Option<Vec<u8>>, received: Sender<(u8, Vec<u8>)>) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut info = Vec::new();
+        push_string(&mut info, "test-broker");
+        info.extend_from_slice(NONCE);
+        stream.write_all(&message(OP_INFO, &info)).unwrap();
+        if let Some(reply) = reply {
+            read_message(&mut stream).unwrap();
+            let _ = stream.write_all(&reply);
+            return;
+        }
+        while let Ok(message) = read_message(&mut stream) {
+            if received.send(message).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// the one byte length string at the start of `buffer`, and the rest
+    fn take_string(buffer: &[u8]) -> (&str, &[u8]) {
+        let length = buffer[0] as usize;
+        (std::str::from_utf8(&buffer[1..1 + length]).unwrap(), &buffer[1 + length..])
+    }
+
+    #[test]
+    fn events_are_published_after_signing_the_brokers_nonce() {
+        let (address, received) = broker(None);
+        let mut publisher = HpfeedsPublisher::start(options(address)).unwrap();
+        let (opcode, auth) = received.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(opcode, OP_AUTH);
+        let (ident, signature) = take_string(&auth);
+        assert_eq!(ident, "sensor");
+        assert_eq!(signature, digest(&SHA1_FOR_LEGACY_USE_ONLY, b"n0nces3cret").as_ref());
+
+        let event: ConnectionEvent = serde_json::from_str(
+            r#"{"event":"accepted","time_seconds":1.0,"peer":"192.0.2.1:22","bytes_sent":0,"trapped_seconds":0.0}"#).unwrap();
+        publisher.publish(&event);
+        let (opcode, publish) = received.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(opcode, OP_PUBLISH);
+        let (ident, rest) = take_string(&publish);
+        let (channel, payload) = take_string(rest);
+        assert_eq!((ident, channel), ("sensor", "endlessh.events"));
+        let published: serde_json::Value = serde_json::from_slice(payload).unwrap();
+        assert_eq!(published["peer"], "192.0.2.1:22");
+    }
+
+    #[test]
+    fn brokers_rejecting_the_credentials_are_an_error() {
+        let (address, _received) = broker(Some(message(OP_ERROR, b"bad ident")));
+        let error = connect(&options(address)).unwrap_err().to_string();
+        assert!(error.contains("rejected sensor - bad ident"), "{}", error);
+
+        let (address, _received) = broker(Some(message(OP_PUBLISH, b"")));
+        assert!(connect(&options(address)).unwrap_err().to_string().contains("unexpected opcode 3"));
+    }
+
+    #[test]
+    fn idents_and_channels_must_fit_a_one_byte_length() {
+        let broker: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        for options in [
+            HpfeedsOptions { ident: String::new(), ..options(broker) },
+            HpfeedsOptions { channel: "c".repeat(256), ..options(broker) },
+        ] {
+            assert!(HpfeedsPublisher::start(options).is_err());
+        }
+        assert!(HpfeedsPublisher::start(HpfeedsOptions { channel: "c".repeat(255), ..options(broker) }).is_ok());
+    }
+
+    #[test]
+    fn oversized_or_undersized_messages_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(&(MAX_RECEIVED_MESSAGE_LENGTH as u32 + 1).to_be_bytes()).unwrap();
+        client.write_all(&[OP_INFO]).unwrap();
+        assert_eq!(read_message(&mut server).unwrap_err().kind(), ErrorKind::InvalidData);
+        client.write_all(&[0, 0, 0, 4, OP_INFO]).unwrap();
+        assert_eq!(read_message(&mut server).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
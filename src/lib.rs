@@ -0,0 +1,42 @@
+//! endlessh-rs as a library - the tarpit core, plus the optional metrics http server and its exporters
+//!
+//! everything is driven by a single mio [Poll](mio::Poll) owned by the embedder
+
+pub mod address_list;
+#[cfg(feature = "elasticsearch")]
+pub mod elasticsearch;
+pub mod endlessh;
+pub mod error;
+pub mod event_log;
+pub mod exposition;
+pub mod firewall;
+pub mod geo;
+pub mod notify;
+pub mod offenders;
+pub mod pacing;
+pub mod policy;
+pub mod process;
+pub mod proxy_protocol;
+pub mod report;
+pub mod scheduler;
+pub mod socket;
+pub mod store;
+
+#[cfg(feature = "metrics")]
+pub mod fleet;
+#[cfg(feature = "metrics")]
+pub mod health;
+#[cfg(feature = "hpfeeds")]
+pub mod hpfeeds;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "remote-lists")]
+pub mod remote_list;
+#[cfg(feature = "metrics")]
+pub mod textfile;
+#[cfg(feature = "metrics")]
+pub mod timeseries;
+#[cfg(feature = "metrics-tls")]
+pub mod tls;
+
+pub use error::{EndlesshError, Result};
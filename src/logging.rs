@@ -0,0 +1,636 @@
+//! the logger behind the `log` macros used throughout the crate
+//!
+//! filtering is left to `log::max_level`, so the level can be changed at runtime (see the admin api). dependencies
+//! (e.g. the http client) are only heard from at warn and above, their debug output drowns out ours
+//!
+//! log lines can carry structured fields (`debug!(peer:% = peer, bytes = n; "...")`), which are appended as `key=value`
+//! in text logs and syslog messages, and become keys of their own in json logs and journal entries (`PEER=...`)
+
+use std::fmt::{Display, Formatter};
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::kv::{self, Key, Value, VisitSource, VisitValue};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// how each log line is written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `LEVEL message key=value ...`, for reading
+    Text,
+    /// one json object per line, for journald, loki and friends
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format \"{}\", expected text or json", v)),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// where log lines go, unless there's a log file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    Stdout,
+    /// the local syslog daemon's /dev/log
+    #[cfg(unix)]
+    Syslog,
+    /// the systemd journal's native socket, with structured fields
+    #[cfg(target_os = "linux")]
+    Journald,
+}
+
+impl FromStr for LogOutput {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        match v {
+            "stdout" => Ok(LogOutput::Stdout),
+            #[cfg(unix)]
+            "syslog" => Ok(LogOutput::Syslog),
+            #[cfg(target_os = "linux")]
+            "journald" => Ok(LogOutput::Journald),
+            _ => Err(format!("unknown log output \"{}\", this platform supports: {}", v, LogOutput::SUPPORTED.join(", "))),
+        }
+    }
+}
+
+impl Display for LogOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            LogOutput::Stdout => write!(f, "stdout"),
+            #[cfg(unix)]
+            LogOutput::Syslog => write!(f, "syslog"),
+            #[cfg(target_os = "linux")]
+            LogOutput::Journald => write!(f, "journald"),
+        }
+    }
+}
+
+impl LogOutput {
+    const SUPPORTED: &'static [&'static str] = &[
+        "stdout",
+        #[cfg(unix)]
+        "syslog",
+        #[cfg(target_os = "linux")]
+        "journald",
+    ];
+}
+
+/// where to log instead of stdout, and when to start a new file
+pub struct LogFileOptions {
+    pub path: PathBuf,
+    /// rotate once the file reaches this size
+    pub rotate_bytes: Option<u64>,
+    /// rotate once the file has been written to for this long
+    pub rotate_interval: Option<Duration>,
+    /// how many rotated files (`<path>.1` is the newest) to keep
+    pub keep: usize,
+}
+
+struct LogFile {
+    options: LogFileOptions,
+    file: File,
+    written: u64,
+    opened_time: Instant,
+}
+
+impl LogFile {
+
+    fn open(options: LogFileOptions) -> io::Result<Self> {
+        let file = Self::append_to(&options.path)?;
+        Ok(LogFile {
+            written: file.metadata()?.len(),
+            file,
+            opened_time: Instant::now(),
+            options,
+        })
+    }
+
+    fn append_to(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// picks up a new file at the same path, e.g. after logrotate moved the old one away
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = Self::append_to(&self.options.path)?;
+        self.written = self.file.metadata()?.len();
+        self.opened_time = Instant::now();
+        Ok(())
+    }
+
+    fn rotated_path(&self, generation: usize) -> PathBuf {
+        let mut path = self.options.path.clone().into_os_string();
+        path.push(format!(".{}", generation));
+        PathBuf::from(path)
+    }
+
+    /// shifts `<path>.N` to `<path>.N+1`, dropping the oldest, then starts a fresh `<path>`
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.options.keep == 0 {
+            File::create(&self.options.path)?;
+        } else {
+            for generation in (1..self.options.keep).rev() {
+                let _ = rename(self.rotated_path(generation), self.rotated_path(generation + 1));
+            }
+            rename(&self.options.path, self.rotated_path(1))?;
+        }
+        self.reopen()
+    }
+
+    fn needs_rotation(&self) -> bool {
+        self.options.rotate_bytes.is_some_and(|max| self.written >= max)
+            || self.options.rotate_interval.is_some_and(|interval| self.opened_time.elapsed() >= interval)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(target_os = "linux")]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// what syslog and the journal know us by
+#[cfg(unix)]
+const SYSLOG_IDENTIFIER: &str = "endlessh-rs";
+/// the daemon facility, in the high bits of a syslog priority
+#[cfg(unix)]
+const SYSLOG_FACILITY_DAEMON: u8 = 3 << 3;
+
+/// a datagram socket to a local log daemon, reconnected if the daemon restarts
+#[cfg(unix)]
+struct LogSocket {
+    path: &'static str,
+    socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl LogSocket {
+
+    fn connect(path: &'static str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(LogSocket {
+            path,
+            socket,
+        })
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        if self.socket.send(message).is_ok() {
+            return Ok(());
+        }
+        *self = Self::connect(self.path)?;
+        self.socket.send(message).map(|_| ())
+    }
+}
+
+enum Output {
+    Stdout,
+    File(LogFile),
+    #[cfg(unix)]
+    Syslog(LogSocket),
+    #[cfg(target_os = "linux")]
+    Journald(LogSocket),
+}
+
+struct Logger {
+    format: LogFormat,
+    output: Mutex<Output>,
+}
+
+/// appends a record's fields to a text line, leaving out empty ones (e.g. the listener of a client on an unnamed one)
+struct TextFields<'a>(&'a mut String);
+
+/// whether a field was given as None, which every format leaves out like an empty string
+fn is_null(value: &Value) -> bool {
+    struct Null(bool);
+    impl VisitValue<'_> for Null {
+        fn visit_any(&mut self, _: Value) -> Result<(), kv::Error> {
+            Ok(())
+        }
+
+        fn visit_null(&mut self) -> Result<(), kv::Error> {
+            self.0 = true;
+            Ok(())
+        }
+    }
+    let mut null = Null(false);
+    let _ = value.visit(&mut null);
+    null.0
+}
+
+impl<'kvs> VisitSource<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        if is_null(&value) {
+            return Ok(());
+        }
+        let value = value.to_string();
+        if value.is_empty() {
+            return Ok(());
+        }
+        if value.contains(|c: char| c.is_whitespace() || c == '"') {
+            self.0.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            self.0.push_str(&format!(" {}={}", key, value));
+        }
+        Ok(())
+    }
+}
+
+/// adds a record's fields to a json line, keeping numbers and booleans as such
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        if is_null(&value) {
+            return Ok(());
+        }
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else if let Some(n) = value.to_f64() {
+            n.into()
+        } else {
+            let text = value.to_string();
+            if text.is_empty() {
+                return Ok(());
+            }
+            text.into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// adds a record's fields to a journal entry as upper case keys, e.g. `PEER=...`
+#[cfg(target_os = "linux")]
+struct JournalFields<'a>(&'a mut Vec<u8>);
+
+#[cfg(target_os = "linux")]
+impl<'kvs> VisitSource<'kvs> for JournalFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        if is_null(&value) {
+            return Ok(());
+        }
+        let value = value.to_string();
+        if !value.is_empty() {
+            let key: String = key.as_str().chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+            push_journal_field(self.0, key.trim_start_matches('_'), &value);
+        }
+        Ok(())
+    }
+}
+
+/// `KEY=value\n`, or for values with newlines the binary form: the key, a newline, the little endian length and the value
+#[cfg(target_os = "linux")]
+fn push_journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// the syslog severity of a level, which is also the journal's PRIORITY
+#[cfg(unix)]
+fn syslog_severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+impl Logger {
+    /// `<priority>identifier[pid]: message key=value ...`, the daemon adds the time and host
+    #[cfg(unix)]
+    fn syslog_message(record: &Record) -> String {
+        let mut message = format!("<{}>{}[{}]: {}", SYSLOG_FACILITY_DAEMON | syslog_severity(record.level()), SYSLOG_IDENTIFIER,
+            std::process::id(), record.args());
+        let _ = record.key_values().visit(&mut TextFields(&mut message));
+        message
+    }
+
+    #[cfg(target_os = "linux")]
+    fn journal_entry(record: &Record) -> Vec<u8> {
+        let mut entry = Vec::new();
+        push_journal_field(&mut entry, "PRIORITY", &syslog_severity(record.level()).to_string());
+        push_journal_field(&mut entry, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+        push_journal_field(&mut entry, "TARGET", record.target());
+        push_journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+        let _ = record.key_values().visit(&mut JournalFields(&mut entry));
+        entry
+    }
+
+    fn format_line(&self, record: &Record) -> String {
+        let mut line = match self.format {
+            LogFormat::Text => {
+                let mut line = format!("{:<5} {}", record.level(), record.args());
+                let _ = record.key_values().visit(&mut TextFields(&mut line));
+                line
+            },
+            LogFormat::Json => {
+                let mut object = serde_json::Map::new();
+                object.insert("level".to_string(), record.level().as_str().into());
+                object.insert("target".to_string(), record.target().into());
+                object.insert("message".to_string(), record.args().to_string().into());
+                let _ = record.key_values().visit(&mut JsonFields(&mut object));
+                serde_json::Value::Object(object).to_string()
+            },
+        };
+        line.push('\n');
+        line
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (metadata.level() <= Level::Warn || metadata.target().starts_with(env!("CARGO_CRATE_NAME")))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut output = self.output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &mut *output {
+            // a closed stdout isn't worth dying over
+            Output::Stdout => {
+                let _ = std::io::stdout().lock().write_all(self.format_line(record).as_bytes());
+            },
+            Output::File(file) => {
+                let line = self.format_line(record);
+                if let Err(e) = file.write_line(&line) {
+                    eprintln!("failed to write to log file {} - {}: {}", file.options.path.display(), e, line.trim_end());
+                }
+            },
+            #[cfg(unix)]
+            Output::Syslog(socket) => {
+                let message = Self::syslog_message(record);
+                if let Err(e) = socket.send(message.as_bytes()) {
+                    eprintln!("failed to log to {} - {}: {}", socket.path, e, message);
+                }
+            },
+            #[cfg(target_os = "linux")]
+            Output::Journald(socket) => {
+                if let Err(e) = socket.send(&Self::journal_entry(record)) {
+                    eprintln!("failed to log to {} - {}: {}", socket.path, e, record.args());
+                }
+            },
+        }
+    }
+
+    fn flush(&self) {
+        let mut output = self.output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = match &mut *output {
+            Output::Stdout => std::io::stdout().flush(),
+            Output::File(file) => file.file.flush(),
+            #[cfg(unix)]
+            Output::Syslog(_) => Ok(()),
+            #[cfg(target_os = "linux")]
+            Output::Journald(_) => Ok(()),
+        };
+    }
+}
+
+/// logs to `file` if given, otherwise to `output`. `format` only applies to stdout and files
+pub fn init(level: LevelFilter, format: LogFormat, output: LogOutput, file: Option<LogFileOptions>) -> io::Result<()> {
+    let output = match (file, output) {
+        (Some(options), _) => Output::File(LogFile::open(options)?),
+        (None, LogOutput::Stdout) => Output::Stdout,
+        #[cfg(unix)]
+        (None, LogOutput::Syslog) => Output::Syslog(LogSocket::connect(SYSLOG_SOCKET)?),
+        #[cfg(target_os = "linux")]
+        (None, LogOutput::Journald) => Output::Journald(LogSocket::connect(JOURNALD_SOCKET)?),
+    };
+    let logger = LOGGER.get_or_init(|| Logger { format, output: Mutex::new(output) });
+    log::set_logger(logger).expect("logger already initialized");
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// reopens the log file, if there is one - for SIGHUP after an external rotation
+#[cfg_attr(not(unix), allow(dead_code))]
+pub fn reopen() -> io::Result<()> {
+    let Some(logger) = LOGGER.get() else {
+        return Ok(());
+    };
+    let mut output = logger.output.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match &mut *output {
+        Output::File(file) => file.reopen(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger(format: LogFormat) -> Logger {
+        Logger { format, output: Mutex::new(Output::Stdout) }
+    }
+
+    /// a fresh directory for a test's log files
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("endlessh-rs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn log_files_are_rotated_at_the_size_keeping_the_newest() {
+        let dir = log_dir("rotate");
+        let path = dir.join("endlessh.log");
+        let mut file = LogFile::open(LogFileOptions { path: path.clone(), rotate_bytes: Some(10), rotate_interval: None, keep: 2 }).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n", "fifth\n"] {
+            file.write_line(line).unwrap();
+        }
+        // a line that takes the file past the size still goes in whole, the next starts a new file
+        assert_eq!(read(dir.join("endlessh.log")), "fifth\n");
+        assert_eq!(read(dir.join("endlessh.log.1")), "third\nfourth\n");
+        assert_eq!(read(dir.join("endlessh.log.2")), "first\nsecond\n");
+        assert!(!dir.join("endlessh.log.3").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_files_kept_for_none_are_truncated_instead() {
+        let dir = log_dir("truncate");
+        let path = dir.join("endlessh.log");
+        std::fs::write(&path, "from before\n").unwrap();
+        let mut file = LogFile::open(LogFileOptions { path: path.clone(), rotate_bytes: Some(12), rotate_interval: None, keep: 0 }).unwrap();
+        file.write_line("new\n").unwrap();
+        assert_eq!(read(path), "new\n");
+        assert!(!dir.join("endlessh.log.1").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn log_files_are_rotated_after_the_interval() {
+        let dir = log_dir("interval");
+        let path = dir.join("endlessh.log");
+        let mut file = LogFile::open(LogFileOptions { path: path.clone(), rotate_bytes: None, rotate_interval: Some(Duration::from_millis(50)), keep: 1 }).unwrap();
+        file.write_line("old\n").unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        file.write_line("new\n").unwrap();
+        assert_eq!((read(path), read(dir.join("endlessh.log.1"))), ("new\n".to_string(), "old\n".to_string()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reopened_log_files_pick_up_a_new_file_at_the_path() {
+        let dir = log_dir("reopen");
+        let path = dir.join("endlessh.log");
+        let mut file = LogFile::open(LogFileOptions { path: path.clone(), rotate_bytes: Some(10), rotate_interval: None, keep: 1 }).unwrap();
+        file.write_line("before\n").unwrap();
+        // as logrotate would
+        rename(&path, dir.join("moved.log")).unwrap();
+        file.reopen().unwrap();
+        file.write_line("after\n").unwrap();
+        assert_eq!((read(path), read(dir.join("moved.log"))), ("after\n".to_string(), "before\n".to_string()));
+        // and counts the new file's size towards the next rotation
+        assert_eq!(file.written, 6);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dependencies_are_only_heard_from_at_warn_and_above() {
+        let logger = logger(LogFormat::Text);
+        log::set_max_level(LevelFilter::Debug);
+        let enabled = |level, target| logger.enabled(&Metadata::builder().level(level).target(target).build());
+        assert!(enabled(Level::Debug, "endlessh_rs::endlessh"));
+        assert!(!enabled(Level::Trace, "endlessh_rs::endlessh"));
+        assert!(!enabled(Level::Info, "ureq::pool"));
+        assert!(enabled(Level::Warn, "ureq::pool"));
+    }
+
+    /// formats a "client left" line with a few typical fields
+    fn client_left(logger: &Logger) -> String {
+        with_client_left(|record| logger.format_line(record))
+    }
+
+    fn with_client_left<T>(f: impl FnOnce(&Record) -> T) -> T {
+        let fields = [
+            ("peer", Value::from("192.0.2.1:22")),
+            ("listener", Value::from("")),
+            ("trapped_seconds", Value::from(1.5)),
+            ("bytes", Value::from(33u64)),
+            ("chatty", Value::from(false)),
+            ("variant", Value::null()),
+            ("banner", Value::from("two words")),
+        ];
+        f(&Record::builder()
+            .args(format_args!("client left"))
+            .level(Level::Debug)
+            .target("endlessh_rs::endlessh")
+            .key_values(&fields)
+            .build())
+    }
+
+    #[test]
+    fn text_lines_append_the_fields_that_have_values() {
+        assert_eq!(client_left(&logger(LogFormat::Text)),
+            "DEBUG client left peer=192.0.2.1:22 trapped_seconds=1.5 bytes=33 chatty=false banner=\"two words\"\n");
+    }
+
+    #[test]
+    fn json_lines_keep_numbers_and_booleans_as_such() {
+        let line = client_left(&logger(LogFormat::Json));
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+        let object: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(object, serde_json::json!({
+            "level": "DEBUG",
+            "target": "endlessh_rs::endlessh",
+            "message": "client left",
+            "peer": "192.0.2.1:22",
+            "trapped_seconds": 1.5,
+            "bytes": 33,
+            "chatty": false,
+            "banner": "two words",
+        }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn syslog_messages_carry_the_daemon_facility_and_severity() {
+        assert_eq!(with_client_left(Logger::syslog_message), format!(
+            "<31>endlessh-rs[{}]: client left peer=192.0.2.1:22 trapped_seconds=1.5 bytes=33 chatty=false banner=\"two words\"",
+            std::process::id()));
+        let warning = Logger::syslog_message(&Record::builder().args(format_args!("full")).level(Level::Warn).build());
+        assert!(warning.starts_with("<28>endlessh-rs["), "{}", warning);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn journal_entries_hold_each_field_on_its_own_line() {
+        let entry = String::from_utf8(with_client_left(Logger::journal_entry)).unwrap();
+        assert_eq!(entry, "PRIORITY=7\nSYSLOG_IDENTIFIER=endlessh-rs\nTARGET=endlessh_rs::endlessh\nMESSAGE=client left\n\
+            PEER=192.0.2.1:22\nTRAPPED_SECONDS=1.5\nBYTES=33\nCHATTY=false\nBANNER=two words\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn journal_values_with_newlines_are_length_prefixed() {
+        let mut entry = Vec::new();
+        push_journal_field(&mut entry, "MESSAGE", "two\nlines");
+        assert_eq!(entry, b"MESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n");
+    }
+
+    #[test]
+    fn log_formats_parse_by_name() {
+        for format in [LogFormat::Text, LogFormat::Json] {
+            assert_eq!(format.to_string().parse(), Ok(format));
+        }
+        assert!("yaml".parse::<LogFormat>().is_err());
+        assert_eq!("stdout".parse(), Ok(LogOutput::Stdout));
+        for output in LogOutput::SUPPORTED {
+            assert_eq!(output.parse::<LogOutput>().unwrap().to_string(), *output);
+        }
+        assert!("stderr".parse::<LogOutput>().unwrap_err().contains("stdout"));
+    }
+}
@@ -3,58 +3,199 @@
  * an implementation of endlessh in rust
  */
 
-mod endlessh;
+#[cfg(feature = "cli")]
+mod config_file;
+mod crash;
+#[cfg(feature = "metrics")]
+mod ctl;
+#[cfg(not(any(feature = "cli", feature = "fixed-config")))]
+mod env_args;
+mod exit;
+mod logging;
+#[cfg(all(feature = "fixed-config", not(feature = "cli")))]
+mod fixed_config;
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+mod sandbox;
+mod shutdown;
+#[cfg(target_os = "linux")]
+mod systemd;
 
-use std::io::ErrorKind::Interrupted;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::path::PathBuf;
+use std::io::{self, ErrorKind::Interrupted};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::{Path, PathBuf};
 
 use std::time::{Duration, Instant};
-use mio::net::TcpListener;
 use mio::{Events, Poll, Token};
-use clap::Parser;
+#[cfg(feature = "cli")]
+use clap::{CommandFactory, FromArgMatches, Parser};
+
+use endlessh_rs::address_list::AddressList;
+use endlessh_rs::offenders::RepeatOffenders;
+use endlessh_rs::endlessh::{self, BannerGenerator, BannerStyle, BannerTemplate, ChattyPenalty, ConnectionEventKind, EndlesshOptions, EndlesshServer, MaxStartups, NewLine,
+    StrategyVariant};
+use endlessh_rs::event_log::EventLog;
+use endlessh_rs::firewall::{Blocker, FirewallBackend};
+use endlessh_rs::process;
+use endlessh_rs::report::{ReportOptions, Reporter};
+#[cfg(feature = "notify")]
+use endlessh_rs::notify::{NotifyOptions, WebhookNotifier};
+use log::{info, warn, LevelFilter};
+use endlessh_rs::scheduler::Scheduler;
+use endlessh_rs::socket::{ListenAddress, ListenSocket, UNIX_PEER};
+use endlessh_rs::EndlesshError;
+#[cfg(feature = "metrics-tls")]
+use endlessh_rs::tls;
+use exit::{fatal, FatalError};
+use shutdown::Shutdown;
+use logging::{LogFormat, LogOutput};
+
+#[cfg(feature = "remote-lists")]
+use {
+    endlessh_rs::remote_list::{ListKind, ListRefresher, ListUpdate, RemoteList},
+    std::sync::mpsc,
+};
+#[cfg(feature = "report")]
+use endlessh_rs::{geo::NetworkTable, report::{EmailReport, Webhook}};
+use mio::Waker;
+use std::sync::Arc;
 
-use endlessh::{EndlesshOptions, EndlesshServer};
+#[cfg(feature = "elasticsearch")]
+use endlessh_rs::elasticsearch::{ElasticsearchExporter, ElasticsearchOptions};
+#[cfg(feature = "hpfeeds")]
+use endlessh_rs::hpfeeds::{HpfeedsOptions, HpfeedsPublisher};
 
 #[cfg(unix)]
 use {
-    mio::net::UnixListener,
-    std::fs::remove_file,
+    signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1},
+    signal_hook_mio::v1_0::Signals,
 };
 
-const SSH_SERVER_TOKEN: Token = Token(0);
-#[cfg(feature = "metrics")]
-mod metrics;
+#[cfg(all(feature = "metrics", unix))]
+use mio::net::UnixListener;
+#[cfg(unix)]
+use std::fs::remove_file;
+
+/// kept well away from the listener and client tokens, which count up from 0
+#[cfg(unix)]
+const SIGNAL_TOKEN: Token = Token(usize::MAX - 1);
+/// woken by the remote list threads when they have a new list, and the firewall thread when a rule has changed - a poll
+/// can only have the one waker
+const WAKE_TOKEN: Token = Token(usize::MAX - 2);
 #[cfg(feature = "metrics")]
-use metrics::MetricServer;
+use {
+    endlessh_rs::{
+        health::{self, Health},
+        endlessh::{ConnectionEvent, HistogramBuckets, VariantTotals},
+        event_log::{read_events, EventReplay},
+        exposition::{Exposition, MetricType},
+        fleet::{Fleet, SensorTotals},
+        metrics::{AdminError, MetricLabel, MetricLabels, MetricServer, MetricServerOptions, MetricSource},
+        store::{self, ConnectionStore, HistoryQuery, MemoryStore, NdjsonStore},
+        textfile::TextfileWriter,
+        timeseries::TimeSeries,
+    },
+    mio::net::TcpListener,
+    std::cell::RefCell,
+    std::collections::BTreeMap,
+};
+/// trapped clients are registered with the poll from here up to the ssh listeners, tokens being reused as clients leave
+const SSH_CLIENT_TOKEN_START: usize = usize::MAX / 4;
+/// ssh listeners count up from here, clear of the metric tokens below - those from the command line first, then any
+/// added through the admin api
+const SSH_LISTENER_TOKEN_START: usize = usize::MAX / 2;
+/// each metric server takes one token for its listener followed by one per client
 #[cfg(feature = "metrics")]
-const METRIC_SERVER_TOKEN: Token = Token(1);
+const METRIC_TOKEN_START: usize = 1;
+
+/// size of `sockaddr_un.sun_path`, including the trailing NUL
 #[cfg(feature = "metrics")]
-const METRIC_CLIENT_TOKEN_START: usize = 2;
+const UNIX_SOCKET_PATH_MAX: usize = if cfg!(target_os = "linux") { 108 } else { 104 };
 
 #[cfg(feature = "metrics")]
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 enum MultiListener {
     Ip(SocketAddr),
     Unix(PathBuf),
+    /// a listening socket passed in already bound, e.g. by systemd
+    Fd(PassedFd),
     Disabled,
 }
 
+/// a passed in socket, by descriptor number or by its systemd FileDescriptorName=
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum PassedFd {
+    Number(i32),
+    Name(String),
+}
+
 #[cfg(feature = "metrics")]
-impl From<&str> for MultiListener {
-    fn from(v: &str) -> MultiListener {
+impl std::str::FromStr for MultiListener {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<MultiListener, Self::Err> {
         if v == "disabled" {
-            MultiListener::Disabled
-        } else if v.starts_with("ip:") {
-            let to_parse: &str = &v[3..];
+            Ok(MultiListener::Disabled)
+        } else if let Some(to_parse) = v.strip_prefix("ip:") {
             match to_parse.parse::<SocketAddr>() {
-                Ok(s) =>  MultiListener::Ip(s),
-                Err(e) => panic!("bad ip address - {}", e),
+                Ok(s) => Ok(MultiListener::Ip(s)),
+                Err(e) => Err(format!("bad ip address - {}", e)),
+            }
+        } else if let Some(path) = v.strip_prefix("unix:") {
+            if path.len() >= UNIX_SOCKET_PATH_MAX {
+                return Err(format!("unix socket path \"{}\" is {} bytes long, the limit on this platform is {}", path, path.len(), UNIX_SOCKET_PATH_MAX - 1));
+            }
+            Ok(MultiListener::Unix(PathBuf::from(path)))
+        } else if let Some(fd) = v.strip_prefix("fd:") {
+            match fd.parse::<i32>() {
+                Ok(number) if number < 3 => Err(format!("descriptor {} is stdin, stdout or stderr", number)),
+                Ok(number) => Ok(MultiListener::Fd(PassedFd::Number(number))),
+                Err(_) if endlessh::is_valid_listener_name(fd) => Ok(MultiListener::Fd(PassedFd::Name(fd.to_string()))),
+                Err(_) => Err(format!("\"{}\" is neither a descriptor number nor a FileDescriptorName=", fd)),
+            }
+        } else {
+            Err("listener must be of the form \"disabled|ip:<socketaddr>|unix:<socketpath>|fd:<number or name>\"".to_string())
+        }
+    }
+}
+
+/// where the connection events behind /recent are kept
+#[cfg(feature = "metrics")]
+#[derive(Clone, PartialEq, Debug)]
+enum HistoryStore {
+    Memory,
+    Ndjson(PathBuf),
+    Sqlite(PathBuf),
+}
+
+#[cfg(feature = "metrics")]
+impl std::str::FromStr for HistoryStore {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<HistoryStore, Self::Err> {
+        if v == "memory" {
+            Ok(HistoryStore::Memory)
+        } else if let Some(path) = v.strip_prefix("ndjson:").filter(|path| !path.is_empty()) {
+            Ok(HistoryStore::Ndjson(PathBuf::from(path)))
+        } else if let Some(path) = v.strip_prefix("sqlite:").filter(|path| !path.is_empty()) {
+            if cfg!(feature = "sqlite") {
+                Ok(HistoryStore::Sqlite(PathBuf::from(path)))
+            } else {
+                Err("this build doesn't include the sqlite feature".to_string())
             }
-        } else if v.starts_with("unix:") {
-            MultiListener::Unix(PathBuf::from(&v[5..]))
         } else {
-            panic!("listener must be of the form \"disabled|ip:<socketaddr>|unix:<socketpath>\"")
+            Err("history store must be of the form \"memory|ndjson:<path>|sqlite:<path>\"".to_string())
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl std::fmt::Display for HistoryStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            HistoryStore::Memory => write!(f, "memory"),
+            HistoryStore::Ndjson(path) => write!(f, "ndjson:{}", path.display()),
+            HistoryStore::Sqlite(path) => write!(f, "sqlite:{}", path.display()),
         }
     }
 }
@@ -62,14 +203,20 @@ impl From<&str> for MultiListener {
 #[cfg(feature = "metrics")]
 impl std::fmt::Display for MultiListener {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        match &self {
-            &MultiListener::Ip(i) => {
+        match self {
+            MultiListener::Ip(i) => {
                 write!(f, "ip:{}", i)
             },
-            &MultiListener::Unix(p) => { 
+            MultiListener::Unix(p) => { 
                 write!(f, "unix:{}", p.display())
             },
-            &MultiListener::Disabled => {
+            MultiListener::Fd(PassedFd::Number(number)) => {
+                write!(f, "fd:{}", number)
+            },
+            MultiListener::Fd(PassedFd::Name(name)) => {
+                write!(f, "fd:{}", name)
+            },
+            MultiListener::Disabled => {
                 write!(f, "disabled")
             },
         }?;
@@ -78,106 +225,2319 @@ impl std::fmt::Display for MultiListener {
 }
 
 
-#[derive(Parser,Clone,Debug)]
-#[command(version, about, long_about = None)]
+const DEFAULT_SSH_LISTEN_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 2222));
+const DEFAULT_SSH_BANNER_LINE_LENGTH: usize = 32;
+const DEFAULT_SSH_MAX_CLIENTS: usize = 4096;
+const DEFAULT_SSH_MESSAGE_DELAY_MS: u64 = 10_000;
+const DEFAULT_SSH_CHATTY_PENALTY: ChattyPenalty = ChattyPenalty::Slow(4);
+const DEFAULT_SSH_REPEAT_OFFENDER_MAX_FACTOR: u32 = 8;
+const DEFAULT_BLOCK_DURATION_SECS: u64 = 3600;
+const DEFAULT_REPORT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+#[cfg(feature = "report")]
+const DEFAULT_SENDMAIL: &str = "/usr/sbin/sendmail";
+#[cfg(feature = "notify")]
+const DEFAULT_NOTIFY_DEDUP_SECS: u64 = 60 * 60;
+const DEFAULT_LOG_ROTATE_KEEP: usize = 5;
+/// ssh lines are at most 255 bytes including the newline
+const MAX_FAREWELL_LINE_LENGTH: usize = 253;
+/// an ephemeral loopback port, so a replay doesn't trap anyone for real
+const REPLAY_SSH_LISTEN_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+/// for any missing parent directories of unix ssh sockets
+#[cfg(unix)]
+const SSH_SOCKET_DIR_MODE: u32 = 0o755;
+const BIND_RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const BIND_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+const WAIT_FOR_ADDRESS_INTERVAL: Duration = Duration::from_millis(500);
+const BLOCK_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const BLOCK_STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+#[cfg(feature = "metrics")]
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+/// how often `endlessh-rs aggregate` looks for events the sensors have appended
+#[cfg(feature = "metrics")]
+const FLEET_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// resident memory `--low-memory` aims to stay under - new clients wait in the listen backlog while it's exceeded
+const LOW_MEMORY_RSS_TARGET_BYTES: u64 = 24 * 1024 * 1024;
+const LOW_MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const LOW_MEMORY_EVENTS_CAPACITY: usize = 16;
+#[cfg(feature = "metrics")]
+const LOW_MEMORY_METRICS_MAX_CLIENTS: usize = 1;
+#[cfg(feature = "metrics")]
+const LOW_MEMORY_METRICS_REQUEST_MAX_BYTES: usize = 1024;
+#[cfg(feature = "metrics")]
+const LOW_MEMORY_METRICS_MAX_HEADERS: usize = 16;
+#[cfg(feature = "metrics")]
+const LOW_MEMORY_TIMESERIES_LENGTH: usize = 60;
+
+/// without the `cli` feature only the fields outside `metrics` exist, and they're read from the environment (or baked in
+/// with `fixed-config`) instead
+#[cfg_attr(feature = "cli", derive(Parser))]
+#[cfg_attr(feature = "cli", command(version, about, long_about = None))]
+#[derive(Clone,Debug)]
 struct Args {
-    #[arg(long, default_value_t=SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 2222)))]
-    ssh_listen_address: SocketAddr,
-    #[arg(long, default_value_t=32)]
+    #[cfg(feature = "metrics")]
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// read any of these options from a toml file, e.g. `ssh_max_clients = 100` - flags given here take precedence
+    #[cfg(feature = "cli")]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=LevelFilter::Info))]
+    log_level: LevelFilter,
+    /// "text", or "json" for one object per line with the structured fields (peer, token, bytes, ...) as keys
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=LogFormat::Text))]
+    log_format: LogFormat,
+    /// "stdout", "syslog" (/dev/log) or "journald" (with the structured fields as journal fields)
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=LogOutput::Stdout))]
+    log_output: LogOutput,
+    /// log to this file instead of stdout, reopened on SIGHUP so logrotate works
+    #[cfg_attr(feature = "cli", arg(long))]
+    log_file: Option<PathBuf>,
+    /// start a new log file once the current one reaches this size
+    #[cfg_attr(feature = "cli", arg(long, requires = "log_file"))]
+    log_rotate_bytes: Option<u64>,
+    /// start a new log file once the current one is this old
+    #[cfg_attr(feature = "cli", arg(long, requires = "log_file"))]
+    log_rotate_interval_secs: Option<u64>,
+    /// how many rotated log files to keep
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_LOG_ROTATE_KEEP))]
+    log_rotate_keep: usize,
+    /// append the final stats to this file on shutdown, or if the process crashes along with the reason
+    #[cfg_attr(feature = "cli", arg(long))]
+    stats_file: Option<PathBuf>,
+    /// on SIGTERM or SIGINT, send each trapped client this line before hanging up - e.g. a fake sshd error
+    #[cfg_attr(feature = "cli", arg(long))]
+    farewell_line: Option<String>,
+    /// on SIGTERM or SIGINT, stop accepting but keep the trapped clients for up to this long before hanging up. a
+    /// second signal hangs up straight away
+    #[cfg_attr(feature = "cli", arg(long, value_name = "SECS"))]
+    shutdown_linger_secs: Option<u64>,
+    /// append every connection event to this file as a line of json, for `endlessh-rs replay`
+    #[cfg_attr(feature = "cli", arg(long))]
+    event_log: Option<PathBuf>,
+    /// name this instance in every connection event and exported metric, to tell the sensors of a fleet apart
+    #[cfg_attr(feature = "cli", arg(long, value_name = "ID"))]
+    sensor_id: Option<String>,
+    /// add an event to the event log for every line sent, not just for clients arriving and leaving
+    #[cfg_attr(feature = "cli", arg(long, requires = "event_log"))]
+    event_log_lines: bool,
+    /// retry binding listeners this many times (with backoff) while their address is in use or not yet available
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=0))]
+    bind_retry: u32,
+    /// wait up to this many seconds for the listen addresses to appear on an interface (e.g. from DHCP or a VPN) before binding
+    #[cfg_attr(feature = "cli", arg(long, value_name = "TIMEOUT_SECS"))]
+    wait_for_address: Option<u64>,
+    /// once the listeners are bound, switch to this user (a name or uid) for good, e.g. to bind port 22 as root without
+    /// staying root. linux only
+    #[cfg_attr(feature = "cli", arg(long))]
+    user: Option<String>,
+    /// the group (a name or gid) to switch to along with --user, instead of the user's primary group. on its own, only
+    /// the group changes. linux only
+    #[cfg_attr(feature = "cli", arg(long))]
+    group: Option<String>,
+    /// once started, only allow the event loop the system calls it needs (a seccomp filter), exiting if it makes any
+    /// other. linux only
+    #[cfg(feature = "sandbox")]
+    #[cfg_attr(feature = "cli", arg(long))]
+    sandbox: bool,
+    /// <ip>:<port> or unix:<path>, may be given more than once to trap clients on several addresses, all sharing the same client slots
+    #[cfg_attr(feature = "cli", arg(long, default_values_t=[ListenAddress::Ip(DEFAULT_SSH_LISTEN_ADDRESS)]))]
+    ssh_listen_address: Vec<ListenAddress>,
+    /// a name for the listener (e.g. edge-22), added to the logs, events and metrics of the clients it traps. given more
+    /// than once, the nth name goes to the nth --ssh-listen-address
+    #[cfg_attr(feature = "cli", arg(long))]
+    ssh_listener_name: Vec<String>,
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_SSH_BANNER_LINE_LENGTH))]
     ssh_banner_line_length: usize,
-    #[arg(long, default_value_t=4096)]
+    /// give each banner line a random length between these two instead of --ssh-banner-line-length
+    #[cfg_attr(feature = "cli", arg(long, requires = "ssh_banner_line_length_max"))]
+    ssh_banner_line_length_min: Option<usize>,
+    #[cfg_attr(feature = "cli", arg(long, requires = "ssh_banner_line_length_min"))]
+    ssh_banner_line_length_max: Option<usize>,
+    /// what banner lines are made of: alphanumeric, hex, words (from --ssh-banner-wordlist) or template (--ssh-banner-template).
+    /// defaults to template or words if either is given, otherwise alphanumeric
+    #[cfg_attr(feature = "cli", arg(long, value_name = "STYLE"))]
+    ssh_banner_style: Option<BannerStyle>,
+    /// the words of the words style, and of a template's {word} (whitespace separated). re-read on SIGHUP, and by the
+    /// admin api's reload
+    #[cfg_attr(feature = "cli", arg(long, value_name = "PATH"))]
+    ssh_banner_wordlist: Option<PathBuf>,
+    /// a line with placeholders filled in afresh for every line - {alnum:N}, {hex:N}, {digits:N} and {word}
+    #[cfg_attr(feature = "cli", arg(long, value_name = "TEMPLATE"))]
+    ssh_banner_template: Option<String>,
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_SSH_MAX_CLIENTS))]
     ssh_max_clients: usize,
-    #[arg(long, default_value_t=10_000)]
+    /// trap at most this many clients from one address at a time, hanging up on the rest
+    #[cfg_attr(feature = "cli", arg(long, value_name = "CLIENTS"))]
+    ssh_max_clients_per_ip: Option<usize>,
+    /// only trap addresses in this cidr block (or address), hanging up on the rest. may be given more than once
+    #[cfg_attr(feature = "cli", arg(long, value_name = "CIDR"))]
+    allow_cidr: Vec<String>,
+    /// more --allow-cidr blocks, one per line, re-read on SIGHUP
+    #[cfg_attr(feature = "cli", arg(long, value_name = "PATH"))]
+    allow_cidr_file: Option<PathBuf>,
+    /// never trap addresses in this cidr block (or address), hanging up on them, even if --allow-cidr has them. may be
+    /// given more than once
+    #[cfg_attr(feature = "cli", arg(long, value_name = "CIDR"))]
+    deny_cidr: Vec<String>,
+    /// more --deny-cidr blocks, one per line, re-read on SIGHUP
+    #[cfg_attr(feature = "cli", arg(long, value_name = "PATH"))]
+    deny_cidr_file: Option<PathBuf>,
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_SSH_MESSAGE_DELAY_MS))]
     ssh_message_delay_ms: u64,
+    /// move each line's delay by a random amount of up to this either way, so the drip isn't perfectly periodic
+    #[cfg_attr(feature = "cli", arg(long))]
+    ssh_message_delay_jitter_ms: Option<u64>,
+    /// trap a share of the clients differently to compare strategies, as <name>=<weight> followed by any of
+    /// ,delay-ms=<ms> ,jitter-ms=<ms> ,style=<style> ,line-length=<bytes or min-max> - the rest are as configured. given
+    /// more than once, each client gets one of the variants picked by weight
+    #[cfg_attr(feature = "cli", arg(long, value_name = "VARIANT"))]
+    ssh_variant: Vec<String>,
+    /// lengthen the delay up to this as the client slots fill up, shortening it back to --ssh-message-delay-ms as they empty
+    #[cfg_attr(feature = "cli", arg(long))]
+    ssh_adaptive_delay_max_ms: Option<u64>,
+    /// hang up on clients whose socket buffer is still full after this many sends in a row
+    #[cfg_attr(feature = "cli", arg(long, value_name = "SENDS"))]
+    ssh_stall_limit: Option<u32>,
+    /// hang up on clients that have advertised a zero tcp window (stopped reading) for this long, linux only
+    #[cfg_attr(feature = "cli", arg(long))]
+    ssh_zero_window_timeout_secs: Option<u64>,
+    /// hang up on clients once they've been trapped this long, freeing their slot for someone new
+    #[cfg_attr(feature = "cli", arg(long, value_name = "SECS"))]
+    ssh_max_trap_seconds: Option<u64>,
+    /// read what clients send and penalize any that send more than this many bytes between two lines
+    #[cfg_attr(feature = "cli", arg(long, value_name = "BYTES"))]
+    ssh_chatty_bytes: Option<usize>,
+    /// "hold" to stop sending to chatty clients, or N to only send them every Nth line
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_SSH_CHATTY_PENALTY))]
+    ssh_chatty_penalty: ChattyPenalty,
+    /// remember the addresses trapped in the last SECS, doubling the delay each one waits between lines every time it comes back
+    #[cfg_attr(feature = "cli", arg(long, value_name = "SECS"))]
+    ssh_repeat_offender_ttl_secs: Option<u64>,
+    /// slow returning addresses down to at most this many times --ssh-message-delay-ms
+    #[cfg_attr(feature = "cli", arg(long, value_name = "FACTOR", default_value_t=DEFAULT_SSH_REPEAT_OFFENDER_MAX_FACTOR))]
+    ssh_repeat_offender_max_factor: u32,
+    /// like openssh's MaxStartups, refuse new connections with a probability of RATE percent from START clients, rising to 100% at FULL
+    #[cfg_attr(feature = "cli", arg(long, value_name = "START:RATE:FULL"))]
+    ssh_max_startups: Option<MaxStartups>,
+    /// trap only this fraction of connections, hanging up on the rest (which are still counted)
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=1.0))]
+    ssh_trap_probability: f64,
+    /// the end of each banner line - "lf", "crlf", "none" for one endless line, or "hex:" and the bytes of any other
+    #[cfg_attr(feature = "cli", arg(long, value_name = "NEWLINE", default_value_t=NewLine::LF))]
+    ssh_newline: NewLine,
+    /// seed the banner lines, jitter, variant picks and sampling, so the same clients at the same times get the same lines
+    #[cfg_attr(feature = "cli", arg(long, value_name = "SEED"))]
+    rng_seed: Option<u64>,
+    /// accept, record and hang up on every connection without trapping it, to size a deployment before it goes live
+    #[cfg_attr(feature = "cli", arg(long))]
+    ssh_dry_run: bool,
+    /// expect haproxy's PROXY header (v1 or v2) on every ssh connection, e.g. behind a load balancer, and log, count and
+    /// allowlist the client it names instead of the balancer
+    #[cfg_attr(feature = "cli", arg(long))]
+    proxy_protocol: bool,
+    /// accept at most this many ssh connections a second, leaving the rest in the listen backlog so a flood or mass scan
+    /// can't hold up the lines to those already trapped
+    #[cfg_attr(feature = "cli", arg(long, value_name = "PER_SEC"))]
+    ssh_accept_rate: Option<u64>,
+    /// how many connections --ssh-accept-rate lets through at once [default: a second's worth]
+    #[cfg_attr(feature = "cli", arg(long, requires = "ssh_accept_rate"))]
+    ssh_accept_burst: Option<u32>,
+    /// shrink the kernel receive buffer (SO_RCVBUF) of every trapped socket to this many bytes, linux only
+    #[cfg_attr(feature = "cli", arg(long, value_name = "BYTES"))]
+    ssh_rcvbuf_bytes: Option<usize>,
+    /// shrink the kernel send buffer (SO_SNDBUF) of every trapped socket to this many bytes, linux only
+    #[cfg_attr(feature = "cli", arg(long, value_name = "BYTES"))]
+    ssh_sndbuf_bytes: Option<usize>,
+    /// a bandwidth budget shared by every trapped client - once it's spent, lines wait their turn and the delay stretches
+    #[cfg_attr(feature = "cli", arg(long))]
+    max_send_bytes_per_sec: Option<u64>,
+    /// for router-class hardware - shrinks buffers, drops per-client history and stops accepting above 24MB resident
+    #[cfg_attr(feature = "cli", arg(long))]
+    low_memory: bool,
+    /// add a firewall rule blocking an address once it has been trapped and disconnected this many times
+    #[cfg_attr(feature = "cli", arg(long, requires = "block_backend"))]
+    block_after: Option<u32>,
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_BLOCK_DURATION_SECS))]
+    block_duration_secs: u64,
+    /// the firewall to add block rules to
+    #[cfg_attr(feature = "cli", arg(long))]
+    block_backend: Option<FirewallBackend>,
+    /// keep the blocks and disconnect counts in this file, so they survive a restart
+    #[cfg_attr(feature = "cli", arg(long, requires = "block_after"))]
+    block_state_file: Option<PathBuf>,
+    /// a list of cidr blocks, one per line, that are never trapped - fetched at startup and every --list-refresh-secs
+    #[cfg(feature = "remote-lists")]
+    #[arg(long)]
+    allowlist_url: Option<String>,
+    /// a list of cidr blocks, one per line, that are blocked on their first disconnect instead of after --block-after
+    #[cfg(feature = "remote-lists")]
+    #[arg(long, requires = "block_after")]
+    blocklist_url: Option<String>,
+    #[cfg(feature = "remote-lists")]
+    #[arg(long, default_value_t=3600)]
+    list_refresh_secs: u64,
+    /// every --report-interval-secs, append a summary of the connections (addresses, longest traps) to this file as a line of json
+    #[cfg_attr(feature = "cli", arg(long))]
+    report_file: Option<PathBuf>,
+    /// post each summary as json to this url
+    #[cfg(feature = "report")]
+    #[arg(long)]
+    report_webhook_url: Option<String>,
+    /// mail each summary to these addresses (comma separated or repeated), through --report-sendmail
+    #[cfg(feature = "report")]
+    #[arg(long, value_delimiter = ',')]
+    report_email_to: Vec<String>,
+    /// the sender of the mailed summaries, the user endlessh-rs runs as if not given
+    #[cfg(feature = "report")]
+    #[arg(long, requires = "report_email_to")]
+    report_email_from: Option<String>,
+    /// the sendmail compatible program the mailed summaries are handed to
+    #[cfg(feature = "report")]
+    #[arg(long, default_value = DEFAULT_SENDMAIL)]
+    report_sendmail: PathBuf,
+    /// a table of address ranges and the networks announcing them, like iptoasn.com's ip2asn-combined.tsv, to rank the countries and networks (ASNs) in each summary too
+    #[cfg(feature = "report")]
+    #[arg(long)]
+    report_networks_file: Option<PathBuf>,
+    #[cfg_attr(feature = "cli", arg(long, default_value_t=DEFAULT_REPORT_INTERVAL_SECS))]
+    report_interval_secs: u64,
+    /// post each newly trapped address as json to this url
+    #[cfg(feature = "notify")]
+    #[arg(long)]
+    notify_webhook_url: Option<String>,
+    /// notify about an address at most once in this many seconds
+    #[cfg(feature = "notify")]
+    #[arg(long, default_value_t=DEFAULT_NOTIFY_DEDUP_SECS, requires = "notify_webhook_url")]
+    notify_webhook_dedup_secs: u64,
+    /// post the notifications together every this many seconds instead of as they come in
+    #[cfg(feature = "notify")]
+    #[arg(long, requires = "notify_webhook_url")]
+    notify_webhook_digest_secs: Option<u64>,
+    /// export every connection event to the _bulk endpoint of this elasticsearch or opensearch cluster, e.g. http://localhost:9200
+    #[cfg(feature = "elasticsearch")]
+    #[arg(long)]
+    elasticsearch_url: Option<String>,
+    /// the index to write events to, %Y, %m and %d are replaced by the event's (utc) date
+    #[cfg(feature = "elasticsearch")]
+    #[arg(long, default_value = "endlessh-%Y.%m.%d")]
+    elasticsearch_index: String,
+    /// sent as an ApiKey authorization header - best kept in a --config file
+    #[cfg(feature = "elasticsearch")]
+    #[arg(long, requires = "elasticsearch_url")]
+    elasticsearch_api_key: Option<String>,
+    #[cfg(feature = "elasticsearch")]
+    #[arg(long, default_value_t=500)]
+    elasticsearch_batch_size: usize,
+    /// send whatever has arrived after this long, even short of a full batch
+    #[cfg(feature = "elasticsearch")]
+    #[arg(long, default_value_t=10)]
+    elasticsearch_flush_secs: u64,
+    /// publish every connection event as json to this hpfeeds broker (host:port)
+    #[cfg(feature = "hpfeeds")]
+    #[arg(long, requires_all = ["hpfeeds_ident", "hpfeeds_secret"])]
+    hpfeeds_broker: Option<String>,
+    /// the name the broker knows this sensor by
+    #[cfg(feature = "hpfeeds")]
+    #[arg(long, requires = "hpfeeds_broker")]
+    hpfeeds_ident: Option<String>,
+    /// best kept in a --config file, where ps can't see it
+    #[cfg(feature = "hpfeeds")]
+    #[arg(long, requires = "hpfeeds_broker")]
+    hpfeeds_secret: Option<String>,
+    #[cfg(feature = "hpfeeds")]
+    #[arg(long, default_value = "endlessh.events")]
+    hpfeeds_channel: String,
     #[cfg(feature = "metrics")]
-    #[arg(long, default_value_t=MultiListener::Disabled)]
-    metrics_listen_address: MultiListener,
+    /// ip:<addr>, unix:<path> or fd:<number or name> for a socket passed in already bound (e.g. by systemd, by its
+    /// FileDescriptorName=). may be given more than once to serve metrics on several listeners
+    #[arg(long, default_values_t=[MultiListener::Disabled])]
+    metrics_listen_address: Vec<MultiListener>,
     #[cfg(feature = "metrics")]
     #[arg(long, default_value_t=3)]
     metrics_max_clients: usize,
+    /// serve the admin api (log level, ssh listener changes and reloads) here, along with everything the metrics
+    /// listeners serve. ip:<loopback addr> or unix:<path>, since the admin api has no authentication - the metrics
+    /// listeners refuse its requests [default: disabled]
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_parser = parse_admin_listener)]
+    admin_listen_address: Option<MultiListener>,
+    /// permissions (octal) for any missing parent directories of unix metric sockets
+    #[cfg(all(feature = "metrics", unix))]
+    #[arg(long, default_value="755", value_parser=parse_octal_mode)]
+    metrics_socket_dir_mode: u32,
+    /// when a metrics listener fails, try to bind it again this often instead of leaving it down. sockets passed in
+    /// can't be bound again
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "SECS")]
+    metrics_rebind_secs: Option<u64>,
+    /// a key=value label added to every exported metric, may be given more than once
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_labels: Vec<MetricLabel>,
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=MetricServerOptions::default().request_max_size)]
+    metrics_request_max_bytes: usize,
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=MetricServerOptions::default().max_headers)]
+    metrics_max_headers: usize,
+    /// close a kept-alive metrics connection after answering this many requests, 1 to close after every response
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=MetricServerOptions::default().max_requests_per_connection)]
+    metrics_max_requests_per_connection: usize,
+    /// hang up on a metrics client that sends or takes nothing for this long, kept-alive or halfway through a request
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=MetricServerOptions::default().idle_timeout.as_secs())]
+    metrics_idle_timeout_secs: u64,
+    /// serve the metrics over https with this pem certificate chain, leaf first
+    #[cfg(feature = "metrics-tls")]
+    #[arg(long, requires = "metrics_tls_key")]
+    metrics_tls_cert: Option<PathBuf>,
+    /// the pem private key of --metrics-tls-cert
+    #[cfg(feature = "metrics-tls")]
+    #[arg(long, requires = "metrics_tls_cert")]
+    metrics_tls_key: Option<PathBuf>,
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=60)]
+    metrics_timeseries_interval_secs: u64,
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=1440)]
+    metrics_timeseries_length: usize,
+    /// how many connection events /recent serves, and all the memory history store keeps
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=100)]
+    metrics_recent_events: usize,
+    /// where the connection events behind /recent are kept: "memory" (gone on a restart), or "ndjson:<path>" or
+    /// "sqlite:<path>" (with the sqlite feature) to keep them across restarts
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=HistoryStore::Memory)]
+    history_store: HistoryStore,
+    /// forget stored connection events older than this
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "SECS")]
+    history_retention_secs: Option<u64>,
+    /// comma separated upper bounds of the trap duration histogram buckets, in seconds
+    #[cfg(feature = "metrics")]
+    #[arg(long, visible_alias = "metrics-trap-duration-seconds-buckets", value_delimiter = ',', default_values_t = endlessh::DEFAULT_TRAPPED_SECONDS_BUCKETS)]
+    metrics_trapped_seconds_buckets: Vec<f64>,
+    /// comma separated upper bounds of the lines per connection histogram buckets
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_delimiter = ',', default_values_t = endlessh::DEFAULT_LINES_PER_CONNECTION_BUCKETS)]
+    metrics_lines_per_connection_buckets: Vec<f64>,
+    /// comma separated upper bounds of the trap score histogram buckets
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_delimiter = ',', default_values_t = endlessh::DEFAULT_TRAP_SCORE_BUCKETS)]
+    metrics_trap_score_buckets: Vec<f64>,
+    /// periodically write the metrics to this file (for node_exporter's textfile collector)
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_textfile: Option<PathBuf>,
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=15)]
+    metrics_textfile_interval_secs: u64,
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value_t=5)]
+    health_check_interval_secs: u64,
+    /// report /readyz as not ready while resident memory is above this
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    health_max_rss_mb: Option<u64>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(clap::Subcommand, Clone, Debug)]
+enum Command {
+    /// control a running instance through its admin api
+    Ctl(ctl::CtlArgs),
+    /// play back an --event-log through the metric listeners and exporters, instead of trapping anyone
+    Replay(ReplayArgs),
+    /// follow the --event-log files of several sensors and serve their combined stats, instead of trapping anyone
+    Aggregate(AggregateArgs),
+}
+
+#[cfg(feature = "metrics")]
+#[derive(clap::Args, Clone, Debug)]
+struct AggregateArgs {
+    /// an event log per sensor, its events counted under their --sensor-id or else the file's name
+    #[arg(required = true)]
+    event_logs: Vec<PathBuf>,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(clap::Args, Clone, Debug)]
+struct ReplayArgs {
+    event_log: PathBuf,
+    /// play the events back this many times faster than they were recorded
+    #[arg(long, default_value_t=1.0)]
+    speed: f64,
+}
+
+/// everything exported when a metrics listener or textfile is enabled
+#[cfg(feature = "metrics")]
+struct Metrics {
+    servers: Vec<MetricEndpoint>,
+    /// for binding a failed listener again
+    server_options: MetricServerOptions,
+    labels: MetricLabels,
+    timeseries: TimeSeries,
+    history: Box<dyn ConnectionStore>,
+    /// how many events /recent serves
+    recent_events: usize,
+    textfile: Option<TextfileWriter>,
+    health: Health,
+    /// listener changes made through the admin api, applied by the event loop once the request is answered
+    listener_changes: RefCell<Vec<ListenerChange>>,
+    /// options reloaded through the admin api, applied by the event loop once the request is answered
+    reload: RefCell<Option<EndlesshOptions>>,
+    /// recorded events to feed through instead of live ones, see `endlessh-rs replay`
+    replay: Option<EventReplay>,
+    /// the sensors whose events are fed through instead of live ones, see `endlessh-rs aggregate`
+    fleet: Option<Fleet>,
+}
+
+/// an admin api request to start or stop an ssh listener
+#[cfg(feature = "metrics")]
+enum ListenerChange {
+    /// already bound, so the request could report bind failures
+    Add(TcpListener, Option<String>),
+    Remove(SocketAddr),
+}
+
+/// a family with a sample per variant or sensor - its name, type, help and how to get the value
+#[cfg(feature = "metrics")]
+type LabelledFamily<T> = (&'static str, MetricType, &'static str, fn(&T) -> f64);
+
+#[cfg(feature = "metrics")]
+fn render_metrics(endlessh_server: &EndlesshServer, labels: &MetricLabels, fleet: Option<&Fleet>) -> String {
+    let mut metrics = Exposition::new();
+    endlessh_server.stats().expose(&mut metrics, labels);
+    for (name, help, value) in endlessh_server.options().fields() {
+        metrics.family(&format!("endlessh_config_{}", name), MetricType::Gauge, help);
+        metrics.sample(labels, value);
+    }
+    metrics.family("endlessh_ssh_message_delay_seconds", MetricType::Gauge, "the delay between lines right now, stretched by --ssh-adaptive-delay-max-ms");
+    metrics.sample(labels, endlessh_server.message_delay().as_secs_f64());
+    metrics.family("endlessh_clients_connected", MetricType::Gauge, "clients trapped right now, out of endlessh_config_max_clients");
+    metrics.sample(labels, endlessh_server.client_count());
+    metrics.family("endlessh_ssh_listener_connections_opened", MetricType::Counter, "connections accepted by each ssh listener");
+    let mut destination_ports = BTreeMap::new();
+    for listener in endlessh_server.listeners() {
+        metrics.sample(labels.with("listener", &listener.label()), listener.connections_opened);
+        // listeners on the same port at different addresses add up, unix sockets have no port
+        if let Some(port) = listener.address.port() {
+            *destination_ports.entry(port).or_insert(0) += listener.connections_opened;
+        }
+    }
+    // every sample of a family has to come together, so the variants and sensors are gone through once per family
+    let variants: Vec<_> = endlessh_server.variants().collect();
+    let variant_families: [LabelledFamily<(&StrategyVariant, &VariantTotals)>; 8] = [
+        ("endlessh_config_variant_weight", MetricType::Gauge, "the share of new connections given to each variant", |(variant, _)| variant.weight as f64),
+        ("endlessh_config_variant_message_delay_seconds", MetricType::Gauge, "each variant's delay between lines", |(variant, _)| variant.message_delay.as_secs_f64()),
+        ("endlessh_ssh_variant_connections_opened", MetricType::Counter, "connections given to each variant", |(_, totals)| totals.connections_opened as f64),
+        ("endlessh_ssh_variant_connections_closed", MetricType::Counter, "closed connections of each variant", |(_, totals)| totals.connections_closed as f64),
+        ("endlessh_ssh_variant_bytes_sent", MetricType::Counter, "bytes sent to each variant's closed connections", |(_, totals)| totals.bytes_sent as f64),
+        ("endlessh_ssh_variant_lines_sent", MetricType::Counter, "lines sent to each variant's closed connections", |(_, totals)| totals.lines_sent as f64),
+        ("endlessh_ssh_variant_trapped_time_seconds", MetricType::Counter, "the time each variant's closed connections were trapped for, added up", |(_, totals)| totals.trapped_seconds),
+        ("endlessh_ssh_variant_trap_score_sum", MetricType::Counter, "the trap scores of each variant's closed connections, added up", |(_, totals)| totals.trap_score),
+    ];
+    for (name, metric_type, help, value) in variant_families.into_iter().filter(|_| !variants.is_empty()) {
+        metrics.family(name, metric_type, help);
+        for variant in &variants {
+            metrics.sample(labels.with("variant", &variant.0.name), value(variant));
+        }
+    }
+    metrics.family("endlessh_ssh_destination_port_connections_opened", MetricType::Counter, "connections accepted on each port, across listeners");
+    for (port, opened) in destination_ports {
+        metrics.sample(labels.with("port", &port.to_string()), opened);
+    }
+    metrics.family("endlessh_ssh_source_port_connections_opened", MetricType::Counter, "connections accepted from each range of source ports");
+    for (first, last, opened) in endlessh_server.stats().source_ports() {
+        metrics.sample(labels.with("source_ports", &format!("{}-{}", first, last)), opened);
+    }
+    let sensors = fleet.map(Fleet::sensors).filter(|sensors| !sensors.is_empty());
+    let fleet_families: [LabelledFamily<SensorTotals>; 5] = [
+        ("endlessh_fleet_connections_opened", MetricType::Counter, "connections each sensor accepted", |totals| totals.connections_opened as f64),
+        ("endlessh_fleet_connections_closed", MetricType::Counter, "connections each sensor closed", |totals| totals.connections_closed as f64),
+        ("endlessh_fleet_bytes_sent", MetricType::Counter, "bytes each sensor sent to its closed connections", |totals| totals.bytes_sent as f64),
+        ("endlessh_fleet_trapped_time_seconds", MetricType::Counter, "the time each sensor's closed connections were trapped for, added up", |totals| totals.trapped_seconds),
+        ("endlessh_fleet_last_event_timestamp_seconds", MetricType::Gauge, "when each sensor's latest event happened, in seconds since the unix epoch", |totals| totals.last_event_seconds),
+    ];
+    for (name, metric_type, help, value) in fleet_families.into_iter().filter(|_| sensors.is_some()) {
+        metrics.family(name, metric_type, help);
+        for (sensor, totals) in sensors.into_iter().flatten() {
+            metrics.sample(labels.with("sensor", sensor), value(totals));
+        }
+    }
+    process::expose(&mut metrics, labels, endlessh_server.stats().started_at);
+    metrics.finish()
 }
 
+/// what the metric server reads from while answering a request
+#[cfg(feature = "metrics")]
+struct MetricView<'a> {
+    endlessh_server: &'a EndlesshServer,
+    labels: &'a MetricLabels,
+    timeseries: &'a TimeSeries,
+    history: &'a dyn ConnectionStore,
+    recent_events: usize,
+    health: &'a Health,
+    admin_api: bool,
+    listener_changes: &'a RefCell<Vec<ListenerChange>>,
+    reload: &'a RefCell<Option<EndlesshOptions>>,
+    fleet: Option<&'a Fleet>,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricSource for MetricView<'_> {
+    fn metrics(&self) -> String {
+        render_metrics(self.endlessh_server, self.labels, self.fleet)
+    }
+
+    fn stats_json(&self) -> String {
+        serde_json::to_string(&**self.endlessh_server.stats()).expect("failed to serialize stats")
+    }
+
+    fn timeseries_json(&self) -> String {
+        self.timeseries.to_json()
+    }
+
+    fn recent_json(&self) -> String {
+        match self.history.recent(self.recent_events) {
+            Ok(events) => serde_json::to_string(&events).expect("failed to serialize recent events"),
+            Err(e) => {
+                warn!("failed to read the recent connection events - {}", e);
+                "[]".to_string()
+            },
+        }
+    }
+
+    fn history_json(&self, query: &HistoryQuery) -> Option<String> {
+        match store::page(self.history, query) {
+            Ok(page) => Some(serde_json::to_string(&page).expect("failed to serialize history page")),
+            Err(e) => {
+                warn!("failed to read the connection history - {}", e);
+                None
+            },
+        }
+    }
+
+    fn healthz(&self) -> String {
+        self.health.liveness_json()
+    }
+
+    fn readyz(&self) -> (bool, String) {
+        health::readiness(self.endlessh_server, Some(self.health))
+    }
+
+    fn set_log_level(&self, level: LevelFilter) -> bool {
+        if !self.admin_api {
+            return false;
+        }
+        log::set_max_level(level);
+        info!("log level set to {} via the admin api", level);
+        true
+    }
+
+    fn clients_json(&self) -> String {
+        serde_json::to_string(&self.endlessh_server.schedule(&Instant::now())).expect("failed to serialize clients")
+    }
+
+    fn listeners_json(&self) -> String {
+        serde_json::to_string(&self.endlessh_server.listeners().collect::<Vec<_>>()).expect("failed to serialize listeners")
+    }
+
+    fn add_listener(&self, address: SocketAddr, name: Option<&str>) -> Result<SocketAddr, AdminError> {
+        if !self.admin_api {
+            return Err(AdminError::Disabled);
+        }
+        let listener = endlessh::bind_listener(address).map_err(|e| AdminError::Failed(e.to_string()))?;
+        let bound = listener.local_addr().map_err(|e| AdminError::Failed(e.to_string()))?;
+        self.listener_changes.borrow_mut().push(ListenerChange::Add(listener, name.map(str::to_string)));
+        Ok(bound)
+    }
+
+    fn remove_listener(&self, address: SocketAddr) -> Result<(), AdminError> {
+        if !self.admin_api {
+            return Err(AdminError::Disabled);
+        }
+        if !self.endlessh_server.listen_addresses().any(|a| *a == ListenAddress::Ip(address)) {
+            return Err(AdminError::NotFound);
+        }
+        self.listener_changes.borrow_mut().push(ListenerChange::Remove(address));
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<(), AdminError> {
+        if !self.admin_api {
+            return Err(AdminError::Disabled);
+        }
+        // read here rather than in the event loop, so a bad config or word list is reported back to the caller
+        *self.reload.borrow_mut() = Some(reloaded_options().map_err(AdminError::Failed)?);
+        Ok(())
+    }
+}
+
+/// `value`, or at most `cap` with `--low-memory`
+fn low_memory_cap<T: Ord>(args: &Args, value: T, cap: T) -> T {
+    if args.low_memory {
+        value.min(cap)
+    } else {
+        value
+    }
+}
+
+/// everything the event loop runs on a timer, see [Scheduler]
+#[derive(Clone, Copy, Debug)]
+enum PeriodicTask {
+    MemoryCheck,
+    BlockExpiry,
+    BlockStateSave,
+    #[cfg(feature = "metrics")]
+    TimeSeriesSample,
+    #[cfg(feature = "metrics")]
+    TextfileWrite,
+    #[cfg(feature = "metrics")]
+    HealthCheck,
+    /// forgetting stored connection events older than the retention
+    #[cfg(feature = "metrics")]
+    HistoryPrune(Duration),
+    #[cfg(feature = "metrics")]
+    MetricsRebind,
+    /// reading what the sensors have appended to their event logs, for `endlessh-rs aggregate`
+    #[cfg(feature = "metrics")]
+    FleetPoll,
+    Report,
+    #[cfg(feature = "notify")]
+    NotifyDigest,
+    /// asking the remote list threads to fetch their lists again
+    #[cfg(feature = "remote-lists")]
+    ListRefresh,
+    #[cfg(target_os = "linux")]
+    Watchdog,
+}
+
+/// the sooner of two optional poll timeouts, where None means "no deadline"
+fn earliest_timeout(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// swaps in a downloaded allowlist or blocklist
+#[cfg(feature = "remote-lists")]
+fn apply_list_update(update: ListUpdate, endlessh_server: &mut EndlesshServer, blocker: Option<&mut Blocker>) {
+    match (update.kind, blocker) {
+        (ListKind::Allow, _) => endlessh_server.set_allowlist(update.list),
+        (ListKind::Block, Some(blocker)) => blocker.set_blocklist(update.list),
+        (ListKind::Block, None) => warn!("ignoring a blocklist, blocking is disabled"),
+    }
+}
+
+/// the configured histogram buckets, which must be finite and in increasing order
+#[cfg(feature = "metrics")]
+fn histogram_buckets(args: &Args) -> HistogramBuckets {
+    for (flag, bounds) in [
+        ("--metrics-trapped-seconds-buckets", &args.metrics_trapped_seconds_buckets),
+        ("--metrics-lines-per-connection-buckets", &args.metrics_lines_per_connection_buckets),
+        ("--metrics-trap-score-buckets", &args.metrics_trap_score_buckets),
+    ] {
+        if bounds.iter().any(|bound| !bound.is_finite()) || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            fatal(FatalError::Config, format!("{} must be finite numbers in increasing order", flag));
+        }
+    }
+    HistogramBuckets {
+        trapped_seconds: args.metrics_trapped_seconds_buckets.clone(),
+        lines_per_connection: args.metrics_lines_per_connection_buckets.clone(),
+        trap_score: args.metrics_trap_score_buckets.clone(),
+    }
+}
+
+/// the event logs to follow for `endlessh-rs aggregate`
+#[cfg(feature = "metrics")]
+fn load_fleet(args: &Args, aggregate_args: &AggregateArgs) -> Fleet {
+    if args.metrics_listen_address.iter().all(|l| *l == MultiListener::Disabled) && args.metrics_textfile.is_none() {
+        fatal(FatalError::Config, "aggregating needs --metrics-listen-address or --metrics-textfile to serve the combined stats");
+    }
+    info!("aggregating the event logs of {} sensors", aggregate_args.event_logs.len());
+    Fleet::new(&aggregate_args.event_logs)
+}
+
+/// --metrics-labels, after a `sensor` label for the --sensor-id - except on an aggregator, whose metrics are labelled
+/// with the sensor each came from
+#[cfg(feature = "metrics")]
+fn metric_labels(args: &Args, aggregating: bool) -> Vec<MetricLabel> {
+    let sensor = args.sensor_id.as_ref().filter(|_| !aggregating)
+        .map(|id| format!("sensor={}", id).parse().expect("a valid sensor id is a valid label value"));
+    sensor.into_iter().chain(args.metrics_labels.iter().cloned()).collect()
+}
+
+/// reads the events for `endlessh-rs replay`, returning them with the speed to play them at
+#[cfg(feature = "metrics")]
+fn load_replay(args: &Args, replay_args: &ReplayArgs) -> (Vec<ConnectionEvent>, f64) {
+    if !(replay_args.speed.is_finite() && replay_args.speed > 0.0) {
+        fatal(FatalError::Config, "--speed must be a positive number");
+    }
+    if args.metrics_listen_address.iter().all(|l| *l == MultiListener::Disabled) && args.metrics_textfile.is_none() {
+        fatal(FatalError::Config, "replaying needs --metrics-listen-address or --metrics-textfile to play the events through");
+    }
+    let path = &replay_args.event_log;
+    let (events, invalid) = read_events(path)
+        .unwrap_or_else(|e| fatal(FatalError::Config, format!("failed to read event log {} - {}", path.display(), e)));
+    if invalid > 0 {
+        warn!("skipped {} lines of {} that aren't connection events", invalid, path.display());
+    }
+    info!("replaying {} events from {} at {}x speed", events.len(), path.display(), replay_args.speed);
+    (events, replay_args.speed)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn event_loop(
     mut poll: Poll,
     mut events: Events,
     mut endlessh_server: EndlesshServer, 
+    mut scheduler: Scheduler<PeriodicTask>,
+    mut blocker: Option<Blocker>,
+    mut event_log: Option<EventLog>,
+    mut reporter: Option<Reporter>,
+    #[cfg(feature = "notify")]
+    mut notify_webhook: Option<WebhookNotifier>,
+    #[cfg(feature = "hpfeeds")]
+    mut hpfeeds: Option<HpfeedsPublisher>,
+    #[cfg(feature = "elasticsearch")]
+    mut elasticsearch: Option<ElasticsearchExporter>,
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    mut shutdown: Shutdown,
+    #[cfg(unix)]
+    mut signals: Signals,
+    #[cfg(feature = "remote-lists")]
+    list_updates: mpsc::Receiver<ListUpdate>,
+    #[cfg(feature = "remote-lists")]
+    list_refreshers: Vec<ListRefresher>,
     #[cfg(feature = "metrics")]
-    mut metric_server: Option<MetricServer>,
+    mut metrics: Option<Metrics>,
+    #[cfg(target_os = "linux")]
+    notifier: Option<systemd::Notifier>,
 ) {
-    let mut timeout = None;
+    // start with a non-blocking poll so every wakeup handler gets to schedule itself
+    let mut timeout = Some(Duration::ZERO);
+    #[cfg(feature = "metrics")]
+    let mut next_ssh_listener_token = SSH_LISTENER_TOKEN_START + endlessh_server.listen_addresses().count();
     loop {
     
         if let Err(err) = poll.poll(&mut events, timeout) {
             if err.kind() == Interrupted {
                 continue;
             }
-            panic!("failed to poll: {}", err);
+            fatal(FatalError::ResourceExhaustion, format!("failed to poll: {}", err));
         }
         let loop_time = Instant::now();
         for event in events.iter() {
             match event.token() {
+                #[cfg(unix)]
+                SIGNAL_TOKEN => {
+                    for signal in signals.pending() {
+                        match signal {
+                            SIGHUP => {
+                                info!("SIGHUP received, reopening the log file");
+                                if let Err(e) = logging::reopen() {
+                                    warn!("failed to reopen the log file - {}", e);
+                                }
+                                #[cfg(feature = "cli")]
+                                reload_config(&mut endlessh_server, reloaded_options(), &loop_time);
+                            },
+                            SIGUSR1 => log_clients(&endlessh_server, &loop_time),
+                            SIGINT | SIGTERM => {
+                                let first_signal = shutdown.begin(if signal == SIGINT { "SIGINT" } else { "SIGTERM" }, &loop_time);
+                                if first_signal {
+                                    endlessh_server.set_accepting(false, &loop_time);
+                                    #[cfg(target_os = "linux")]
+                                    if let Some(notifier) = notifier.as_ref() {
+                                        notifier.notify("STOPPING=1");
+                                    }
+                                }
+                            },
+                            _ => {},
+                        }
+                    }
+                },
+                WAKE_TOKEN => {
+                    #[cfg(feature = "remote-lists")]
+                    for update in list_updates.try_iter() {
+                        apply_list_update(update, &mut endlessh_server, blocker.as_mut());
+                    }
+                    if let Some(blocker) = blocker.as_mut() {
+                        blocker.handle_results(&loop_time);
+                    }
+                },
                 _ if endlessh_server.try_handle_event(event, &loop_time) => {},
                 #[cfg(feature = "metrics")]
-                _ if metric_server.as_mut().is_some_and(|m| m.try_handle_event(event, &mut poll, endlessh_server.stats())) => {},
+                _ if metrics.as_mut().is_some_and(|m| {
+                    let view = MetricView {
+                        endlessh_server: &endlessh_server,
+                        labels: &m.labels,
+                        timeseries: &m.timeseries,
+                        history: m.history.as_ref(),
+                        recent_events: m.recent_events,
+                        health: &m.health,
+                        admin_api: false,
+                        listener_changes: &m.listener_changes,
+                        reload: &m.reload,
+                        fleet: m.fleet.as_ref(),
+                    };
+                    m.servers.iter_mut().any(|endpoint| {
+                        let view = MetricView { admin_api: endpoint.admin, ..view };
+                        endpoint.try_handle_event(event, &mut poll, &view, &loop_time)
+                    })
+                }) => {},
                 rando_token => {
-                    panic!("unexpected token {}", rando_token.0);
+                    // a source closed earlier in this batch can still have events queued
+                    warn!("ignoring an event for unknown token {}", rando_token.0);
                 },
 
             }
         }
+        #[cfg(feature = "metrics")]
+        for change in metrics.as_ref().map(|m| m.listener_changes.take()).unwrap_or_default() {
+            match change {
+                ListenerChange::Add(listener, name) => {
+                    match endlessh_server.add_listener(listener, name.as_deref(), Token(next_ssh_listener_token), &poll) {
+                        Ok(address) => info!("endlessh-rs listening for ssh connections on ip:{} (added via the admin api)", address),
+                        Err(e) => warn!("failed to add ssh listener: {}", e),
+                    }
+                    next_ssh_listener_token += 1;
+                },
+                ListenerChange::Remove(address) => {
+                    if endlessh_server.remove_listener(&ListenAddress::Ip(address), &poll) {
+                        info!("stopped listening for ssh connections on ip:{} (removed via the admin api)", address);
+                    }
+                },
+            }
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(options) = metrics.as_ref().and_then(|m| m.reload.take()) {
+            reload_config(&mut endlessh_server, Ok(options), &loop_time);
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(replay) = metrics.as_mut().and_then(|m| m.replay.as_mut()) {
+            for event in replay.take_due(&loop_time) {
+                endlessh_server.replay_event(event, &loop_time);
+            }
+        }
         timeout = endlessh_server.handle_wakeup(&loop_time);
+        for task in scheduler.take_due(&loop_time) {
+            match task {
+                PeriodicTask::MemoryCheck => {
+                    if let Some(resident) = process::resident_memory_bytes() {
+                        let accepting = resident <= LOW_MEMORY_RSS_TARGET_BYTES;
+                        // nothing new is accepted while shutting down, whatever the memory use
+                        if accepting != endlessh_server.is_accepting() && !shutdown.is_stopping() {
+                            warn!("resident memory is {} bytes, {} new connections", resident, if accepting { "resuming" } else { "pausing" });
+                            endlessh_server.set_accepting(accepting, &loop_time);
+                        }
+                    }
+                },
+                PeriodicTask::BlockExpiry => {
+                    if let Some(blocker) = blocker.as_mut() {
+                        blocker.expire(&loop_time);
+                    }
+                },
+                PeriodicTask::BlockStateSave => {
+                    if let Some(blocker) = blocker.as_ref() {
+                        blocker.save(&loop_time);
+                    }
+                },
+                #[cfg(feature = "metrics")]
+                PeriodicTask::TimeSeriesSample => {
+                    if let Some(m) = metrics.as_mut() {
+                        m.timeseries.take_sample();
+                    }
+                },
+                #[cfg(feature = "metrics")]
+                PeriodicTask::HistoryPrune(retention) => {
+                    if let Some(m) = metrics.as_mut() {
+                        let before = endlessh::unix_seconds(std::time::SystemTime::now()).saturating_sub(retention.as_secs()) as f64;
+                        match m.history.prune(before) {
+                            Ok(0) => {},
+                            Ok(pruned) => info!("forgot {} connection events older than {}s", pruned, retention.as_secs()),
+                            Err(e) => warn!("failed to prune the connection history - {}", e),
+                        }
+                    }
+                },
+                #[cfg(feature = "metrics")]
+                PeriodicTask::TextfileWrite => {
+                    if let Some(m) = metrics.as_mut() {
+                        if let Some(textfile) = m.textfile.as_ref() {
+                            let succeeded = textfile.write(&render_metrics(&endlessh_server, &m.labels, m.fleet.as_ref()));
+                            m.health.record_exporter_result("textfile", succeeded);
+                        }
+                    }
+                },
+                #[cfg(feature = "metrics")]
+                PeriodicTask::HealthCheck => {
+                    if let Some(m) = metrics.as_mut() {
+                        for endpoint in &mut m.servers {
+                            #[cfg(unix)]
+                            endpoint.check_socket_file(&poll);
+                            m.health.record_listener_result(&endpoint.label, endpoint.failure.as_deref());
+                        }
+                        m.health.evaluate(&endlessh_server);
+                    }
+                },
+                #[cfg(feature = "metrics")]
+                PeriodicTask::MetricsRebind => {
+                    if let Some(m) = metrics.as_mut() {
+                        for endpoint in &mut m.servers {
+                            endpoint.rebind(&poll, &m.server_options);
+                        }
+                    }
+                },
+                #[cfg(feature = "metrics")]
+                PeriodicTask::FleetPoll => {
+                    if let Some(fleet) = metrics.as_mut().and_then(|m| m.fleet.as_mut()) {
+                        for event in fleet.poll() {
+                            endlessh_server.ingest_event(event, &loop_time);
+                        }
+                    }
+                },
+                PeriodicTask::Report => {
+                    if let Some(reporter) = reporter.as_mut() {
+                        reporter.publish();
+                    }
+                },
+                #[cfg(feature = "notify")]
+                PeriodicTask::NotifyDigest => {
+                    if let Some(notify_webhook) = notify_webhook.as_mut() {
+                        notify_webhook.publish();
+                    }
+                },
+                #[cfg(feature = "remote-lists")]
+                PeriodicTask::ListRefresh => {
+                    for refresher in &list_refreshers {
+                        refresher.refresh();
+                    }
+                },
+                #[cfg(target_os = "linux")]
+                PeriodicTask::Watchdog => {
+                    if let Some(notifier) = notifier.as_ref() {
+                        notifier.notify("WATCHDOG=1");
+                    }
+                },
+            }
+        }
+        timeout = earliest_timeout(timeout, scheduler.timeout(&loop_time));
+        timeout = earliest_timeout(timeout, shutdown.timeout(&loop_time));
+        #[cfg(feature = "metrics")]
+        if let Some(m) = metrics.as_mut() {
+            for endpoint in &mut m.servers {
+                timeout = earliest_timeout(timeout, endpoint.handle_wakeup(&mut poll, &loop_time));
+            }
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(replay) = metrics.as_ref().and_then(|m| m.replay.as_ref()) {
+            timeout = earliest_timeout(timeout, replay.timeout(&loop_time));
+        }
+        // the clients hung up on here are seen off through the exporters like any other
+        let finishing = shutdown.is_due(&loop_time, endlessh_server.client_count());
+        if finishing {
+            endlessh_server.close_all(shutdown.farewell_line, &loop_time);
+        }
+        for event in endlessh_server.drain_events() {
+            if let Some(event_log) = event_log.as_mut() {
+                event_log.write(&event);
+            }
+            // far too many for anything but the event log
+            if event.event == ConnectionEventKind::LineSent {
+                continue;
+            }
+            if let Some(reporter) = reporter.as_mut() {
+                reporter.record(&event);
+            }
+            #[cfg(feature = "notify")]
+            if let Some(notify_webhook) = notify_webhook.as_mut() {
+                notify_webhook.record(&event, &loop_time);
+            }
+            #[cfg(feature = "hpfeeds")]
+            if let Some(hpfeeds) = hpfeeds.as_mut() {
+                hpfeeds.publish(&event);
+            }
+            #[cfg(feature = "elasticsearch")]
+            if let Some(elasticsearch) = elasticsearch.as_mut() {
+                elasticsearch.export(&event);
+            }
+            if let Some(blocker) = blocker.as_mut() {
+                // unix socket clients have no address to block, and being hung up on at shutdown says nothing about one
+                if event.event == ConnectionEventKind::Closed && event.peer != UNIX_PEER && !finishing {
+                    blocker.record_disconnect(event.peer.ip(), &loop_time);
+                }
+                if event.event == ConnectionEventKind::Banned && event.peer != UNIX_PEER {
+                    blocker.block(event.peer.ip(), &loop_time);
+                }
+            }
+            #[cfg(feature = "metrics")]
+            if let Some(m) = metrics.as_mut() {
+                if let Err(e) = m.history.append(&event) {
+                    warn!("failed to store a connection event - {}", e);
+                }
+            }
+        }
+        if let Some(event_log) = event_log.as_mut() {
+            event_log.flush();
+        }
+        #[cfg(feature = "notify")]
+        if let Some(notify_webhook) = notify_webhook.as_mut().filter(|notify_webhook| notify_webhook.digest().is_none()) {
+            notify_webhook.publish();
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(m) = metrics.as_mut() {
+            flush_history(m);
+        }
+        if finishing {
+            if let Some(blocker) = blocker.as_mut() {
+                blocker.save(&loop_time);
+                blocker.unblock_all();
+            }
+            #[cfg(feature = "elasticsearch")]
+            if let Some(elasticsearch) = elasticsearch.take() {
+                elasticsearch.finish();
+            }
+            shutdown.finish(endlessh_server.stats());
+            return;
+        }
     }
 }
  
 
-fn main() {
-    let args = &Args::parse();
-    let poll = Poll::new().unwrap();
-    let events = Events::with_capacity(128);
-
-    let ssh_listener: TcpListener = TcpListener::bind(args.ssh_listen_address).expect("failed to bind to ssh socket");
-
-    let endlessh_server = EndlesshServer::create(
-        EndlesshOptions {
-            banner_line_length: args.ssh_banner_line_length,
-            max_clients: args.ssh_max_clients,
-            message_delay: Duration::from_millis(args.ssh_message_delay_ms),
-            newline: endlessh::NewLine::LF,
-        },
-        ssh_listener,
-        SSH_SERVER_TOKEN,
-        &poll
+#[cfg(all(feature = "metrics", unix))]
+fn parse_octal_mode(v: &str) -> Result<u32, String> {
+    u32::from_str_radix(v, 8).map_err(|e| format!("not an octal file mode - {}", e))
+}
+
+/// a listener for the admin api, which has no authentication so has to be local - loopback or a unix socket
+#[cfg(feature = "metrics")]
+fn parse_admin_listener(v: &str) -> Result<MultiListener, String> {
+    match v.parse()? {
+        MultiListener::Ip(address) if !address.ip().is_loopback() => Err(format!("{} isn't a loopback address", address.ip())),
+        MultiListener::Fd(_) => Err("a passed in socket can't be checked to be local, give an ip: or unix: address".to_string()),
+        listener => Ok(listener),
+    }
+}
+
+/// creates the socket's parent directories (e.g. /run/endlessh on first boot) and removes any stale socket
+#[cfg(unix)]
+fn prepare_unix_socket_path(path: &std::path::Path, dir_mode: u32) {
+    use std::os::unix::fs::DirBuilderExt;
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty() && !p.exists()) {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(dir_mode)
+            .create(parent)
+            .unwrap_or_else(|e| fatal(FatalError::Bind, format!("failed to create directory {} for unix socket - {}", parent.display(), e)));
+    }
+    let _ = remove_file(path);
+}
+
+/// blocks until `address` is assigned to an interface or --wait-for-address runs out, binding then explains any failure
+fn wait_for_address(args: &Args, address: SocketAddr) {
+    let Some(timeout) = args.wait_for_address else {
+        return;
+    };
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    // binding an ephemeral udp port only succeeds for addresses the host actually has, and needs no privileges
+    let is_assigned = || !matches!(
+        UdpSocket::bind((address.ip(), 0)),
+        Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable
     );
+    if is_assigned() {
+        return;
+    }
+    info!("waiting up to {}s for {} to be assigned to an interface", timeout, address.ip());
+    while !is_assigned() {
+        if Instant::now() >= deadline {
+            warn!("gave up waiting for {} after {}s", address.ip(), timeout);
+            return;
+        }
+        std::thread::sleep(WAIT_FOR_ADDRESS_INTERVAL);
+    }
+    info!("{} is now assigned", address.ip());
+}
 
-    println!("endlessh-rs listening for ssh connections on ip:{}", args.ssh_listen_address);
+/// calls `bind` until it succeeds or fails for good, retrying transient failures up to --bind-retry times with backoff
+fn bind_with_retry<T>(args: &Args, mut bind: impl FnMut() -> endlessh_rs::Result<T>) -> endlessh_rs::Result<T> {
+    let mut delay = BIND_RETRY_INITIAL_DELAY;
+    let mut retries = 0;
+    loop {
+        match bind() {
+            Err(e) if e.is_transient() && retries < args.bind_retry => {
+                retries += 1;
+                warn!("{}, retry {}/{} in {}s", e, retries, args.bind_retry, delay.as_secs());
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(BIND_RETRY_MAX_DELAY);
+            },
+            result => return result,
+        }
+    }
+}
 
-    #[cfg(feature = "metrics")]
-    let metric_server: Option<MetricServer> = match &args.metrics_listen_address {
-        MultiListener::Disabled => None,
+/// the --history-store, or the memory one for a replay, which shouldn't add to a real history
+#[cfg(feature = "metrics")]
+fn open_history_store(args: &Args, replaying: bool) -> Box<dyn ConnectionStore> {
+    let store: endlessh_rs::Result<Box<dyn ConnectionStore>> = match &args.history_store {
+        _ if replaying => Ok(Box::new(MemoryStore::new(args.metrics_recent_events))),
+        HistoryStore::Memory => Ok(Box::new(MemoryStore::new(low_memory_cap(args, args.metrics_recent_events, 0)))),
+        HistoryStore::Ndjson(path) => NdjsonStore::open(path.clone()).map(|store| Box::new(store) as Box<dyn ConnectionStore>),
+        #[cfg(feature = "sqlite")]
+        HistoryStore::Sqlite(path) => endlessh_rs::store::SqliteStore::open(path.clone()).map(|store| Box::new(store) as Box<dyn ConnectionStore>),
+        #[cfg(not(feature = "sqlite"))]
+        HistoryStore::Sqlite(_) => unreachable!("sqlite history stores aren't parsed without the sqlite feature"),
+    };
+    store.unwrap_or_else(|e| fatal(FatalError::Config, e))
+}
+
+/// writes out the events stored this pass of the event loop, a failure counting against /readyz until one succeeds
+#[cfg(feature = "metrics")]
+fn flush_history(metrics: &mut Metrics) {
+    let result = metrics.history.flush();
+    if let Err(e) = &result {
+        warn!("failed to store the connection events - {}", e);
+    }
+    metrics.health.record_exporter_result("history", result.is_ok());
+}
+
+/// a metric server, and what it takes to bring it back if its listener breaks
+#[cfg(feature = "metrics")]
+struct MetricEndpoint {
+    /// None while the listener is down
+    server: Option<MetricServer>,
+    /// what to bind again, None for a socket passed in already bound
+    address: Option<MultiListener>,
+    /// the address, for the logs and /readyz
+    label: String,
+    listener_token: Token,
+    client_tokens: std::ops::Range<usize>,
+    /// why the listener went down, until it's bound again
+    failure: Option<String>,
+    /// the --admin-listen-address, the only endpoint that answers admin api requests
+    admin: bool,
+}
+
+#[cfg(feature = "metrics")]
+impl MetricEndpoint {
+
+    fn new(server: MetricServer, address: Option<MultiListener>, label: String, listener_token: Token, client_tokens: std::ops::Range<usize>) -> Self {
+        MetricEndpoint { server: Some(server), address, label, listener_token, client_tokens, failure: None, admin: false }
+    }
+
+    /// whether the event was this endpoint's, taking the server down if its listener turns out to be broken
+    fn try_handle_event(&mut self, event: &mio::event::Event, poll: &mut Poll, source: &MetricView, now: &Instant) -> bool {
+        let Some(server) = self.server.as_mut() else {
+            // left over from before it went down
+            return event.token() == self.listener_token || self.client_tokens.contains(&event.token().0);
+        };
+        match server.try_handle_event(event, poll, source, now) {
+            Ok(handled) => handled,
+            Err(e) => {
+                self.fail(e.to_string(), poll);
+                true
+            },
+        }
+    }
+
+    /// hangs up on idle clients, returning when the next one is due
+    fn handle_wakeup(&mut self, poll: &mut Poll, now: &Instant) -> Option<Duration> {
+        let server = self.server.as_mut()?;
+        match server.handle_wakeup(poll, now) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                self.fail(e.to_string(), poll);
+                None
+            },
+        }
+    }
+
+    fn fail(&mut self, failure: String, poll: &Poll) {
+        warn!("metrics listener {} failed, carrying on without it - {}", self.label, failure);
+        if let Some(server) = self.server.take() {
+            server.close(poll);
+        }
+        self.failure = Some(failure);
+    }
+
+    /// a unix socket whose file was removed still accepts, but nothing can connect to it any more
+    #[cfg(unix)]
+    fn check_socket_file(&mut self, poll: &Poll) {
+        if let (Some(_), Some(MultiListener::Unix(path))) = (&self.server, &self.address) {
+            if std::fs::symlink_metadata(path).is_err() {
+                self.fail(format!("its socket file {} was removed", path.display()), poll);
+            }
+        }
+    }
+
+    /// one attempt at binding a down listener again, the next --metrics-rebind-secs makes another
+    fn rebind(&mut self, poll: &Poll, options: &MetricServerOptions) {
+        let (None, Some(address)) = (&self.server, &self.address) else {
+            return;
+        };
+        let (listener_token, client_tokens) = (self.listener_token, self.client_tokens.clone());
+        let server = match address {
+            MultiListener::Ip(ip) => endlessh::bind_listener(*ip)
+                .and_then(|listener| MetricServer::new_tcp(poll, listener, listener_token, client_tokens, options.clone())),
+            #[cfg(unix)]
+            MultiListener::Unix(path) => {
+                let _ = remove_file(path);
+                UnixListener::bind(path).map_err(|e| EndlesshError::bind(address, None, e))
+                    .and_then(|listener| MetricServer::new_unix(poll, listener, listener_token, client_tokens, options.clone()))
+            },
+            _ => return,
+        };
+        match server {
+            Ok(server) => {
+                info!("endlessh-rs listening for metrics connections on {} again", address);
+                self.server = Some(server);
+                self.failure = None;
+            },
+            Err(e) => warn!("failed to bind metrics listener {} again - {}", address, e),
+        }
+    }
+
+}
+
+#[cfg(feature = "metrics")]
+fn bind_metric_server(args: &Args, poll: &Poll, listener: &MultiListener, passed_sockets: &mut Vec<(PassedFd, ListenSocket)>, listener_token: Token,
+    client_tokens: std::ops::Range<usize>, options: MetricServerOptions) -> MetricEndpoint {
+    let endpoint_tokens = client_tokens.clone();
+    let metric_server = match listener {
+        MultiListener::Disabled => unreachable!("disabled metric listeners are never bound"),
         MultiListener::Ip(ip) => {
-            let tcp_listener = TcpListener::bind(*ip).expect("failed to bind to TCP socket");
-            println!("endlessh-rs listening for metrics connections on {}", args.metrics_listen_address);
-            Some(MetricServer::new_tcp(&poll, tcp_listener, METRIC_SERVER_TOKEN, METRIC_CLIENT_TOKEN_START..METRIC_CLIENT_TOKEN_START+args.metrics_max_clients))
+            wait_for_address(args, *ip);
+            let tcp_listener = bind_with_retry(args, || endlessh::bind_listener(*ip))
+                .unwrap_or_else(|e| fatal(FatalError::Bind, e));
+            MetricServer::new_tcp(poll, tcp_listener, listener_token, client_tokens, options)
+                .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, e))
         },
         #[cfg(unix)]
         MultiListener::Unix(path) => {
-            let _ = remove_file(path);
-            let unix_listener = UnixListener::bind(path).expect("failed to bind to unix socket");
-            println!("endlessh-rs listening for metrics connections on {}", args.metrics_listen_address);
-            Some(MetricServer::new_unix(&poll, unix_listener, METRIC_SERVER_TOKEN, METRIC_CLIENT_TOKEN_START..METRIC_CLIENT_TOKEN_START+args.metrics_max_clients))
+            prepare_unix_socket_path(path, args.metrics_socket_dir_mode);
+            let unix_listener = bind_with_retry(args, || UnixListener::bind(path).map_err(|e| EndlesshError::bind(listener, None, e)))
+                .unwrap_or_else(|e| fatal(FatalError::Bind, e));
+            MetricServer::new_unix(poll, unix_listener, listener_token, client_tokens, options)
+                .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, e))
         },
         #[cfg(not(unix))]
         MultiListener::Unix(_) => {
-            panic!("unix sockets are not supported on this platform")
+            fatal(FatalError::Config, "unix sockets are not supported on this platform")
+        },
+        MultiListener::Fd(fd) => {
+            let socket = take_passed_socket(fd, passed_sockets).unwrap_or_else(|e| fatal(FatalError::Config, e));
+            return serve_metrics(poll, socket, listener_token, client_tokens, options);
+        },
+    };
+    info!("endlessh-rs listening for metrics connections on {}", listener);
+    MetricEndpoint::new(metric_server, Some(listener.clone()), listener.to_string(), listener_token, endpoint_tokens)
+}
+
+/// the socket an `fd:` listener names - one systemd passed in, or for a number, any listening socket this process was
+/// started with
+#[cfg(feature = "metrics")]
+fn take_passed_socket(fd: &PassedFd, passed_sockets: &mut Vec<(PassedFd, ListenSocket)>) -> Result<ListenSocket, String> {
+    if let Some(i) = passed_sockets.iter().position(|(claim, _)| claim == fd) {
+        return Ok(passed_sockets.remove(i).1);
+    }
+    match fd {
+        #[cfg(target_os = "linux")]
+        PassedFd::Number(number) => systemd::adopt(*number),
+        #[cfg(not(target_os = "linux"))]
+        PassedFd::Number(_) => Err("fd: listeners are only supported on linux".to_string()),
+        PassedFd::Name(name) => Err(format!("systemd passed in no socket named {} for --metrics-listen-address fd:{}", name, name)),
+    }
+}
+
+/// a metric server on a socket passed in already bound
+#[cfg(feature = "metrics")]
+fn serve_metrics(poll: &Poll, listener: ListenSocket, listener_token: Token, client_tokens: std::ops::Range<usize>, options: MetricServerOptions) -> MetricEndpoint {
+    let address = listener.local_address().map_or_else(|e| e.to_string(), |address| address.to_string());
+    let endpoint_tokens = client_tokens.clone();
+    let metric_server = match listener {
+        ListenSocket::Tcp(listener) => MetricServer::new_tcp(poll, listener, listener_token, client_tokens, options),
+        #[cfg(unix)]
+        ListenSocket::Unix(listener) => MetricServer::new_unix(poll, listener, listener_token, client_tokens, options),
+    }.unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, e));
+    info!("endlessh-rs listening for metrics connections on {} (passed in)", address);
+    MetricEndpoint::new(metric_server, None, address, listener_token, endpoint_tokens)
+}
+
+/// the sockets systemd passed in
+#[derive(Default)]
+struct InheritedListeners {
+    /// with their names, taking the place of --ssh-listen-address
+    ssh: Vec<(ListenSocket, Option<String>)>,
+    /// those named `metrics`, taking the place of --metrics-listen-address
+    metrics: Vec<ListenSocket>,
+    /// set aside for the --metrics-listen-address fd: listeners
+    claimed: Vec<(PassedFd, ListenSocket)>,
+}
+
+/// the `fd:` listeners among --metrics-listen-address
+#[cfg(all(feature = "metrics", target_os = "linux"))]
+fn passed_fd_claims(args: &Args) -> Vec<&PassedFd> {
+    args.metrics_listen_address.iter()
+        .filter_map(|listener| match listener {
+            MultiListener::Fd(fd) => Some(fd),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(all(not(feature = "metrics"), target_os = "linux"))]
+fn passed_fd_claims(_: &Args) -> Vec<&PassedFd> {
+    Vec::new()
+}
+
+/// sorts the sockets systemd passed in, setting aside those `claims` name
+#[cfg(target_os = "linux")]
+fn inherited_listeners(claims: &[&PassedFd]) -> InheritedListeners {
+    let mut ssh_listeners = Vec::new();
+    let mut metric_listeners = Vec::new();
+    let mut claimed = Vec::new();
+    for socket in systemd::listen_fds().unwrap_or_else(|e| fatal(FatalError::Config, e)) {
+        let claim = claims.iter().find(|claim| match claim {
+            PassedFd::Number(number) => *number == socket.fd,
+            PassedFd::Name(name) => socket.name.as_ref() == Some(name),
+        });
+        if let Some(claim) = claim {
+            claimed.push(((*claim).clone(), socket.listener));
+            continue;
+        }
+        match socket.name {
+            Some(name) if name == systemd::METRICS_FD_NAME => metric_listeners.push(socket.listener),
+            Some(name) if !endlessh::is_valid_listener_name(&name) => {
+                fatal(FatalError::Config, format!("FileDescriptorName={} isn't a valid listener name", name));
+            },
+            name => ssh_listeners.push((socket.listener, name)),
+        }
+    }
+    InheritedListeners { ssh: ssh_listeners, metrics: metric_listeners, claimed }
+}
+
+fn bind_ssh_listener(args: &Args, address: &ListenAddress) -> ListenSocket {
+    match address {
+        ListenAddress::Ip(ip) => {
+            wait_for_address(args, *ip);
+            bind_with_retry(args, || endlessh::bind_listener(*ip)).map(ListenSocket::Tcp)
+        },
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            // the socket's directory is usually shared with the proxy in front, so it's left readable by it
+            prepare_unix_socket_path(path, SSH_SOCKET_DIR_MODE);
+            bind_with_retry(args, || mio::net::UnixListener::bind(path).map_err(|e| EndlesshError::bind(address, None, e))).map(ListenSocket::Unix)
+        },
+    }.unwrap_or_else(|e| fatal(FatalError::Bind, e))
+}
+
+/// the cargo features compiled in, for the startup summary
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "cli")]
+    "cli",
+    #[cfg(feature = "fixed-config")]
+    "fixed-config",
+    #[cfg(feature = "metrics")]
+    "metrics",
+    #[cfg(feature = "metrics-tls")]
+    "metrics-tls",
+    #[cfg(feature = "remote-lists")]
+    "remote-lists",
+    #[cfg(feature = "report")]
+    "report",
+    #[cfg(feature = "notify")]
+    "notify",
+    #[cfg(feature = "elasticsearch")]
+    "elasticsearch",
+    #[cfg(feature = "hpfeeds")]
+    "hpfeeds",
+    #[cfg(feature = "sqlite")]
+    "sqlite",
+];
+
+/// what a misconfigured deployment needs to be debugged from its logs alone: the build, the resolved tarpit options, the
+/// limits they run into and every listener, one line each
+fn log_startup(args: &Args, endlessh_server: &EndlesshServer, replaying: bool) {
+    let features = FEATURES.join(",");
+    info!(version = env!("CARGO_PKG_VERSION"), features = features.as_str(), os = std::env::consts::OS, arch = std::env::consts::ARCH,
+        pid = std::process::id(), log_level:% = args.log_level, log_format:% = args.log_format; "endlessh-rs {} starting", env!("CARGO_PKG_VERSION"));
+    let options = endlessh_server.options();
+    let variants = options.variants.iter().map(|variant| format!("{}={}", variant.name, variant.weight)).collect::<Vec<_>>().join(",");
+    info!(max_clients = options.max_clients, message_delay_ms = options.message_delay.as_millis() as u64,
+        message_delay_jitter_ms = options.message_delay_jitter.map(|d| d.as_millis() as u64),
+        adaptive_delay_max_ms = options.adaptive_delay_max.map(|d| d.as_millis() as u64), banner_line_length = options.banner_line_length, banner_style:% = options.banner.style(), newline:% = options.newline,
+        variants = (!variants.is_empty()).then_some(variants.as_str()),
+        banner_line_length_max = options.banner_line_length_max,
+        max_clients_per_ip = options.max_clients_per_ip, allow_cidrs = options.allow_cidrs.as_ref().map(AddressList::len),
+        deny_cidrs = options.deny_cidrs.as_ref().map(AddressList::len), trap_probability = options.trap_probability, rng_seed = options.rng_seed, stall_limit = options.stall_limit, zero_window_timeout_secs = options.zero_window_timeout.map(|d| d.as_secs()),
+        max_trap_seconds = options.max_trap_duration.map(|d| d.as_secs()),
+        chatty_bytes = options.chatty_threshold, repeat_offender_ttl_secs = options.repeat_offenders.map(|r| r.ttl.as_secs()),
+        repeat_offender_max_factor = options.repeat_offenders.map(|r| r.max_factor), max_startups = options.max_startups.map(|m| m.to_string()).as_deref(), max_send_bytes_per_sec = options.max_send_rate,
+        accept_rate = options.accept_rate, accept_burst = options.accept_burst, rcvbuf_bytes = options.receive_buffer, sndbuf_bytes = options.send_buffer,
+        sensor_id = options.sensor_id.as_deref(), dry_run = options.dry_run, proxy_protocol = options.proxy_protocol, low_memory = args.low_memory; "tarpit configuration");
+    let open_files_limit = process::open_files_limit();
+    info!(open_files_limit, preallocated_clients = options.preallocate_clients.then_some(options.max_clients); "resource limits");
+    // each client holds a descriptor, and the listeners, poll and log need a few more
+    if open_files_limit.is_some_and(|limit| options.max_clients as u64 + 64 > limit) {
+        warn!("--ssh-max-clients {} is close to or above the open file limit of {}, accepting will fail before the client slots fill",
+            options.max_clients, open_files_limit.unwrap_or_default());
+    }
+    if replaying {
+        return;
+    }
+    for listener in endlessh_server.listeners() {
+        info!(address:% = listener.address, name = listener.name.as_deref().unwrap_or_default(); "endlessh-rs listening for ssh connections on {}", listener.address);
+    }
+}
+
+/// the tarpit's options from the `ssh_*` flags, checking the ones that depend on each other
+fn ssh_options(args: &Args) -> Result<EndlesshOptions, String> {
+    if !(0.0..=1.0).contains(&args.ssh_trap_probability) {
+        return Err("--ssh-trap-probability must be between 0 and 1".to_string());
+    }
+    if args.ssh_message_delay_jitter_ms.is_some_and(|jitter| jitter >= args.ssh_message_delay_ms) {
+        return Err("--ssh-message-delay-jitter-ms must be shorter than --ssh-message-delay-ms".to_string());
+    }
+    if args.ssh_adaptive_delay_max_ms.is_some_and(|max| max < args.ssh_message_delay_ms) {
+        return Err("--ssh-adaptive-delay-max-ms can't be shorter than --ssh-message-delay-ms".to_string());
+    }
+    let (banner_line_length, banner_line_length_max) = match (args.ssh_banner_line_length_min, args.ssh_banner_line_length_max) {
+        (None, None) => (args.ssh_banner_line_length, None),
+        (Some(min), Some(max)) if min <= max => (min, Some(max)),
+        (Some(_), Some(_)) => return Err("--ssh-banner-line-length-min can't be above --ssh-banner-line-length-max".to_string()),
+        _ => return Err("--ssh-banner-line-length-min and --ssh-banner-line-length-max must be given together".to_string()),
+    };
+    if args.ssh_max_clients_per_ip == Some(0) {
+        return Err("--ssh-max-clients-per-ip must be above zero".to_string());
+    }
+    if args.sensor_id.as_deref().is_some_and(|id| !endlessh::is_valid_listener_name(id)) {
+        return Err("--sensor-id must be up to 64 letters, digits, '-', '_' or '.'".to_string());
+    }
+    if args.ssh_max_trap_seconds == Some(0) {
+        return Err("--ssh-max-trap-seconds must be above zero".to_string());
+    }
+    if args.max_send_bytes_per_sec == Some(0) {
+        return Err("--max-send-bytes-per-sec must be above zero".to_string());
+    }
+    if args.ssh_accept_rate == Some(0) || args.ssh_accept_burst == Some(0) {
+        return Err("--ssh-accept-rate and --ssh-accept-burst must be above zero".to_string());
+    }
+    let repeat_offenders = match args.ssh_repeat_offender_ttl_secs {
+        None => None,
+        Some(0) => return Err("--ssh-repeat-offender-ttl-secs must be above zero".to_string()),
+        Some(_) if args.ssh_repeat_offender_max_factor < 2 => return Err("--ssh-repeat-offender-max-factor must be at least 2".to_string()),
+        Some(_) if args.low_memory => return Err("--ssh-repeat-offender-ttl-secs needs per-address tracking, which --low-memory disables".to_string()),
+        Some(ttl) => Some(RepeatOffenders { ttl: Duration::from_secs(ttl), max_factor: args.ssh_repeat_offender_max_factor }),
+    };
+    let words = args.ssh_banner_wordlist.as_deref().map(read_banner_words).transpose()?;
+    let banner = banner_generator(args, banner_style(args), words.clone())?;
+    let variants = args.ssh_variant.iter()
+        .map(|spec| strategy_variant(spec, args, (banner_line_length, banner_line_length_max), &words))
+        .collect::<Result<Vec<_>, _>>()?;
+    let styles: Vec<BannerStyle> = [banner.style()].into_iter().chain(variants.iter().map(|variant| variant.banner.style())).collect();
+    if args.ssh_banner_template.is_some() && !styles.contains(&BannerStyle::Template) {
+        return Err("--ssh-banner-template is only used by the template style".to_string());
+    }
+    if args.ssh_banner_wordlist.is_some() && !styles.iter().any(|style| matches!(style, BannerStyle::Words | BannerStyle::Template)) {
+        return Err("--ssh-banner-wordlist is only used by the words and template styles".to_string());
+    }
+    if !variants.is_empty() && args.ssh_adaptive_delay_max_ms.is_some() {
+        return Err("--ssh-variant can't be combined with --ssh-adaptive-delay-max-ms, the variants' delays would stop being comparable".to_string());
+    }
+    let allow_cidrs = cidr_list("--allow-cidr", &args.allow_cidr, args.allow_cidr_file.as_deref())?;
+    let deny_cidrs = cidr_list("--deny-cidr", &args.deny_cidr, args.deny_cidr_file.as_deref())?;
+    Ok(EndlesshOptions {
+        banner_line_length,
+        banner_line_length_max,
+        banner,
+        variants,
+        max_clients: args.ssh_max_clients,
+        max_clients_per_ip: args.ssh_max_clients_per_ip,
+        allow_cidrs,
+        deny_cidrs,
+        message_delay: Duration::from_millis(args.ssh_message_delay_ms),
+        message_delay_jitter: args.ssh_message_delay_jitter_ms.filter(|&jitter| jitter > 0).map(Duration::from_millis),
+        adaptive_delay_max: args.ssh_adaptive_delay_max_ms.map(Duration::from_millis),
+        stall_limit: args.ssh_stall_limit,
+        zero_window_timeout: args.ssh_zero_window_timeout_secs.map(Duration::from_secs),
+        max_trap_duration: args.ssh_max_trap_seconds.map(Duration::from_secs),
+        chatty_threshold: args.ssh_chatty_bytes,
+        chatty_penalty: args.ssh_chatty_penalty,
+        sensor_id: args.sensor_id.clone(),
+        repeat_offenders,
+        max_startups: args.ssh_max_startups,
+        trap_probability: args.ssh_trap_probability,
+        rng_seed: args.rng_seed,
+        dry_run: args.ssh_dry_run,
+        proxy_protocol: args.proxy_protocol,
+        newline: args.ssh_newline.clone(),
+        preallocate_clients: !args.low_memory,
+        line_events: args.event_log.is_some() && args.event_log_lines,
+        max_send_rate: args.max_send_bytes_per_sec,
+        accept_rate: args.ssh_accept_rate,
+        accept_burst: args.ssh_accept_burst,
+        receive_buffer: args.ssh_rcvbuf_bytes,
+        send_buffer: args.ssh_sndbuf_bytes,
+        #[cfg(feature = "metrics")]
+        histogram_buckets: histogram_buckets(args),
+        #[cfg(not(feature = "metrics"))]
+        histogram_buckets: Default::default(),
+    })
+}
+
+/// the blocks of --allow-cidr or --deny-cidr and its -file, None if neither was given
+fn cidr_list(flag: &str, blocks: &[String], file: Option<&Path>) -> Result<Option<AddressList>, String> {
+    if blocks.is_empty() && file.is_none() {
+        return Ok(None);
+    }
+    let mut text = blocks.join("\n");
+    if let Some(path) = file {
+        let lines = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}-file {} - {}", flag, path.display(), e))?;
+        text.push('\n');
+        text.push_str(&lines);
+    }
+    match AddressList::parse(&text) {
+        (_, invalid) if invalid > 0 => Err(format!("{} has {} entries that aren't cidr blocks or addresses", flag, invalid)),
+        (list, _) if list.is_empty() => Err(format!("{} has no cidr blocks", flag)),
+        (list, _) => Ok(Some(list)),
+    }
+}
+
+/// the --report-networks-file, whose odd unreadable line is only worth a warning - the table comes from elsewhere
+#[cfg(feature = "report")]
+fn network_table(path: &Path) -> Result<NetworkTable, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("failed to read --report-networks-file {} - {}", path.display(), e))?;
+    let (table, invalid) = NetworkTable::parse(&text);
+    if table.is_empty() {
+        return Err(format!("--report-networks-file {} has no routed address ranges", path.display()));
+    }
+    if invalid > 0 {
+        warn!("skipped {} lines of --report-networks-file {} that aren't address ranges", invalid, path.display());
+    }
+    info!("read {} address ranges from {}", table.len(), path.display());
+    Ok(table)
+}
+
+/// the --ssh-banner-style, or the style the other banner flags given imply
+fn banner_style(args: &Args) -> BannerStyle {
+    args.ssh_banner_style.unwrap_or(match (&args.ssh_banner_template, &args.ssh_banner_wordlist) {
+        (Some(_), _) => BannerStyle::Template,
+        (None, Some(_)) => BannerStyle::Words,
+        (None, None) => BannerStyle::Alphanumeric,
+    })
+}
+
+/// a banner of the style, with the word list and template it needs
+fn banner_generator(args: &Args, style: BannerStyle, words: Option<Vec<String>>) -> Result<BannerGenerator, String> {
+    match style {
+        BannerStyle::Alphanumeric => Ok(BannerGenerator::Alphanumeric),
+        BannerStyle::Hex => Ok(BannerGenerator::Hex),
+        BannerStyle::Words => words.map(BannerGenerator::Words).ok_or_else(|| "--ssh-banner-style words needs --ssh-banner-wordlist".to_string()),
+        BannerStyle::Template => {
+            let template = args.ssh_banner_template.as_deref().ok_or("--ssh-banner-style template needs --ssh-banner-template")?;
+            BannerTemplate::parse(template, words).map(BannerGenerator::Template).map_err(|e| e.to_string())
+        },
+    }
+}
+
+/// a --ssh-variant, starting from the tarpit's own delay, banner style and line lengths
+fn strategy_variant(spec: &str, args: &Args, line_lengths: (usize, Option<usize>), words: &Option<Vec<String>>) -> Result<StrategyVariant, String> {
+    let invalid = |reason: String| format!("invalid --ssh-variant \"{}\" - {}", spec, reason);
+    let mut settings = spec.split(',');
+    let (name, weight) = settings.next().and_then(|first| first.split_once('='))
+        .ok_or_else(|| invalid("expected <name>=<weight> first".to_string()))?;
+    if !endlessh::is_valid_listener_name(name) {
+        return Err(invalid("the name must be up to 64 letters, digits, '-', '_' or '.'".to_string()));
+    }
+    let mut variant = StrategyVariant {
+        name: name.to_string(),
+        weight: weight.parse().ok().filter(|&weight| weight > 0).ok_or_else(|| invalid("the weight must be a whole number above zero".to_string()))?,
+        message_delay: Duration::from_millis(args.ssh_message_delay_ms),
+        message_delay_jitter: args.ssh_message_delay_jitter_ms.filter(|&jitter| jitter > 0).map(Duration::from_millis),
+        banner: BannerGenerator::Alphanumeric,
+        banner_line_length: line_lengths.0,
+        banner_line_length_max: line_lengths.1,
+    };
+    let mut style = banner_style(args);
+    for setting in settings {
+        let (key, value) = setting.split_once('=').ok_or_else(|| invalid(format!("expected <setting>=<value>, not \"{}\"", setting)))?;
+        let bad_value = |e: &dyn std::fmt::Display| invalid(format!("bad {} \"{}\" - {}", key, value, e));
+        match key {
+            "delay-ms" => variant.message_delay = Duration::from_millis(value.parse().map_err(|e| bad_value(&e))?),
+            "jitter-ms" => variant.message_delay_jitter = Some(value.parse().map_err(|e| bad_value(&e))?).filter(|&jitter| jitter > 0).map(Duration::from_millis),
+            "style" => style = value.parse().map_err(|e| bad_value(&e))?,
+            "line-length" => {
+                let (shortest, longest) = value.split_once('-').unwrap_or((value, value));
+                variant.banner_line_length = shortest.parse().map_err(|e| bad_value(&e))?;
+                let longest: usize = longest.parse().map_err(|e| bad_value(&e))?;
+                variant.banner_line_length_max = (longest != variant.banner_line_length).then_some(longest);
+            },
+            _ => return Err(invalid(format!("unknown setting \"{}\", expected delay-ms, jitter-ms, style or line-length", key))),
+        }
+    }
+    if variant.message_delay_jitter.is_some_and(|jitter| jitter >= variant.message_delay) {
+        return Err(invalid("its jitter must be shorter than its delay".to_string()));
+    }
+    variant.banner = banner_generator(args, style, words.clone()).map_err(invalid)?;
+    Ok(variant)
+}
+
+/// the words of a --ssh-banner-wordlist, skipping any that can't go in a banner line
+fn read_banner_words(path: &Path) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("failed to read --ssh-banner-wordlist {} - {}", path.display(), e))?;
+    let words: Vec<String> = text.split_whitespace()
+        .filter(|word| word.bytes().all(|b| b.is_ascii_graphic()) && !word.starts_with("SSH-"))
+        .map(str::to_string)
+        .collect();
+    if words.is_empty() {
+        return Err(format!("--ssh-banner-wordlist {} has no usable words", path.display()));
+    }
+    Ok(words)
+}
+
+/// the `ssh_*` options from a fresh read of the command line and --config file, and of the files they name
+#[cfg(all(feature = "cli", any(unix, feature = "metrics")))]
+fn reloaded_options() -> Result<EndlesshOptions, String> {
+    parse_args().and_then(|args| ssh_options(&args))
+}
+
+/// logs every trapped client, longest trapped first, one line each with its details as fields - `key=value` pairs in
+/// the text format, or a json object per line
+#[cfg(unix)]
+fn log_clients(endlessh_server: &EndlesshServer, now: &Instant) {
+    let mut clients = endlessh_server.schedule(now);
+    clients.sort_by(|a, b| a.connected_time_seconds.total_cmp(&b.connected_time_seconds));
+    info!(clients = clients.len(); "SIGUSR1 received, listing {} trapped clients", clients.len());
+    let unix_now = endlessh::unix_seconds(std::time::SystemTime::now()) as f64;
+    for client in &clients {
+        info!(peer:% = client.peer, listener = client.listener.as_deref().unwrap_or_default(), connected_time_seconds = client.connected_time_seconds as u64,
+            trapped_seconds = (unix_now - client.connected_time_seconds).max(0.0) as u64, lines_sent = client.lines_sent, bytes_sent = client.bytes_sent,
+            chatty = client.chatty, variant = client.variant.as_deref(); "trapped client");
+    }
+}
+
+/// applies reloaded `ssh_*` options (on SIGHUP or from the admin api) to the running tarpit. anything else (listen
+/// addresses, metrics, logging) still needs a restart
+#[cfg(all(feature = "cli", any(unix, feature = "metrics")))]
+fn reload_config(endlessh_server: &mut EndlesshServer, options: Result<EndlesshOptions, String>, now: &Instant) {
+    let old = endlessh_server.options();
+    let (old_max_clients, old_message_delay) = (old.max_clients, old.message_delay);
+    let reloaded = options.and_then(|options| endlessh_server.reconfigure(options, now).map_err(|e| e.to_string()));
+    match reloaded {
+        Ok(()) => {
+            let new = endlessh_server.options();
+            info!("reloaded the configuration - max clients {} (was {}), message delay {:?} (was {:?}), {} clients still trapped",
+                new.max_clients, old_max_clients, new.message_delay, old_message_delay, endlessh_server.client_count());
         },
+        Err(e) => warn!("failed to reload the configuration, keeping the current one - {}", e),
+    }
+}
+
+/// the command line, plus whatever it doesn't set from the --config file. exits straight away for --help and --version
+#[cfg(feature = "cli")]
+fn parse_args() -> Result<Args, String> {
+    let clap_error = |e: clap::Error| {
+        if !e.use_stderr() {
+            e.exit()
+        }
+        e.render().to_string().trim_end().to_string()
+    };
+    let mut command = Args::command();
+    let cli: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let matches = command.try_get_matches_from_mut(&cli).map_err(clap_error)?;
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Args::from_arg_matches(&matches).map_err(clap_error);
+    };
+    let file_flags = config_file::flags(path, &command, &matches)?;
+    // straight after the program name, so they can't end up as arguments of a subcommand
+    let combined = cli.iter().take(1).cloned().chain(file_flags.into_iter().map(Into::into)).chain(cli.iter().skip(1).cloned());
+    command.try_get_matches_from_mut(combined).and_then(|matches| Args::from_arg_matches(&matches)).map_err(clap_error)
+}
+
+fn main() {
+    #[cfg(feature = "cli")]
+    let args = &parse_args().unwrap_or_else(|e| fatal(FatalError::Config, e));
+    #[cfg(all(feature = "fixed-config", not(feature = "cli")))]
+    let args = &fixed_config::args();
+    #[cfg(not(any(feature = "cli", feature = "fixed-config")))]
+    let args = &env_args::parse().unwrap_or_else(|e| fatal(FatalError::Config, e));
+    let log_file = args.log_file.clone().map(|path| logging::LogFileOptions {
+        path,
+        rotate_bytes: args.log_rotate_bytes,
+        rotate_interval: args.log_rotate_interval_secs.map(Duration::from_secs),
+        keep: args.log_rotate_keep,
+    });
+    if log_file.is_some() && args.log_output != LogOutput::Stdout {
+        fatal(FatalError::Config, format!("--log-file and --log-output {} can't be used together", args.log_output));
+    }
+    let log_target = if log_file.is_some() { "log file".to_string() } else { format!("{} log output", args.log_output) };
+    logging::init(args.log_level, args.log_format, args.log_output, log_file)
+        .unwrap_or_else(|e| fatal(FatalError::Config, format!("failed to open {} - {}", log_target, e)));
+    #[cfg(feature = "metrics")]
+    if let Some(Command::Ctl(ctl_args)) = &args.command {
+        ctl::run(ctl_args);
+    }
+    #[cfg(feature = "metrics")]
+    let replay = match &args.command {
+        Some(Command::Replay(replay_args)) => Some(load_replay(args, replay_args)),
+        _ => None,
+    };
+    #[cfg(feature = "metrics")]
+    let fleet = match &args.command {
+        Some(Command::Aggregate(aggregate_args)) => Some(load_fleet(args, aggregate_args)),
+        _ => None,
+    };
+    // an aggregator doesn't trap anyone either, it only plays the sensors' events through
+    #[cfg(feature = "metrics")]
+    let replaying = replay.is_some() || fleet.is_some();
+    #[cfg(not(feature = "metrics"))]
+    let replaying = false;
+    if !args.ssh_listener_name.iter().all(|name| endlessh::is_valid_listener_name(name)) {
+        fatal(FatalError::Config, "--ssh-listener-name must be up to 64 letters, digits, '-', '_' or '.'");
+    }
+    if args.ssh_listener_name.len() > args.ssh_listen_address.len() {
+        fatal(FatalError::Config, "there are more --ssh-listener-name than --ssh-listen-address");
+    }
+    let ssh_options = ssh_options(args).unwrap_or_else(|e| fatal(FatalError::Config, e));
+    let poll = Poll::new().unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, format!("failed to create poll - {}", e)));
+    #[cfg(unix)]
+    let signals = {
+        let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM, SIGUSR1])
+            .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, format!("failed to register signal handlers - {}", e)));
+        poll.registry().register(&mut signals, SIGNAL_TOKEN, mio::Interest::READABLE)
+            .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, format!("failed to poll for signals - {}", e)));
+        signals
+    };
+    let events = Events::with_capacity(low_memory_cap(args, 128, LOW_MEMORY_EVENTS_CAPACITY));
+
+    #[cfg(target_os = "linux")]
+    let inherited = inherited_listeners(&passed_fd_claims(args));
+    #[cfg(target_os = "linux")]
+    let (notifier, watchdog_interval) = systemd::Notifier::from_env().unwrap_or_else(|e| fatal(FatalError::Config, e));
+    #[cfg(not(target_os = "linux"))]
+    let inherited = InheritedListeners::default();
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    let InheritedListeners { ssh: inherited_ssh_listeners, metrics: inherited_metric_listeners, claimed: passed_sockets } = inherited;
+    #[cfg(not(feature = "metrics"))]
+    if !inherited_metric_listeners.is_empty() {
+        fatal(FatalError::Config, "systemd passed in a metrics socket, but metrics aren't compiled in");
+    }
+
+    // nothing real should get mixed in with a replay, so listen where no one will find us
+    let ssh_listen_addresses = if replaying { &[ListenAddress::Ip(REPLAY_SSH_LISTEN_ADDRESS)][..] } else { &args.ssh_listen_address[..] };
+    let ssh_listeners_inherited = !replaying && !inherited_ssh_listeners.is_empty();
+    let ssh_listeners: Vec<(ListenSocket, Option<String>)> = if ssh_listeners_inherited {
+        inherited_ssh_listeners
+    } else {
+        ssh_listen_addresses.iter().enumerate()
+            .map(|(i, address)| (bind_ssh_listener(args, address), args.ssh_listener_name.get(i).cloned()))
+            .collect()
     };
+    let mut ssh_listeners = ssh_listeners.into_iter();
+    let (first_ssh_listener, first_ssh_listener_name) = ssh_listeners.next().unwrap_or_else(|| fatal(FatalError::Config, "no --ssh-listen-address given"));
+    let mut endlessh_server = EndlesshServer::create(
+        ssh_options,
+        first_ssh_listener,
+        first_ssh_listener_name.as_deref(),
+        Token(SSH_LISTENER_TOKEN_START),
+        &poll
+    ).unwrap_or_else(|e| match e {
+        EndlesshError::Config(_) => fatal(FatalError::Config, e),
+        _ => fatal(FatalError::ResourceExhaustion, e),
+    });
+    for (i, (ssh_listener, name)) in ssh_listeners.enumerate().map(|(i, listener)| (i + 1, listener)) {
+        endlessh_server.add_listener(ssh_listener, name.as_deref(), Token(SSH_LISTENER_TOKEN_START + i), &poll)
+            .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, e));
+    }
+    endlessh_server.watch_clients(SSH_CLIENT_TOKEN_START..SSH_LISTENER_TOKEN_START, &poll)
+        .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, e));
+
+    log_startup(args, &endlessh_server, replaying);
+
+    if let Some(stats_file) = &args.stats_file {
+        crash::install_panic_hook(endlessh_server.stats().clone(), stats_file.clone());
+    }
+
+    #[cfg(feature = "metrics")]
+    if args.metrics_max_requests_per_connection == 0 {
+        fatal(FatalError::Config, "--metrics-max-requests-per-connection must be above zero");
+    }
+    #[cfg(feature = "metrics")]
+    if args.metrics_idle_timeout_secs == 0 {
+        fatal(FatalError::Config, "--metrics-idle-timeout-secs must be above zero");
+    }
+    #[cfg(feature = "metrics")]
+    if args.metrics_timeseries_interval_secs == 0 {
+        fatal(FatalError::Config, "--metrics-timeseries-interval-secs must be at least 1");
+    }
+    #[cfg(feature = "metrics")]
+    if args.health_check_interval_secs == 0 {
+        fatal(FatalError::Config, "--health-check-interval-secs must be at least 1");
+    }
+    #[cfg(feature = "metrics")]
+    if args.metrics_textfile.is_some() && args.metrics_textfile_interval_secs == 0 {
+        fatal(FatalError::Config, "--metrics-textfile-interval-secs must be at least 1");
+    }
+    #[cfg(feature = "metrics")]
+    let metric_server_options = MetricServerOptions {
+        request_max_size: low_memory_cap(args, args.metrics_request_max_bytes, LOW_MEMORY_METRICS_REQUEST_MAX_BYTES),
+        max_headers: low_memory_cap(args, args.metrics_max_headers, LOW_MEMORY_METRICS_MAX_HEADERS),
+        max_requests_per_connection: args.metrics_max_requests_per_connection,
+        idle_timeout: Duration::from_secs(args.metrics_idle_timeout_secs),
+        #[cfg(feature = "metrics-tls")]
+        tls: match (&args.metrics_tls_cert, &args.metrics_tls_key) {
+            (Some(cert), Some(key)) => Some(tls::load_server_config(cert, key).unwrap_or_else(|e| fatal(FatalError::Config, e))),
+            _ => None,
+        },
+    };
+    #[cfg(feature = "metrics")]
+    let metrics_max_clients = low_memory_cap(args, args.metrics_max_clients, LOW_MEMORY_METRICS_MAX_CLIENTS);
+    // the unix sockets to remove on shutdown, only those bound here - systemd's are for it to clean up
+    #[cfg_attr(not(feature = "metrics"), allow(unused_mut))]
+    let mut socket_paths: Vec<PathBuf> = ssh_listen_addresses.iter()
+        .filter(|_| !ssh_listeners_inherited)
+        .filter_map(|address| address.path().map(Path::to_path_buf))
+        .collect();
+    #[cfg(feature = "metrics")]
+    if inherited_metric_listeners.is_empty() {
+        socket_paths.extend(args.metrics_listen_address.iter().filter_map(|listener| match listener {
+            MultiListener::Unix(path) => Some(path.clone()),
+            _ => None,
+        }));
+    }
+    #[cfg(all(feature = "metrics", unix))]
+    if let Some(MultiListener::Unix(path)) = &args.admin_listen_address {
+        socket_paths.push(path.clone());
+    }
+    #[cfg(feature = "metrics")]
+    if !inherited_metric_listeners.is_empty() && !passed_sockets.is_empty() {
+        fatal(FatalError::Config, "systemd passed in a socket named metrics, which takes the place of every --metrics-listen-address including the fd: ones");
+    }
+    #[cfg(feature = "metrics")]
+    let metric_servers: Vec<MetricEndpoint> = if !inherited_metric_listeners.is_empty() {
+        inherited_metric_listeners.into_iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let listener_token = METRIC_TOKEN_START + i * (1 + metrics_max_clients);
+                let client_tokens = listener_token + 1..listener_token + 1 + metrics_max_clients;
+                serve_metrics(&poll, listener, Token(listener_token), client_tokens, metric_server_options.clone())
+            })
+            .collect()
+    } else {
+        let mut passed_sockets = passed_sockets;
+        let metric_servers = args.metrics_listen_address.iter()
+            .filter(|listener| **listener != MultiListener::Disabled)
+            .enumerate()
+            .map(|(i, listener)| {
+                let listener_token = METRIC_TOKEN_START + i * (1 + metrics_max_clients);
+                let client_tokens = listener_token + 1..listener_token + 1 + metrics_max_clients;
+                bind_metric_server(args, &poll, listener, &mut passed_sockets, Token(listener_token), client_tokens, metric_server_options.clone())
+            })
+            .collect();
+        // e.g. a unit with both an ipv4 and an ipv6 socket, but one fd: listener for its name
+        if let Some((fd, _)) = passed_sockets.first() {
+            fatal(FatalError::Config, format!("systemd passed in more sockets for --metrics-listen-address {} than it was given", MultiListener::Fd(fd.clone())));
+        }
+        metric_servers
+    };
+    // after the metric servers' tokens, and bound like one of them
+    #[cfg(feature = "metrics")]
+    let admin_listener_token = METRIC_TOKEN_START + metric_servers.len() * (1 + metrics_max_clients);
+    #[cfg(feature = "metrics")]
+    let metric_servers: Vec<MetricEndpoint> = metric_servers.into_iter()
+        .chain(args.admin_listen_address.as_ref().filter(|listener| **listener != MultiListener::Disabled).map(|listener| {
+            let listener_token = admin_listener_token;
+            let client_tokens = listener_token + 1..listener_token + 1 + metrics_max_clients;
+            let endpoint = bind_metric_server(args, &poll, listener, &mut Vec::new(), Token(listener_token), client_tokens, metric_server_options.clone());
+            info!("serving the admin api on {}", listener);
+            MetricEndpoint { admin: true, ..endpoint }
+        }))
+        .collect();
+    #[cfg(feature = "metrics")]
+    let mut metrics = (!metric_servers.is_empty() || args.metrics_textfile.is_some()).then(|| Metrics {
+        servers: metric_servers,
+        server_options: metric_server_options,
+        labels: MetricLabels::new(&metric_labels(args, fleet.is_some())),
+        timeseries: TimeSeries::new(endlessh_server.stats().clone(), low_memory_cap(args, args.metrics_timeseries_length, LOW_MEMORY_TIMESERIES_LENGTH)),
+        history: open_history_store(args, replay.is_some()),
+        // per-client history isn't worth the memory on a router
+        recent_events: low_memory_cap(args, args.metrics_recent_events, 0),
+        textfile: args.metrics_textfile.clone().map(TextfileWriter::new),
+        health: Health::new(args.health_max_rss_mb.map(|mb| mb * 1024 * 1024)
+            .or(args.low_memory.then_some(LOW_MEMORY_RSS_TARGET_BYTES))),
+        listener_changes: RefCell::new(Vec::new()),
+        reload: RefCell::new(None),
+        replay: replay.map(|(events, speed)| EventReplay::new(events, speed, &Instant::now())),
+        fleet,
+    });
+
+    #[cfg(feature = "sandbox")]
+    let sandboxed = args.sandbox;
+    #[cfg(not(feature = "sandbox"))]
+    let sandboxed = false;
+    // the filter refuses bind and listen, which the admin api's new listeners and rebinding need
+    #[cfg(feature = "metrics")]
+    if sandboxed && args.admin_listen_address.as_ref().is_some_and(|listener| *listener != MultiListener::Disabled) {
+        fatal(FatalError::Config, "--admin-listen-address can't work with --sandbox, added listeners are bound from the event loop");
+    }
+    #[cfg(feature = "metrics")]
+    if sandboxed && args.metrics_rebind_secs.is_some() {
+        fatal(FatalError::Config, "--metrics-rebind-secs can't work with --sandbox, listeners are bound again from the event loop");
+    }
+
+    // every listener is bound, nothing after this needs root
+    if args.user.is_some() || args.group.is_some() {
+        #[cfg(target_os = "linux")]
+        match process::drop_privileges(args.user.as_deref(), args.group.as_deref()) {
+            Ok((uid, gid)) => info!("switched to uid {} gid {}", uid, gid),
+            Err(e) => fatal(FatalError::PrivilegeDrop, e),
+        }
+        #[cfg(not(target_os = "linux"))]
+        fatal(FatalError::Config, "--user and --group are only supported on linux");
+    }
+
+    let waker = Arc::new(Waker::new(poll.registry(), WAKE_TOKEN)
+        .unwrap_or_else(|e| fatal(FatalError::ResourceExhaustion, format!("failed to create a waker - {}", e))));
+    // the firewall commands run on a thread started here, before --sandbox filters the event loop
+    #[cfg_attr(not(feature = "remote-lists"), allow(unused_mut))]
+    let mut blocker = match (args.block_after, args.block_backend) {
+        (Some(_), _) if replaying => None,
+        (Some(_), _) if args.user.is_some() => fatal(FatalError::Config, "blocking can't work with --user, the firewall commands need root"),
+        (Some(_), _) if args.low_memory => fatal(FatalError::Config, "blocking needs per-address tracking, which --low-memory disables"),
+        (Some(_), _) if args.proxy_protocol => fatal(FatalError::Config, "blocking can't work with --proxy-protocol, the firewall only sees the load balancer"),
+        (Some(threshold), Some(backend)) => {
+            let mut blocker = Blocker::new(backend, threshold, Duration::from_secs(args.block_duration_secs), waker.clone())
+                .unwrap_or_else(|e| fatal(FatalError::Config, e));
+            if let Some(path) = &args.block_state_file {
+                blocker.persist_to(path.clone(), &Instant::now())
+                    .unwrap_or_else(|e| fatal(FatalError::Config, format!("failed to restore the block state from {} - {}", path.display(), e)));
+            }
+            Some(blocker)
+        },
+        (Some(_), None) => fatal(FatalError::Config, "blocking needs a firewall backend"),
+        (None, _) => None,
+    };
+
+    #[cfg(feature = "remote-lists")]
+    if args.list_refresh_secs == 0 && (args.allowlist_url.is_some() || args.blocklist_url.is_some()) {
+        fatal(FatalError::Config, "--list-refresh-secs must be at least 1");
+    }
+    #[cfg(feature = "remote-lists")]
+    let (list_updates, list_refreshers) = {
+        let (sender, receiver) = mpsc::channel();
+        let mut refreshers = Vec::new();
+        for (kind, url) in [(ListKind::Allow, &args.allowlist_url), (ListKind::Block, &args.blocklist_url)] {
+            let Some(url) = url else {
+                continue;
+            };
+            let mut remote_list = RemoteList::new(kind, url.clone());
+            // a feed being down shouldn't stop the tarpit, it's retried on the next refresh
+            match remote_list.fetch() {
+                Ok(Some(list)) => apply_list_update(ListUpdate { kind, list }, &mut endlessh_server, blocker.as_mut()),
+                Ok(None) => {},
+                Err(e) => warn!("{}, retrying in {}s", e, args.list_refresh_secs),
+            }
+            refreshers.push(remote_list.watch(sender.clone(), waker.clone()));
+        }
+        (receiver, refreshers)
+    };
+
+    if args.farewell_line.as_ref().is_some_and(|line| line.contains(['\r', '\n']) || line.len() > MAX_FAREWELL_LINE_LENGTH) {
+        fatal(FatalError::Config, format!("--farewell-line must be a single line of at most {} bytes", MAX_FAREWELL_LINE_LENGTH));
+    }
+    let event_log = args.event_log.clone().filter(|_| !replaying).map(|path| EventLog::open(path.clone())
+        .unwrap_or_else(|e| fatal(FatalError::Config, format!("failed to open event log {} - {}", path.display(), e))));
+
+    #[cfg(feature = "report")]
+    let reporting = args.report_file.is_some() || args.report_webhook_url.is_some() || !args.report_email_to.is_empty();
+    #[cfg(not(feature = "report"))]
+    let reporting = args.report_file.is_some();
+    #[cfg(feature = "report")]
+    if sandboxed && args.report_webhook_url.is_some() {
+        fatal(FatalError::Config, "--report-webhook-url can't work with --sandbox, each report is posted from a thread the event loop starts");
+    }
+    #[cfg(feature = "report")]
+    if sandboxed && !args.report_email_to.is_empty() {
+        fatal(FatalError::Config, "--report-email-to can't work with --sandbox, each report is mailed by running --report-sendmail");
+    }
+    if reporting && args.report_interval_secs == 0 {
+        fatal(FatalError::Config, "--report-interval-secs must be at least 1");
+    }
+    // the default fills in the fields of the features left out
+    #[cfg_attr(feature = "report", allow(clippy::needless_update))]
+    let reporter = reporting.then(|| Reporter::new(ReportOptions {
+        file: args.report_file.clone(),
+        #[cfg(feature = "report")]
+        webhook: args.report_webhook_url.clone().map(Webhook::new),
+        #[cfg(feature = "report")]
+        email: (!args.report_email_to.is_empty()).then(|| {
+            EmailReport::new(args.report_sendmail.clone(), args.report_email_from.clone(), args.report_email_to.clone())
+                .unwrap_or_else(|e| fatal(FatalError::Config, format!("--report-email-to - {}", e)))
+        }),
+        #[cfg(feature = "report")]
+        networks: args.report_networks_file.as_deref().map(|path| Arc::new(network_table(path).unwrap_or_else(|e| fatal(FatalError::Config, e)))),
+        ..ReportOptions::default()
+    }));
+    #[cfg(feature = "notify")]
+    if sandboxed && args.notify_webhook_url.is_some() {
+        fatal(FatalError::Config, "--notify-webhook-url can't work with --sandbox, each notification is posted from a thread the event loop starts");
+    }
+    #[cfg(feature = "notify")]
+    if args.notify_webhook_digest_secs == Some(0) {
+        fatal(FatalError::Config, "--notify-webhook-digest-secs must be at least 1");
+    }
+    #[cfg(feature = "notify")]
+    let notify_webhook = args.notify_webhook_url.clone().filter(|_| !replaying).map(|url| WebhookNotifier::new(url, NotifyOptions {
+        dedup: Duration::from_secs(args.notify_webhook_dedup_secs),
+        digest: args.notify_webhook_digest_secs.map(Duration::from_secs),
+    }));
+
+    #[cfg(feature = "hpfeeds")]
+    let hpfeeds = args.hpfeeds_broker.clone().filter(|_| !replaying).map(|broker| HpfeedsPublisher::start(HpfeedsOptions {
+        broker,
+        ident: args.hpfeeds_ident.clone().unwrap_or_default(),
+        secret: args.hpfeeds_secret.clone().unwrap_or_default(),
+        channel: args.hpfeeds_channel.clone(),
+    }).unwrap_or_else(|e| fatal(FatalError::Config, e)));
+    #[cfg(feature = "elasticsearch")]
+    let elasticsearch = args.elasticsearch_url.clone().filter(|_| !replaying).map(|url| ElasticsearchExporter::start(ElasticsearchOptions {
+        url,
+        index: args.elasticsearch_index.clone(),
+        api_key: args.elasticsearch_api_key.clone(),
+        batch_size: args.elasticsearch_batch_size,
+        flush_interval: Duration::from_secs(args.elasticsearch_flush_secs),
+    }).unwrap_or_else(|e| fatal(FatalError::Config, e)));
+
+    let mut scheduler = Scheduler::new();
+    let now = Instant::now();
+    if reporter.is_some() {
+        scheduler.schedule(PeriodicTask::Report, Duration::from_secs(args.report_interval_secs), &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+    }
+    #[cfg(feature = "notify")]
+    if let Some(digest) = notify_webhook.as_ref().and_then(WebhookNotifier::digest) {
+        scheduler.schedule(PeriodicTask::NotifyDigest, digest, &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+    }
+    if args.low_memory {
+        scheduler.schedule(PeriodicTask::MemoryCheck, LOW_MEMORY_CHECK_INTERVAL, &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+    }
+    if blocker.is_some() {
+        scheduler.schedule(PeriodicTask::BlockExpiry, BLOCK_EXPIRY_CHECK_INTERVAL, &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        if args.block_state_file.is_some() {
+            scheduler.schedule(PeriodicTask::BlockStateSave, BLOCK_STATE_SAVE_INTERVAL, &now)
+                .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        }
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(m) = metrics.as_mut() {
+        scheduler.schedule(PeriodicTask::TimeSeriesSample, Duration::from_secs(args.metrics_timeseries_interval_secs), &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        scheduler.schedule(PeriodicTask::HealthCheck, Duration::from_secs(args.health_check_interval_secs), &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        if let Some(retention) = args.history_retention_secs.map(Duration::from_secs) {
+            scheduler.schedule(PeriodicTask::HistoryPrune(retention), HISTORY_PRUNE_INTERVAL, &now)
+                .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        }
+        if m.fleet.is_some() {
+            scheduler.schedule(PeriodicTask::FleetPoll, FLEET_POLL_INTERVAL, &now)
+                .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        }
+        if args.metrics_rebind_secs == Some(0) {
+            fatal(FatalError::Config, "--metrics-rebind-secs must be above zero");
+        }
+        if let Some(secs) = args.metrics_rebind_secs.filter(|_| !m.servers.is_empty()) {
+            scheduler.schedule(PeriodicTask::MetricsRebind, Duration::from_secs(secs), &now)
+                .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        }
+        if let Some(textfile) = &m.textfile {
+            // write the textfile straight away so the collector doesn't report it missing
+            let succeeded = textfile.write(&render_metrics(&endlessh_server, &m.labels, m.fleet.as_ref()));
+            m.health.record_exporter_result("textfile", succeeded);
+            scheduler.schedule(PeriodicTask::TextfileWrite, Duration::from_secs(args.metrics_textfile_interval_secs), &now)
+                .unwrap_or_else(|e| fatal(FatalError::Config, e));
+        }
+    }
+    #[cfg(feature = "remote-lists")]
+    if !list_refreshers.is_empty() {
+        scheduler.schedule(PeriodicTask::ListRefresh, Duration::from_secs(args.list_refresh_secs), &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+    }
+    // pinging at half the interval leaves the other half for a slow turn of the event loop
+    #[cfg(target_os = "linux")]
+    if let (Some(_), Some(interval)) = (&notifier, watchdog_interval) {
+        scheduler.schedule(PeriodicTask::Watchdog, interval / 2, &now)
+            .unwrap_or_else(|e| fatal(FatalError::Config, e));
+    }
+    // everything is bound and registered, so a Type=notify unit can now count as started
+    #[cfg(target_os = "linux")]
+    if let Some(notifier) = notifier.as_ref() {
+        notifier.notify("READY=1");
+    }
+
+    #[cfg(feature = "sandbox")]
+    if sandboxed {
+        #[cfg(target_os = "linux")]
+        {
+            sandbox::install().unwrap_or_else(|e| fatal(FatalError::Sandbox, e));
+            info!("sandboxed the event loop");
+        }
+        #[cfg(not(target_os = "linux"))]
+        fatal(FatalError::Config, "--sandbox is only supported on linux");
+    }
 
     event_loop(
         poll,
         events,
         endlessh_server,
+        scheduler,
+        blocker,
+        event_log,
+        reporter,
+        #[cfg(feature = "notify")]
+        notify_webhook,
+        #[cfg(feature = "hpfeeds")]
+        hpfeeds,
+        #[cfg(feature = "elasticsearch")]
+        elasticsearch,
+        Shutdown::new(args.shutdown_linger_secs.map(Duration::from_secs), args.farewell_line.as_deref(), args.stats_file.as_deref(), socket_paths),
+        #[cfg(unix)]
+        signals,
+        #[cfg(feature = "remote-lists")]
+        list_updates,
+        #[cfg(feature = "remote-lists")]
+        list_refreshers,
         #[cfg(feature = "metrics")]
-        metric_server
+        metrics,
+        #[cfg(target_os = "linux")]
+        notifier
     );
 
 }
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(["endlessh-rs"].iter().chain(flags)).unwrap()
+    }
+
+    #[test]
+    fn binds_are_only_retried_for_transient_failures_and_as_often_as_asked() {
+        let mut attempts = 0;
+        let result: endlessh_rs::Result<()> = bind_with_retry(&args(&["--bind-retry", "3"]), || {
+            attempts += 1;
+            Err(EndlesshError::bind("0.0.0.0:22", Some(22), io::ErrorKind::PermissionDenied.into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result: endlessh_rs::Result<()> = bind_with_retry(&args(&[]), || {
+            attempts += 1;
+            Err(EndlesshError::bind("0.0.0.0:22", Some(22), io::ErrorKind::AddrInUse.into()))
+        });
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(attempts, 1);
+
+        // the first retry waits a second
+        let mut attempts = 0;
+        let result = bind_with_retry(&args(&["--bind-retry", "3"]), || {
+            attempts += 1;
+            match attempts {
+                1 => Err(EndlesshError::bind("0.0.0.0:22", Some(22), io::ErrorKind::AddrNotAvailable.into())),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[test]
+    fn addresses_are_waited_for_until_assigned_or_out_of_time() {
+        let wait = args(&["--wait-for-address", "1"]);
+        let started = Instant::now();
+        wait_for_address(&wait, "127.0.0.1:22".parse().unwrap());
+        assert!(started.elapsed() < Duration::from_millis(500));
+        // documentation addresses are on no interface
+        wait_for_address(&wait, "192.0.2.1:22".parse().unwrap());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn low_memory_caps_buffers_and_drops_per_address_tracking() {
+        let low_memory = args(&["--low-memory"]);
+        assert_eq!(low_memory_cap(&low_memory, 128, LOW_MEMORY_EVENTS_CAPACITY), LOW_MEMORY_EVENTS_CAPACITY);
+        assert_eq!(low_memory_cap(&low_memory, 8, LOW_MEMORY_EVENTS_CAPACITY), 8);
+        assert_eq!(low_memory_cap(&args(&[]), 128, LOW_MEMORY_EVENTS_CAPACITY), 128);
+        assert!(!ssh_options(&low_memory).unwrap().preallocate_clients);
+        assert!(ssh_options(&args(&[])).unwrap().preallocate_clients);
+        assert!(ssh_options(&args(&["--low-memory", "--ssh-repeat-offender-ttl-secs", "60"])).is_err());
+    }
+
+    #[test]
+    fn the_admin_api_only_listens_locally() {
+        assert_eq!(parse_admin_listener("ip:127.0.0.1:9001"), Ok(MultiListener::Ip("127.0.0.1:9001".parse().unwrap())));
+        assert_eq!(parse_admin_listener("ip:[::1]:9001"), Ok(MultiListener::Ip("[::1]:9001".parse().unwrap())));
+        assert_eq!(parse_admin_listener("unix:/run/endlessh/admin.sock"), Ok(MultiListener::Unix(PathBuf::from("/run/endlessh/admin.sock"))));
+        assert_eq!(parse_admin_listener("disabled"), Ok(MultiListener::Disabled));
+        assert!(parse_admin_listener("ip:0.0.0.0:9001").is_err());
+        assert!(parse_admin_listener("ip:192.0.2.1:9001").is_err());
+        assert!(parse_admin_listener("fd:admin").is_err());
+    }
+
+    #[test]
+    fn the_wordlist_is_read_again_for_each_reload() {
+        let path = std::env::temp_dir().join(format!("endlessh-rs-words-{}.txt", std::process::id()));
+        let wordlist = args(&["--ssh-banner-wordlist", path.to_str().unwrap()]);
+        std::fs::write(&path, "alpha\nbeta SSH-2.0-OpenSSH\n").unwrap();
+        assert_eq!(ssh_options(&wordlist).unwrap().banner, BannerGenerator::Words(vec!["alpha".to_string(), "beta".to_string()]));
+        std::fs::write(&path, "gamma \u{7}bell").unwrap();
+        assert_eq!(ssh_options(&wordlist).unwrap().banner, BannerGenerator::Words(vec!["gamma".to_string()]));
+        std::fs::write(&path, "SSH-2.0\n").unwrap();
+        let error = ssh_options(&wordlist).err().unwrap();
+        assert!(error.contains("no usable words"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+        assert!(ssh_options(&wordlist).err().unwrap().contains("failed to read --ssh-banner-wordlist"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn connections_are_counted_per_destination_port_across_listeners() {
+        let mut poll = Poll::new().unwrap();
+        let first = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = first.local_addr().unwrap().port();
+        // the whole of 127/8 is loopback on linux
+        let second = TcpListener::bind(SocketAddr::from(([127, 0, 0, 2], port))).unwrap();
+        let mut endlessh_server = EndlesshServer::create(EndlesshOptions::default(), first, None, Token(0), &poll).unwrap();
+        endlessh_server.add_listener(second, Some("alt"), Token(1), &poll).unwrap();
+        let _clients = [[127, 0, 0, 1], [127, 0, 0, 2]].map(|ip| std::net::TcpStream::connect(SocketAddr::from((ip, port))).unwrap());
+        let mut events = Events::with_capacity(8);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while endlessh_server.client_count() < 2 {
+            assert!(Instant::now() < deadline, "the clients were never accepted");
+            poll.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+            for event in events.iter() {
+                endlessh_server.try_handle_event(event, &Instant::now());
+            }
+        }
+
+        let metrics = render_metrics(&endlessh_server, &MetricLabels::default(), None);
+        assert!(metrics.contains(&format!("endlessh_ssh_destination_port_connections_opened_total{{port=\"{}\"}} 2\n", port)), "{}", metrics);
+        assert!(metrics.contains("endlessh_ssh_listener_connections_opened_total{listener=\"alt\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("# TYPE endlessh_clients_connected gauge\nendlessh_clients_connected 2\n"), "{}", metrics);
+        assert!(metrics.lines().any(|line| line.starts_with("process_open_fds ")), "{}", metrics);
+        let source_ports: Vec<f64> = metrics.lines()
+            .filter_map(|line| line.strip_prefix("endlessh_ssh_source_port_connections_opened_total{source_ports="))
+            .map(|sample| sample.rsplit(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(source_ports.len(), 16);
+        assert_eq!(source_ports.iter().sum::<f64>(), 2.0);
+    }
+}
@@ -1,6 +1,10 @@
 use std::collections::{HashMap, VecDeque};
-use std::io::{copy, Cursor, Read, Write};
+use std::io::{Read, Write};
 use std::io::ErrorKind;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use httparse::Request;
 use mio::Poll;
@@ -8,25 +12,182 @@ use mio::{Interest,{event, Token}};
 use mio::net::{TcpListener,TcpStream};
 
 use httparse::Status;
+use log::{debug, trace, warn, LevelFilter};
+
+use crate::endlessh::is_valid_listener_name;
+use crate::error::{self, EndlesshError};
+use crate::exposition;
+use crate::store::HistoryQuery;
 
 #[cfg(unix)]
 use mio::net::{UnixListener,UnixStream};
+#[cfg(feature = "metrics-tls")]
+use {
+    crate::tls::TlsStream,
+    std::sync::Arc,
+};
+
+// an empty body still needs its length, for the client to find the end of it on a kept connection
+const HTTP_400_RESPONSE: &str = "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+const HTTP_403_RESPONSE: &str = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+const HTTP_404_RESPONSE: &str = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+const HTTP_405_RESPONSE: &str = "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n";
+const HTTP_431_RESPONSE: &str = "HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\n\r\n";
+
+#[derive(Clone)]
+pub struct MetricServerOptions {
+    /// requests that don't fit in this many bytes are rejected
+    pub request_max_size: usize,
+    /// requests with more headers than this are rejected
+    pub max_headers: usize,
+    /// a kept-alive connection is closed after answering this many requests, 1 closing after every response
+    pub max_requests_per_connection: usize,
+    /// a client that sends or takes nothing for this long is hung up on, whether it's idle between requests or
+    /// stalled halfway through one
+    pub idle_timeout: Duration,
+    /// serve https with this config, see [crate::tls::load_server_config]
+    #[cfg(feature = "metrics-tls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
 
-const METRIC_HTTP_REQUEST_MAX_SIZE: usize = 8192;
+impl Default for MetricServerOptions {
+    fn default() -> Self {
+        MetricServerOptions {
+            request_max_size: 8192,
+            max_headers: 64,
+            max_requests_per_connection: 100,
+            idle_timeout: Duration::from_secs(30),
+            #[cfg(feature = "metrics-tls")]
+            tls: None,
+        }
+    }
+}
+
+/// what to do with a client once its buffered request has been parsed
+#[derive(Debug, PartialEq)]
+pub enum RequestOutcome {
+    /// the request isn't complete yet, keep reading
+    Incomplete,
+    /// send this response, then read the next request if `keep_alive`, or else close
+    Respond { response: String, keep_alive: bool },
+    /// the request is garbage, close without a response
+    Close,
+}
+
+/// the bytes of an http request received so far, kept separate from the socket so parsing can be driven without one
+pub struct RequestBuffer {
+    buffer: Box<[u8]>,
+    position: usize,
+}
 
-const HTTP_404_RESPONSE: &str = "HTTP/1.1 404 Not Found\r\n\r\n";
-const HTTP_405_RESPONSE: &str = "HTTP/1.1 405 Method Not Allowed\r\n\r\n";
+impl RequestBuffer {
+    pub fn new(max_size: usize) -> Self {
+        RequestBuffer {
+            buffer: vec![0_u8; max_size].into_boxed_slice(),
+            position: 0,
+        }
+    }
 
-type HttpRequestBuffer = [u8; METRIC_HTTP_REQUEST_MAX_SIZE];
+    /// appends whatever `reader` has available, keeping any bytes received before an error
+    ///
+    /// fails with `WriteZero` once the buffer is full, leaving the rest unread - a pipelined request that didn't fit
+    /// is read once the one before it has been parsed out of the way
+    pub fn fill(&mut self, reader: &mut impl Read) -> std::io::Result<u64> {
+        let mut total = 0;
+        loop {
+            if self.position == self.buffer.len() {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            match reader.read(&mut self.buffer[self.position..]) {
+                Ok(0) => return Ok(total),
+                Ok(n) => {
+                    self.position += n;
+                    total += n as u64;
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// parses the bytes received so far and decides how to answer. a complete request is dropped from the buffer,
+    /// leaving any pipelined after it
+    pub fn parse(&mut self, options: &MetricServerOptions, source: &impl MetricSource) -> RequestOutcome {
+        let mut headers = vec![httparse::EMPTY_HEADER; options.max_headers];
+        let mut request_parser = Request::new(&mut headers);
+        let (outcome, parsed_length) = match request_parser.parse(&self.buffer[..self.position]) {
+            // http request has completed
+            Ok(Status::Complete(length)) => {
+                let keep_alive = is_kept_alive(&request_parser);
+                (RequestOutcome::Respond { response: MetricServer::route(&request_parser, source), keep_alive }, length)
+            },
+            Ok(Status::Partial) if self.position == self.buffer.len() => {
+                debug!("metric request exceeded {} bytes", self.buffer.len());
+                (RequestOutcome::Respond { response: HTTP_431_RESPONSE.to_string(), keep_alive: false }, 0)
+            },
+            Ok(Status::Partial) => (RequestOutcome::Incomplete, 0),
+            Err(httparse::Error::TooManyHeaders) => {
+                debug!("metric request exceeded {} headers", options.max_headers);
+                (RequestOutcome::Respond { response: HTTP_431_RESPONSE.to_string(), keep_alive: false }, 0)
+            },
+            Err(e) => {
+                debug!("bad http request from metric client: {}", e);
+                (RequestOutcome::Close, 0)
+            },
+        };
+        self.buffer.copy_within(parsed_length..self.position, 0);
+        self.position -= parsed_length;
+        outcome
+    }
+}
+
+/// whether the client wants the connection kept for another request - by default with http/1.1, only when asked with
+/// http/1.0. a request with a body never is, the body would be read as the next request
+fn is_kept_alive(request: &Request) -> bool {
+    let header = |name: &str| request.headers.iter().find(|header| header.name.eq_ignore_ascii_case(name)).map(|header| header.value);
+    if header("transfer-encoding").is_some() || header("content-length").is_some_and(|length| length != b"0") {
+        return false;
+    }
+    let connection = header("connection").map(|value| String::from_utf8_lossy(value).to_ascii_lowercase()).unwrap_or_default();
+    let mut options = connection.split(',').map(str::trim);
+    if options.clone().any(|option| option == "close") {
+        return false;
+    }
+    request.version == Some(1) || options.any(|option| option == "keep-alive")
+}
+
+/// writes the rest of the response from `written` on, counting what the socket takes so a `WouldBlock` can be picked
+/// up where it left off. flushed too, as tls may still hold records the socket hasn't taken
+fn write_response(stream: &mut impl Write, response: &[u8], written: &mut usize) -> std::io::Result<()> {
+    while *written < response.len() {
+        match stream.write(&response[*written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => *written += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    stream.flush()
+}
+
+/// the response with a header telling the client the connection closes after it
+fn closing(response: String) -> String {
+    response.replacen("\r\n", "\r\nConnection: close\r\n", 1)
+}
 
 enum MetricRequestStatus {
-    ReadingRequest(HttpRequestBuffer, usize),
-    WritingResponse(Box<dyn Read>),
+    ReadingRequest(RequestBuffer),
+    /// the response, how much of it the socket has taken, and the buffer holding what's been received of the next
+    /// request if the connection is kept
+    WritingResponse(Vec<u8>, usize, Option<RequestBuffer>),
 }
 
 struct HttpClient {
     stream: Box<dyn MioStream>,
     connection_status: MetricRequestStatus,
+    requests_answered: usize,
+    /// when the client last had an event, for the idle timeout
+    last_active: Instant,
 }
 
 trait MioStreamGiver: event::Source {
@@ -49,104 +210,296 @@ impl MioStreamGiver for UnixListener {
 
 trait MioStream: Read + Write + event::Source {}
 impl MioStream for TcpStream {}
+#[cfg(feature = "metrics-tls")]
+impl MioStream for TlsStream<Box<dyn MioStream>> {}
 #[cfg(unix)]
 impl MioStream for UnixStream {}
 
+const JSON_CONTENT_TYPE: &str = "application/json";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+/// admin api - POST /log-level/<level> changes the log level
+const LOG_LEVEL_PATH: &str = "/log-level/";
+/// GET lists the ssh listeners, and the admin api adds (POST) or removes (DELETE) /listeners/<address>
+const LISTENERS_PATH: &str = "/listeners";
+const LISTENER_PATH: &str = "/listeners/";
+/// admin api - POST re-reads the config and the files it names, like SIGHUP
+const RELOAD_PATH: &str = "/reload";
+/// GET /history?since=<unix seconds>&ip=<address>&limit=<n>&offset=<n> pages through the connection history
+const HISTORY_PATH: &str = "/history";
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1000;
+
+/// why an admin api request wasn't carried out
+#[derive(Debug)]
+pub enum AdminError {
+    /// the admin api is disabled, or isn't served on the listener the request came in on
+    Disabled,
+    /// there's nothing to act on, e.g. no such listener
+    NotFound,
+    /// it was tried and failed, the reason is sent back to the client
+    Failed(String),
+}
+
+/// a `key=value` label attached to every exported metric
+#[derive(Clone, Debug)]
+pub struct MetricLabel {
+    key: String,
+    value: String,
+}
+
+impl FromStr for MetricLabel {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        let (key, value) = v.split_once('=').ok_or("label must be of the form \"key=value\"")?;
+        let valid_key = key.chars().enumerate().all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if key.is_empty() || !valid_key || key.starts_with("__") {
+            return Err(format!("\"{}\" is not a valid label name", key));
+        }
+        Ok(MetricLabel {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// a set of labels, rendered once as `{key="value",...}` (or nothing when empty)
+#[derive(Clone, Default)]
+pub struct MetricLabels(String);
+
+impl MetricLabels {
+    pub fn new(labels: &[MetricLabel]) -> Self {
+        if labels.is_empty() {
+            return MetricLabels::default();
+        }
+        let rendered: Vec<String> = labels.iter()
+            .map(|l| format!("{}=\"{}\"", l.key, l.value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+            .collect();
+        MetricLabels(format!("{{{}}}", rendered.join(",")))
+    }
+}
+
+impl MetricLabels {
+    /// these labels plus one more, e.g. a histogram bucket's `le`
+    pub fn with(&self, key: &str, value: &str) -> String {
+        exposition::with_label(&self.0, key, value)
+    }
+}
+
+impl Display for MetricLabels {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(&self.0)
+    }
+}
+
+/// supplies the response bodies served by the metric server
+pub trait MetricSource {
+    /// the openmetrics exposition served at /metrics
+    fn metrics(&self) -> String;
+    /// the json document served at /stats
+    fn stats_json(&self) -> String;
+    /// the json array of periodic snapshots served at /timeseries
+    fn timeseries_json(&self) -> String;
+    /// the json array of recent connection events served at /recent
+    fn recent_json(&self) -> String;
+    /// the json page of the connection history served at /history, or None if it couldn't be read
+    fn history_json(&self, _query: &HistoryQuery) -> Option<String> {
+        None
+    }
+    /// the json served at /healthz, always with a 200 - that the event loop answered is what it reports
+    fn healthz(&self) -> String;
+    /// whether new clients can be trapped, and the json reasons why not, served at /readyz
+    fn readyz(&self) -> (bool, String);
+    /// applies a log level sent to the admin api, returning false if the admin api is disabled
+    fn set_log_level(&self, _level: LevelFilter) -> bool {
+        false
+    }
+    /// the json array of trapped clients and their schedule served at /clients
+    fn clients_json(&self) -> String {
+        "[]".to_string()
+    }
+    /// the json array of ssh listeners served at /listeners
+    fn listeners_json(&self) -> String {
+        "[]".to_string()
+    }
+    /// starts an ssh listener sent to the admin api, returning the address it's bound to
+    fn add_listener(&self, _address: SocketAddr, _name: Option<&str>) -> Result<SocketAddr, AdminError> {
+        Err(AdminError::Disabled)
+    }
+    /// stops an ssh listener sent to the admin api
+    fn remove_listener(&self, _address: SocketAddr) -> Result<(), AdminError> {
+        Err(AdminError::Disabled)
+    }
+    /// reloads the tarpit's options when the admin api asks to
+    fn reload(&self) -> Result<(), AdminError> {
+        Err(AdminError::Disabled)
+    }
+}
+
+fn admin_response(result: Result<String, AdminError>) -> String {
+    match result {
+        Ok(body) => generate_http_response("200 OK", TEXT_CONTENT_TYPE, body),
+        Err(AdminError::Disabled) => HTTP_403_RESPONSE.to_string(),
+        Err(AdminError::NotFound) => HTTP_404_RESPONSE.to_string(),
+        Err(AdminError::Failed(reason)) => generate_http_response("409 Conflict", TEXT_CONTENT_TYPE, format!("{}\n", reason)),
+    }
+}
+
+/// the /history query string, None if it has a parameter that isn't one of its own or doesn't parse
+fn history_query(query: &str) -> Option<HistoryQuery> {
+    let mut history = HistoryQuery { limit: DEFAULT_HISTORY_LIMIT, ..HistoryQuery::default() };
+    for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+        let (name, value) = parameter.split_once('=')?;
+        match name {
+            "since" => history.since = Some(value.parse::<f64>().ok().filter(|since| since.is_finite())?),
+            "ip" => history.ip = Some(value.parse().ok()?),
+            "limit" => history.limit = value.parse().ok().filter(|&limit| limit <= MAX_HISTORY_LIMIT)?,
+            "offset" => history.offset = value.parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some(history)
+}
+
 fn generate_http_response(
-    to_body: &impl ToString,
+    status: &str,
+    content_type: &str,
+    body: String,
 ) -> String {
-    let body = to_body.to_string();
     format!(
         concat!(
-            "HTTP/1.1 200 OK\r\n",
-            "Content-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n",
+            "HTTP/1.1 {}\r\n",
+            "Content-Type: {}\r\n",
             "Content-Length: {}\r\n\r\n{}",
         ),
+        status,
+        content_type,
         body.len(),
         body
     )
 }
 
+/// whether an accept error means the listener itself is gone, rather than a shortage that will pass
+fn is_listener_broken(e: &std::io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    if let Some(errno) = e.raw_os_error() {
+        return matches!(errno, libc::EBADF | libc::ENOTSOCK | libc::EINVAL | libc::EOPNOTSUPP);
+    }
+    e.kind() == ErrorKind::InvalidInput
+}
+
 pub struct MetricServer {
     listener: Box<dyn MioStreamGiver>,
     listener_token: Token,
     listener_accept_available: bool,
     available_connections: VecDeque<Token>,
     current_connections: HashMap<Token,HttpClient>,
+    options: MetricServerOptions,
 }
 
 
 
 impl MetricServer {
 
-    fn create(poll: &Poll, mut listener: Box<dyn MioStreamGiver>, listener_token: Token, client_token_range: std::ops::Range<usize>) -> Self {
-        poll.registry().register(&mut listener, listener_token, Interest::READABLE).unwrap();
+    fn create(poll: &Poll, mut listener: Box<dyn MioStreamGiver>, listener_token: Token, client_token_range: std::ops::Range<usize>, options: MetricServerOptions) -> error::Result<Self> {
+        poll.registry().register(&mut listener, listener_token, Interest::READABLE)
+            .map_err(|source| EndlesshError::Register { what: "metrics listener", source })?;
         let available_connections: VecDeque<Token> = client_token_range.into_iter()
-        .map(|t| Token(t) )
+        .map(Token)
         .collect();
         let num_available = available_connections.len();
-        MetricServer {
+        Ok(MetricServer {
             listener,
             listener_token,
             listener_accept_available: false,
             available_connections,
             current_connections: HashMap::with_capacity(num_available),
-        }
+            options,
+        })
     }
 
-    pub fn new_tcp(poll: &Poll, listener: TcpListener, listener_token: Token, client_token_range: std::ops::Range<usize>) -> Self {
-        MetricServer::create(poll, Box::new(listener), listener_token, client_token_range)
+    pub fn new_tcp(poll: &Poll, listener: TcpListener, listener_token: Token, client_token_range: std::ops::Range<usize>, options: MetricServerOptions) -> error::Result<Self> {
+        MetricServer::create(poll, Box::new(listener), listener_token, client_token_range, options)
     }
 
     #[cfg(unix)]
-    pub fn new_unix(poll: &Poll, listener: UnixListener, listener_token: Token, client_token_range: std::ops::Range<usize>) -> Self {
-        MetricServer::create(poll, Box::new(listener), listener_token, client_token_range)
+    pub fn new_unix(poll: &Poll, listener: UnixListener, listener_token: Token, client_token_range: std::ops::Range<usize>, options: MetricServerOptions) -> error::Result<Self> {
+        MetricServer::create(poll, Box::new(listener), listener_token, client_token_range, options)
     }
 
-    pub fn try_handle_event(&mut self, event: &event::Event, poll: &mut Poll, response: &impl ToString) -> bool {
-        return if self.listener_token == event.token() {
-            println!("metric server token");
+    /// returns whether the event belonged to this server, or an error if the listener is broken
+    pub fn try_handle_event(&mut self, event: &event::Event, poll: &mut Poll, source: &impl MetricSource, now: &Instant) -> error::Result<bool> {
+        if self.listener_token == event.token() {
+            trace!("metric listener is readable");
             self.listener_accept_available = true;
-            self.try_accept_new_connections(poll);
-            true
-        } else if let Some((client_token, client)) = self.current_connections.remove_entry(&event.token()) {
-            println!("metric client token");
-            if let Some(client) = self.handle_client(poll, &client_token, client, response) {
+            self.try_accept_new_connections(poll, now)?;
+            Ok(true)
+        } else if let Some((client_token, mut client)) = self.current_connections.remove_entry(&event.token()) {
+            client.last_active = *now;
+            if let Some(client) = self.handle_client(poll, &client_token, client, source) {
                 assert!(self.current_connections.insert(client_token, client).is_none());
             } else {
-                println!("available conn1: {:?}", self.available_connections);
-                println!("killing client: {}", client_token.0);
+                debug!(token = client_token.0; "closed metric client");
                 self.available_connections.push_back(client_token);
-                println!("available conn: {:?}", self.available_connections);
             }
             // in case the number of clients dropped from the max
-            self.try_accept_new_connections(poll);
-            true
+            self.try_accept_new_connections(poll, now)?;
+            Ok(true)
         } else {
-            false
-        };
+            Ok(false)
+        }
     }
 
-    fn try_accept_new_connections(&mut self, poll: &mut Poll) {
-        while self.listener_accept_available && !self.available_connections.is_empty() {
+    /// hangs up on clients that have been idle for the idle timeout, returning how long until the next one would be,
+    /// or an error if the listener is broken
+    pub fn handle_wakeup(&mut self, poll: &mut Poll, now: &Instant) -> error::Result<Option<Duration>> {
+        let idle_timeout = self.options.idle_timeout;
+        let idle: Vec<Token> = self.current_connections.iter()
+            .filter(|(_, client)| now.saturating_duration_since(client.last_active) >= idle_timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in &idle {
+            if let Some(mut client) = self.current_connections.remove(token) {
+                debug!(token = token.0; "hung up on idle metric client");
+                let _ = poll.registry().deregister(&mut client.stream);
+                self.available_connections.push_back(*token);
+            }
+        }
+        if !idle.is_empty() {
+            self.try_accept_new_connections(poll, now)?;
+        }
+        Ok(self.current_connections.values()
+            .map(|client| (client.last_active + idle_timeout).saturating_duration_since(*now))
+            .min())
+    }
 
-            println!("doing an accept");
-            println!("available tokens: {:?}", self.available_connections);
+    fn try_accept_new_connections(&mut self, poll: &mut Poll, now: &Instant) -> error::Result<()> {
+        while self.listener_accept_available && !self.available_connections.is_empty() {
 
             // due to https://github.com/rust-lang/rust/issues/53667
 
             match self.listener.accept_stream() {
-                Ok(mut stream) => {
-                    
+                Ok(stream) => {
+                    let mut stream = match self.wrap_stream(stream) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("failed to start tls with metric client: {}", e);
+                            continue;
+                        },
+                    };
                     let token = self.available_connections.pop_front().expect("available connections is empty");
-                    println!("accepting a new stream with token: {}", token.0);
-
-                    poll.registry().register(&mut stream, token, Interest::READABLE).expect("failed to poll on metric client stream");
+                    if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+                        warn!(token = token.0; "failed to register metric client: {}", e);
+                        self.available_connections.push_back(token);
+                        continue;
+                    }
                     let new_client = HttpClient {
                         stream,
-                        connection_status: MetricRequestStatus::ReadingRequest([0_u8; METRIC_HTTP_REQUEST_MAX_SIZE], 0)
+                        connection_status: MetricRequestStatus::ReadingRequest(RequestBuffer::new(self.options.request_max_size)),
+                        requests_answered: 0,
+                        last_active: *now,
                     };
-                    println!("accepted new metric connection with token {}", token.0);
+                    debug!(token = token.0, free_tokens = self.available_connections.len(); "accepted metric client");
 
                     self.current_connections.insert(token, new_client);
     
@@ -154,94 +507,310 @@ impl MetricServer {
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
                     self.listener_accept_available = false;
                 }
+                Err(e) if is_listener_broken(&e) => {
+                    return Err(EndlesshError::Accept(e));
+                }
                 Err(e) => {
-                    panic!("failed to accept connection: {}", e);
+                    // e.g. out of file descriptors - leave the connection queued until the listener is readable again
+                    warn!("failed to accept metric client: {}", e);
+                    self.listener_accept_available = false;
                 }
             }
     
         }
+        Ok(())
     }
 
-    fn handle_client(&self, poll: &mut Poll, token: &Token, mut client: HttpClient, http_response_body: &impl ToString) -> Option<HttpClient> {
+    /// the accepted stream, with tls on top if the server serves https
+    fn wrap_stream(&self, stream: Box<dyn MioStream>) -> std::io::Result<Box<dyn MioStream>> {
+        #[cfg(feature = "metrics-tls")]
+        if let Some(config) = &self.options.tls {
+            return Ok(Box::new(TlsStream::new(config.clone(), stream)?));
+        }
+        Ok(stream)
+    }
+
+    /// deregisters the listener and hangs up on its clients
+    pub fn close(mut self, poll: &Poll) {
+        let _ = poll.registry().deregister(&mut self.listener);
+        for client in self.current_connections.values_mut() {
+            let _ = poll.registry().deregister(&mut client.stream);
+        }
+    }
+
+    fn route(request: &Request, source: &impl MetricSource) -> String {
+        match (request.method, request.path) {
+            (Some("GET"), Some("/metrics")) => generate_http_response("200 OK", exposition::CONTENT_TYPE, source.metrics()),
+            (Some("GET"), Some("/stats")) => generate_http_response("200 OK", JSON_CONTENT_TYPE, source.stats_json()),
+            (Some("GET"), Some("/timeseries")) => generate_http_response("200 OK", JSON_CONTENT_TYPE, source.timeseries_json()),
+            (Some("GET"), Some("/recent")) => generate_http_response("200 OK", JSON_CONTENT_TYPE, source.recent_json()),
+            (Some("GET"), Some("/clients")) => generate_http_response("200 OK", JSON_CONTENT_TYPE, source.clients_json()),
+            (Some("GET"), Some(path)) if path.split('?').next() == Some(HISTORY_PATH) => {
+                let Some(query) = history_query(path.split_once('?').map_or("", |(_, query)| query)) else {
+                    return HTTP_400_RESPONSE.to_string();
+                };
+                match source.history_json(&query) {
+                    Some(page) => generate_http_response("200 OK", JSON_CONTENT_TYPE, page),
+                    None => generate_http_response("503 Service Unavailable", TEXT_CONTENT_TYPE, "the connection history can't be read\n".to_string()),
+                }
+            },
+            (Some("GET"), Some("/healthz")) => generate_http_response("200 OK", JSON_CONTENT_TYPE, source.healthz()),
+            (Some("GET"), Some("/readyz")) => {
+                let (ready, reasons) = source.readyz();
+                let status = if ready { "200 OK" } else { "503 Service Unavailable" };
+                generate_http_response(status, JSON_CONTENT_TYPE, reasons)
+            },
+            (Some("POST"), Some(path)) if path.starts_with(LOG_LEVEL_PATH) => {
+                match path[LOG_LEVEL_PATH.len()..].parse::<LevelFilter>() {
+                    Ok(level) if source.set_log_level(level) => generate_http_response("200 OK", TEXT_CONTENT_TYPE, format!("{}\n", level)),
+                    Ok(_) => HTTP_403_RESPONSE.to_string(),
+                    Err(_) => HTTP_400_RESPONSE.to_string(),
+                }
+            },
+            (Some("GET"), Some(LISTENERS_PATH)) => generate_http_response("200 OK", JSON_CONTENT_TYPE, source.listeners_json()),
+            (Some(method @ ("POST" | "DELETE")), Some(path)) if path.starts_with(LISTENER_PATH) => {
+                // POST /listeners/<address>?name=<name>
+                let (address, name) = match path[LISTENER_PATH.len()..].split_once('?') {
+                    Some((address, query)) => (address, query.strip_prefix("name=")),
+                    None => (&path[LISTENER_PATH.len()..], None),
+                };
+                let Ok(address) = address.parse::<SocketAddr>() else {
+                    return HTTP_400_RESPONSE.to_string();
+                };
+                if name.is_some_and(|name| !is_valid_listener_name(name)) {
+                    return HTTP_400_RESPONSE.to_string();
+                }
+                admin_response(if method == "POST" {
+                    source.add_listener(address, name).map(|bound| format!("{}\n", bound))
+                } else {
+                    source.remove_listener(address).map(|()| format!("{}\n", address))
+                })
+            },
+            (Some("POST"), Some(RELOAD_PATH)) => admin_response(source.reload().map(|()| "reloading\n".to_string())),
+            (_, Some("/metrics" | "/stats" | "/timeseries" | "/recent" | "/clients" | "/healthz" | "/readyz" | LISTENERS_PATH | RELOAD_PATH)) => HTTP_405_RESPONSE.to_string(),
+            (_, Some(path)) if path.starts_with(LISTENER_PATH) => HTTP_405_RESPONSE.to_string(),
+            (_, Some(path)) if path.split('?').next() == Some(HISTORY_PATH) => HTTP_405_RESPONSE.to_string(),
+            (_, Some(path)) if path.starts_with(LOG_LEVEL_PATH) => HTTP_405_RESPONSE.to_string(),
+            _ => HTTP_404_RESPONSE.to_string(),
+        }
+    }
+
+    fn handle_client(&self, poll: &mut Poll, token: &Token, mut client: HttpClient, source: &impl MetricSource) -> Option<HttpClient> {
         match client.connection_status {
-        MetricRequestStatus::ReadingRequest(mut buffer, mut current_position) => {
-            let cursor = &mut Cursor::new(&mut buffer[current_position..]);
-            match copy(&mut client.stream, cursor) {
+        MetricRequestStatus::ReadingRequest(mut request) => {
+            match request.fill(&mut client.stream) {
                 Ok(0) => {
-                    println!("copied no bytes to cursor");
-                    poll.registry().deregister(&mut client.stream).unwrap();
+                    trace!(token = token.0; "metric client hung up");
+                    let _ = poll.registry().deregister(&mut client.stream);
                     return None;
                 },
                 Ok(n) => {
-                    println!("read {} bytes to buffer", n);
-                    current_position += cursor.position() as usize;
+                    trace!(token = token.0, bytes = n; "read from metric client");
                 },
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    println!("metric read would block");
-                    current_position += cursor.position() as usize;
+                    trace!(token = token.0; "metric read would block");
                 },
-                Err(e) => {
-                    println!("cursor copy error: {}", e);
-                    poll.registry().deregister(&mut client.stream).unwrap();
-                    return None;
-                },
-            };
-            let mut request_parser = Request::new(&mut []);
-            match request_parser.parse( &buffer[..current_position] ) {
-                Ok(Status::Complete(_)) | Err(httparse::Error::TooManyHeaders) => { },
-                Ok(Status::Partial) => {
-                    client.connection_status = MetricRequestStatus::ReadingRequest(buffer, current_position);
-                    return Some(client);
+                Err(e) if e.kind() == ErrorKind::WriteZero => {
+                    // the request buffer is full - let the parser decide if that's a problem
                 },
                 Err(e) => {
-                    println!("bad http request from metric client: {}", e);
-                    poll.registry().deregister(&mut client.stream).unwrap();
+                    debug!(token = token.0; "failed to read from metric client: {}", e);
+                    let _ = poll.registry().deregister(&mut client.stream);
                     return None;
                 },
             };
-
-            // http request has completed
-
-            match request_parser.path {
-                Some("/metrics") => {
-                    match request_parser.method {
-                        Some("GET") => {
-                            let response = generate_http_response(http_response_body);
-                            client.connection_status = MetricRequestStatus::WritingResponse(Box::new(Cursor::new(response)));
-                        },
-                        _ => {
-                            client.connection_status = MetricRequestStatus::WritingResponse(Box::new(Cursor::new(HTTP_405_RESPONSE)));
-                        },
-                    }
-                },
-                _ => {
-                    client.connection_status = MetricRequestStatus::WritingResponse(Box::new(Cursor::new(HTTP_404_RESPONSE)));
-                },
-            }
-            poll.registry().reregister(&mut client.stream, *token, Interest::WRITABLE).unwrap();
+            client.connection_status = self.answer(poll, token, &mut client.stream, &mut client.requests_answered, request, source)?;
             Some(client)
         },
-        MetricRequestStatus::WritingResponse(mut to_write) => {
-            match copy(&mut to_write, &mut client.stream) {
-                Ok(0) => {
-                    println!("wrote no bytes to client");
-                },
-                Ok(n) => {
-                    println!("wrote {} bytes to client", n);
+        MetricRequestStatus::WritingResponse(response, mut written, next_request) => {
+            match write_response(&mut client.stream, &response, &mut written) {
+                Ok(()) => {
+                    trace!(token = token.0, bytes = written; "wrote response to metric client");
                 },
                 Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    println!("metric write would block");
-                    client.connection_status = MetricRequestStatus::WritingResponse(to_write);
+                    trace!(token = token.0, bytes = written; "metric write would block");
+                    client.connection_status = MetricRequestStatus::WritingResponse(response, written, next_request);
                     return Some(client)
                 },
                 Err(e) => {
-                    println!("cursor copy error: {}", e);
+                    debug!(token = token.0; "failed to write to metric client: {}", e);
+                    let _ = poll.registry().deregister(&mut client.stream);
+                    return None;
                 },
             };
-            poll.registry().deregister(&mut client.stream).unwrap();
-            None
+            let Some(request) = next_request else {
+                let _ = poll.registry().deregister(&mut client.stream);
+                return None;
+            };
+            if let Err(e) = poll.registry().reregister(&mut client.stream, *token, Interest::READABLE) {
+                warn!(token = token.0; "failed to reregister metric client: {}", e);
+                let _ = poll.registry().deregister(&mut client.stream);
+                return None;
+            }
+            // a pipelined request may have been read along with the last one, and won't make the socket readable again
+            client.connection_status = self.answer(poll, token, &mut client.stream, &mut client.requests_answered, request, source)?;
+            Some(client)
         },
         }
     }
+
+    /// answers the request in the buffer once it's complete, returning what the client does next, or None once it's
+    /// been hung up on
+    fn answer(&self, poll: &mut Poll, token: &Token, stream: &mut Box<dyn MioStream>, requests_answered: &mut usize, mut request: RequestBuffer,
+        source: &impl MetricSource) -> Option<MetricRequestStatus> {
+        let (response, keep_alive) = match request.parse(&self.options, source) {
+            RequestOutcome::Respond { response, keep_alive } => (response, keep_alive),
+            RequestOutcome::Incomplete => return Some(MetricRequestStatus::ReadingRequest(request)),
+            RequestOutcome::Close => {
+                let _ = poll.registry().deregister(stream);
+                return None;
+            },
+        };
+        *requests_answered += 1;
+        let keep_alive = keep_alive && *requests_answered < self.options.max_requests_per_connection;
+        let response = if keep_alive { response } else { closing(response) };
+        if let Err(e) = poll.registry().reregister(stream, *token, Interest::WRITABLE) {
+            warn!(token = token.0; "failed to reregister metric client: {}", e);
+            let _ = poll.registry().deregister(stream);
+            return None;
+        }
+        Some(MetricRequestStatus::WritingResponse(response.into_bytes(), 0, keep_alive.then_some(request)))
+    }
     
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `admin` enables the admin api
+    #[derive(Default)]
+    struct Source {
+        admin: bool,
+        log_level: std::cell::Cell<Option<LevelFilter>>,
+    }
+
+    impl MetricSource for Source {
+        fn metrics(&self) -> String {
+            "endlessh_ssh_connections_opened_total 0\n".to_string()
+        }
+
+        fn stats_json(&self) -> String {
+            "{}".to_string()
+        }
+
+        fn timeseries_json(&self) -> String {
+            "[]".to_string()
+        }
+
+        fn recent_json(&self) -> String {
+            "[]".to_string()
+        }
+
+        fn healthz(&self) -> String {
+            "{}".to_string()
+        }
+
+        fn readyz(&self) -> (bool, String) {
+            (true, "{}".to_string())
+        }
+
+        fn set_log_level(&self, level: LevelFilter) -> bool {
+            self.log_level.set(Some(level));
+            self.admin
+        }
+    }
+
+    fn options() -> MetricServerOptions {
+        MetricServerOptions { request_max_size: 64, max_headers: 2, ..MetricServerOptions::default() }
+    }
+
+    fn status(outcome: &RequestOutcome) -> Option<(&str, bool)> {
+        match outcome {
+            RequestOutcome::Respond { response, keep_alive } => Some((response.lines().next().unwrap(), *keep_alive)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn requests_are_answered_once_the_last_byte_arrives() {
+        let mut request = RequestBuffer::new(64);
+        let bytes = b"GET /stats HTTP/1.1\r\n\r\n";
+        for (i, byte) in bytes.iter().enumerate() {
+            assert_eq!(request.fill(&mut &[*byte][..]).unwrap(), 1);
+            let outcome = request.parse(&options(), &Source::default());
+            if i + 1 < bytes.len() {
+                assert_eq!(outcome, RequestOutcome::Incomplete);
+            } else {
+                assert_eq!(status(&outcome), Some(("HTTP/1.1 200 OK", true)));
+            }
+        }
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut request = RequestBuffer::new(64);
+        request.fill(&mut &b"GET /nope HTTP/1.1\r\n\r\nGET /stats HTTP/1.0\r\n\r\nGET"[..]).unwrap();
+        assert_eq!(status(&request.parse(&options(), &Source::default())), Some(("HTTP/1.1 404 Not Found", true)));
+        assert_eq!(status(&request.parse(&options(), &Source::default())), Some(("HTTP/1.1 200 OK", false)));
+        assert_eq!(request.parse(&options(), &Source::default()), RequestOutcome::Incomplete);
+    }
+
+    #[test]
+    fn requests_that_dont_fit_are_refused() {
+        let mut request = RequestBuffer::new(64);
+        let mut reader = &[b'a'; 100][..];
+        let filled = request.fill(&mut &b"GET /"[..]).unwrap();
+        assert_eq!(request.fill(&mut reader).unwrap_err().kind(), ErrorKind::WriteZero);
+        // the rest is left unread
+        assert_eq!(reader.len(), 100 - (64 - filled as usize));
+        assert_eq!(status(&request.parse(&options(), &Source::default())), Some(("HTTP/1.1 431 Request Header Fields Too Large", false)));
+
+        let mut request = RequestBuffer::new(64);
+        request.fill(&mut &b"GET /stats HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n"[..]).unwrap();
+        assert_eq!(status(&request.parse(&options(), &Source::default())), Some(("HTTP/1.1 431 Request Header Fields Too Large", false)));
+    }
+
+    #[test]
+    fn garbage_is_hung_up_on_without_a_response() {
+        let mut request = RequestBuffer::new(64);
+        request.fill(&mut &b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"[..]).unwrap();
+        assert_eq!(request.parse(&options(), &Source::default()), RequestOutcome::Close);
+    }
+
+    fn respond(request: &str, source: &impl MetricSource) -> String {
+        let mut buffer = RequestBuffer::new(1024);
+        buffer.fill(&mut request.as_bytes()).unwrap();
+        match buffer.parse(&MetricServerOptions::default(), source) {
+            RequestOutcome::Respond { response, .. } => response,
+            outcome => panic!("no response to {:?}, {:?}", request, outcome),
+        }
+    }
+
+    #[test]
+    fn log_levels_are_set_through_the_admin_api_only() {
+        let admin = Source { admin: true, ..Source::default() };
+        let response = respond("POST /log-level/debug HTTP/1.1\r\n\r\n", &admin);
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("\r\n\r\nDEBUG\n"), "{}", response);
+        assert_eq!(admin.log_level.get(), Some(LevelFilter::Debug));
+        assert!(respond("POST /log-level/loud HTTP/1.1\r\n\r\n", &admin).starts_with("HTTP/1.1 400 "));
+        assert!(respond("GET /log-level/debug HTTP/1.1\r\n\r\n", &admin).starts_with("HTTP/1.1 405 "));
+        assert!(respond("POST /log-level/debug HTTP/1.1\r\n\r\n", &Source::default()).starts_with("HTTP/1.1 403 "));
+    }
+
+    #[test]
+    fn connections_are_kept_as_each_http_version_expects() {
+        let kept = |head: &str| {
+            let mut request = RequestBuffer::new(256);
+            request.fill(&mut format!("{}\r\n\r\n", head).as_bytes()).unwrap();
+            status(&request.parse(&MetricServerOptions::default(), &Source::default())).unwrap().1
+        };
+        assert!(kept("GET /stats HTTP/1.1"));
+        assert!(!kept("GET /stats HTTP/1.1\r\nConnection: Close"));
+        assert!(!kept("GET /stats HTTP/1.1\r\nConnection: keep-alive, close"));
+        assert!(!kept("GET /stats HTTP/1.0"));
+        assert!(kept("GET /stats HTTP/1.0\r\nConnection: Keep-Alive"));
+        assert!(kept("GET /stats HTTP/1.1\r\nContent-Length: 0"));
+        assert!(!kept("GET /stats HTTP/1.1\r\nContent-Length: 5"));
+        assert!(!kept("GET /stats HTTP/1.1\r\nTransfer-Encoding: chunked"));
+    }
+}
@@ -0,0 +1,151 @@
+//! notifying someone of the addresses being trapped as they arrive, throttled so a scan wave doesn't turn into a
+//! thousand messages: an address is notified about at most once per dedup window, and in digest mode the notifications
+//! are held back and sent together every so often
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::endlessh::{ConnectionEvent, ConnectionEventKind};
+
+/// past this many remembered addresses new ones are suppressed, so a scan from a huge range can't eat the memory
+const MAX_TRACKED_ADDRESSES: usize = 100_000;
+
+/// how one notifier is throttled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotifyOptions {
+    /// an address is notified about again only once this long has passed since the last time
+    pub dedup: Duration,
+    /// if set, notifications are sent together this often instead of as they come in
+    pub digest: Option<Duration>,
+}
+
+/// an address that was just trapped
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub address: IpAddr,
+    /// when it arrived, in seconds since the unix epoch
+    pub time_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+}
+
+/// what one message to a notifier carries
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NotificationBatch {
+    pub addresses: Vec<Notification>,
+    /// arrivals left out since the last batch, from addresses already notified about within the dedup window
+    pub suppressed: u64,
+}
+
+/// decides which arrivals a notifier hears about, and collects them into batches
+pub struct NotifyThrottle {
+    options: NotifyOptions,
+    /// when each address was last notified about
+    notified: HashMap<IpAddr, Instant>,
+    pending: Vec<Notification>,
+    suppressed: u64,
+    next_sweep: Option<Instant>,
+}
+
+impl NotifyThrottle {
+
+    pub fn new(options: NotifyOptions) -> Self {
+        NotifyThrottle {
+            options,
+            notified: HashMap::new(),
+            pending: Vec::new(),
+            suppressed: 0,
+            next_sweep: None,
+        }
+    }
+
+    pub fn options(&self) -> &NotifyOptions {
+        &self.options
+    }
+
+    /// queues a notification for a newly trapped client, unless its address was notified about within the window
+    pub fn record(&mut self, event: &ConnectionEvent, now: &Instant) {
+        if event.event != ConnectionEventKind::Accepted {
+            return;
+        }
+        self.sweep(now);
+        let address = event.peer.ip().to_canonical();
+        let remembered = match self.notified.get(&address) {
+            Some(notified) => now.saturating_duration_since(*notified) < self.options.dedup,
+            None => self.notified.len() >= MAX_TRACKED_ADDRESSES,
+        };
+        if remembered {
+            self.suppressed += 1;
+            return;
+        }
+        self.notified.insert(address, *now);
+        self.pending.push(Notification {
+            address,
+            time_seconds: event.time_seconds,
+            listener: event.listener.clone(),
+            sensor: event.sensor.clone(),
+        });
+    }
+
+    /// the notifications queued since the last batch, or None if there are none to send
+    pub fn take_batch(&mut self) -> Option<NotificationBatch> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(NotificationBatch {
+            addresses: std::mem::take(&mut self.pending),
+            suppressed: std::mem::take(&mut self.suppressed),
+        })
+    }
+
+    /// forgets the addresses notified about over a window ago, at most once per window so it stays cheap
+    fn sweep(&mut self, now: &Instant) {
+        if self.next_sweep.is_some_and(|next_sweep| *now < next_sweep) {
+            return;
+        }
+        let dedup = self.options.dedup;
+        self.notified.retain(|_, notified| now.saturating_duration_since(*notified) < dedup);
+        self.next_sweep = Some(*now + dedup);
+    }
+
+}
+
+/// posts the notifications to a webhook, as they come in or as a digest
+#[cfg(feature = "notify")]
+pub struct WebhookNotifier {
+    throttle: NotifyThrottle,
+    webhook: crate::report::Webhook,
+}
+
+#[cfg(feature = "notify")]
+impl WebhookNotifier {
+
+    pub fn new(url: String, options: NotifyOptions) -> Self {
+        WebhookNotifier {
+            throttle: NotifyThrottle::new(options),
+            webhook: crate::report::Webhook::new(url),
+        }
+    }
+
+    /// how often the digest is sent, None if notifications are sent as they come in
+    pub fn digest(&self) -> Option<Duration> {
+        self.throttle.options().digest
+    }
+
+    pub fn record(&mut self, event: &ConnectionEvent, now: &Instant) {
+        self.throttle.record(event, now);
+    }
+
+    /// posts what's been queued since the last time, if anything
+    pub fn publish(&mut self) {
+        if let Some(batch) = self.throttle.take_batch() {
+            self.webhook.send("notification", &batch);
+        }
+    }
+
+}
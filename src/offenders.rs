@@ -0,0 +1,59 @@
+//! remembering the addresses that have been trapped before, so a scanner that drops and reconnects is trapped more
+//! slowly each time it comes back
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// how returning addresses are slowed down - each trap session an address has had within `ttl` of the last one doubles
+/// the delay of its next, up to `max_factor` times `message_delay`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepeatOffenders {
+    pub ttl: Duration,
+    pub max_factor: u32,
+}
+
+/// the trap sessions of each address, forgotten `ttl` after the last one ended
+#[derive(Default)]
+pub(crate) struct OffenderMemory {
+    /// sessions in a row, and when the last one ended
+    sessions: HashMap<IpAddr, (u32, Instant)>,
+    next_sweep: Option<Instant>,
+}
+
+impl OffenderMemory {
+
+    /// how many sessions in a row `ip` has had before the one it's starting, 0 for a newcomer
+    pub fn returns(&self, ip: IpAddr, config: &RepeatOffenders, now: &Instant) -> u32 {
+        match self.sessions.get(&ip.to_canonical()) {
+            Some(&(sessions, ended)) if now.saturating_duration_since(ended) < config.ttl => sessions,
+            _ => 0,
+        }
+    }
+
+    /// how many times `message_delay` a client that has come back `returns` times waits between lines
+    pub fn delay_factor(returns: u32, config: &RepeatOffenders) -> u32 {
+        2_u32.saturating_pow(returns).min(config.max_factor)
+    }
+
+    /// counts a session from `ip` that has just ended, starting over if the last one was too long ago
+    pub fn record(&mut self, ip: IpAddr, config: &RepeatOffenders, now: &Instant) {
+        self.sweep(config.ttl, now);
+        let (sessions, ended) = self.sessions.entry(ip.to_canonical()).or_insert((0, *now));
+        if now.saturating_duration_since(*ended) >= config.ttl {
+            *sessions = 0;
+        }
+        *sessions = sessions.saturating_add(1);
+        *ended = *now;
+    }
+
+    /// forgets the addresses whose last session ended over `ttl` ago, at most once per `ttl` so it stays cheap
+    fn sweep(&mut self, ttl: Duration, now: &Instant) {
+        if self.next_sweep.is_some_and(|next_sweep| *now < next_sweep) {
+            return;
+        }
+        self.sessions.retain(|_, (_, ended)| now.saturating_duration_since(*ended) < ttl);
+        self.next_sweep = Some(*now + ttl);
+    }
+
+}
@@ -0,0 +1,154 @@
+//! a token bucket of bytes, so the tarpit's sends can be held to a bandwidth budget, and one of connections, so a flood
+//! of them can't hold up the lines
+//!
+//! the bucket is refilled by the clock rather than a timer - whoever sends asks how long until it can afford its next
+//! line and sleeps that long, so a budget costs no wakeups of its own. every protocol's sends are meant to draw from
+//! the same budget, which today is only the ssh tarpit's
+
+use std::time::{Duration, Instant};
+
+/// the shortest wait handed out, so a nearly refilled bucket can't make the event loop spin
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+#[derive(Debug)]
+pub struct SendBudget {
+    bytes_per_second: f64,
+    /// a second's worth, but at least one whole line
+    capacity: f64,
+    available: f64,
+    refilled_time: Instant,
+}
+
+impl SendBudget {
+
+    /// starts full, so the first second's lines go out straight away. `bytes_per_second` must be above zero, which the
+    /// tarpit checks of its options
+    pub fn new(bytes_per_second: u64, line_length: usize, now: &Instant) -> Self {
+        Self::with_capacity(bytes_per_second, (bytes_per_second as f64).max(line_length as f64), now)
+    }
+
+    fn with_capacity(per_second: u64, capacity: f64, now: &Instant) -> Self {
+        SendBudget {
+            bytes_per_second: per_second as f64,
+            capacity,
+            available: capacity,
+            refilled_time: *now,
+        }
+    }
+
+    fn refill(&mut self, now: &Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_time).as_secs_f64();
+        self.available = (self.available + elapsed * self.bytes_per_second).min(self.capacity);
+        self.refilled_time = self.refilled_time.max(*now);
+    }
+
+    /// None if `bytes` can be sent now, otherwise how long until they can
+    pub fn wait_for(&mut self, bytes: usize, now: &Instant) -> Option<Duration> {
+        self.refill(now);
+        let missing = bytes as f64 - self.available;
+        (missing > 0.0).then(|| Duration::from_secs_f64(missing / self.bytes_per_second).max(MIN_WAIT))
+    }
+
+    /// takes what was actually written, which may be less than was waited for
+    pub fn spend(&mut self, bytes: usize) {
+        self.available -= bytes as f64;
+    }
+}
+
+/// connections accepted per second, so a mass scan is taken in a burst at a time with the lines sent in between
+#[derive(Debug)]
+pub struct AcceptBudget(SendBudget);
+
+impl AcceptBudget {
+
+    /// starts full, so the first `burst` connections are accepted straight away. both must be above zero, which the
+    /// tarpit checks of its options
+    pub fn new(per_second: u64, burst: u32, now: &Instant) -> Self {
+        AcceptBudget(SendBudget::with_capacity(per_second, burst as f64, now))
+    }
+
+    /// None if a connection can be accepted now, otherwise how long until one can
+    pub fn wait(&mut self, now: &Instant) -> Option<Duration> {
+        self.0.wait_for(1, now)
+    }
+
+    pub fn take(&mut self) {
+        self.0.spend(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_wait_for_the_bytes_missing_at_the_budget_rate() {
+        let now = Instant::now();
+        let mut budget = SendBudget::new(100, 10, &now);
+        // starts with a second's worth
+        assert_eq!(budget.wait_for(100, &now), None);
+        budget.spend(100);
+        assert_eq!(budget.wait_for(10, &now), Some(Duration::from_millis(100)));
+        assert_eq!(budget.wait_for(10, &(now + Duration::from_millis(60))), Some(Duration::from_millis(40)));
+        assert_eq!(budget.wait_for(10, &(now + Duration::from_millis(100))), None);
+        // a short write only takes what was written
+        budget.spend(4);
+        assert_eq!(budget.wait_for(6, &(now + Duration::from_millis(100))), None);
+    }
+
+    #[test]
+    fn send_budgets_hold_at_most_a_second_or_a_line() {
+        let now = Instant::now();
+        let mut budget = SendBudget::new(100, 10, &now);
+        budget.spend(100);
+        // an idle hour doesn't bank more than a second's worth
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(budget.wait_for(100, &later), None);
+        assert_eq!(budget.wait_for(101, &later), Some(Duration::from_millis(10)));
+
+        // a line longer than a second's worth can still go out, once the bucket has filled
+        let mut budget = SendBudget::new(10, 25, &now);
+        assert_eq!(budget.wait_for(25, &now), None);
+        budget.spend(25);
+        assert_eq!(budget.wait_for(25, &now), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn send_waits_are_never_shorter_than_the_minimum() {
+        let now = Instant::now();
+        let mut budget = SendBudget::new(1_000_000, 10, &now);
+        budget.spend(1_000_000);
+        assert_eq!(budget.wait_for(1, &now), Some(MIN_WAIT));
+    }
+
+    #[test]
+    fn send_budgets_ignore_the_clock_going_backwards() {
+        let now = Instant::now() + Duration::from_secs(1);
+        let mut budget = SendBudget::new(100, 10, &now);
+        budget.spend(100);
+        assert_eq!(budget.wait_for(10, &(now - Duration::from_secs(1))), Some(Duration::from_millis(100)));
+        // and refills from the latest time seen, not the earlier one
+        assert_eq!(budget.wait_for(10, &(now + Duration::from_millis(100))), None);
+    }
+    #[test]
+    fn accepts_come_in_bursts_then_at_the_rate() {
+        let now = Instant::now();
+        let mut budget = AcceptBudget::new(10, 3, &now);
+        for _ in 0..3 {
+            assert_eq!(budget.wait(&now), None);
+            budget.take();
+        }
+        assert_eq!(budget.wait(&now), Some(Duration::from_millis(100)));
+        let later = now + Duration::from_millis(100);
+        assert_eq!(budget.wait(&later), None);
+        budget.take();
+        assert_eq!(budget.wait(&later), Some(Duration::from_millis(100)));
+        // an idle spell refills no more than the burst
+        let idle = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(budget.wait(&idle), None);
+            budget.take();
+        }
+        assert!(budget.wait(&idle).is_some());
+    }
+}
@@ -0,0 +1,150 @@
+//! deciding what becomes of each client as it arrives
+//!
+//! the server's own checks come first - the allowlist, `allow_cidrs` and `deny_cidrs`, `max_clients_per_ip`,
+//! `max_startups` and `trap_probability` - then any [AcceptPolicy] the embedder added, in the order they were added.
+//! the first to give a [Verdict] decides, and a client nobody objects to is trapped (or passed, in a dry run)
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::address_list::AddressList;
+
+/// past this many distinct addresses the strike counts are forgotten, rather than growing without bound
+const MAX_TRACKED_ADDRESSES: usize = 65536;
+
+/// a client that has just connected, as a policy sees it
+#[derive(Clone, Copy, Debug)]
+pub struct Arrival<'a> {
+    /// its real address, after any PROXY header
+    pub peer: SocketAddr,
+    /// the name of the listener it came in on
+    pub listener: Option<&'a str>,
+    /// clients trapped right now, out of `max_clients`
+    pub clients: usize,
+    pub max_clients: usize,
+    /// how many times this address has connected before, as far as the server remembers
+    pub strikes: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Trap,
+    /// accept it and hang up straight away, counted as opened and closed like a dry run
+    Pass,
+    /// hang up without counting it as trapped
+    Close,
+    /// hang up, and record a [Banned](crate::endlessh::ConnectionEventKind::Banned) event for the embedder to block
+    /// the address on
+    Ban,
+}
+
+/// a step of the accept decision, e.g. a rate limit or a reputation lookup - it runs on the event loop, so it
+/// mustn't block, and is Send so the server can be moved to the thread running it
+pub trait AcceptPolicy: Send {
+    /// None leaves the client to the next policy
+    fn decide(&mut self, arrival: &Arrival) -> Option<Verdict>;
+}
+
+/// everything that decides on a client before it's trapped
+#[derive(Default)]
+pub(crate) struct Admission {
+    /// peers that are hung up on instead of trapped
+    pub allowlist: AddressList,
+    policies: Vec<Box<dyn AcceptPolicy>>,
+    /// only counted once there's a policy to read them
+    strikes: HashMap<IpAddr, u32>,
+}
+
+impl Admission {
+    pub fn add_policy(&mut self, policy: Box<dyn AcceptPolicy>) {
+        self.policies.push(policy);
+    }
+
+    /// the first verdict of the added policies, counting a strike against the address either way
+    pub fn decide(&mut self, peer: SocketAddr, listener: Option<&str>, clients: usize, max_clients: usize) -> Option<Verdict> {
+        if self.policies.is_empty() {
+            return None;
+        }
+        let ip = peer.ip().to_canonical();
+        if self.strikes.len() >= MAX_TRACKED_ADDRESSES && !self.strikes.contains_key(&ip) {
+            self.strikes.clear();
+        }
+        let strikes = self.strikes.entry(ip).or_insert(0);
+        let arrival = Arrival { peer, listener, clients, max_clients, strikes: *strikes };
+        *strikes += 1;
+        self.policies.iter_mut().find_map(|policy| policy.decide(&arrival))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// a policy made of a closure
+    struct Decide<F>(F);
+
+    impl<F: FnMut(&Arrival) -> Option<Verdict> + Send> AcceptPolicy for Decide<F> {
+        fn decide(&mut self, arrival: &Arrival) -> Option<Verdict> {
+            (self.0)(arrival)
+        }
+    }
+
+    fn peer(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn the_first_policy_with_a_verdict_decides() {
+        let mut admission = Admission::default();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let log = asked.clone();
+        admission.add_policy(Box::new(Decide(move |arrival: &Arrival| {
+            log.lock().unwrap().push((arrival.listener.map(str::to_string), arrival.clients, arrival.max_clients));
+            None
+        })));
+        admission.add_policy(Box::new(Decide(|arrival: &Arrival| (arrival.peer.port() == 1).then_some(Verdict::Close))));
+        admission.add_policy(Box::new(Decide(|_: &Arrival| Some(Verdict::Pass))));
+        admission.add_policy(Box::new(Decide(|_: &Arrival| -> Option<Verdict> { panic!("asked after a verdict") })));
+
+        assert_eq!(admission.decide(peer("192.0.2.1:1"), Some("public"), 3, 10), Some(Verdict::Close));
+        assert_eq!(admission.decide(peer("192.0.2.1:2"), None, 4, 10), Some(Verdict::Pass));
+        assert_eq!(*asked.lock().unwrap(), [(Some("public".to_string()), 3, 10), (None, 4, 10)]);
+    }
+
+    #[test]
+    fn strikes_count_earlier_arrivals_from_the_same_address() {
+        let mut admission = Admission::default();
+        assert_eq!(admission.decide(peer("192.0.2.1:1"), None, 0, 10), None);
+        // nothing is counted without a policy to read it
+        assert!(admission.strikes.is_empty());
+
+        let strikes = Arc::new(Mutex::new(Vec::new()));
+        let log = strikes.clone();
+        admission.add_policy(Box::new(Decide(move |arrival: &Arrival| {
+            log.lock().unwrap().push(arrival.strikes);
+            None
+        })));
+        for address in ["192.0.2.1:1", "192.0.2.1:2", "192.0.2.2:1", "[::ffff:192.0.2.1]:3"] {
+            assert_eq!(admission.decide(peer(address), None, 0, 10), None);
+        }
+        // a dual stack listener's mapped address is the same client
+        assert_eq!(*strikes.lock().unwrap(), [0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn strikes_are_forgotten_past_the_tracked_addresses() {
+        let mut admission = Admission::default();
+        admission.add_policy(Box::new(Decide(|_: &Arrival| None)));
+        for i in 0..MAX_TRACKED_ADDRESSES as u32 {
+            admission.decide(SocketAddr::from((i.to_be_bytes(), 1)), None, 0, 10);
+        }
+        assert_eq!(admission.strikes.len(), MAX_TRACKED_ADDRESSES);
+        // addresses already tracked are still counted
+        admission.decide(SocketAddr::from((1_u32.to_be_bytes(), 1)), None, 0, 10);
+        assert_eq!(admission.strikes.len(), MAX_TRACKED_ADDRESSES);
+        admission.decide(peer("198.51.100.1:1"), None, 0, 10);
+        assert_eq!(admission.strikes.len(), 1);
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt::Display;
+use std::time::SystemTime;
+
+use crate::endlessh::unix_seconds;
+use crate::exposition::{Exposition, MetricType};
+
+/// adds the process metrics, named like the prometheus client libraries' process collectors so their dashboards and
+/// alerts work as is - the cpu counter's sample is `process_cpu_seconds_total`. those the platform can't tell are left
+/// out
+pub fn expose(exposition: &mut Exposition, labels: &impl Display, started_at: SystemTime) {
+    let gauges = [
+        ("process_open_fds", "open file descriptors", open_files()),
+        ("process_max_fds", "the limit on open file descriptors", open_files_limit()),
+        ("process_resident_memory_bytes", "resident memory size in bytes", resident_memory_bytes()),
+    ];
+    for (name, help, value) in gauges {
+        if let Some(value) = value {
+            exposition.family(name, MetricType::Gauge, help);
+            exposition.sample(labels, value);
+        }
+    }
+    if let Some(cpu_seconds) = cpu_seconds() {
+        exposition.family("process_cpu_seconds", MetricType::Counter, "total user and system cpu time spent in seconds");
+        exposition.sample(labels, cpu_seconds);
+    }
+    exposition.family("process_start_time_seconds", MetricType::Gauge, "start time of the process since unix epoch in seconds");
+    exposition.sample(labels, unix_seconds(started_at));
+}
+
+/// resident set size of this process, where the platform makes it cheap to find out
+#[cfg(target_os = "linux")]
+pub fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status.lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_memory_bytes() -> Option<u64> {
+    None
+}
+
+/// file descriptors this process has open
+#[cfg(target_os = "linux")]
+pub fn open_files() -> Option<u64> {
+    // less the one reading the directory
+    Some(std::fs::read_dir("/proc/self/fd").ok()?.count().saturating_sub(1) as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_files() -> Option<u64> {
+    None
+}
+
+/// user and system cpu time this process has used, in seconds
+#[cfg(target_os = "linux")]
+pub fn cpu_seconds() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // the command name in parentheses can have spaces of its own, utime and stime are the 12th and 13th fields after it
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let ticks = fields.next()?.parse::<u64>().ok()? + fields.next()?.parse::<u64>().ok()?;
+    // SAFETY: sysconf only reads a value
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks_per_second > 0).then(|| ticks as f64 / ticks_per_second as f64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cpu_seconds() -> Option<f64> {
+    None
+}
+
+/// the soft limit on open file descriptors, which every trapped client takes one of
+#[cfg(target_os = "linux")]
+pub fn open_files_limit() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: getrlimit only writes the struct it's handed
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    (result == 0 && limit.rlim_cur != libc::RLIM_INFINITY).then_some(limit.rlim_cur)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_files_limit() -> Option<u64> {
+    None
+}
+
+/// switches to `user` and `group` (names or numeric ids) for good, clearing the supplementary groups. without a group
+/// the user's primary group is used, without a user only the group changes
+///
+/// returns the uid and gid now in effect, after checking that root can't be regained
+#[cfg(target_os = "linux")]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let user = user.map(lookup_user).transpose()?;
+    // SAFETY: getuid and getgid can't fail
+    let (uid, primary_gid) = user.unwrap_or_else(|| unsafe { (libc::getuid(), libc::getgid()) });
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid,
+    };
+    // SAFETY: an empty list needs no pointer
+    if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+        return Err(format!("failed to clear the supplementary groups - {}", std::io::Error::last_os_error()));
+    }
+    // the group first, the user can't change it afterwards
+    // SAFETY: plain syscalls on this process
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(format!("failed to switch to group {} - {}", gid, std::io::Error::last_os_error()));
+    }
+    // SAFETY: as above
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(format!("failed to switch to user {} - {}", uid, std::io::Error::last_os_error()));
+    }
+    // SAFETY: as above, and setuid(0) only succeeds if the drop didn't stick
+    let regained = unsafe { uid != 0 && libc::setuid(0) == 0 };
+    // SAFETY: these can't fail
+    let (effective_uid, effective_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if regained || effective_uid != uid || effective_gid != gid {
+        return Err(format!("still running as uid {} gid {} after switching to uid {} gid {}", effective_uid, effective_gid, uid, gid));
+    }
+    Ok((uid, gid))
+}
+
+/// the uid and primary gid of a user name or uid
+#[cfg(target_os = "linux")]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let name = std::ffi::CString::new(user).map_err(|_| format!("bad user name {:?}", user))?;
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: passwd is plain data, for which all zeroes is a valid value
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        // SAFETY: the pointers describe `entry` and `buffer`, which outlive the call, and the strings it fills in point
+        // into `buffer` - only the ids are read out of it
+        let result = match user.parse::<libc::uid_t>() {
+            Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) },
+            Err(_) => unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) },
+        };
+        match result {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if found.is_null() => return Err(format!("no such user {}", user)),
+            0 => return Ok((entry.pw_uid, entry.pw_gid)),
+            e => return Err(format!("failed to look up user {} - {}", user, std::io::Error::from_raw_os_error(e))),
+        }
+    }
+}
+
+/// the gid of a group name or gid - a number is taken as is, groups needn't exist to be switched to
+#[cfg(target_os = "linux")]
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = std::ffi::CString::new(group).map_err(|_| format!("bad group name {:?}", group))?;
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: group is plain data, for which all zeroes is a valid value
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        // SAFETY: as in lookup_user
+        let result = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+        match result {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if found.is_null() => return Err(format!("no such group {}", group)),
+            0 => return Ok(entry.gr_gid),
+            e => return Err(format!("failed to look up group {} - {}", group, std::io::Error::from_raw_os_error(e))),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_are_looked_up_by_name_or_uid() {
+        assert_eq!(lookup_user("root"), Ok((0, 0)));
+        assert_eq!(lookup_user("0"), Ok((0, 0)));
+        assert_eq!(lookup_user("no-such-user-endlessh").unwrap_err(), "no such user no-such-user-endlessh");
+        assert!(lookup_user("bad\0name").unwrap_err().starts_with("bad user name"));
+    }
+
+    #[test]
+    fn groups_are_looked_up_by_name_and_numbers_taken_as_is() {
+        assert_eq!(lookup_group("root"), Ok(0));
+        // needn't exist
+        assert_eq!(lookup_group("64999"), Ok(64_999));
+        assert_eq!(lookup_group("no-such-group-endlessh").unwrap_err(), "no such group no-such-group-endlessh");
+    }
+
+    #[test]
+    fn open_files_are_counted_without_the_directory_being_read() {
+        let open = open_files().unwrap();
+        // stdin, stdout and stderr at least, other tests open files at the same time so nothing more exact holds
+        assert!(open >= 3, "{} open", open);
+        assert!(open <= open_files_limit().unwrap_or(u64::MAX), "{} open", open);
+    }
+
+    #[test]
+    fn memory_and_cpu_time_are_read_from_proc() {
+        let memory = resident_memory_bytes().unwrap();
+        assert!(memory > 0 && memory.is_multiple_of(1024), "{} bytes", memory);
+        let spent = cpu_seconds().unwrap();
+        assert!(spent >= 0.0, "{} seconds", spent);
+        assert!(cpu_seconds().unwrap() >= spent);
+    }
+
+    #[test]
+    fn the_process_families_are_exposed_with_the_labels() {
+        let mut exposition = Exposition::new();
+        expose(&mut exposition, &"{instance=\"a\"}", SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000));
+        let body = exposition.finish();
+        for name in ["process_open_fds", "process_max_fds", "process_resident_memory_bytes"] {
+            assert!(body.contains(&format!("# TYPE {} gauge\n{}{{instance=\"a\"}} ", name, name)), "no {} in {}", name, body);
+        }
+        assert!(body.contains("# TYPE process_cpu_seconds counter\nprocess_cpu_seconds_total{instance=\"a\"} "), "unexpected metrics {}", body);
+        assert!(body.contains("process_start_time_seconds{instance=\"a\"} 1700000000\n"), "unexpected metrics {}", body);
+    }
+}
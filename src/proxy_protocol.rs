@@ -0,0 +1,110 @@
+//! haproxy's PROXY protocol, the header a load balancer sends ahead of a connection to say who it's relaying
+//!
+//! both versions are understood - v1 is a line of text, v2 a binary block starting with a fixed signature. only the
+//! source address is used, the rest (destination, TLVs) is skipped. the header can arrive in pieces, so parsing what's
+//! been read so far may just say to wait for more
+//!
+//! see https://www.haproxy.org/download/3.0/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// the longest v1 header, a TCP6 line with both addresses and ports at their longest
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// the signature, version and command, address family and transport, and the length of the rest
+const V2_FIXED_LENGTH: usize = 16;
+/// the addresses plus room for a balancer's TLVs - anything longer is refused rather than buffered
+pub const MAX_HEADER_LENGTH: usize = 1024;
+
+const V2_COMMAND_LOCAL: u8 = 0x0;
+const V2_COMMAND_PROXY: u8 = 0x1;
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// relayed on behalf of a client at this address
+    Proxied(SocketAddr),
+    /// the balancer's own connection, e.g. a health check, or one it can't give an address for
+    Local,
+}
+
+/// the header at the start of `data`, None if it hasn't all arrived yet
+pub fn parse(data: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    if data.is_empty() {
+        Ok(None)
+    } else if data[0] == V1_PREFIX[0] {
+        parse_v1(data)
+    } else if data[0] == V2_SIGNATURE[0] {
+        parse_v2(data)
+    } else {
+        Err("not a PROXY header".to_string())
+    }
+}
+
+fn parse_v1(data: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    let prefix_length = data.len().min(V1_PREFIX.len());
+    if data[..prefix_length] != V1_PREFIX[..prefix_length] {
+        return Err("not a PROXY header".to_string());
+    }
+    let searched = &data[..data.len().min(V1_MAX_LENGTH)];
+    let Some(end) = searched.windows(2).position(|w| w == b"\r\n") else {
+        return if data.len() < V1_MAX_LENGTH { Ok(None) } else { Err("v1 header too long".to_string()) };
+    };
+    let line = std::str::from_utf8(&data[..end]).map_err(|_| "v1 header isn't ascii".to_string())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        // the balancer doesn't know, and the rest of the line is to be ignored
+        [_, "UNKNOWN", ..] => Ok(Some(ProxyHeader::Local)),
+        [_, protocol @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let source: IpAddr = source.parse().map_err(|_| format!("bad v1 source address {}", source))?;
+            if source.is_ipv4() != (protocol == "TCP4") {
+                return Err(format!("v1 source address {} isn't {}", source, protocol));
+            }
+            let source_port: u16 = source_port.parse().map_err(|_| format!("bad v1 source port {}", source_port))?;
+            Ok(Some(ProxyHeader::Proxied(SocketAddr::new(source, source_port))))
+        },
+        _ => Err(format!("malformed v1 header {:?}", line)),
+    }
+}
+
+fn parse_v2(data: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    let prefix_length = data.len().min(V2_SIGNATURE.len());
+    if data[..prefix_length] != V2_SIGNATURE[..prefix_length] {
+        return Err("not a PROXY header".to_string());
+    }
+    if data.len() < V2_FIXED_LENGTH {
+        return Ok(None);
+    }
+    let (version, command) = (data[12] >> 4, data[12] & 0x0f);
+    if version != 2 {
+        return Err(format!("unsupported v2 version {}", version));
+    }
+    let family = data[13] >> 4;
+    let length = V2_FIXED_LENGTH + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if length > MAX_HEADER_LENGTH {
+        return Err(format!("v2 header of {} bytes is too long", length));
+    }
+    let Some(addresses) = data.get(V2_FIXED_LENGTH..length) else {
+        return Ok(None);
+    };
+    match (command, family) {
+        (V2_COMMAND_LOCAL, _) => Ok(Some(ProxyHeader::Local)),
+        // source and destination addresses, then source and destination ports
+        (V2_COMMAND_PROXY, V2_FAMILY_INET) if addresses.len() >= 12 => {
+            let source = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(ProxyHeader::Proxied(SocketAddr::new(source.into(), port))))
+        },
+        (V2_COMMAND_PROXY, V2_FAMILY_INET6) if addresses.len() >= 36 => {
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(ProxyHeader::Proxied(SocketAddr::new(source.into(), port))))
+        },
+        (V2_COMMAND_PROXY, V2_FAMILY_INET | V2_FAMILY_INET6) => Err("v2 address block too short".to_string()),
+        // unspecified or unix addresses say nothing that could be logged or blocked
+        (V2_COMMAND_PROXY, _) => Ok(Some(ProxyHeader::Local)),
+        (command, _) => Err(format!("unknown v2 command {}", command)),
+    }
+}
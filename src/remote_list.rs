@@ -0,0 +1,206 @@
+//! allowlists and blocklists downloaded over http(s) and refreshed in the background
+//!
+//! fetching happens on a thread of its own so a slow feed never stalls the event loop - the event loop's scheduler asks
+//! for each refresh, and each new list is sent back over a channel and the poll is woken to swap it in whole
+
+use std::fmt::{Display, Formatter};
+use std::sync::mpsc::{self, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use mio::Waker;
+
+use crate::address_list::AddressList;
+use crate::error::{self, EndlesshError};
+
+/// how long a single download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListKind {
+    /// addresses that are never trapped
+    Allow,
+    /// addresses that are blocked on their first disconnect
+    Block,
+}
+
+impl Display for ListKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ListKind::Allow => write!(f, "allowlist"),
+            ListKind::Block => write!(f, "blocklist"),
+        }
+    }
+}
+
+/// a freshly downloaded list, replacing the previous one of its kind
+pub struct ListUpdate {
+    pub kind: ListKind,
+    pub list: AddressList,
+}
+
+pub struct RemoteList {
+    kind: ListKind,
+    url: String,
+    /// from the last successful download, so an unchanged feed costs a 304
+    etag: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl RemoteList {
+
+    pub fn new(kind: ListKind, url: String) -> Self {
+        RemoteList {
+            kind,
+            url,
+            etag: None,
+            agent: ureq::AgentBuilder::new()
+                .timeout(FETCH_TIMEOUT)
+                .user_agent(concat!("endlessh-rs/", env!("CARGO_PKG_VERSION")))
+                .build(),
+        }
+    }
+
+    /// downloads the list, returning None if it hasn't changed since the last call
+    pub fn fetch(&mut self) -> error::Result<Option<AddressList>> {
+        let mut request = self.agent.get(&self.url);
+        if let Some(etag) = &self.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        let response = request.call().map_err(|e| self.error(e))?;
+        if response.status() == 304 {
+            debug!("{} at {} is unchanged", self.kind, self.url);
+            return Ok(None);
+        }
+        let etag = response.header("ETag").map(str::to_string);
+        let body = response.into_string().map_err(|e| self.error(e))?;
+        let (list, invalid) = AddressList::parse(&body);
+        // e.g. an html error page served with a 200, which mustn't wipe out the previous list
+        if list.is_empty() && invalid > 0 {
+            return Err(self.error(format!("no addresses in {} lines", invalid)));
+        }
+        if invalid > 0 {
+            warn!("skipped {} unparseable lines in the {} at {}", invalid, self.kind, self.url);
+        }
+        info!("fetched {} ranges for the {} from {}", list.len(), self.kind, self.url);
+        self.etag = etag;
+        Ok(Some(list))
+    }
+
+    fn error(&self, reason: impl Display) -> EndlesshError {
+        EndlesshError::Fetch {
+            url: self.url.clone(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// re-fetches the list on a background thread whenever the returned [ListRefresher] asks, sending changes to
+    /// `updates` and waking the poll
+    pub fn watch(mut self, updates: Sender<ListUpdate>, waker: Arc<Waker>) -> ListRefresher {
+        // a refresh asked for while one is already waiting adds nothing
+        let (requests, refreshes) = mpsc::sync_channel(1);
+        thread::spawn(move || for () in refreshes {
+            match self.fetch() {
+                Ok(Some(list)) => {
+                    // the event loop is gone, so are we
+                    if updates.send(ListUpdate { kind: self.kind, list }).is_err() {
+                        return;
+                    }
+                    if let Err(e) = waker.wake() {
+                        warn!("failed to wake the event loop for a new {}: {}", self.kind, e);
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => warn!("{}, keeping the previous {}", e, self.kind),
+            }
+        });
+        ListRefresher {
+            requests,
+        }
+    }
+}
+
+/// the event loop's end of a [RemoteList::watch] thread, which stops once this is dropped
+pub struct ListRefresher {
+    requests: SyncSender<()>,
+}
+
+impl ListRefresher {
+
+    /// asks for the list to be fetched again, unless a fetch is already waiting to start
+    pub fn refresh(&self) {
+        if let Err(TrySendError::Disconnected(())) = self.requests.try_send(()) {
+            warn!("the list refresh thread has stopped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, headers, body.len(), body)
+    }
+
+    /// answers one request per response with it, handing back each request's head
+    fn serve(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/blocklist.txt", listener.local_addr().unwrap());
+        let server = thread::spawn(move || responses.into_iter().map(|response| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut head).unwrap() > 2 && !head.ends_with("\r\n\r\n") {}
+            stream.write_all(response.as_bytes()).unwrap();
+            head
+        }).collect());
+        (url, server)
+    }
+
+    #[test]
+    fn unchanged_lists_cost_a_304() {
+        let (url, server) = serve(vec![
+            response("200 OK", "ETag: \"v1\"\r\n", "192.0.2.0/24\n198.51.100.7\n"),
+            response("304 Not Modified", "", ""),
+        ]);
+        let mut list = RemoteList::new(ListKind::Block, url);
+        let fetched = list.fetch().unwrap().unwrap();
+        assert_eq!(fetched.len(), 2);
+        assert!(list.fetch().unwrap().is_none());
+        let requests = server.join().unwrap();
+        assert!(!requests[0].to_ascii_lowercase().contains("if-none-match"));
+        assert!(requests[1].to_ascii_lowercase().contains("if-none-match: \"v1\""), "{}", requests[1]);
+    }
+
+    #[test]
+    fn error_pages_dont_replace_the_list() {
+        let (url, server) = serve(vec![
+            response("200 OK", "", "<html>\nmaintenance\n</html>\n\n"),
+            response("503 Service Unavailable", "", ""),
+        ]);
+        let mut list = RemoteList::new(ListKind::Allow, url.clone());
+        let error = list.fetch().unwrap_err().to_string();
+        assert!(error.contains(&url) && error.contains("no addresses in 3 lines"), "{}", error);
+        assert!(list.fetch().is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watched_lists_are_fetched_when_a_refresh_is_asked_for() {
+        let (url, server) = serve(vec![response("200 OK", "", "192.0.2.0/24\n")]);
+        let poll = mio::Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), mio::Token(0)).unwrap());
+        let (sender, updates) = mpsc::channel();
+        let refresher = RemoteList::new(ListKind::Block, url).watch(sender, waker);
+        assert!(updates.recv_timeout(Duration::from_millis(100)).is_err());
+        refresher.refresh();
+        let update = updates.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!((update.kind, update.list.len()), (ListKind::Block, 1));
+        server.join().unwrap();
+    }
+}
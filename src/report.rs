@@ -0,0 +1,545 @@
+//! a periodic (by default daily) digest of who was trapped, appended to a file and optionally posted to a webhook or mailed
+//!
+//! the report is built from the same connection events as the event log, so it costs one pass over each event and a set
+//! of the addresses seen. given a [NetworkTable] it ranks the countries and networks (ASNs) the connections came from too
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::endlessh::{ConnectionEvent, ConnectionEventKind};
+use crate::geo::NetworkTable;
+
+/// how many entries each ranking keeps
+const REPORT_TOP_LENGTH: usize = 10;
+/// past this many distinct addresses new ones are no longer counted, so a scan from a huge range can't eat the memory
+const MAX_TRACKED_ADDRESSES: usize = 100_000;
+#[cfg(any(feature = "report", feature = "notify"))]
+const WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AddressCount {
+    pub address: IpAddr,
+    pub connections: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CountryCount {
+    /// the two letter code the networks are registered under
+    pub country: String,
+    pub connections: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NetworkCount {
+    pub asn: u32,
+    pub name: String,
+    pub connections: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LongTrap {
+    pub peer: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener: Option<String>,
+    pub trapped_seconds: f64,
+    pub bytes_sent: usize,
+}
+
+/// one period's digest
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// seconds since the unix epoch
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+    pub connections: u64,
+    pub unique_addresses: usize,
+    /// whether more addresses turned up than are tracked, making unique_addresses a lower bound
+    pub unique_addresses_capped: bool,
+    /// total time spent trapped by the clients that left in the period
+    pub trapped_seconds: f64,
+    pub top_addresses: Vec<AddressCount>,
+    /// only with a [NetworkTable], and only counting the connections from addresses it has
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_countries: Vec<CountryCount>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_networks: Vec<NetworkCount>,
+    pub longest_traps: Vec<LongTrap>,
+}
+
+/// collects events until the next summary is taken
+#[derive(Debug)]
+pub struct Report {
+    start_seconds: f64,
+    connections: u64,
+    addresses: HashMap<IpAddr, u64>,
+    addresses_capped: bool,
+    networks: Option<Arc<NetworkTable>>,
+    countries: HashMap<String, u64>,
+    asns: HashMap<u32, u64>,
+    trapped_seconds: f64,
+    /// longest first
+    longest_traps: Vec<LongTrap>,
+}
+
+fn unix_seconds() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Report {
+
+    pub fn new() -> Self {
+        Report {
+            start_seconds: unix_seconds(),
+            connections: 0,
+            addresses: HashMap::new(),
+            addresses_capped: false,
+            networks: None,
+            countries: HashMap::new(),
+            asns: HashMap::new(),
+            trapped_seconds: 0.0,
+            longest_traps: Vec::with_capacity(REPORT_TOP_LENGTH + 1),
+        }
+    }
+
+    /// a report that also ranks the countries and networks of the addresses in `networks`
+    pub fn with_networks(networks: Arc<NetworkTable>) -> Self {
+        Report {
+            networks: Some(networks),
+            ..Report::new()
+        }
+    }
+
+    pub fn record(&mut self, event: &ConnectionEvent) {
+        match event.event {
+            ConnectionEventKind::Accepted => {
+                self.connections += 1;
+                let address = event.peer.ip().to_canonical();
+                if let Some(count) = self.addresses.get_mut(&address) {
+                    *count += 1;
+                } else if self.addresses.len() < MAX_TRACKED_ADDRESSES {
+                    self.addresses.insert(address, 1);
+                } else {
+                    self.addresses_capped = true;
+                }
+                // there are far fewer networks than addresses, and no more of them than the table has
+                if let Some(network) = self.networks.as_ref().and_then(|networks| networks.lookup(address)) {
+                    *self.asns.entry(network.asn).or_default() += 1;
+                    if let Some(country) = network.country {
+                        match self.countries.get_mut(country) {
+                            Some(count) => *count += 1,
+                            None => {
+                                self.countries.insert(country.to_string(), 1);
+                            },
+                        }
+                    }
+                }
+            },
+            ConnectionEventKind::LineSent | ConnectionEventKind::Banned => {},
+            ConnectionEventKind::Closed => {
+                self.trapped_seconds += event.trapped_seconds;
+                let position = self.longest_traps.partition_point(|t| t.trapped_seconds >= event.trapped_seconds);
+                if position < REPORT_TOP_LENGTH {
+                    self.longest_traps.insert(position, LongTrap {
+                        peer: event.peer,
+                        listener: event.listener.clone(),
+                        trapped_seconds: event.trapped_seconds,
+                        bytes_sent: event.bytes_sent,
+                    });
+                    self.longest_traps.truncate(REPORT_TOP_LENGTH);
+                }
+            },
+        }
+    }
+
+    /// the summary of everything recorded since the last one, starting the next period
+    pub fn take_summary(&mut self) -> Summary {
+        let next = Report {
+            networks: self.networks.clone(),
+            ..Report::new()
+        };
+        let report = std::mem::replace(self, next);
+        let mut top_addresses: Vec<AddressCount> = report.addresses.iter()
+            .map(|(&address, &connections)| AddressCount { address, connections })
+            .collect();
+        top_addresses.sort_unstable_by_key(|a| (Reverse(a.connections), a.address));
+        top_addresses.truncate(REPORT_TOP_LENGTH);
+        let mut top_countries: Vec<CountryCount> = report.countries.into_iter()
+            .map(|(country, connections)| CountryCount { country, connections })
+            .collect();
+        top_countries.sort_unstable_by(|a, b| b.connections.cmp(&a.connections).then_with(|| a.country.cmp(&b.country)));
+        top_countries.truncate(REPORT_TOP_LENGTH);
+        let mut top_asns: Vec<(u32, u64)> = report.asns.into_iter().collect();
+        top_asns.sort_unstable_by_key(|&(asn, connections)| (Reverse(connections), asn));
+        top_asns.truncate(REPORT_TOP_LENGTH);
+        let top_networks = top_asns.into_iter()
+            .map(|(asn, connections)| NetworkCount {
+                asn,
+                name: report.networks.as_ref().and_then(|networks| networks.name(asn)).unwrap_or_default().to_string(),
+                connections,
+            })
+            .collect();
+        Summary {
+            start_seconds: report.start_seconds,
+            end_seconds: self.start_seconds,
+            connections: report.connections,
+            unique_addresses: report.addresses.len(),
+            unique_addresses_capped: report.addresses_capped,
+            trapped_seconds: report.trapped_seconds,
+            top_addresses,
+            top_countries,
+            top_networks,
+            longest_traps: report.longest_traps,
+        }
+    }
+}
+
+impl Summary {
+
+    /// the summary as plain text, for reading in a mail client
+    pub fn text(&self) -> String {
+        let hours = (self.end_seconds - self.start_seconds) / 3600.0;
+        let mut text = format!("{} connections from {}{} addresses over {:.1} hours, {:.0}s spent trapped\n",
+            self.connections, self.unique_addresses, if self.unique_addresses_capped { " or more" } else { "" }, hours, self.trapped_seconds);
+        if !self.top_addresses.is_empty() {
+            text.push_str("\ntop addresses:\n");
+            for count in &self.top_addresses {
+                text.push_str(&format!("  {:<39}  {}\n", count.address, count.connections));
+            }
+        }
+        if !self.top_countries.is_empty() {
+            text.push_str("\ntop countries:\n");
+            for count in &self.top_countries {
+                text.push_str(&format!("  {:<39}  {}\n", count.country, count.connections));
+            }
+        }
+        if !self.top_networks.is_empty() {
+            text.push_str("\ntop networks:\n");
+            for count in &self.top_networks {
+                text.push_str(&format!("  {:<39}  {}\n", format!("AS{} {}", count.asn, count.name), count.connections));
+            }
+        }
+        if !self.longest_traps.is_empty() {
+            text.push_str("\nlongest traps:\n");
+            for trap in &self.longest_traps {
+                text.push_str(&format!("  {:<39}  {:.0}s, {} bytes\n", trap.peer, trap.trapped_seconds, trap.bytes_sent));
+            }
+        }
+        text
+    }
+}
+
+/// where each summary goes, and what goes into it
+#[derive(Default)]
+pub struct ReportOptions {
+    /// appended to as a line of json
+    pub file: Option<PathBuf>,
+    #[cfg(feature = "report")]
+    pub webhook: Option<Webhook>,
+    pub email: Option<EmailReport>,
+    /// to rank the countries and networks the connections came from as well as their addresses
+    pub networks: Option<Arc<NetworkTable>>,
+}
+
+/// where finished summaries go
+pub struct Reporter {
+    report: Report,
+    file: Option<PathBuf>,
+    #[cfg(feature = "report")]
+    webhook: Option<Webhook>,
+    email: Option<EmailReport>,
+}
+
+impl Reporter {
+
+    pub fn new(options: ReportOptions) -> Self {
+        Reporter {
+            report: options.networks.map_or_else(Report::new, Report::with_networks),
+            file: options.file,
+            #[cfg(feature = "report")]
+            webhook: options.webhook,
+            email: options.email,
+        }
+    }
+
+    pub fn record(&mut self, event: &ConnectionEvent) {
+        self.report.record(event);
+    }
+
+    /// ends the period, sending its summary everywhere it's configured to go
+    pub fn publish(&mut self) {
+        let summary = self.report.take_summary();
+        info!("report: {} connections from {} addresses, {:.0}s trapped", summary.connections, summary.unique_addresses, summary.trapped_seconds);
+        if let Some(path) = &self.file {
+            if let Err(e) = append(path, &summary) {
+                warn!("failed to write the report to {} - {}", path.display(), e);
+            }
+        }
+        #[cfg(feature = "report")]
+        if let Some(webhook) = &self.webhook {
+            webhook.send("report", &summary);
+        }
+        if let Some(email) = &self.email {
+            email.send(&summary);
+        }
+    }
+}
+
+/// mails each summary through the local mail transfer agent's sendmail, as cron does, so there's no smtp server or
+/// credentials to configure here
+#[derive(Clone, Debug)]
+pub struct EmailReport {
+    sendmail: PathBuf,
+    from: Option<String>,
+    to: Vec<String>,
+}
+
+impl EmailReport {
+
+    /// fails without a recipient, or for an address that could break out of its header or pass for a sendmail option
+    pub fn new(sendmail: PathBuf, from: Option<String>, to: Vec<String>) -> Result<Self, String> {
+        if to.is_empty() {
+            return Err("a mailed report needs at least one recipient".to_string());
+        }
+        if let Some(address) = from.iter().chain(&to).find(|address| !is_valid_email_address(address)) {
+            return Err(format!("\"{}\" is not an email address", address));
+        }
+        Ok(EmailReport { sendmail, from, to })
+    }
+
+    /// hands the message to sendmail on a thread of its own, so a slow mail server never stalls the event loop
+    pub fn send(&self, summary: &Summary) {
+        let message = self.message(summary);
+        let mut command = Command::new(&self.sendmail);
+        // -i so a line of just "." doesn't end the message early
+        command.arg("-i");
+        if let Some(from) = &self.from {
+            command.arg("-f").arg(from);
+        }
+        command.args(&self.to).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped());
+        let sendmail = self.sendmail.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = run_sendmail(command, &message) {
+                warn!("failed to mail the report with {} - {}", sendmail.display(), e);
+            }
+        });
+    }
+
+    fn message(&self, summary: &Summary) -> String {
+        let mut message = String::new();
+        if let Some(from) = &self.from {
+            message.push_str(&format!("From: {}\n", from));
+        }
+        message.push_str(&format!("To: {}\n", self.to.join(", ")));
+        message.push_str(&format!("Subject: endlessh-rs report - {} connections from {} addresses\n", summary.connections, summary.unique_addresses));
+        message.push_str("MIME-Version: 1.0\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\n");
+        message.push_str(&summary.text());
+        message
+    }
+}
+
+fn is_valid_email_address(address: &str) -> bool {
+    address.contains('@') && !address.starts_with('-')
+        && !address.chars().any(|c| c.is_whitespace() || c.is_control() || ",;<>\"".contains(c))
+}
+
+fn run_sendmail(mut command: Command, message: &str) -> io::Result<()> {
+    let mut child = command.spawn()?;
+    let written = child.stdin.take().expect("sendmail's stdin is piped").write_all(message.as_bytes());
+    // waited on even if the write failed, so it isn't left a zombie
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("{} - {}", output.status, String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    written
+}
+
+/// appends the summary as one line of json
+fn append(path: &Path, summary: &Summary) -> io::Result<()> {
+    let mut line = serde_json::to_string(summary).expect("failed to serialize report");
+    line.push('\n');
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())
+}
+
+/// posts json on a thread of its own, so a slow endpoint never stalls the event loop - the reports' summaries, and the
+/// notifications of [crate::notify]
+#[cfg(any(feature = "report", feature = "notify"))]
+pub struct Webhook {
+    url: String,
+    agent: ureq::Agent,
+}
+
+#[cfg(any(feature = "report", feature = "notify"))]
+impl Webhook {
+
+    pub fn new(url: String) -> Self {
+        Webhook {
+            url,
+            agent: ureq::AgentBuilder::new()
+                .timeout(WEBHOOK_TIMEOUT)
+                .user_agent(concat!("endlessh-rs/", env!("CARGO_PKG_VERSION")))
+                .build(),
+        }
+    }
+
+    pub fn send(&self, what: &str, body: &impl Serialize) {
+        let body = serde_json::to_string(body).expect("failed to serialize webhook body");
+        let what = what.to_string();
+        let request = self.agent.post(&self.url).set("Content-Type", "application/json");
+        let url = self.url.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = request.send_string(&body) {
+                warn!("failed to post the {} to {} - {}", what, url, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: ConnectionEventKind, peer: &str, trapped_seconds: f64) -> ConnectionEvent {
+        serde_json::from_value(serde_json::json!({
+            "event": kind,
+            "time_seconds": 0.0,
+            "peer": peer,
+            "bytes_sent": 100,
+            "trapped_seconds": trapped_seconds,
+        })).unwrap()
+    }
+
+    /// a report of `arrivals` connections from each address, each trapped for as many seconds as its position
+    fn report_of(mut report: Report, arrivals: &[(&str, usize)]) -> Summary {
+        for (i, &(address, connections)) in arrivals.iter().enumerate() {
+            let peer = format!("{}:22", address);
+            for _ in 0..connections {
+                report.record(&event(ConnectionEventKind::Accepted, &peer, 0.0));
+                report.record(&event(ConnectionEventKind::Closed, &peer, i as f64));
+            }
+        }
+        report.take_summary()
+    }
+
+    #[test]
+    fn the_busiest_addresses_and_longest_traps_are_ranked() {
+        let arrivals: Vec<(String, usize)> = (1..=14).map(|i| (format!("192.0.2.{}", i), i % 4)).collect();
+        let arrivals: Vec<(&str, usize)> = arrivals.iter().map(|(address, count)| (address.as_str(), *count)).collect();
+        let summary = report_of(Report::new(), &arrivals);
+        assert_eq!(summary.connections, 21);
+        assert_eq!(summary.unique_addresses, 11);
+        assert!(!summary.unique_addresses_capped);
+        let top: Vec<_> = summary.top_addresses.iter().map(|count| (count.address.to_string(), count.connections)).collect();
+        // ties in address order
+        assert_eq!(&top[..4], [
+            ("192.0.2.3".to_string(), 3), ("192.0.2.7".to_string(), 3), ("192.0.2.11".to_string(), 3), ("192.0.2.2".to_string(), 2),
+        ]);
+        assert_eq!(top.len(), REPORT_TOP_LENGTH);
+        let longest: Vec<_> = summary.longest_traps.iter().map(|trap| trap.trapped_seconds).collect();
+        assert_eq!(longest, [13.0, 13.0, 12.0, 10.0, 10.0, 10.0, 9.0, 9.0, 8.0, 6.0]);
+        assert_eq!(summary.trapped_seconds, (0..14).map(|i| (i * ((i + 1) % 4)) as f64).sum::<f64>());
+        assert!(summary.top_countries.is_empty() && summary.top_networks.is_empty());
+    }
+
+    #[test]
+    fn each_summary_starts_the_next_period() {
+        let mut report = Report::new();
+        report.record(&event(ConnectionEventKind::Accepted, "192.0.2.1:22", 0.0));
+        let first = report.take_summary();
+        let second = report.take_summary();
+        assert_eq!((first.connections, second.connections), (1, 0));
+        assert_eq!(first.end_seconds, second.start_seconds);
+        assert!(second.top_addresses.is_empty());
+        // and says nothing of countries without a table
+        let json = serde_json::to_value(&second).unwrap();
+        assert!(json.get("top_countries").is_none() && json.get("top_networks").is_none());
+    }
+
+    #[test]
+    fn countries_and_networks_are_ranked_from_the_table() {
+        let (networks, _) = NetworkTable::parse("\
+192.0.2.0\t192.0.2.127\t64496\tUS\tEXAMPLE-ONE
+192.0.2.128\t192.0.2.255\t64497\tUS\tEXAMPLE-TWO
+198.51.100.0\t198.51.100.255\t64498\tNone\tEXAMPLE-THREE
+2001:db8::\t2001:db8::ffff\t64499\tDE\tEXAMPLE-FOUR
+");
+        let report = Report::with_networks(Arc::new(networks));
+        let summary = report_of(report, &[("192.0.2.1", 1), ("192.0.2.200", 2), ("198.51.100.1", 3), ("[2001:db8::1]", 2), ("203.0.113.1", 5)]);
+        let countries: Vec<_> = summary.top_countries.iter().map(|count| (count.country.as_str(), count.connections)).collect();
+        assert_eq!(countries, [("US", 3), ("DE", 2)]);
+        let networks: Vec<_> = summary.top_networks.iter().map(|count| (count.asn, count.name.as_str(), count.connections)).collect();
+        assert_eq!(networks, [(64498, "EXAMPLE-THREE", 3), (64497, "EXAMPLE-TWO", 2), (64499, "EXAMPLE-FOUR", 2), (64496, "EXAMPLE-ONE", 1)]);
+        assert!(summary.text().contains("\ntop networks:\n  AS64498 EXAMPLE-THREE"));
+    }
+
+    #[test]
+    fn mailed_reports_need_plain_addresses() {
+        let email = |from: Option<&str>, to: &[&str]| {
+            EmailReport::new(PathBuf::from("sendmail"), from.map(str::to_string), to.iter().map(|to| to.to_string()).collect())
+        };
+        assert!(email(None, &["ops@example.com"]).is_ok());
+        assert!(email(Some("tarpit@example.com"), &["ops@example.com", "security@example.com"]).is_ok());
+        assert!(email(None, &[]).is_err());
+        for invalid in ["ops", "-oQ@example.com", "ops@example.com\nBcc: x@example.com", "Ops <ops@example.com>", "a@example.com, b@example.com"] {
+            assert!(email(None, &[invalid]).is_err(), "{}", invalid);
+            assert!(email(Some(invalid), &["ops@example.com"]).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reports_are_handed_to_sendmail() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, Instant};
+
+        let dir = std::env::temp_dir().join(format!("endlessh-rs-sendmail-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let sendmail = dir.join("sendmail");
+        std::fs::write(&sendmail, "#!/bin/sh\nd=$(dirname \"$0\")\necho \"$@\" > \"$d/args\"\ncat > \"$d/message.tmp\" && mv \"$d/message.tmp\" \"$d/message\"\n").unwrap();
+        std::fs::set_permissions(&sendmail, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let email = EmailReport::new(sendmail, Some("tarpit@example.com".into()), vec!["ops@example.com".into(), "security@example.com".into()]).unwrap();
+        let summary = report_of(Report::new(), &[("192.0.2.1", 2)]);
+        email.send(&summary);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !dir.join("message").exists() {
+            assert!(Instant::now() < deadline, "sendmail was never run");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let args = std::fs::read_to_string(dir.join("args")).unwrap();
+        let message = std::fs::read_to_string(dir.join("message")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(args, "-i -f tarpit@example.com ops@example.com security@example.com\n");
+        let (headers, body) = message.split_once("\n\n").unwrap();
+        assert!(headers.starts_with("From: tarpit@example.com\nTo: ops@example.com, security@example.com\n"), "{}", headers);
+        assert!(headers.contains("\nSubject: endlessh-rs report - 2 connections from 1 addresses\n"), "{}", headers);
+        assert_eq!(body, summary.text());
+        assert!(body.contains("\ntop addresses:\n  192.0.2.1"), "{}", body);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sendmail_failing_is_an_error() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "cat > /dev/null; echo 'no such user' >&2; exit 67"]).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped());
+        let error = run_sendmail(command, "Subject: test\n\nbody\n").unwrap_err();
+        assert!(error.to_string().contains("no such user"), "{}", error);
+        assert!(run_sendmail(Command::new("/nonexistent/sendmail"), "").is_err());
+    }
+}
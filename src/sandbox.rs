@@ -0,0 +1,320 @@
+//! a seccomp filter for the event loop, the one thread attackers can reach
+//!
+//! installed once startup is done, it allows what the loop does after that - polling, accepting, reading and writing
+//! sockets, writing its log and state files, timers and memory - and refuses exec, ptrace, binding and listening, and
+//! anything else not listed. a few calls are only allowed with certain arguments: sockets have to be unix, ipv4 or ipv6
+//! streams or datagrams (syslog and journald are reconnected from the loop), memory can't be made executable, and
+//! ioctl can only set non-blocking or close-on-exec. opening files isn't restricted by path, since the log, event log
+//! and state files can be anywhere. the filter is per thread and inherited by threads started later, so exporters
+//! started beforehand (remote lists, elasticsearch, hpfeeds) keep their network access, and the firewall worker can
+//! still run its commands
+//!
+//! a blocked call raises SIGSYS, whose handler names the call before exiting, so a missing entry is easy to report
+
+use std::io;
+
+use crate::exit::FatalError;
+
+/// the architecture the syscall numbers below are for, see linux/audit.h
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("the sandbox feature only knows the syscalls of x86_64 and aarch64");
+
+// classic bpf, see linux/filter.h - the filter only loads words, masks and compares them, and returns
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+/// offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+/// the first argument, each is 8 bytes and the low word comes first on both architectures
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+/// what the event loop still calls once it's running
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // the loop itself
+    libc::SYS_epoll_pwait, libc::SYS_epoll_ctl, libc::SYS_accept4, libc::SYS_read, libc::SYS_readv,
+    libc::SYS_write, libc::SYS_writev, libc::SYS_close, libc::SYS_shutdown, libc::SYS_recvfrom, libc::SYS_sendto,
+    libc::SYS_recvmsg, libc::SYS_sendmsg, libc::SYS_getsockopt, libc::SYS_setsockopt, libc::SYS_getsockname,
+    libc::SYS_getpeername, libc::SYS_fcntl,
+    // reconnecting to syslog or journald, the socket itself is checked below
+    libc::SYS_connect,
+    // log rotation, the event log, state and textfile writes, reloads and the memory check
+    libc::SYS_openat, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_lseek, libc::SYS_renameat,
+    libc::SYS_renameat2, libc::SYS_unlinkat, libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_getdents64,
+    libc::SYS_prlimit64,
+    // memory, time and the runtime
+    libc::SYS_brk, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_madvise,
+    libc::SYS_clock_gettime, libc::SYS_clock_nanosleep, libc::SYS_nanosleep, libc::SYS_gettimeofday,
+    libc::SYS_futex, libc::SYS_sched_yield, libc::SYS_getrandom, libc::SYS_getpid, libc::SYS_gettid,
+    libc::SYS_tgkill, libc::SYS_rt_sigreturn, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigaction,
+    libc::SYS_sigaltstack, libc::SYS_restart_syscall, libc::SYS_exit, libc::SYS_exit_group,
+    // a sqlite history store's database, journal and locks
+    #[cfg(feature = "sqlite")] libc::SYS_pread64,
+    #[cfg(feature = "sqlite")] libc::SYS_pwrite64,
+    #[cfg(feature = "sqlite")] libc::SYS_ftruncate,
+    // the older calls libc still makes on x86_64
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")] libc::SYS_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")] libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_poll,
+];
+
+/// one argument of a call that's only allowed with certain values, the argument is masked before comparing
+struct ArgumentCheck {
+    index: u32,
+    mask: u32,
+    allowed: &'static [u32],
+}
+
+impl ArgumentCheck {
+    /// the instructions it takes, a load, the mask unless it keeps everything, and a comparison per value
+    fn length(&self) -> usize {
+        1 + usize::from(self.mask != u32::MAX) + self.allowed.len()
+    }
+}
+
+const NOT_EXECUTABLE: ArgumentCheck = ArgumentCheck { index: 2, mask: libc::PROT_EXEC as u32, allowed: &[0] };
+
+/// what the event loop calls with arguments worth checking, each check has to pass
+const CHECKED_SYSCALLS: &[(libc::c_long, &[ArgumentCheck])] = &[
+    (libc::SYS_socket, &[
+        ArgumentCheck { index: 0, mask: u32::MAX, allowed: &[libc::AF_UNIX as u32, libc::AF_INET as u32, libc::AF_INET6 as u32] },
+        ArgumentCheck {
+            index: 1,
+            mask: !((libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) as u32),
+            allowed: &[libc::SOCK_STREAM as u32, libc::SOCK_DGRAM as u32],
+        },
+    ]),
+    (libc::SYS_mmap, &[NOT_EXECUTABLE]),
+    (libc::SYS_mprotect, &[NOT_EXECUTABLE]),
+    (libc::SYS_ioctl, &[ArgumentCheck { index: 1, mask: u32::MAX, allowed: &[libc::FIONBIO as u32, libc::FIOCLEX as u32] }]),
+];
+
+/// sandboxes the calling thread and any it starts from now on
+pub fn install() -> Result<(), String> {
+    install_sigsys_handler().map_err(|e| format!("failed to handle SIGSYS - {}", e))?;
+    let filter = filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    // SAFETY: no_new_privs takes no pointers, and is what lets an unprivileged process install a filter
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!("failed to set no_new_privs - {}", io::Error::last_os_error()));
+    }
+    // SAFETY: the kernel copies the program, which points at `filter` for the duration of the call
+    if unsafe { libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, 0, &program as *const libc::sock_fprog) } != 0 {
+        return Err(format!("failed to install the seccomp filter - {}", io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn filter() -> Vec<libc::sock_filter> {
+    let statement = |code, k| libc::sock_filter { code, jt: 0, jf: 0, k };
+    let jump = |k, jt, jf| libc::sock_filter { code: BPF_JMP_JEQ_K, jt, jf, k };
+    let mut filter = vec![
+        // a call made through another architecture's table would have different numbers, refuse it outright
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(AUDIT_ARCH, 1, 0),
+        statement(BPF_RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    // each checked call gets a block ending in its own allow and trap, anything else skips over it
+    for (syscall, checks) in CHECKED_SYSCALLS {
+        let mut block = Vec::new();
+        for (i, check) in checks.iter().enumerate() {
+            let later: usize = checks[i + 1..].iter().map(ArgumentCheck::length).sum();
+            block.push(statement(BPF_LD_W_ABS, SECCOMP_DATA_ARGS + 8 * check.index));
+            if check.mask != u32::MAX {
+                block.push(statement(BPF_ALU_AND_K, check.mask));
+            }
+            // a match skips the remaining values to the next check, the last value's mismatch skips to the trap
+            for (j, &value) in check.allowed.iter().enumerate() {
+                let remaining = check.allowed.len() - 1 - j;
+                let mismatch = if remaining == 0 { later + 1 } else { 0 };
+                block.push(jump(value, remaining as u8, mismatch as u8));
+            }
+        }
+        block.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+        block.push(statement(BPF_RET_K, libc::SECCOMP_RET_TRAP));
+        filter.push(jump(*syscall as u32, 0, block.len() as u8));
+        filter.extend(block);
+    }
+    // each match jumps to the allow at the end, past the remaining comparisons and the trap
+    for (i, &syscall) in ALLOWED_SYSCALLS.iter().enumerate() {
+        filter.push(jump(syscall as u32, (ALLOWED_SYSCALLS.len() - i) as u8, 0));
+    }
+    filter.push(statement(BPF_RET_K, libc::SECCOMP_RET_TRAP));
+    filter.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+    filter
+}
+
+/// the start of a SIGSYS siginfo_t - the generic header, then the _sigsys member of its union
+#[repr(C)]
+#[allow(dead_code)]
+struct SigsysInfo {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    call_address: *mut libc::c_void,
+    syscall: libc::c_int,
+    arch: libc::c_uint,
+}
+
+fn install_sigsys_handler() -> io::Result<()> {
+    // SAFETY: sigaction is plain data, for which all zeroes is a valid value (no flags, an empty mask)
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = on_sigsys as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO;
+    // SAFETY: the handler only makes async-signal-safe calls
+    match unsafe { libc::sigaction(libc::SIGSYS, &action, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// the same json line as a fatal error, built without allocating since this runs in a signal handler
+extern "C" fn on_sigsys(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // SAFETY: the kernel passes a SIGSYS siginfo_t, which starts with these fields
+    let syscall = unsafe { (*info.cast::<SigsysInfo>()).syscall };
+    let exit_code = FatalError::Sandbox.exit_code();
+    let mut line = Line { bytes: [0; 128], length: 0 };
+    line.push(b"{\"fatal\":\"sandbox\",\"exit_code\":");
+    line.push_decimal(exit_code as u32);
+    line.push(b",\"message\":\"the sandbox blocked system call ");
+    line.push_decimal(syscall as u32);
+    line.push(b"\"}\n");
+    // SAFETY: write and _exit are async-signal-safe, and both are allowed by the filter
+    unsafe {
+        libc::write(libc::STDERR_FILENO, line.bytes.as_ptr().cast(), line.length);
+        libc::_exit(exit_code);
+    }
+}
+
+/// a fixed size buffer to format into, anything past the end is cut off
+struct Line {
+    bytes: [u8; 128],
+    length: usize,
+}
+
+impl Line {
+    fn push(&mut self, bytes: &[u8]) {
+        let end = (self.length + bytes.len()).min(self.bytes.len());
+        self.bytes[self.length..end].copy_from_slice(&bytes[..end - self.length]);
+        self.length = end;
+    }
+
+    fn push_decimal(&mut self, mut value: u32) {
+        let mut digits = [0; 10];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (value % 10) as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.push(&digits[start..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// runs the filter the way the kernel would for a call with these arguments
+    fn run(filter: &[libc::sock_filter], arch: u32, syscall: libc::c_long, args: [u64; 6]) -> u32 {
+        let mut data = Vec::new();
+        data.extend((syscall as i32).to_ne_bytes());
+        data.extend(arch.to_ne_bytes());
+        data.extend(0u64.to_ne_bytes());
+        for arg in args {
+            data.extend(arg.to_ne_bytes());
+        }
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = filter[pc];
+            pc += 1;
+            match instruction.code {
+                BPF_LD_W_ABS => {
+                    let offset = instruction.k as usize;
+                    accumulator = u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+                },
+                BPF_ALU_AND_K => accumulator &= instruction.k,
+                BPF_JMP_JEQ_K if accumulator == instruction.k => pc += usize::from(instruction.jt),
+                BPF_JMP_JEQ_K => pc += usize::from(instruction.jf),
+                BPF_RET_K => return instruction.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn call(syscall: libc::c_long, args: [u64; 6]) -> u32 {
+        run(&filter(), AUDIT_ARCH, syscall, args)
+    }
+
+    #[test]
+    fn other_architectures_are_killed() {
+        assert_eq!(run(&filter(), 0x4000_0003, libc::SYS_read, [0; 6]), libc::SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[test]
+    fn every_listed_call_is_allowed_and_others_trapped() {
+        for &syscall in ALLOWED_SYSCALLS {
+            assert_eq!(call(syscall, [0; 6]), libc::SECCOMP_RET_ALLOW, "system call {}", syscall);
+        }
+        for syscall in [libc::SYS_execve, libc::SYS_ptrace, libc::SYS_bind, libc::SYS_listen, libc::SYS_openat2] {
+            assert_eq!(call(syscall, [0; 6]), libc::SECCOMP_RET_TRAP, "system call {}", syscall);
+        }
+    }
+
+    #[test]
+    fn sockets_are_limited_to_unix_and_ip_streams_and_datagrams() {
+        let socket = |domain: libc::c_int, kind: libc::c_int| call(libc::SYS_socket, [domain as u64, kind as u64, 0, 0, 0, 0]);
+        assert_eq!(socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_NONBLOCK), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(socket(libc::AF_INET6, libc::SOCK_DGRAM), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(socket(libc::AF_PACKET, libc::SOCK_DGRAM), libc::SECCOMP_RET_TRAP);
+        assert_eq!(socket(libc::AF_NETLINK, libc::SOCK_DGRAM), libc::SECCOMP_RET_TRAP);
+        assert_eq!(socket(libc::AF_INET, libc::SOCK_RAW), libc::SECCOMP_RET_TRAP);
+        assert_eq!(socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC), libc::SECCOMP_RET_TRAP);
+    }
+
+    #[test]
+    fn memory_can_not_be_made_executable() {
+        let protection = |syscall, prot: libc::c_int| call(syscall, [0, 4096, prot as u64, 0, 0, 0]);
+        for syscall in [libc::SYS_mmap, libc::SYS_mprotect] {
+            assert_eq!(protection(syscall, libc::PROT_READ | libc::PROT_WRITE), libc::SECCOMP_RET_ALLOW);
+            assert_eq!(protection(syscall, libc::PROT_NONE), libc::SECCOMP_RET_ALLOW);
+            assert_eq!(protection(syscall, libc::PROT_READ | libc::PROT_EXEC), libc::SECCOMP_RET_TRAP);
+        }
+    }
+
+    #[test]
+    fn ioctl_only_sets_blocking_and_close_on_exec() {
+        let ioctl = |request: u32| call(libc::SYS_ioctl, [3, u64::from(request), 0, 0, 0, 0]);
+        assert_eq!(ioctl(libc::FIONBIO as u32), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(ioctl(libc::FIOCLEX as u32), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(ioctl(libc::TIOCSTI as u32), libc::SECCOMP_RET_TRAP);
+    }
+
+    #[test]
+    fn every_jump_lands_inside_the_filter() {
+        let filter = filter();
+        for (pc, instruction) in filter.iter().enumerate() {
+            if instruction.code == BPF_JMP_JEQ_K {
+                assert!(pc + 1 + usize::from(instruction.jt.max(instruction.jf)) < filter.len(), "jump at {}", pc);
+            }
+        }
+        assert_eq!(filter.last().map(|instruction| instruction.code), Some(BPF_RET_K));
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+use crate::error::{self, EndlesshError};
+
+struct ScheduledTask<T> {
+    task: T,
+    interval: Duration,
+    next_run_time: Instant,
+}
+
+/// periodic tasks driven by the event loop's poll timeout, so no feature needs its own thread and timer
+///
+/// tasks are plain identifiers (much like mio tokens) - the event loop decides what running one means
+pub struct Scheduler<T> {
+    tasks: Vec<ScheduledTask<T>>,
+}
+
+impl<T: Copy> Default for Scheduler<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy> Scheduler<T> {
+
+    pub fn new() -> Self {
+        Scheduler {
+            tasks: Vec::new(),
+        }
+    }
+
+    /// runs `task` every `interval`, the first time one interval from `now`
+    ///
+    /// a zero interval is refused, the task would be due again on every turn of the event loop
+    pub fn schedule(&mut self, task: T, interval: Duration, now: &Instant) -> error::Result<()> {
+        if interval.is_zero() {
+            return Err(EndlesshError::Config("task interval must not be zero".to_string()));
+        }
+        self.tasks.push(ScheduledTask {
+            task,
+            interval,
+            next_run_time: *now + interval,
+        });
+        Ok(())
+    }
+
+    /// returns the tasks that are due and schedules their next run
+    pub fn take_due(&mut self, now: &Instant) -> Vec<T> {
+        let mut due = Vec::new();
+        for scheduled in self.tasks.iter_mut().filter(|t| t.next_run_time <= *now) {
+            due.push(scheduled.task);
+            // skip any runs missed while the event loop was busy rather than bursting to catch up
+            while scheduled.next_run_time <= *now {
+                scheduled.next_run_time += scheduled.interval;
+            }
+        }
+        due
+    }
+
+    /// how long the event loop may sleep before the next task is due
+    pub fn timeout(&self, now: &Instant) -> Option<Duration> {
+        self.tasks.iter()
+            .map(|t| t.next_run_time.saturating_duration_since(*now))
+            .min()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_intervals_are_refused() {
+        let mut scheduler = Scheduler::new();
+        let now = Instant::now();
+        assert!(matches!(scheduler.schedule(1, Duration::ZERO, &now), Err(EndlesshError::Config(_))));
+        assert_eq!(scheduler.timeout(&now), None);
+        scheduler.schedule(2, Duration::from_secs(1), &now).unwrap();
+        assert_eq!(scheduler.take_due(&(now + Duration::from_secs(1))), [2]);
+    }
+}
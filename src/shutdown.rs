@@ -0,0 +1,163 @@
+//! stopping on SIGTERM or SIGINT - optionally once the trapped clients have lingered a while, so a restart doesn't
+//! free every bot at the same moment - and leaving the final stats behind
+
+use std::fs::{remove_file, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use endlessh_rs::endlessh::{unix_seconds, EndlesshStats};
+
+#[derive(Serialize)]
+struct ShutdownRecord<'a> {
+    stopped_at_seconds: u64,
+    reason: &'a str,
+    stats: &'a EndlesshStats,
+}
+
+pub struct Shutdown<'a> {
+    linger: Option<Duration>,
+    pub farewell_line: Option<&'a str>,
+    stats_file: Option<&'a Path>,
+    /// unix sockets bound by this process, rather than passed in by systemd
+    socket_paths: Vec<PathBuf>,
+    /// the signal, and when whoever is still trapped gets hung up on
+    stopping: Option<(&'static str, Instant)>,
+}
+
+impl<'a> Shutdown<'a> {
+
+    pub fn new(linger: Option<Duration>, farewell_line: Option<&'a str>, stats_file: Option<&'a Path>, socket_paths: Vec<PathBuf>) -> Self {
+        Shutdown { linger, farewell_line, stats_file, socket_paths, stopping: None }
+    }
+
+    /// true for the first signal, after which no new clients should be accepted. a second one cuts the linger short
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn begin(&mut self, signal: &'static str, now: &Instant) -> bool {
+        match (self.stopping, self.linger) {
+            (None, Some(linger)) => {
+                info!("{} received, hanging up on the trapped clients in {}s, or as soon as they've all left", signal, linger.as_secs());
+                self.stopping = Some((signal, *now + linger));
+                true
+            },
+            (None, None) => {
+                info!("{} received, shutting down", signal);
+                self.stopping = Some((signal, *now));
+                true
+            },
+            (Some(_), _) => {
+                info!("{} received again, shutting down now", signal);
+                self.stopping = Some((signal, *now));
+                false
+            },
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.is_some()
+    }
+
+    pub fn is_due(&self, now: &Instant, clients: usize) -> bool {
+        self.stopping.is_some_and(|(_, deadline)| clients == 0 || *now >= deadline)
+    }
+
+    pub fn timeout(&self, now: &Instant) -> Option<Duration> {
+        self.stopping.map(|(_, deadline)| deadline.saturating_duration_since(*now))
+    }
+
+    /// logs the final stats and appends them to the stats file, then removes the sockets. the clients must be gone
+    pub fn finish(&self, stats: &EndlesshStats) {
+        let reason = self.stopping.map_or("shutdown", |(signal, _)| signal);
+        info!(connections_opened = stats.connections_opened.load(Ordering::Relaxed), connections_closed = stats.connections_closed.load(Ordering::Relaxed),
+            trapped_time_seconds = stats.trapped_time().as_secs(), bytes_sent = stats.bytes_sent.load(Ordering::Relaxed),
+            uptime_seconds = stats.uptime().as_secs(); "final stats");
+        if let Some(stats_file) = self.stats_file {
+            if let Err(e) = write_shutdown_record(stats, stats_file, reason) {
+                warn!("failed to write the final stats to {} - {}", stats_file.display(), e);
+            }
+        }
+        for path in &self.socket_paths {
+            match remove_file(path) {
+                // e.g. a metrics socket whose file was removed, and never bound again
+                Err(e) if e.kind() != ErrorKind::NotFound => warn!("failed to remove unix socket {} - {}", path.display(), e),
+                _ => {},
+            }
+        }
+    }
+
+}
+
+fn write_shutdown_record(stats: &EndlesshStats, stats_file: &Path, reason: &str) -> std::io::Result<()> {
+    let record = ShutdownRecord {
+        stopped_at_seconds: unix_seconds(SystemTime::now()),
+        reason,
+        stats,
+    };
+    let mut file = OpenOptions::new().create(true).append(true).open(stats_file)?;
+    writeln!(file, "{}", serde_json::to_string(&record)?)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lingering_ends_once_the_clients_leave_or_time_is_up() {
+        let mut shutdown = Shutdown::new(Some(Duration::from_secs(30)), None, None, Vec::new());
+        let now = Instant::now();
+        assert!(!shutdown.is_stopping() && !shutdown.is_due(&now, 0));
+        assert_eq!(shutdown.timeout(&now), None);
+
+        assert!(shutdown.begin("SIGTERM", &now));
+        assert!(shutdown.is_stopping());
+        assert!(!shutdown.is_due(&now, 3));
+        assert!(shutdown.is_due(&now, 0));
+        assert_eq!(shutdown.timeout(&(now + Duration::from_secs(10))), Some(Duration::from_secs(20)));
+        assert!(shutdown.is_due(&(now + Duration::from_secs(30)), 3));
+
+        // a second signal doesn't wait any longer
+        let later = now + Duration::from_secs(5);
+        assert!(!shutdown.begin("SIGINT", &later));
+        assert!(shutdown.is_due(&later, 3));
+        assert_eq!(shutdown.timeout(&later), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn without_a_linger_the_first_signal_is_due_straight_away() {
+        let mut shutdown = Shutdown::new(None, None, None, Vec::new());
+        let now = Instant::now();
+        assert!(shutdown.begin("SIGINT", &now));
+        assert!(shutdown.is_due(&now, 100));
+    }
+
+    #[test]
+    fn finishing_appends_the_final_stats_and_removes_the_sockets() {
+        let dir = std::env::temp_dir().join(format!("endlessh-rs-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let stats_file = dir.join("stats.jsonl");
+        let socket = dir.join("ssh.sock");
+        std::fs::write(&stats_file, "{\"reason\":\"earlier\"}\n").unwrap();
+        std::fs::write(&socket, "").unwrap();
+        let stats = EndlesshStats::default();
+        stats.connections_opened.store(3, Ordering::Relaxed);
+
+        let mut shutdown = Shutdown::new(None, None, Some(&stats_file), vec![socket.clone(), dir.join("never-bound.sock")]);
+        shutdown.begin("SIGTERM", &Instant::now());
+        shutdown.finish(&stats);
+        assert!(!socket.exists());
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&stats_file).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1]["reason"], "SIGTERM");
+        assert_eq!(records[1]["stats"]["connections_opened"], 3);
+        assert!(records[1]["stopped_at_seconds"].as_u64().unwrap() > 1_000_000_000);
+    }
+}
@@ -0,0 +1,524 @@
+//! what the tarpit needs from the sockets it traps clients on
+//!
+//! the tarpit is generic over its streams so it can be driven by mocks, which just don't know any tcp state, and so other
+//! kinds of connection (a tls wrapper, a unix socket behind a proxy) get the same scheduling, stats and events as tcp.
+//! a `Tarpit<Box<dyn TrappedStream>>` mixes kinds, at the cost of an allocation per client
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Empty, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use mio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use mio::net::{UnixListener, UnixStream};
+use mio::{event, Interest, Registry, Token};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// the peer of a unix socket client, which has no address of its own. it's never blocked
+pub const UNIX_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+pub trait TrappedStream: Read + Write {
+    /// whether the peer is advertising a zero receive window, i.e. still acking but no longer reading, rather than
+    /// having vanished. only meaningful right after a write would have blocked, None where it can't be told
+    fn window_closed(&self) -> Option<bool> {
+        None
+    }
+
+    /// the kernel's view of the connection, None where it can't be had
+    fn tcp_stats(&self) -> Option<TcpStats> {
+        None
+    }
+}
+
+/// network level counters for a connection, added to its close event
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcpStats {
+    /// smoothed round trip time, in microseconds
+    pub rtt_us: u32,
+    pub rtt_var_us: u32,
+    /// segments retransmitted over the connection's lifetime
+    pub retransmits: u32,
+    /// bytes the peer acknowledged, i.e. actually delivered
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub segments_out: u32,
+    pub segments_in: u32,
+}
+
+impl TrappedStream for TcpStream {
+    #[cfg(target_os = "linux")]
+    fn window_closed(&self) -> Option<bool> {
+        use std::os::fd::AsRawFd;
+        let info = tcp_info(self.as_raw_fd())?;
+        // with our send buffer full, nothing in flight means the window is shut. a peer that's gone instead leaves
+        // data unacked while the kernel retransmits it
+        Some(info.unacked == 0 && info.retransmits == 0)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn tcp_stats(&self) -> Option<TcpStats> {
+        use std::os::fd::AsRawFd;
+        let info = tcp_info(self.as_raw_fd())?;
+        Some(TcpStats {
+            rtt_us: info.rtt,
+            rtt_var_us: info.rttvar,
+            retransmits: info.total_retrans,
+            bytes_acked: info.bytes_acked,
+            bytes_received: info.bytes_received,
+            segments_out: info.segs_out,
+            segments_in: info.segs_in,
+        })
+    }
+}
+
+/// connections handed over by a local proxy, with no tcp state of their own to look at
+#[cfg(unix)]
+impl TrappedStream for mio::net::UnixStream {}
+
+impl TrappedStream for Empty {}
+
+/// a client of the server, from whichever kind of listener it came in on
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// sets the kernel's receive and send buffer sizes for the connection (SO_RCVBUF and SO_SNDBUF), those not given
+    /// being left as they are. linux only, elsewhere nothing is changed
+    pub fn set_buffer_sizes(&self, receive: Option<usize>, send: Option<usize>) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            let fd = match self {
+                ClientStream::Tcp(stream) => stream.as_raw_fd(),
+                ClientStream::Unix(stream) => stream.as_raw_fd(),
+            };
+            for (option, size) in [(libc::SO_RCVBUF, receive), (libc::SO_SNDBUF, send)] {
+                if let Some(size) = size {
+                    set_buffer_size(fd, option, size)?;
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (receive, send);
+        Ok(())
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl event::Source for ClientStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.deregister(registry),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+impl TrappedStream for ClientStream {
+    fn window_closed(&self) -> Option<bool> {
+        match self {
+            ClientStream::Tcp(stream) => stream.window_closed(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.window_closed(),
+        }
+    }
+
+    fn tcp_stats(&self) -> Option<TcpStats> {
+        match self {
+            ClientStream::Tcp(stream) => stream.tcp_stats(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.tcp_stats(),
+        }
+    }
+}
+
+/// where an ssh listener listens - `<ip>:<port>` (or `ip:<ip>:<port>`, like the metric listeners) or `unix:<path>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    Ip(SocketAddr),
+    /// e.g. for haproxy to forward to
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    /// the port clients connect to, unix sockets have none
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ListenAddress::Ip(address) => Some(address.port()),
+            #[cfg(unix)]
+            ListenAddress::Unix(_) => None,
+        }
+    }
+
+    /// the socket file, for ip addresses none
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            ListenAddress::Ip(_) => None,
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => Some(path),
+        }
+    }
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(v: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = v.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(format!("can't listen on {} - unix sockets are not supported on this platform", path));
+        }
+        v.strip_prefix("ip:").unwrap_or(v).parse()
+            .map(ListenAddress::Ip)
+            .map_err(|e| format!("bad listen address \"{}\" - {}, expected <ip>:<port> or unix:<path>", v, e))
+    }
+}
+
+/// ip addresses are written bare, as they always have been in logs and the admin api
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ListenAddress::Ip(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for ListenAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// a bound ssh listener of either kind
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for ListenSocket {
+    fn from(listener: TcpListener) -> Self {
+        ListenSocket::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for ListenSocket {
+    fn from(listener: UnixListener) -> Self {
+        ListenSocket::Unix(listener)
+    }
+}
+
+impl ListenSocket {
+
+    /// the next waiting client, and its address - [UNIX_PEER] for unix sockets
+    pub fn accept(&self) -> io::Result<(ClientStream, SocketAddr)> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.accept().map(|(stream, peer)| (ClientStream::Tcp(stream), peer)),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => listener.accept().map(|(stream, _)| (ClientStream::Unix(stream), UNIX_PEER)),
+        }
+    }
+
+    pub fn local_address(&self) -> io::Result<ListenAddress> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.local_addr().map(ListenAddress::Ip),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => {
+                let address = listener.local_addr()?;
+                let path = address.as_pathname().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket"))?;
+                Ok(ListenAddress::Unix(path.to_path_buf()))
+            },
+        }
+    }
+}
+
+impl event::Source for ListenSocket {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.register(registry, token, interests),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.reregister(registry, token, interests),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.deregister(registry),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener) => listener.deregister(registry),
+        }
+    }
+}
+
+impl<S: TrappedStream + ?Sized> TrappedStream for Box<S> {
+    fn window_closed(&self) -> Option<bool> {
+        (**self).window_closed()
+    }
+
+    fn tcp_stats(&self) -> Option<TcpStats> {
+        (**self).tcp_stats()
+    }
+}
+
+/// the start of linux's `struct tcp_info`, which only ever grows at the end. libc's copy stops before the byte counters
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct TcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    flags: u8,
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+    rcv_rtt: u32,
+    rcv_space: u32,
+    total_retrans: u32,
+    pacing_rate: u64,
+    max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+    segs_out: u32,
+    segs_in: u32,
+}
+
+/// the kernel doubles the size for its own bookkeeping, and rounds it up to its minimum
+#[cfg(target_os = "linux")]
+fn set_buffer_size(fd: std::os::fd::RawFd, option: libc::c_int, size: usize) -> io::Result<()> {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    // SAFETY: the pointer and length describe `size`, which the kernel only reads
+    let result = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, option, (&size as *const libc::c_int).cast(), std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// None if the call fails, or the kernel (older than 4.2) doesn't fill in every field
+#[cfg(target_os = "linux")]
+fn tcp_info(fd: std::os::fd::RawFd) -> Option<TcpInfo> {
+    let mut info = TcpInfo::default();
+    let mut len = std::mem::size_of::<TcpInfo>() as libc::socklen_t;
+    // SAFETY: the pointer and length describe `info`, which the kernel writes at most `len` bytes of
+    let result = unsafe { libc::getsockopt(fd, libc::IPPROTO_TCP, libc::TCP_INFO, (&mut info as *mut TcpInfo).cast(), &mut len) };
+    (result == 0 && len as usize >= std::mem::size_of::<TcpInfo>()).then_some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+
+    use super::*;
+
+    /// both ends of a loopback connection, the accepted one non-blocking as the tarpit gets them
+    #[cfg(target_os = "linux")]
+    fn connection() -> (TcpStream, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        (TcpStream::from_std(accepted), client)
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tcp_connections_report_what_the_kernel_saw() {
+        use std::time::{Duration, Instant};
+
+        let (mut server, mut client) = connection();
+        server.write_all(b"hello").unwrap();
+        let mut received = [0; 5];
+        client.read_exact(&mut received).unwrap();
+        client.write_all(b"hi").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let stats = loop {
+            let stats = server.tcp_stats().expect("no tcp_info on this kernel");
+            if stats.bytes_acked >= 5 && stats.bytes_received >= 2 {
+                break stats;
+            }
+            assert!(Instant::now() < deadline, "the peer's acks never arrived - {:?}", stats);
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!((stats.bytes_acked, stats.bytes_received), (5, 2));
+        assert!(stats.segments_out >= 2 && stats.segments_in >= 2, "{:?}", stats);
+        assert_eq!(stats.retransmits, 0);
+        // everything sent has been acked, and nothing is being retransmitted
+        assert_eq!(server.window_closed(), Some(true));
+        assert_eq!(ClientStream::Tcp(server).tcp_stats().map(|stats| stats.bytes_acked), Some(5));
+    }
+
+    #[test]
+    fn streams_without_tcp_state_have_no_stats() {
+        assert_eq!(empty().tcp_stats(), None);
+        assert_eq!(empty().window_closed(), None);
+        #[cfg(unix)]
+        {
+            let (local, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+            let stream = ClientStream::Unix(UnixStream::from_std(local));
+            assert_eq!(stream.tcp_stats(), None);
+            assert_eq!(stream.window_closed(), None);
+        }
+    }
+
+    #[test]
+    fn listen_addresses_print_as_they_parse() {
+        let address: ListenAddress = "127.0.0.1:22".parse().unwrap();
+        assert_eq!(address, ListenAddress::Ip(SocketAddr::from(([127, 0, 0, 1], 22))));
+        assert_eq!((address.to_string(), address.port(), address.path()), ("127.0.0.1:22".to_string(), Some(22), None));
+        // the metric listeners' spelling, written back bare
+        let address: ListenAddress = "ip:[::1]:2222".parse().unwrap();
+        assert_eq!(address.to_string(), "[::1]:2222");
+        assert_eq!(serde_json::to_string(&address).unwrap(), "\"[::1]:2222\"");
+        for invalid in ["localhost:22", "127.0.0.1", "ip:", ""] {
+            let error = invalid.parse::<ListenAddress>().unwrap_err();
+            assert!(error.contains("expected <ip>:<port> or unix:<path>"), "{}", error);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_socket_addresses_have_a_path_and_no_port() {
+        let address: ListenAddress = "unix:/run/endlessh.sock".parse().unwrap();
+        assert_eq!(address, ListenAddress::Unix(PathBuf::from("/run/endlessh.sock")));
+        assert_eq!((address.port(), address.path()), (None, Some(Path::new("/run/endlessh.sock"))));
+        assert_eq!(address.to_string(), "unix:/run/endlessh.sock");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_listeners_accept_clients_as_the_unix_peer() {
+        let path = std::env::temp_dir().join(format!("endlessh-rs-socket-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = ListenSocket::from(UnixListener::bind(&path).unwrap());
+        assert_eq!(listener.local_address().unwrap(), ListenAddress::Unix(path.clone()));
+        let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        let (mut stream, peer) = listener.accept().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(peer, UNIX_PEER);
+        stream.write_all(b"SSH-").unwrap();
+        let mut received = [0; 4];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"SSH-");
+    }
+
+    /// SO_RCVBUF or SO_SNDBUF as the kernel reports it, double what was set to leave room for its bookkeeping
+    #[cfg(target_os = "linux")]
+    fn buffer_size(stream: &ClientStream, option: libc::c_int) -> usize {
+        use std::os::fd::AsRawFd;
+        let ClientStream::Tcp(stream) = stream else {
+            unreachable!("only tcp streams are checked");
+        };
+        let mut size: libc::c_int = 0;
+        let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the pointer and length describe `size`, which the kernel writes at most `length` bytes of
+        let result = unsafe { libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, option, (&mut size as *mut libc::c_int).cast(), &mut length) };
+        assert_eq!(result, 0, "{}", io::Error::last_os_error());
+        size as usize
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn buffer_sizes_are_only_set_when_given() {
+        let (server, _client) = connection();
+        let stream = ClientStream::Tcp(server);
+        let receive = buffer_size(&stream, libc::SO_RCVBUF);
+        stream.set_buffer_sizes(None, Some(8192)).unwrap();
+        assert_eq!(buffer_size(&stream, libc::SO_SNDBUF), 2 * 8192);
+        assert_eq!(buffer_size(&stream, libc::SO_RCVBUF), receive);
+        stream.set_buffer_sizes(Some(4096), None).unwrap();
+        assert_eq!(buffer_size(&stream, libc::SO_RCVBUF), 2 * 4096);
+        assert_eq!(buffer_size(&stream, libc::SO_SNDBUF), 2 * 8192);
+        // sizes past what a c int holds are capped rather than wrapped around
+        stream.set_buffer_sizes(Some(usize::MAX), None).unwrap();
+        assert!(buffer_size(&stream, libc::SO_RCVBUF) > 2 * 4096);
+    }
+}
+
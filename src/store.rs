@@ -0,0 +1,304 @@
+//! where connection events are kept for the endpoints that look back over them, /recent and /history
+//!
+//! [MemoryStore] keeps the last few in memory and forgets them on a restart, [NdjsonStore] appends them to a json-lines
+//! file (the same format as the event log) and [SqliteStore] (with the `sqlite` feature) to a database. the event loop
+//! appends as events happen and calls [ConnectionStore::flush] once per pass, so a busy loop costs one write
+
+use std::collections::VecDeque;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::endlessh::ConnectionEvent;
+use crate::error::{self, EndlesshError};
+
+/// a place to keep connection events - it's used from the event loop, so it mustn't block for long, and is Send so the
+/// server and its store can be moved to the thread running it
+pub trait ConnectionStore: Send {
+    /// records an event, which may only be kept for good on the next [ConnectionStore::flush]
+    fn append(&mut self, event: &ConnectionEvent) -> error::Result<()>;
+
+    /// makes the appended events durable, and visible to [ConnectionStore::query]
+    fn flush(&mut self) -> error::Result<()> {
+        Ok(())
+    }
+
+    /// the events matching the query, newest first
+    fn query(&self, query: &HistoryQuery) -> error::Result<Vec<ConnectionEvent>>;
+
+    /// at most `limit` of the latest events, newest first
+    fn recent(&self, limit: usize) -> error::Result<Vec<ConnectionEvent>> {
+        self.query(&HistoryQuery { limit, ..HistoryQuery::default() })
+    }
+
+    /// forgets the events from before `before` (seconds since the unix epoch), returning how many there were
+    fn prune(&mut self, before: f64) -> error::Result<usize>;
+}
+
+/// a page of the events a store holds, newest first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// only events from this time on, in seconds since the unix epoch
+    pub since: Option<f64>,
+    /// only events from this address
+    pub ip: Option<IpAddr>,
+    /// how many of the matching events to skip, for the pages after the first
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+
+    pub fn matches(&self, event: &ConnectionEvent) -> bool {
+        self.since.is_none_or(|since| event.time_seconds >= since)
+            && self.ip.is_none_or(|ip| event.peer.ip().to_canonical() == ip.to_canonical())
+    }
+
+}
+
+/// the events of a query, and where the next page starts if there is one
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub events: Vec<ConnectionEvent>,
+    pub next_offset: Option<usize>,
+}
+
+/// runs the query, asking for one more event than it wants to tell whether there's another page
+pub fn page(store: &dyn ConnectionStore, query: &HistoryQuery) -> error::Result<HistoryPage> {
+    let mut events = store.query(&HistoryQuery { limit: query.limit.saturating_add(1), ..query.clone() })?;
+    let next_offset = (events.len() > query.limit).then(|| query.offset.saturating_add(query.limit));
+    events.truncate(query.limit);
+    Ok(HistoryPage { events, next_offset })
+}
+
+fn store_error(store: &str, reason: impl std::fmt::Display) -> EndlesshError {
+    EndlesshError::Store { store: store.to_string(), reason: reason.to_string() }
+}
+
+/// the last few events, so "what just hit me?" needs no log shipping
+pub struct MemoryStore {
+    length: usize,
+    events: VecDeque<ConnectionEvent>,
+}
+
+impl MemoryStore {
+
+    pub fn new(length: usize) -> Self {
+        MemoryStore {
+            length,
+            events: VecDeque::with_capacity(length),
+        }
+    }
+
+}
+
+impl ConnectionStore for MemoryStore {
+    fn append(&mut self, event: &ConnectionEvent) -> error::Result<()> {
+        if self.length == 0 {
+            return Ok(());
+        }
+        if self.events.len() == self.length {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+        Ok(())
+    }
+
+    fn query(&self, query: &HistoryQuery) -> error::Result<Vec<ConnectionEvent>> {
+        Ok(self.events.iter().rev()
+            .filter(|event| query.matches(event))
+            .skip(query.offset)
+            .take(query.limit)
+            .cloned()
+            .collect())
+    }
+
+    fn prune(&mut self, before: f64) -> error::Result<usize> {
+        let length = self.events.len();
+        self.events.retain(|event| event.time_seconds >= before);
+        Ok(length - self.events.len())
+    }
+}
+
+/// every event as a line of json, in the order they happened
+pub struct NdjsonStore {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl NdjsonStore {
+
+    pub fn open(path: PathBuf) -> error::Result<Self> {
+        let file = Self::open_for_append(&path)?;
+        Ok(NdjsonStore { path, file })
+    }
+
+    fn open_for_append(path: &Path) -> error::Result<BufWriter<File>> {
+        OpenOptions::new().create(true).append(true).open(path)
+            .map(BufWriter::new)
+            .map_err(|e| store_error(&path.display().to_string(), e))
+    }
+
+    fn error(&self, reason: impl std::fmt::Display) -> EndlesshError {
+        store_error(&self.path.display().to_string(), reason)
+    }
+
+    /// every event in the file, skipping lines that aren't one, e.g. one cut short by a crash
+    fn read(&self) -> error::Result<impl Iterator<Item = ConnectionEvent>> {
+        let file = File::open(&self.path).map_err(|e| self.error(e))?;
+        Ok(BufReader::new(file).lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok()))
+    }
+}
+
+impl ConnectionStore for NdjsonStore {
+    fn append(&mut self, event: &ConnectionEvent) -> error::Result<()> {
+        let mut line = serde_json::to_string(event).expect("failed to serialize connection event");
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).map_err(|e| self.error(e))
+    }
+
+    fn flush(&mut self) -> error::Result<()> {
+        self.file.flush().map_err(|e| self.error(e))
+    }
+
+    /// reads the whole file, keeping only the last page's worth of matching events
+    fn query(&self, query: &HistoryQuery) -> error::Result<Vec<ConnectionEvent>> {
+        let keep = query.offset.saturating_add(query.limit);
+        let mut events = VecDeque::new();
+        for event in self.read()?.filter(|event| query.matches(event)) {
+            if events.len() == keep {
+                events.pop_front();
+            }
+            if keep > 0 {
+                events.push_back(event);
+            }
+        }
+        Ok(events.into_iter().rev().skip(query.offset).collect())
+    }
+
+    /// rewrites the file without the old events, replacing it in one rename so a crash leaves one or the other
+    fn prune(&mut self, before: f64) -> error::Result<usize> {
+        self.flush()?;
+        let mut pruned = 0;
+        let mut kept = String::new();
+        for event in self.read()? {
+            if event.time_seconds < before {
+                pruned += 1;
+                continue;
+            }
+            kept.push_str(&serde_json::to_string(&event).expect("failed to serialize connection event"));
+            kept.push('\n');
+        }
+        if pruned == 0 {
+            return Ok(0);
+        }
+        let temporary = self.path.with_extension("pruning");
+        std::fs::write(&temporary, kept).map_err(|e| self.error(e))?;
+        rename(&temporary, &self.path).map_err(|e| self.error(e))?;
+        self.file = Self::open_for_append(&self.path)?;
+        Ok(pruned)
+    }
+}
+
+/// the events in a table of their own, indexed by time, with each event's json alongside the columns it's looked up by
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: rusqlite::Connection,
+    /// appended since the last flush, written in one transaction
+    pending: Vec<ConnectionEvent>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+
+    pub fn open(path: PathBuf) -> error::Result<Self> {
+        let error = |e: rusqlite::Error| store_error(&path.display().to_string(), e);
+        let connection = rusqlite::Connection::open(&path).map_err(error)?;
+        connection.execute_batch("
+            PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY,
+                time_seconds REAL NOT NULL,
+                event TEXT NOT NULL,
+                peer TEXT NOT NULL,
+                json TEXT NOT NULL,
+                ip TEXT
+            );
+        ").map_err(error)?;
+        // databases from before /history have no ip column, and their events no ip to be found by
+        let has_ip: bool = connection.query_row("SELECT COUNT(*) > 0 FROM pragma_table_info('events') WHERE name = 'ip'", [], |row| row.get(0))
+            .map_err(error)?;
+        if !has_ip {
+            connection.execute_batch("ALTER TABLE events ADD COLUMN ip TEXT").map_err(error)?;
+        }
+        connection.execute_batch("
+            CREATE INDEX IF NOT EXISTS events_time_seconds ON events (time_seconds);
+            CREATE INDEX IF NOT EXISTS events_ip ON events (ip, id);
+        ").map_err(error)?;
+        Ok(SqliteStore { path, connection, pending: Vec::new() })
+    }
+
+    fn error(&self, reason: impl std::fmt::Display) -> EndlesshError {
+        store_error(&self.path.display().to_string(), reason)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ConnectionStore for SqliteStore {
+    fn append(&mut self, event: &ConnectionEvent) -> error::Result<()> {
+        self.pending.push(event.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> error::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let path = self.path.display().to_string();
+        let error = |e: rusqlite::Error| store_error(&path, e);
+        let transaction = self.connection.transaction().map_err(error)?;
+        {
+            let mut insert = transaction.prepare_cached("INSERT INTO events (time_seconds, event, peer, json, ip) VALUES (?1, ?2, ?3, ?4, ?5)")
+                .map_err(error)?;
+            for event in &self.pending {
+                let kind = serde_json::to_value(event.event).expect("failed to serialize connection event kind");
+                let json = serde_json::to_string(event).expect("failed to serialize connection event");
+                let ip = event.peer.ip().to_canonical().to_string();
+                insert.execute(rusqlite::params![event.time_seconds, kind.as_str(), event.peer.to_string(), json, ip]).map_err(error)?;
+            }
+        }
+        transaction.commit().map_err(error)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn query(&self, query: &HistoryQuery) -> error::Result<Vec<ConnectionEvent>> {
+        let mut select = self.connection.prepare_cached("
+            SELECT json FROM events
+            WHERE (?1 IS NULL OR time_seconds >= ?1) AND (?2 IS NULL OR ip = ?2)
+            ORDER BY id DESC LIMIT ?3 OFFSET ?4
+        ").map_err(|e| self.error(e))?;
+        let ip = query.ip.map(|ip| ip.to_canonical().to_string());
+        let rows = select.query_map(rusqlite::params![query.since, ip, query.limit as i64, query.offset as i64], |row| row.get::<_, String>(0))
+            .map_err(|e| self.error(e))?;
+        let mut events = Vec::new();
+        for json in rows {
+            // rows another version wrote and this one can't read are skipped, like bad lines of an event log
+            if let Ok(event) = serde_json::from_str(&json.map_err(|e| self.error(e))?) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    fn prune(&mut self, before: f64) -> error::Result<usize> {
+        self.flush()?;
+        self.connection.execute("DELETE FROM events WHERE time_seconds < ?1", [before]).map_err(|e| self.error(e))
+    }
+}
@@ -0,0 +1,220 @@
+//! systemd socket activation - listeners opened by a .socket unit and passed in already bound, so port 22 can be
+//! trapped on without endlessh-rs ever running as root
+//!
+//! see sd_listen_fds(3): the descriptors start at 3, `LISTEN_FDS` says how many, `LISTEN_PID` who they're for and
+//! `LISTEN_FDNAMES` what each was called with FileDescriptorName=
+//!
+//! and readiness notification, so a Type=notify unit is only started once the listeners are up, with a watchdog to
+//! restart it if the event loop ever stops turning
+
+use std::env;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+use log::warn;
+use mio::net::{TcpListener, UnixListener};
+
+use endlessh_rs::socket::ListenSocket;
+
+const LISTEN_FDS_START: RawFd = 3;
+/// the FileDescriptorName= that makes a socket a metrics listener, every other one traps ssh clients
+pub const METRICS_FD_NAME: &str = "metrics";
+
+pub struct InheritedSocket {
+    pub listener: ListenSocket,
+    pub fd: RawFd,
+    /// its FileDescriptorName=, unless it was left to default to the unit's name
+    pub name: Option<String>,
+}
+
+/// the sockets passed to this process, none if it wasn't socket activated. the variables are cleared either way, so
+/// nothing started from here (e.g. the firewall commands) takes the sockets to be meant for it
+pub fn listen_fds() -> Result<Vec<InheritedSocket>, String> {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    for variable in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(variable);
+    }
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Ok(Vec::new());
+    };
+    // inherited from a parent that was socket activated, rather than passed to us
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = count.parse().map_err(|_| format!("LISTEN_FDS={} isn't a number", count))?;
+    let mut names = names.as_deref().unwrap_or_default().split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            let name = names.next().filter(|name| !name.is_empty() && !name.ends_with(".socket")).map(str::to_string);
+            adopt(fd).map(|listener| InheritedSocket { listener, fd, name })
+        })
+        .collect()
+}
+
+/// takes ownership of a passed descriptor, which must be a listening stream socket
+pub fn adopt(fd: RawFd) -> Result<ListenSocket, String> {
+    let mut listening: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the pointer and length describe `listening`, which the kernel writes at most `length` bytes of
+    let result = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN, (&mut listening as *mut libc::c_int).cast(), &mut length) };
+    if result != 0 {
+        return Err(format!("passed descriptor {} isn't a socket - {}", fd, std::io::Error::last_os_error()));
+    }
+    if listening == 0 {
+        return Err(format!("passed descriptor {} isn't a listening socket - the .socket unit needs Accept=no", fd));
+    }
+    // SAFETY: sockaddr_storage is plain data, for which all zeroes is a valid value
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: the pointer and length describe `address`, which the kernel writes at most `length` bytes of
+    let result = unsafe { libc::getsockname(fd, (&mut address as *mut libc::sockaddr_storage).cast(), &mut length) };
+    if result != 0 {
+        return Err(format!("failed to look up passed socket {} - {}", fd, std::io::Error::last_os_error()));
+    }
+    let listener = match address.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => {
+            // SAFETY: systemd handed the descriptor to this process, and nothing else in it knows the number
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true).map(|_| ListenSocket::Tcp(TcpListener::from_std(listener)))
+        },
+        libc::AF_UNIX => {
+            // SAFETY: as above
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true).map(|_| ListenSocket::Unix(UnixListener::from_std(listener)))
+        },
+        family => return Err(format!("passed socket {} has unsupported address family {}", fd, family)),
+    };
+    // the std types set close-on-exec for sockets they open, but these were opened by systemd
+    // SAFETY: fcntl on a descriptor this process owns
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    listener.map_err(|e| format!("failed to make passed socket {} non-blocking - {}", fd, e))
+}
+
+/// the service manager's notification socket, for Type=notify units - see sd_notify(3)
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+}
+
+impl Notifier {
+
+    /// None unless started by a service manager that's listening. the variables are cleared, as with listen_fds, and
+    /// the watchdog interval is read on the way
+    pub fn from_env() -> Result<(Option<Notifier>, Option<Duration>), String> {
+        let path = env::var("NOTIFY_SOCKET").ok();
+        let watchdog_pid = env::var("WATCHDOG_PID").ok();
+        let watchdog_usec = env::var("WATCHDOG_USEC").ok();
+        for variable in ["NOTIFY_SOCKET", "WATCHDOG_PID", "WATCHDOG_USEC"] {
+            env::remove_var(variable);
+        }
+        let Some(path) = path else {
+            return Ok((None, None));
+        };
+        // an abstract socket, named from the second byte on
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&path),
+        }.map_err(|e| format!("NOTIFY_SOCKET={} isn't a usable socket address - {}", path, e))?;
+        let socket = UnixDatagram::unbound().map_err(|e| format!("failed to create a notification socket - {}", e))?;
+        let watchdog_interval = match watchdog_usec {
+            // meant for another process, as with LISTEN_PID
+            _ if watchdog_pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) => None,
+            Some(usec) => match usec.parse() {
+                Ok(0) | Err(_) => return Err(format!("WATCHDOG_USEC={} isn't a positive number", usec)),
+                Ok(usec) => Some(Duration::from_micros(usec)),
+            },
+            None => None,
+        };
+        Ok((Some(Notifier { socket, address }), watchdog_interval))
+    }
+
+    /// a failure is only logged - a service manager that stopped listening has bigger problems than this
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            warn!("failed to notify the service manager of {} - {}", state.trim_end(), e);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::IntoRawFd;
+
+    use super::*;
+
+    #[test]
+    fn passed_listeners_of_either_kind_are_adopted() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let adopted = adopt(tcp.into_raw_fd()).unwrap();
+        assert_eq!(adopted.local_address().unwrap().to_string(), address.to_string());
+
+        let path = std::env::temp_dir().join(format!("endlessh-rs-adopt-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let adopted = adopt(unix.into_raw_fd()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(adopted.local_address().unwrap().path(), Some(path.as_path()));
+        // non-blocking, as the event loop needs
+        assert_eq!(adopted.accept().err().map(|e| e.kind()), Some(std::io::ErrorKind::WouldBlock));
+    }
+
+    #[test]
+    fn only_listening_sockets_are_adopted() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let error = adopt(connected.into_raw_fd()).err().unwrap();
+        assert!(error.contains("Accept=no"), "{}", error);
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let error = adopt(file.into_raw_fd()).err().unwrap();
+        assert!(error.contains("isn't a socket"), "{}", error);
+    }
+
+    // the only test to touch the LISTEN_* variables, which are process wide
+    #[test]
+    fn sockets_passed_to_another_process_are_left_alone() {
+        env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+        env::set_var("LISTEN_FDS", "1");
+        env::set_var("LISTEN_FDNAMES", "metrics");
+        assert!(listen_fds().unwrap().is_empty());
+        assert!(env::var_os("LISTEN_PID").is_none() && env::var_os("LISTEN_FDS").is_none() && env::var_os("LISTEN_FDNAMES").is_none());
+
+        assert!(listen_fds().unwrap().is_empty());
+        env::set_var("LISTEN_PID", std::process::id().to_string());
+        env::set_var("LISTEN_FDS", "all");
+        assert_eq!(listen_fds().err().as_deref(), Some("LISTEN_FDS=all isn't a number"));
+    }
+
+    // the only test to touch the NOTIFY_SOCKET and WATCHDOG_* variables
+    #[test]
+    fn notifications_reach_the_service_managers_socket() {
+        let name = format!("endlessh-rs-notify-{}", std::process::id());
+        let manager = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        env::set_var("WATCHDOG_USEC", "30000000");
+        let (notifier, watchdog_interval) = Notifier::from_env().unwrap();
+        assert_eq!(watchdog_interval, Some(Duration::from_secs(30)));
+        assert!(env::var_os("NOTIFY_SOCKET").is_none() && env::var_os("WATCHDOG_USEC").is_none());
+        notifier.unwrap().notify("READY=1");
+        let mut received = [0; 16];
+        let n = manager.recv(&mut received).unwrap();
+        assert_eq!(&received[..n], b"READY=1");
+
+        // a watchdog meant for another process
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        env::set_var("WATCHDOG_PID", (std::process::id() + 1).to_string());
+        env::set_var("WATCHDOG_USEC", "30000000");
+        let (notifier, watchdog_interval) = Notifier::from_env().unwrap();
+        assert!(notifier.is_some() && watchdog_interval.is_none());
+
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        env::set_var("WATCHDOG_USEC", "0");
+        assert_eq!(Notifier::from_env().err().as_deref(), Some("WATCHDOG_USEC=0 isn't a positive number"));
+        assert!(matches!(Notifier::from_env(), Ok((None, None))));
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::{rename, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+use log::warn;
+
+/// writes the metrics exposition to a file for node_exporter's textfile collector
+pub struct TextfileWriter {
+    path: PathBuf,
+}
+
+impl TextfileWriter {
+
+    pub fn new(path: PathBuf) -> Self {
+        TextfileWriter {
+            path,
+        }
+    }
+
+    /// returns whether the write succeeded
+    pub fn write(&self, contents: &str) -> bool {
+        if let Err(e) = self.write_atomically(contents.as_bytes()) {
+            warn!("failed to write metrics to {} - {}", self.path.display(), e);
+            return false;
+        }
+        true
+    }
+
+    // the collector may read at any moment, so never let it see a half-written file
+    fn write_atomically(&self, contents: &[u8]) -> std::io::Result<()> {
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(contents)?;
+        temp_file.sync_all()?;
+        rename(&temp_path, &self.path)
+    }
+
+}
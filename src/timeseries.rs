@@ -0,0 +1,50 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::endlessh::{unix_seconds, EndlesshStats, StatsSnapshot};
+
+#[derive(Serialize)]
+struct Sample {
+    time_seconds: u64,
+    #[serde(flatten)]
+    stats: StatsSnapshot,
+}
+
+/// a fixed-size ring of periodic stats snapshots, so graphs can be drawn without external storage
+pub struct TimeSeries {
+    stats: Arc<EndlesshStats>,
+    length: usize,
+    samples: VecDeque<Sample>,
+}
+
+impl TimeSeries {
+
+    pub fn new(stats: Arc<EndlesshStats>, length: usize) -> Self {
+        TimeSeries {
+            stats,
+            length,
+            samples: VecDeque::with_capacity(length),
+        }
+    }
+
+    pub fn take_sample(&mut self) {
+        if self.length == 0 {
+            return;
+        }
+        if self.samples.len() == self.length {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            time_seconds: unix_seconds(SystemTime::now()),
+            stats: self.stats.snapshot(),
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.samples).expect("failed to serialize time series")
+    }
+
+}
@@ -0,0 +1,122 @@
+//! https for the metric server, so a scraper across an untrusted network can't be read or fed fake numbers
+//!
+//! the handshake happens in the metric server's own event loop: a [TlsStream] reads and writes like the plain socket
+//! under it, returning `WouldBlock` until enough of the handshake has gone back and forth
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use mio::{event, Interest, Registry, Token};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::{ServerConfig, ServerConnection};
+
+use crate::error::{self, EndlesshError};
+
+/// the certificate chain and its private key, both pem files, as the metric server's tls config
+pub fn load_server_config(cert: &Path, key: &Path) -> error::Result<Arc<ServerConfig>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| EndlesshError::Tls(format!("failed to read certificates from {} - {}", cert.display(), e)))?;
+    if chain.is_empty() {
+        return Err(EndlesshError::Tls(format!("{} has no certificates", cert.display())));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| EndlesshError::Tls(format!("failed to read a private key from {} - {}", key.display(), e)))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, private_key))
+        .map_err(|e| EndlesshError::Tls(format!("{} and {} can't be used - {}", cert.display(), key.display(), e)))?;
+    Ok(Arc::new(config))
+}
+
+/// a socket with tls on top
+pub(crate) struct TlsStream<S: Read + Write> {
+    connection: ServerConnection,
+    stream: S,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    pub(crate) fn new(config: Arc<ServerConfig>, stream: S) -> io::Result<Self> {
+        let connection = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(TlsStream { connection, stream })
+    }
+
+    /// writes out whatever records are waiting, `WouldBlock` if the socket didn't take them all
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            if self.connection.write_tls(&mut self.stream)? == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // the handshake's replies, which a full socket may have held back until this writable event
+            match self.write_records() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                result => result?,
+            }
+            match self.connection.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                result => return result,
+            }
+            if self.connection.read_tls(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.connection.process_new_packets() {
+                // try to tell the client why
+                let _ = self.write_records();
+                return Err(io::Error::new(ErrorKind::InvalidData, e));
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // nothing more is taken until the socket has caught up, so a slow client can't make the records pile up
+        self.write_records()?;
+        let written = self.connection.writer().write(buf)?;
+        match self.write_records() {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+            result => result?,
+        }
+        Ok(written)
+    }
+
+    /// `WouldBlock` until every record has been written to the socket
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_records()?;
+        self.stream.flush()
+    }
+}
+
+/// says goodbye with a close_notify where the socket has room for it, clients treat a bare hang up as a truncation
+impl<S: Read + Write> Drop for TlsStream<S> {
+    fn drop(&mut self) {
+        self.connection.send_close_notify();
+        let _ = self.write_records();
+    }
+}
+
+/// registered for both readiness kinds whatever it's asked for, as tls may have to write while the server reads and
+/// read while it writes
+impl<S: Read + Write + event::Source> event::Source for TlsStream<S> {
+    fn register(&mut self, registry: &Registry, token: Token, _interests: Interest) -> io::Result<()> {
+        self.stream.register(registry, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, _interests: Interest) -> io::Result<()> {
+        self.stream.reregister(registry, token, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.stream.deregister(registry)
+    }
+}
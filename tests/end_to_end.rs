@@ -0,0 +1,833 @@
+#![cfg(feature = "metrics")]
+
+mod support;
+
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use endlessh_rs::address_list::AddressList;
+use endlessh_rs::endlessh::{BannerGenerator, BannerTemplate, EndlesshOptions, EndlesshStats, NewLine, StrategyVariant, Tarpit};
+use endlessh_rs::metrics::MetricServerOptions;
+use endlessh_rs::offenders::RepeatOffenders;
+use endlessh_rs::policy::{AcceptPolicy, Arrival, Verdict};
+use support::{Harness, SshClient, TrickleStream};
+
+fn fast_options() -> EndlesshOptions {
+    EndlesshOptions {
+        banner_line_length: 16,
+        message_delay: Duration::from_millis(20),
+        ..EndlesshOptions::default()
+    }
+}
+
+#[test]
+fn trapped_clients_receive_random_banner_lines() {
+    let harness = Harness::start(fast_options());
+    let mut client = harness.connect_ssh();
+    for _ in 0..3 {
+        let line = client.read_line();
+        assert_eq!(line.len(), 16);
+        assert!(line.bytes().all(|b| b.is_ascii_alphanumeric()), "unexpected banner line {:?}", line);
+        assert!(!line.starts_with("SSH-"));
+    }
+}
+
+#[test]
+fn banner_lines_vary_in_length_within_the_range() {
+    let harness = Harness::start(EndlesshOptions {
+        banner_line_length: 8,
+        banner_line_length_max: Some(24),
+        message_delay: Duration::from_millis(5),
+        ..EndlesshOptions::default()
+    });
+    let mut client = harness.connect_ssh();
+    let lengths: Vec<usize> = (0..20).map(|_| client.read_line().len()).collect();
+    assert!(lengths.iter().all(|length| (8..=24).contains(length)), "unexpected line lengths {:?}", lengths);
+    assert!(lengths.iter().any(|&length| length != lengths[0]), "every line was {} bytes", lengths[0]);
+}
+
+#[test]
+fn template_banner_lines_fill_in_placeholders_but_never_start_with_ssh() {
+    let template = BannerTemplate::parse("SSH-{digits:2} id={hex:4}", None).unwrap();
+    let harness = Harness::start(EndlesshOptions {
+        banner: BannerGenerator::Template(template),
+        ..fast_options()
+    });
+    let mut client = harness.connect_ssh();
+    for _ in 0..3 {
+        let line = client.read_line();
+        assert!(line.starts_with("SSH_"), "unexpected banner line {:?}", line);
+        let (digits, id) = line["SSH_".len()..].split_once(" id=").unwrap();
+        assert!(digits.len() == 2 && digits.bytes().all(|b| b.is_ascii_digit()), "unexpected banner line {:?}", line);
+        assert!(id.len() == 4 && id.bytes().all(|b| b.is_ascii_hexdigit()), "unexpected banner line {:?}", line);
+    }
+}
+
+#[test]
+fn stats_track_opened_and_closed_connections() {
+    let harness = Harness::start(fast_options());
+    let mut first = harness.connect_ssh();
+    let mut second = harness.connect_ssh();
+    first.read_line();
+    second.read_line();
+    assert_eq!(harness.stats.connections_opened.load(Ordering::Relaxed), 2);
+
+    drop(first);
+    harness.wait_for("the dropped client to be closed", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+    second.read_line();
+    // counted just after the write, so possibly after the line is read
+    harness.wait_for("the lines to be counted", |h| h.stats.bytes_sent.load(Ordering::Relaxed) >= 3 * 17);
+}
+
+#[test]
+fn readyz_fails_while_every_client_slot_is_taken() {
+    let harness = Harness::start(EndlesshOptions {
+        max_clients: 1,
+        ..fast_options()
+    });
+    let (status, body) = harness.scrape("/readyz");
+    assert_eq!(status, 200, "unexpected readiness {}", body);
+
+    let mut client = harness.connect_ssh();
+    client.read_line();
+    let (status, body) = harness.scrape("/readyz");
+    assert_eq!(status, 503);
+    assert!(body.contains("\"at_capacity\""), "unexpected readiness {}", body);
+    // busy isn't dead
+    assert_eq!(harness.scrape("/healthz").0, 200);
+
+    drop(client);
+    harness.wait_for("the slot to be freed", |h| h.scrape("/readyz").0 == 200);
+}
+
+#[test]
+fn trap_durations_are_observed_as_clients_leave() {
+    let harness = Harness::start(fast_options());
+    let mut client = harness.connect_ssh();
+    client.read_line();
+    drop(client);
+    harness.wait_for("the dropped client to be closed", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+
+    let (_, body) = harness.scrape("/metrics");
+    assert!(body.contains("# TYPE endlessh_trap_duration_seconds histogram\n"), "unexpected metrics {}", body);
+    assert!(body.contains("endlessh_trap_duration_seconds_bucket{le=\"+Inf\"} 1\nendlessh_trap_duration_seconds_sum "), "unexpected metrics {}", body);
+    assert!(body.contains("endlessh_trap_duration_seconds_count 1\n"), "unexpected metrics {}", body);
+    // and under its deprecated name
+    assert!(body.contains("endlessh_ssh_trapped_seconds_count 1\n"), "unexpected metrics {}", body);
+}
+
+#[test]
+fn metrics_endpoint_reflects_trapped_clients() {
+    let harness = Harness::start(fast_options());
+    let mut client = harness.connect_ssh();
+    client.read_line();
+
+    let (status, body) = harness.scrape("/metrics");
+    assert_eq!(status, 200);
+    assert!(body.contains("# TYPE endlessh_ssh_connections_opened counter\nendlessh_ssh_connections_opened_total 1\n"), "unexpected metrics {}", body);
+    assert!(body.contains("endlessh_ssh_trapped_seconds_bucket{le=\"+Inf\"} 0\n"), "unexpected metrics {}", body);
+    assert!(body.ends_with("\n# EOF\n"), "unexpected metrics {}", body);
+    // the process collector's names, which dashboards and alerts go by
+    for sample in ["process_cpu_seconds_total ", "process_open_fds ", "process_max_fds ", "process_resident_memory_bytes ", "process_start_time_seconds "] {
+        assert!(body.lines().any(|line| line.starts_with(sample)), "no {}in {}", sample, body);
+    }
+    assert!(body.contains("# TYPE process_cpu_seconds counter\n"), "unexpected metrics {}", body);
+
+    let (status, body) = harness.scrape("/stats");
+    assert_eq!(status, 200);
+    let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(stats["connections_opened"], 1);
+}
+
+#[test]
+fn clients_endpoint_shows_each_clients_schedule() {
+    let harness = Harness::start(fast_options());
+    let mut client = harness.connect_ssh();
+    client.read_line();
+
+    let (status, body) = harness.scrape("/clients");
+    assert_eq!(status, 200);
+    let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let clients = clients.as_array().unwrap();
+    assert_eq!(clients.len(), 1, "unexpected clients {}", body);
+    assert_eq!(clients[0]["delay_seconds"], 0.02);
+    assert!(clients[0]["next_send_seconds"].as_f64().unwrap() <= 0.02, "unexpected clients {}", body);
+    assert!(clients[0]["lines_sent"].as_u64().unwrap() >= 1);
+}
+
+#[test]
+fn metric_server_rejects_unknown_paths_and_methods() {
+    let harness = Harness::start(fast_options());
+    assert_eq!(harness.scrape("/nope").0, 404);
+    let response = harness.request(b"POST /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(support::parse_response(&response).0, 405);
+}
+
+#[test]
+fn kept_alive_connections_answer_pipelined_requests_in_order() {
+    let harness = Harness::start(fast_options());
+    // sent in one write, the first keeping the connection and the second closing it
+    let response = harness.request(b"GET /nope HTTP/1.1\r\nHost: localhost\r\n\r\nGET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let second = response.strip_prefix("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").expect("the first response should be a 404");
+    assert!(second.starts_with("HTTP/1.1 200 OK\r\nConnection: close\r\n"), "unexpected second response {}", second);
+}
+
+#[test]
+fn pipelined_requests_beyond_the_request_buffer_are_read_once_it_has_room() {
+    let harness = Harness::start_with_metric_options(fast_options(), MetricServerOptions {
+        request_max_size: 128,
+        ..MetricServerOptions::default()
+    });
+    // each fits in the buffer, both together don't
+    let padding = "x".repeat(48);
+    let request = format!("GET /nope HTTP/1.1\r\nX-Padding: {}\r\n\r\nGET /readyz HTTP/1.1\r\nX-Padding: {}\r\nConnection: close\r\n\r\n", padding, padding);
+    let response = harness.request(request.as_bytes());
+    let second = response.strip_prefix("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").expect("the first response should be a 404");
+    assert_eq!(support::parse_response(second).0, 200);
+}
+
+#[test]
+fn idle_metric_clients_are_hung_up_on() {
+    let idle_timeout = Duration::from_millis(200);
+    let harness = Harness::start_with_metric_options(fast_options(), MetricServerOptions {
+        idle_timeout,
+        ..MetricServerOptions::default()
+    });
+    // kept alive after its response, until it's been idle long enough
+    let started = Instant::now();
+    let response = harness.request(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(started.elapsed() >= idle_timeout, "hung up on after {:?}", started.elapsed());
+    assert_eq!(support::parse_response(&response).0, 200);
+    // and a client that never finishes its request
+    let started = Instant::now();
+    assert_eq!(harness.request(b"GET /readyz HTTP/1.1\r\n"), "");
+    assert!(started.elapsed() >= idle_timeout, "hung up on after {:?}", started.elapsed());
+}
+
+#[cfg(feature = "metrics-tls")]
+#[test]
+fn metrics_are_served_over_https() {
+    use std::io::Read;
+
+    let harness = Harness::start_with_metric_options(fast_options(), support::tls_options());
+
+    // plain http gets a tls alert at most
+    let response = harness.request(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert!(!response.starts_with("HTTP/"), "plain http was answered: {}", response);
+
+    let mut stream = harness.connect_https();
+    stream.write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    // ends with a close_notify, anything else is an error
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert_eq!(support::parse_response(&response), (200, r#"{"status":"ready","reasons":[]}"#.to_string()));
+}
+
+/// a /timeseries body of some 18MB, more than even loopback's autotuned socket buffers hold
+fn large_timeseries(harness: &Harness) -> String {
+    let samples: Vec<String> = (0..1_000_000).map(|i| format!("{{\"sample\":{}}}", i)).collect();
+    let body = format!("[{}]", samples.join(","));
+    *harness.timeseries.lock().unwrap() = body.clone();
+    body
+}
+
+#[test]
+fn large_responses_reach_slow_readers_intact() {
+    let harness = Harness::start(fast_options());
+    let body = large_timeseries(&harness);
+    let mut stream = TcpStream::connect(harness.metrics_address).unwrap();
+    stream.write_all(b"GET /timeseries HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let (status, received) = support::parse_response(&support::read_slowly(&mut stream));
+    assert_eq!(status, 200);
+    assert!(received == body, "got {} bytes of a {} byte body", received.len(), body.len());
+}
+
+#[cfg(feature = "metrics-tls")]
+#[test]
+fn large_responses_reach_slow_readers_intact_over_https() {
+    let harness = Harness::start_with_metric_options(fast_options(), support::tls_options());
+    let body = large_timeseries(&harness);
+    let mut stream = harness.connect_https();
+    stream.write_all(b"GET /timeseries HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let (status, received) = support::parse_response(&support::read_slowly(&mut stream));
+    assert_eq!(status, 200);
+    assert!(received == body, "got {} bytes of a {} byte body", received.len(), body.len());
+}
+
+#[test]
+fn metric_server_rejects_oversized_requests() {
+    let harness = Harness::start(fast_options());
+    let padding = "x".repeat(16 * 1024);
+    let response = harness.request(format!("GET /metrics HTTP/1.1\r\nX-Padding: {}\r\n\r\n", padding).as_bytes());
+    assert_eq!(support::parse_response(&response).0, 431);
+}
+
+#[test]
+fn listener_changes_need_the_admin_api() {
+    let harness = Harness::start(fast_options());
+    let response = harness.request(b"POST /listeners/127.0.0.1:0 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(support::parse_response(&response).0, 403);
+    let response = harness.request(b"POST /listeners/nonsense HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(support::parse_response(&response).0, 400);
+}
+
+#[test]
+fn clients_are_trapped_from_every_listener() {
+    let second = mio::net::TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let second_address = second.local_addr().unwrap();
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut listeners = vec![(second.into(), Some("alt"))];
+    #[cfg(unix)]
+    let socket_path = std::env::temp_dir().join(format!("endlessh-rs-listeners-{}.sock", std::process::id()));
+    #[cfg(unix)]
+    {
+        let _ = std::fs::remove_file(&socket_path);
+        listeners.push((mio::net::UnixListener::bind(&socket_path).unwrap().into(), None));
+    }
+    let harness = Harness::start_with_listeners(fast_options(), listeners);
+
+    let mut first = harness.connect_ssh();
+    let mut alt = SshClient::connect(second_address);
+    assert!(!first.read_line().is_empty());
+    assert!(!alt.read_line().is_empty());
+    #[cfg(unix)]
+    {
+        let mut unix = BufReader::new(std::os::unix::net::UnixStream::connect(&socket_path).unwrap());
+        let mut line = String::new();
+        unix.read_line(&mut line).unwrap();
+        assert!(line.ends_with('\n'), "{:?}", line);
+        let _ = std::fs::remove_file(&socket_path);
+    }
+    let listeners = if cfg!(unix) { 3 } else { 2 };
+    harness.wait_for("a client from each listener", |h| h.stats.connections_opened.load(Ordering::Relaxed) == listeners);
+
+    let (_, body) = harness.scrape("/clients");
+    let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let alt_clients = clients.as_array().unwrap().iter().filter(|client| client["listener"] == "alt").count();
+    assert_eq!(alt_clients, 1, "unexpected clients {}", body);
+}
+
+#[test]
+fn proxy_protocol_clients_are_trapped_under_their_real_address() {
+    let harness = Harness::start(EndlesshOptions {
+        proxy_protocol: true,
+        ..fast_options()
+    });
+    let mut v1 = harness.connect_ssh();
+    // a header split across segments is waited for
+    v1.send(b"PROXY TCP4 203.0.113.7 ");
+    thread::sleep(Duration::from_millis(50));
+    v1.send(b"192.0.2.1 51234 22\r\n");
+    v1.read_line();
+
+    let mut v2 = harness.connect_ssh();
+    v2.send(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c");
+    v2.send(&[198, 51, 100, 9, 192, 0, 2, 1, 0xd4, 0x31, 0, 22]);
+    v2.read_line();
+
+    let (_, body) = harness.scrape("/clients");
+    let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let mut peers: Vec<&str> = clients.as_array().unwrap().iter().map(|c| c["peer"].as_str().unwrap()).collect();
+    peers.sort_unstable();
+    assert_eq!(peers, ["198.51.100.9:54321", "203.0.113.7:51234"]);
+
+    let mut bare = harness.connect_ssh();
+    bare.send(b"SSH-2.0-OpenSSH_9.6\r\n");
+    harness.wait_for("the headerless client to be dropped", |h| h.stats.proxy_header_errors.load(Ordering::Relaxed) == 1);
+    assert_eq!(harness.stats.connections_opened.load(Ordering::Relaxed), 2);
+}
+
+/// lets an address in once, then bans it
+struct OneStrike;
+
+impl AcceptPolicy for OneStrike {
+    fn decide(&mut self, arrival: &Arrival) -> Option<Verdict> {
+        (arrival.strikes > 0).then_some(Verdict::Ban)
+    }
+}
+
+#[test]
+fn accept_policies_decide_after_the_built_in_checks() {
+    let harness = Harness::start_with(fast_options(), |server| server.add_policy(OneStrike));
+    let mut first = harness.connect_ssh();
+    first.read_line();
+    let _second = harness.connect_ssh();
+    harness.wait_for("the second connection to be banned", |h| h.stats.connections_banned.load(Ordering::Relaxed) == 1);
+    assert_eq!(harness.stats.connections_opened.load(Ordering::Relaxed), 1);
+    first.read_line();
+}
+
+#[test]
+fn clients_past_the_per_ip_limit_are_hung_up_on() {
+    let harness = Harness::start(EndlesshOptions { max_clients_per_ip: Some(1), ..fast_options() });
+    let mut first = harness.connect_ssh();
+    first.read_line();
+    let _second = harness.connect_ssh();
+    harness.wait_for("the second connection to be rejected", |h| h.stats.connections_rejected.load(Ordering::Relaxed) == 1);
+    drop(first);
+    harness.wait_for("the first client to leave", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+    let mut third = harness.connect_ssh();
+    third.read_line();
+    assert_eq!(harness.stats.connections_opened.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn denied_addresses_are_hung_up_on_even_when_allowed() {
+    let cidrs = |text| Some(AddressList::parse(text).0);
+    let harness = Harness::start(EndlesshOptions { allow_cidrs: cidrs("127.0.0.0/8"), deny_cidrs: cidrs("127.0.0.1"), ..fast_options() });
+    let _client = harness.connect_ssh();
+    harness.wait_for("the connection to be filtered", |h| h.stats.connections_filtered.load(Ordering::Relaxed) == 1);
+    assert_eq!(harness.stats.connections_opened.load(Ordering::Relaxed), 0);
+}
+
+/// lets the first client be trapped, passes the second and closes the rest
+struct Countdown(u32);
+
+impl AcceptPolicy for Countdown {
+    fn decide(&mut self, _arrival: &Arrival) -> Option<Verdict> {
+        self.0 += 1;
+        match self.0 {
+            1 => None,
+            2 => Some(Verdict::Pass),
+            _ => Some(Verdict::Close),
+        }
+    }
+}
+
+#[test]
+fn passed_clients_count_as_opened_and_closed_clients_as_rejected() {
+    let harness = Harness::start_with(fast_options(), |server| server.add_policy(Countdown(0)));
+    let mut trapped = harness.connect_ssh();
+    trapped.read_line();
+    let _passed = harness.connect_ssh();
+    harness.wait_for("the passed client to be closed", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+    let _closed = harness.connect_ssh();
+    harness.wait_for("the last client to be rejected", |h| h.stats.connections_rejected.load(Ordering::Relaxed) == 1);
+    assert_eq!(harness.stats.connections_opened.load(Ordering::Relaxed), 2);
+    assert_eq!(harness.stats.connections_banned.load(Ordering::Relaxed), 0);
+    trapped.read_line();
+}
+
+#[test]
+fn only_the_sampled_share_of_clients_is_trapped() {
+    let harness = Harness::start(EndlesshOptions { trap_probability: 0.5, rng_seed: Some(7), ..fast_options() });
+    let _clients: Vec<_> = (0..20).map(|_| harness.connect_ssh()).collect();
+    let counted = |h: &Harness| h.stats.connections_opened.load(Ordering::Relaxed) + h.stats.connections_sampled_out.load(Ordering::Relaxed);
+    harness.wait_for("every connection to be sampled", |h| counted(h) == 20);
+    let trapped = harness.stats.connections_opened.load(Ordering::Relaxed);
+    assert!((3..=17).contains(&trapped), "{} of 20 trapped", trapped);
+
+    let nobody = Harness::start(EndlesshOptions { trap_probability: 0.0, ..fast_options() });
+    let _client = nobody.connect_ssh();
+    nobody.wait_for("the connection to be sampled out", |h| h.stats.connections_sampled_out.load(Ordering::Relaxed) == 1);
+    assert_eq!(nobody.stats.connections_opened.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn startup_is_summarised_with_a_line_per_listener() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .args(["--ssh-listen-address", "127.0.0.1:0", "--ssh-listen-address", "127.0.0.1:0", "--ssh-listener-name", "alt"])
+        .args(["--ssh-max-clients", "16", "--log-format", "json"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let lines: Vec<serde_json::Value> = BufReader::new(child.stdout.take().unwrap()).lines()
+        .take(5)
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(lines.len(), 5, "{:?}", lines);
+
+    assert_eq!(lines[0]["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(lines[0]["pid"], child.id());
+    let features = lines[0]["features"].as_str().unwrap().split(',').collect::<Vec<_>>();
+    assert!(features.contains(&"cli") && features.contains(&"metrics"), "{}", lines[0]);
+    assert_eq!((&lines[1]["message"], &lines[1]["max_clients"]), (&"tarpit configuration".into(), &16.into()));
+    assert_eq!(lines[2]["message"], "resource limits");
+
+    let listeners: Vec<_> = lines[3..].iter().map(|line| (line["address"].as_str().unwrap(), line.get("name"))).collect();
+    assert_eq!(listeners[0].1, Some(&"alt".into()));
+    assert_eq!(listeners[1].1, None);
+    assert_ne!(listeners[0].0, listeners[1].0);
+    assert!(listeners.iter().all(|(address, _)| address.starts_with("127.0.0.1:") && !address.ends_with(":0")), "{:?}", listeners);
+}
+
+#[test]
+fn zero_intervals_are_refused_at_startup() {
+    for flag in ["--metrics-timeseries-interval-secs", "--health-check-interval-secs"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+            .args(["--ssh-listen-address", "127.0.0.1:0", flag, "0"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(78));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(&format!("{} must be at least 1", flag)), "{}", stderr);
+    }
+}
+
+#[test]
+#[cfg(feature = "remote-lists")]
+fn a_zero_list_refresh_is_refused_at_startup() {
+    let output = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .args(["--ssh-listen-address", "127.0.0.1:0", "--allowlist-url", "http://127.0.0.1:1/allowlist.txt", "--list-refresh-secs", "0"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(78));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--list-refresh-secs must be at least 1"), "{}", stderr);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn the_service_manager_hears_of_readiness_the_watchdog_and_stopping() {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = std::env::temp_dir().join(format!("endlessh-rs-notify-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let manager = UnixDatagram::bind(&path).unwrap();
+    manager.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .args(["--ssh-listen-address", "127.0.0.1:0"])
+        .env("NOTIFY_SOCKET", &path)
+        .env("WATCHDOG_USEC", "200000")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let next = || {
+        let mut state = [0; 64];
+        let n = manager.recv(&mut state).unwrap();
+        String::from_utf8_lossy(&state[..n]).into_owned()
+    };
+    assert_eq!(next(), "READY=1");
+    // pinged at half the interval
+    let pinged = Instant::now();
+    assert_eq!(next(), "WATCHDOG=1");
+    assert_eq!(next(), "WATCHDOG=1");
+    assert!(pinged.elapsed() < Duration::from_secs(1), "{:?}", pinged.elapsed());
+    // SAFETY: signalling a child that hasn't been waited on yet
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    let mut state = next();
+    while state == "WATCHDOG=1" {
+        state = next();
+    }
+    assert_eq!(state, "STOPPING=1");
+    assert!(child.wait().unwrap().success());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn root_is_given_up_once_the_listeners_are_bound() {
+    // SAFETY: getuid can't fail
+    if unsafe { libc::getuid() } != 0 {
+        return;
+    }
+    let mut child = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .args(["--ssh-listen-address", "127.0.0.1:0", "--user", "65534", "--group", "65533"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let switched = BufReader::new(child.stdout.take().unwrap()).lines()
+        .map(Result::unwrap)
+        .find(|line| line.contains("switched to"));
+    let status = std::fs::read_to_string(format!("/proc/{}/status", child.id())).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(switched.as_deref(), Some("INFO  switched to uid 65534 gid 65533"));
+    let ids = |key: &str| status.lines().find_map(|line| line.strip_prefix(key)).unwrap().split_whitespace().collect::<Vec<_>>();
+    assert_eq!(ids("Uid:"), ["65534"; 4]);
+    assert_eq!(ids("Gid:"), ["65533"; 4]);
+    assert!(ids("Groups:").is_empty());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .args(["--ssh-listen-address", "127.0.0.1:0", "--user", "no-such-user-endlessh"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(77));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no such user no-such-user-endlessh"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn terminating_says_goodbye_to_the_clients_and_removes_the_sockets() {
+    use std::io::Read;
+
+    let socket_path = std::env::temp_dir().join(format!("endlessh-rs-shutdown-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket_path);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .arg("--ssh-listen-address").arg(format!("unix:{}", socket_path.display()))
+        .args(["--ssh-message-delay-ms", "50", "--shutdown-linger-secs", "60", "--farewell-line", "goodbye"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines().map(Result::unwrap);
+    assert!(lines.any(|line| line.contains("listening for ssh connections")));
+    let mut client = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut first = [0; 1];
+    client.read_exact(&mut first).unwrap();
+
+    // SAFETY: signalling a child that hasn't been waited on yet
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    assert!(lines.any(|line| line.contains("SIGTERM received, hanging up on the trapped clients in 60s")));
+    // a second signal cuts the linger short
+    // SAFETY: as above
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let mut rest = String::new();
+    client.read_to_string(&mut rest).unwrap();
+    assert!(rest.ends_with("goodbye\n"), "{:?}", rest);
+    assert!(child.wait().unwrap().success());
+    assert!(!socket_path.exists());
+}
+
+#[test]
+fn returning_addresses_wait_longer_between_lines() {
+    let repeat_offenders = Some(RepeatOffenders { ttl: Duration::from_secs(60), max_factor: 8 });
+    let harness = Harness::start(EndlesshOptions { repeat_offenders, ..fast_options() });
+    let mut first = harness.connect_ssh();
+    first.read_line();
+    drop(first);
+    harness.wait_for("the first client to leave", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+    let mut second = harness.connect_ssh();
+    second.read_line();
+    assert_eq!(harness.stats.repeat_offenders.load(Ordering::Relaxed), 1);
+
+    let (_, body) = harness.scrape("/clients");
+    let clients: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(clients[0]["delay_seconds"], 0.04, "unexpected clients {}", body);
+}
+
+#[test]
+fn clients_are_hung_up_on_after_the_max_trap_duration() {
+    let harness = Harness::start(EndlesshOptions { max_trap_duration: Some(Duration::from_millis(100)), ..fast_options() });
+    let _client = harness.connect_ssh();
+    harness.wait_for("the client to be hung up on", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+    assert_eq!(harness.stats.max_trap_evictions.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn history_endpoint_pages_through_an_addresses_events() {
+    let harness = Harness::start(fast_options());
+    let _first = harness.connect_ssh();
+    let _second = harness.connect_ssh();
+    harness.wait_for("both clients to be trapped", |h| h.stats.connections_opened.load(Ordering::Relaxed) == 2);
+
+    let (status, body) = harness.scrape("/history?ip=127.0.0.1&limit=1");
+    assert_eq!(status, 200);
+    let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(page["events"].as_array().unwrap().len(), 1, "unexpected page {}", body);
+    assert_eq!(page["events"][0]["event"], "accepted");
+    assert_eq!(page["next_offset"], 1);
+
+    let (_, body) = harness.scrape("/history?ip=127.0.0.1&limit=1&offset=1");
+    let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(page["events"].as_array().unwrap().len(), 1, "unexpected page {}", body);
+    assert_eq!(page["next_offset"], serde_json::Value::Null);
+
+    let (_, body) = harness.scrape("/history?ip=192.0.2.1");
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["events"], serde_json::json!([]));
+    assert_eq!(harness.scrape("/history?limit=many").0, 400);
+}
+
+#[test]
+fn events_carry_the_sensor_id() {
+    let harness = Harness::start(EndlesshOptions { sensor_id: Some("edge-1".to_string()), ..fast_options() });
+    let _client = harness.connect_ssh();
+    harness.wait_for("the client to be trapped", |h| h.stats.connections_opened.load(Ordering::Relaxed) == 1);
+
+    let (_, body) = harness.scrape("/recent");
+    let events: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(events[0]["sensor"], "edge-1", "unexpected events {}", body);
+}
+
+#[test]
+fn jittered_clients_are_each_sent_lines_when_due() {
+    let harness = Harness::start(EndlesshOptions { message_delay_jitter: Some(Duration::from_millis(15)), ..fast_options() });
+    let mut clients: Vec<_> = (0..3).map(|_| harness.connect_ssh()).collect();
+    for _ in 0..5 {
+        for client in &mut clients {
+            assert_eq!(client.read_line().len(), 16);
+        }
+    }
+
+    let (_, body) = harness.scrape("/clients");
+    let schedule: serde_json::Value = serde_json::from_str(&body).unwrap();
+    for client in schedule.as_array().unwrap() {
+        assert!(client["next_send_seconds"].as_f64().unwrap() <= 0.035, "unexpected clients {}", body);
+    }
+}
+
+#[test]
+fn closed_connections_are_scored() {
+    let harness = Harness::start(fast_options());
+    let mut client = harness.connect_ssh();
+    for _ in 0..3 {
+        client.read_line();
+    }
+    drop(client);
+    harness.wait_for("the client to be closed", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+
+    let (_, body) = harness.scrape("/recent");
+    let events: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(events[0]["event"], "closed", "unexpected events {}", body);
+    // at least 3 lines of 17 bytes
+    assert!(events[0]["score"].as_f64().unwrap() >= 2.0, "unexpected events {}", body);
+    assert_eq!(harness.stats.trap_score.count(), 1);
+}
+
+#[test]
+fn strategy_variants_split_clients_and_keep_their_own_totals() {
+    let variant = |name: &str, banner| StrategyVariant {
+        name: name.to_string(),
+        weight: 1,
+        message_delay: Duration::from_millis(20),
+        message_delay_jitter: None,
+        banner,
+        banner_line_length: 16,
+        banner_line_length_max: None,
+    };
+    let harness = Harness::start(EndlesshOptions {
+        variants: vec![variant("letters", BannerGenerator::Alphanumeric), variant("hex", BannerGenerator::Hex)],
+        ..fast_options()
+    });
+    let mut clients: Vec<_> = (0..8).map(|_| harness.connect_ssh()).collect();
+    let lines: Vec<String> = clients.iter_mut().map(|client| client.read_line()).collect();
+
+    let (_, body) = harness.scrape("/clients");
+    let schedule: serde_json::Value = serde_json::from_str(&body).unwrap();
+    for client in schedule.as_array().unwrap() {
+        assert!(client["variant"] == "letters" || client["variant"] == "hex", "unexpected clients {}", body);
+    }
+    // a hex line could pass for letters and digits, but not the other way round
+    assert!(lines.iter().all(|line| line.len() == 16 && line.bytes().all(|b| b.is_ascii_alphanumeric())), "unexpected lines {:?}", lines);
+
+    drop(clients);
+    harness.wait_for("the clients to be closed", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 8);
+    let (_, body) = harness.scrape("/recent");
+    let events: serde_json::Value = serde_json::from_str(&body).unwrap();
+    for event in events.as_array().unwrap() {
+        assert!(event["variant"] == "letters" || event["variant"] == "hex", "unexpected events {}", body);
+    }
+}
+
+#[test]
+fn seeded_tarpits_send_the_same_banner() {
+    let banner = || {
+        let harness = Harness::start(EndlesshOptions { rng_seed: Some(42), banner_line_length_max: Some(40), ..fast_options() });
+        let mut client = harness.connect_ssh();
+        (0..3).map(|_| client.read_line()).collect::<Vec<_>>()
+    };
+    assert_eq!(banner(), banner());
+}
+
+#[test]
+fn lines_end_with_the_configured_terminator() {
+    let newline: NewLine = "hex:7c0a".parse().unwrap();
+    assert_eq!(newline.to_string(), "hex:7c0a");
+    let harness = Harness::start(EndlesshOptions { newline, ..fast_options() });
+    let mut client = harness.connect_ssh();
+    for _ in 0..3 {
+        let line = client.read_line();
+        assert!(line.len() == 17 && line.ends_with('|'), "unexpected line {:?}", line);
+    }
+}
+
+#[test]
+fn client_input_is_drained_and_counted() {
+    // slow enough that only reading it as it arrives counts it in time
+    let harness = Harness::start(EndlesshOptions { message_delay: Duration::from_secs(60), ..fast_options() });
+    let mut client = harness.connect_ssh();
+    client.read_line();
+    client.send(b"SSH-2.0-OpenSSH_9.6\r\n");
+    harness.wait_for("the input to be counted", |h| h.stats.bytes_received.load(Ordering::Relaxed) == 21);
+}
+
+#[test]
+fn clients_that_hang_up_are_closed_straight_away() {
+    let harness = Harness::start(EndlesshOptions { message_delay: Duration::from_secs(60), ..fast_options() });
+    let mut client = harness.connect_ssh();
+    client.read_line();
+    drop(client);
+    harness.wait_for("the client to be closed", |h| h.stats.connections_closed.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn partly_written_lines_are_finished_before_the_next() {
+    let written = Rc::new(RefCell::new(Vec::new()));
+    let options = fast_options();
+    let delay = options.message_delay;
+    let mut tarpit = Tarpit::new(options, Arc::new(EndlesshStats::default())).unwrap();
+    let mut now = Instant::now();
+    tarpit.trap(TrickleStream { room: 10, written: written.clone() }, "192.0.2.1:22".parse().unwrap(), None, &now);
+    for _ in 0..4 {
+        tarpit.wakeup(&now, |_| {});
+        now += delay + Duration::from_millis(1);
+    }
+
+    let written = String::from_utf8(written.take()).unwrap();
+    let lines: Vec<&str> = written.split_terminator('\n').collect();
+    assert!(lines.len() == 2 && lines.iter().all(|line| line.len() == 16), "unexpected output {:?}", written);
+    assert_eq!(tarpit.stats().partial_writes.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn accepts_beyond_the_rate_wait_in_the_backlog() {
+    let harness = Harness::start(EndlesshOptions { accept_rate: Some(4), accept_burst: Some(1), ..fast_options() });
+    let _clients: Vec<_> = (0..3).map(|_| harness.connect_ssh()).collect();
+    harness.wait_for("the first client to be trapped", |h| h.stats.connections_opened.load(Ordering::Relaxed) >= 1);
+    // the other two take a quarter of a second each
+    assert!(harness.stats.connections_opened.load(Ordering::Relaxed) < 3);
+    harness.wait_for("the rest to be trapped", |h| h.stats.connections_opened.load(Ordering::Relaxed) == 3);
+    assert!(harness.stats.accepts_deferred.load(Ordering::Relaxed) >= 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn sigusr1_lists_the_trapped_clients_longest_trapped_first() {
+    use std::io::Read;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_endlessh-rs"))
+        .args(["--ssh-listen-address", "127.0.0.1:0", "--ssh-message-delay-ms", "50", "--log-format", "json"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(&line.unwrap()).unwrap());
+    let address = lines.find_map(|line| line["address"].as_str().map(str::to_string)).unwrap();
+    let mut clients: Vec<TcpStream> = Vec::new();
+    for _ in 0..2 {
+        let mut client = TcpStream::connect(&address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        // two lines, so the first is trapped for longer and has been sent more
+        client.read_exact(&mut [0; 1]).unwrap();
+        thread::sleep(Duration::from_millis(60));
+        clients.push(client);
+    }
+
+    // SAFETY: signalling a child that hasn't been waited on yet
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGUSR1) };
+    let listing = lines.find(|line| line["message"] == "SIGUSR1 received, listing 2 trapped clients").unwrap();
+    assert_eq!(listing["clients"], 2);
+    let listed: Vec<_> = lines.by_ref().take(2).collect();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let peers: Vec<String> = clients.iter().map(|client| client.local_addr().unwrap().to_string()).collect();
+    assert_eq!(listed.iter().map(|client| client["peer"].as_str().unwrap()).collect::<Vec<_>>(), peers);
+    for client in &listed {
+        assert_eq!(client["message"], "trapped client");
+        assert!(client["lines_sent"].as_u64().unwrap() >= 1 && client["bytes_sent"].as_u64().unwrap() > 0, "{}", client);
+        assert_eq!(client["chatty"], false);
+        assert!(client["connected_time_seconds"].as_u64().unwrap() > 1_000_000_000);
+    }
+    assert!(listed[0]["lines_sent"].as_u64() > listed[1]["lines_sent"].as_u64(), "{:?}", listed);
+}
+
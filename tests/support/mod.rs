@@ -0,0 +1,352 @@
+//! in-process harness for end to end tests
+//!
+//! runs an ssh tarpit and a metric server on ephemeral loopback ports in a background thread, driven by the same
+//! poll/wakeup cycle the binary uses, plus small blocking clients to poke at them
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::cell::RefCell;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use mio::net::TcpListener;
+use mio::{Events, Poll, Token};
+
+use endlessh_rs::endlessh::{EndlesshOptions, EndlesshServer, EndlesshStats};
+use endlessh_rs::exposition::Exposition;
+use endlessh_rs::health;
+use endlessh_rs::metrics::{MetricServer, MetricServerOptions, MetricSource};
+use endlessh_rs::process;
+use endlessh_rs::socket::{ListenSocket, TrappedStream};
+use endlessh_rs::store::{self, ConnectionStore, HistoryQuery, MemoryStore};
+#[cfg(feature = "metrics-tls")]
+use {
+    endlessh_rs::tls,
+    rustls::pki_types::{pem::PemObject, CertificateDer},
+};
+
+const SSH_TOKEN: Token = Token(0);
+const METRIC_TOKEN: Token = Token(1);
+const METRIC_MAX_CLIENTS: usize = 8;
+/// listeners added with [Harness::start_with_listeners] are registered from here up
+const EXTRA_SSH_TOKEN_START: usize = 100;
+/// trapped clients are registered from here up
+const SSH_CLIENT_TOKEN_START: usize = 1000;
+const SSH_MAX_WATCHED_CLIENTS: usize = 64;
+/// upper bound on how long the loop sleeps, so a stop request is noticed promptly
+const MAX_POLL_TIMEOUT: Duration = Duration::from_millis(20);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const HISTORY_LENGTH: usize = 100;
+
+/// serves whatever the server itself knows - the binary layers labels, time series and health on top of this
+struct HarnessSource<'a> {
+    endlessh_server: &'a EndlesshServer,
+    history: &'a MemoryStore,
+    timeseries: &'a Mutex<String>,
+}
+
+impl MetricSource for HarnessSource<'_> {
+    fn metrics(&self) -> String {
+        let mut exposition = Exposition::new();
+        self.endlessh_server.stats().expose(&mut exposition, &"");
+        process::expose(&mut exposition, &"", self.endlessh_server.stats().started_at);
+        exposition.finish()
+    }
+
+    fn stats_json(&self) -> String {
+        serde_json::to_string(&**self.endlessh_server.stats()).expect("failed to serialize stats")
+    }
+
+    fn timeseries_json(&self) -> String {
+        self.timeseries.lock().unwrap().clone()
+    }
+
+    fn recent_json(&self) -> String {
+        serde_json::to_string(&self.history.recent(HISTORY_LENGTH).unwrap()).expect("failed to serialize recent events")
+    }
+
+    fn history_json(&self, query: &HistoryQuery) -> Option<String> {
+        Some(serde_json::to_string(&store::page(self.history, query).unwrap()).expect("failed to serialize history page"))
+    }
+
+    fn clients_json(&self) -> String {
+        serde_json::to_string(&self.endlessh_server.schedule(&Instant::now())).expect("failed to serialize clients")
+    }
+
+    fn healthz(&self) -> String {
+        r#"{"status":"alive"}"#.to_string()
+    }
+
+    fn readyz(&self) -> (bool, String) {
+        health::readiness(self.endlessh_server, None)
+    }
+}
+
+/// addresses and shared state handed back from the server thread once it's listening
+struct Started {
+    ssh_address: SocketAddr,
+    metrics_address: SocketAddr,
+    stats: Arc<EndlesshStats>,
+}
+
+pub struct Harness {
+    pub ssh_address: SocketAddr,
+    pub metrics_address: SocketAddr,
+    pub stats: Arc<EndlesshStats>,
+    /// what /timeseries serves, the harness takes no samples of its own
+    pub timeseries: Arc<Mutex<String>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Harness {
+    pub fn start(options: EndlesshOptions) -> Self {
+        Self::start_with(options, |_| {})
+    }
+
+    /// like start, with `configure` run on the server before it starts listening, e.g. to add accept policies
+    pub fn start_with(options: EndlesshOptions, configure: impl FnOnce(&mut EndlesshServer) + Send + 'static) -> Self {
+        Self::start_serving(options, MetricServerOptions::default(), Vec::new(), configure)
+    }
+
+    /// like start, also trapping clients from each of `listeners` under its name
+    pub fn start_with_listeners(options: EndlesshOptions, listeners: Vec<(ListenSocket, Option<&'static str>)>) -> Self {
+        Self::start_serving(options, MetricServerOptions::default(), listeners, |_| {})
+    }
+
+    /// like start, with the metric server's own options, e.g. to serve https
+    pub fn start_with_metric_options(options: EndlesshOptions, metric_options: MetricServerOptions) -> Self {
+        Self::start_serving(options, metric_options, Vec::new(), |_| {})
+    }
+
+    fn start_serving(options: EndlesshOptions, metric_options: MetricServerOptions, listeners: Vec<(ListenSocket, Option<&'static str>)>, configure: impl FnOnce(&mut EndlesshServer) + Send + 'static) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (started_sender, started_receiver) = mpsc::channel();
+        let thread_stop = stop.clone();
+        let timeseries = Arc::new(Mutex::new("[]".to_string()));
+        let thread_timeseries = timeseries.clone();
+        let thread = thread::spawn(move || run(options, metric_options, listeners, configure, thread_timeseries, thread_stop, started_sender));
+        let started: Started = started_receiver.recv_timeout(CLIENT_TIMEOUT).expect("harness failed to start");
+        Harness {
+            ssh_address: started.ssh_address,
+            metrics_address: started.metrics_address,
+            stats: started.stats,
+            timeseries,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn connect_ssh(&self) -> SshClient {
+        SshClient::connect(self.ssh_address)
+    }
+
+    /// sends a GET for `path` and returns the status code and body
+    pub fn scrape(&self, path: &str) -> (u16, String) {
+        let response = self.request(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes());
+        parse_response(&response)
+    }
+
+    /// writes raw bytes to the metric server and returns everything it sends back before closing
+    pub fn request(&self, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(self.metrics_address).expect("failed to connect to metric server");
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        // the server may answer and hang up before reading all of an oversized request
+        let _ = stream.write_all(request);
+        let mut response = Vec::new();
+        let mut chunk = [0_u8; 4096];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => response.extend_from_slice(&chunk[..n]),
+                // closing with unread request bytes resets the connection, possibly after the response arrived
+                Err(e) if !response.is_empty() && e.kind() == ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("failed to read response: {}", e),
+            }
+        }
+        String::from_utf8(response).expect("response was not utf-8")
+    }
+
+    /// a tls connection to the metric server, trusting the test ca - see [tls_options]
+    #[cfg(feature = "metrics-tls")]
+    pub fn connect_https(&self) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(fixture("metrics-tls-ca.pem")).unwrap()).unwrap();
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection = rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let stream = TcpStream::connect(self.metrics_address).expect("failed to connect to metric server");
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        rustls::StreamOwned::new(connection, stream)
+    }
+
+    /// polls `condition` until it holds, panicking after a few seconds
+    pub fn wait_for(&self, what: &str, condition: impl Fn(&Self) -> bool) {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        while !condition(self) {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            // don't double panic if the test is already failing
+            if thread.join().is_err() && !thread::panicking() {
+                panic!("harness event loop panicked");
+            }
+        }
+    }
+}
+
+fn run(options: EndlesshOptions, metric_options: MetricServerOptions, listeners: Vec<(ListenSocket, Option<&'static str>)>, configure: impl FnOnce(&mut EndlesshServer), timeseries: Arc<Mutex<String>>, stop: Arc<AtomicBool>, started: mpsc::Sender<Started>) {
+    let mut poll = Poll::new().unwrap();
+    let mut events = Events::with_capacity(128);
+
+    let ssh_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let ssh_address = ssh_listener.local_addr().unwrap();
+    let mut endlessh_server = EndlesshServer::create(options, ssh_listener, None, SSH_TOKEN, &poll).unwrap();
+    for (i, (listener, name)) in listeners.into_iter().enumerate() {
+        endlessh_server.add_listener(listener, name, Token(EXTRA_SSH_TOKEN_START + i), &poll).unwrap();
+    }
+    endlessh_server.watch_clients(SSH_CLIENT_TOKEN_START..SSH_CLIENT_TOKEN_START + SSH_MAX_WATCHED_CLIENTS, &poll).unwrap();
+    configure(&mut endlessh_server);
+
+    let metrics_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let metrics_address = metrics_listener.local_addr().unwrap();
+    let client_tokens = METRIC_TOKEN.0 + 1..METRIC_TOKEN.0 + 1 + METRIC_MAX_CLIENTS;
+    let mut metric_server = MetricServer::new_tcp(&poll, metrics_listener, METRIC_TOKEN, client_tokens, metric_options).unwrap();
+
+    started.send(Started {
+        ssh_address,
+        metrics_address,
+        stats: endlessh_server.stats().clone(),
+    }).unwrap();
+
+    let mut history = MemoryStore::new(HISTORY_LENGTH);
+    let mut timeout = Some(Duration::ZERO);
+    while !stop.load(Ordering::Relaxed) {
+        let capped = timeout.map_or(MAX_POLL_TIMEOUT, |t| t.min(MAX_POLL_TIMEOUT));
+        poll.poll(&mut events, Some(capped)).unwrap();
+        let loop_time = Instant::now();
+        for event in events.iter() {
+            if endlessh_server.try_handle_event(event, &loop_time) {
+                continue;
+            }
+            let source = HarnessSource { endlessh_server: &endlessh_server, history: &history, timeseries: &timeseries };
+            assert!(metric_server.try_handle_event(event, &mut poll, &source, &loop_time).unwrap(), "unexpected token {}", event.token().0);
+        }
+        timeout = endlessh_server.handle_wakeup(&loop_time);
+        if let Some(metric_timeout) = metric_server.handle_wakeup(&mut poll, &loop_time).unwrap() {
+            timeout = Some(timeout.map_or(metric_timeout, |t| t.min(metric_timeout)));
+        }
+        for event in endlessh_server.drain_events() {
+            history.append(&event).unwrap();
+        }
+    }
+}
+
+/// a file under tests/fixtures
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// serving https with a leaf for localhost signed by the test ca
+#[cfg(feature = "metrics-tls")]
+pub fn tls_options() -> MetricServerOptions {
+    MetricServerOptions {
+        tls: Some(tls::load_server_config(&fixture("metrics-tls-cert.pem"), &fixture("metrics-tls-key.pem")).unwrap()),
+        ..MetricServerOptions::default()
+    }
+}
+
+/// reads to the end a little at a time, after letting the server fill its socket up
+pub fn read_slowly(stream: &mut impl Read) -> String {
+    thread::sleep(Duration::from_millis(200));
+    let mut response = Vec::new();
+    let mut chunk = [0_u8; 64 * 1024];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&chunk[..n]),
+            Err(e) => panic!("failed to read response: {}", e),
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    String::from_utf8(response).expect("response was not utf-8")
+}
+
+/// splits a raw http response into its status code and body
+pub fn parse_response(response: &str) -> (u16, String) {
+    let status = response.split(' ').nth(1).and_then(|code| code.parse().ok()).expect("malformed status line");
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    (status, body.to_string())
+}
+
+/// a victim connected to the tarpit
+pub struct SshClient {
+    reader: BufReader<TcpStream>,
+}
+
+impl SshClient {
+    pub fn connect(address: SocketAddr) -> Self {
+        let stream = TcpStream::connect(address).expect("failed to connect to ssh server");
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        SshClient {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    /// writes raw bytes to the tarpit, e.g. a PROXY header
+    pub fn send(&mut self, data: &[u8]) {
+        self.reader.get_mut().write_all(data).expect("failed to write to ssh server");
+    }
+
+    /// blocks until the next banner line arrives, returning it without the newline
+    pub fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("failed to read banner line");
+        assert!(line.ends_with('\n'), "connection closed mid line: {:?}", line);
+        line.pop();
+        line
+    }
+}
+
+/// a stream for driving a [Tarpit](endlessh_rs::endlessh::Tarpit) directly, whose socket buffer only ever has room
+/// for a few bytes at a time. what it's written is kept in `written`
+pub struct TrickleStream {
+    pub room: usize,
+    pub written: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Read for TrickleStream {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for TrickleStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.room);
+        self.written.borrow_mut().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TrappedStream for TrickleStream {}